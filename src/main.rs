@@ -4,12 +4,38 @@ use tokio::stream::StreamExt;
 
 mod redis;
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    let mut listener = TcpListener::bind("127.0.0.1:6379").await?;
+fn main() -> io::Result<()> {
+    let config = match redis::Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // Scripts run on the runtime's threads, with their stack.
+    tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .thread_stack_size(redis::STACK_SIZE)
+        .build()?
+        .block_on(serve(config))
+}
+
+async fn serve(config: redis::Config) -> io::Result<()> {
+    let server = match redis::Server::new(&config) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Fatal error loading the DB: {}. Exiting.", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Bind through std: mio 0.6 converts socket addresses by transmuting std's
+    // layout, which no longer matches libc on newer toolchains.
+    let listener = std::net::TcpListener::bind("127.0.0.1:6379")?;
+    let mut listener = TcpListener::from_std(listener)?;
 
     let mut incoming = listener.incoming();
-    let server = redis::Server::new();
 
     while let Some(stream) = incoming.next().await {
         match stream {
@@ -1,14 +1,147 @@
 use std::convert::TryInto;
-use std::io;
+use std::io::{self, Write};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
 use tokio::sync::Mutex;
 
+mod aof;
+mod bitfield;
+mod bitmaps;
+mod blocking;
+mod command;
+mod config;
+mod crc64;
+mod databases;
+mod dict;
+mod float;
+mod functions;
+mod geo;
+mod geohash;
+mod glob;
+mod hashes;
+mod hyperloglog;
+mod keys;
+mod lists;
+mod lua;
+mod lzf;
+mod manifest;
+mod notify;
+mod persistence;
+mod pubsub;
+mod random;
+mod ranges;
+mod rdb;
+mod scan;
+mod scripting;
+mod server;
+mod sets;
+mod sha1;
+mod skiplist;
+mod sort;
+mod stats;
+mod storage;
+mod streams;
+mod strings;
+mod transaction;
+mod zsets;
+
+use blocking::BlockingCommand;
+use command::Command;
+pub use config::Config;
+use functions::FunctionCommand;
+pub use lua::STACK_SIZE;
+use persistence::Persistence;
+use pubsub::{SubscribeCommand, Subscriptions};
+use scripting::ScriptCommand;
+use server::ServerCommand;
+use storage::Store;
+use transaction::{Transaction, TransactionCommand, Watches};
+
 #[derive(Debug)]
 pub enum Error {
     Io(std::io::Error),
     Argument(String),
     TryFromInt(std::num::TryFromIntError),
+    WrongArity(String),
+    Syntax,
+    /// A command no family parses.
+    UnknownCommand(String),
+    NotInteger,
+    NotFloat,
+    Overflow,
+    InvalidExpire(String),
+    WrongType,
+    BusyKey,
+    BusyGroup,
+    NoGroup(String),
+    InvalidHll,
+    CorruptHll,
+    /// A command other than the few allowed once a connection subscribes.
+    Subscribed(String),
+    ExecAbort,
+    NoScript,
+    /// An error raised by a script, with its code and where it was raised.
+    Script(String),
+    /// A command sent while a script runs past the busy reply threshold.
+    Busy,
+    NotBusy,
+    Unkillable,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "ERR {}", e),
+            Error::Argument(message) => write!(f, "ERR {}", message),
+            Error::TryFromInt(e) => write!(f, "ERR {}", e),
+            Error::WrongArity(command) => {
+                write!(f, "ERR wrong number of arguments for '{}' command", command)
+            }
+            Error::Syntax => write!(f, "ERR syntax error"),
+            Error::UnknownCommand(command) => write!(f, "ERR not implemented: {}", command),
+            Error::NotInteger => write!(f, "ERR value is not an integer or out of range"),
+            Error::NotFloat => write!(f, "ERR value is not a valid float"),
+            Error::Overflow => write!(f, "ERR increment or decrement would overflow"),
+            Error::InvalidExpire(command) => {
+                write!(f, "ERR invalid expire time in '{}' command", command)
+            }
+            Error::WrongType => write!(
+                f,
+                "WRONGTYPE Operation against a key holding the wrong kind of value"
+            ),
+            Error::BusyKey => write!(f, "BUSYKEY Target key name already exists."),
+            Error::BusyGroup => write!(f, "BUSYGROUP Consumer Group name already exists"),
+            Error::NoGroup(message) => write!(f, "NOGROUP {}", message),
+            Error::InvalidHll => {
+                write!(f, "WRONGTYPE Key is not a valid HyperLogLog string value.")
+            }
+            Error::CorruptHll => write!(f, "INVALIDOBJ Corrupted HLL object detected"),
+            Error::Subscribed(command) => write!(
+                f,
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                command
+            ),
+            Error::ExecAbort => write!(
+                f,
+                "EXECABORT Transaction discarded because of previous errors."
+            ),
+            Error::NoScript => write!(f, "NOSCRIPT No matching script. Please use EVAL."),
+            Error::Script(message) => write!(f, "{}", message),
+            Error::Busy => write!(
+                f,
+                "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE."
+            ),
+            Error::NotBusy => write!(f, "NOTBUSY No scripts in execution right now."),
+            Error::Unkillable => write!(
+                f,
+                "UNKILLABLE Sorry the script already executed write commands against the dataset. \
+                 You can either wait the script termination or kill the server in a hard way using \
+                 the SHUTDOWN NOSAVE command."
+            ),
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -26,12 +159,31 @@ impl From<std::num::TryFromIntError> for Error {
 #[derive(Clone)]
 enum Value {
     Nil,
+    /// The null reply of commands that otherwise answer with an array.
+    NilArray,
     Int(i64),
-    String(String),
+    String(Vec<u8>),
+    Status(String),
+    Error(String),
     Array(usize, Vec<Value>),
+    /// Key/value pairs, sent as a flat array of alternating keys and values
+    /// to clients speaking RESP2.
+    Map(Vec<(Value, Value)>),
 }
 
 impl Value {
+    fn array(data: Vec<Value>) -> Value {
+        Value::Array(data.len(), data)
+    }
+
+    fn bulk(data: &str) -> Value {
+        Value::String(data.as_bytes().to_vec())
+    }
+
+    fn ok() -> Value {
+        Value::Status("OK".to_owned())
+    }
+
     fn is_complete(&self) -> bool {
         match &self {
             Value::Array(size, data) => *size == data.len() && data.iter().all(Value::is_complete),
@@ -39,6 +191,31 @@ impl Value {
         }
     }
 
+    fn to_int(&self) -> Result<i64, Error> {
+        match self {
+            Value::Int(n) => Ok(*n),
+            Value::String(data) => parse_int(data).ok_or(Error::NotInteger),
+            _ => Err(Error::NotInteger),
+        }
+    }
+
+    fn to_float(&self) -> Result<f64, Error> {
+        match self {
+            Value::Int(n) => Ok(*n as f64),
+            Value::String(data) => float::parse(data).ok_or(Error::NotFloat),
+            _ => Err(Error::NotFloat),
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            Value::Int(n) => Ok(n.to_string().into_bytes()),
+            Value::String(data) => Ok(data.clone()),
+            Value::Status(data) => Ok(data.clone().into_bytes()),
+            _ => Err(Error::Argument("value is not a string".to_owned())),
+        }
+    }
+
     fn append(&mut self, val: Value) {
         assert!(!self.is_complete());
 
@@ -53,155 +230,118 @@ impl Value {
             panic!("only array types can be appended with a value");
         }
     }
-}
 
-impl std::fmt::Display for Value {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    /// Serializes the value in RESP wire format.
+    fn encode(&self, out: &mut Vec<u8>) {
         match self {
-            Value::Array(size, data) => write!(
-                f,
-                "*{}{}\r\n",
-                size,
-                data.iter()
-                    .map(std::string::ToString::to_string)
-                    .collect::<String>()
-            ),
-            Value::String(data) => write!(f, "${}\r\n{}\r\n", data.as_bytes().len(), data),
-            Value::Int(n) => write!(f, ":{}\r\n", n),
-            Value::Nil => write!(f, "$-1\r\n"),
+            Value::Array(size, data) => {
+                encode_header(out, b'*', *size);
+                for value in data {
+                    value.encode(out);
+                }
+            }
+            Value::Map(entries) => {
+                encode_header(out, b'*', entries.len() * 2);
+                for (key, value) in entries {
+                    key.encode(out);
+                    value.encode(out);
+                }
+            }
+            Value::String(data) => {
+                encode_header(out, b'$', data.len());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Value::Status(message) => {
+                out.push(b'+');
+                out.extend_from_slice(message.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Value::Error(message) => {
+                out.push(b'-');
+                out.extend_from_slice(message.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Value::Int(n) => {
+                out.push(b':');
+                let _ = write!(out, "{}\r\n", n);
+            }
+            Value::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Value::NilArray => out.extend_from_slice(b"*-1\r\n"),
         }
     }
 }
 
-enum Command {
-    Ping,
-    Echo(String),
-    Get(String),
-    Set(String, Value, Option<std::time::Instant>),
-}
-
-impl Command {
-    fn from_value(value: Value) -> Result<Command, Error> {
-        match value {
-            Value::Array(_, data) => Command::from_array(data),
-            Value::String(data) => Command::from_string(&data),
-            _ => Err(Error::Argument("wrong argument type".to_owned())),
-        }
+/// Writes the type byte and length line that aggregates and bulk strings
+/// start with, formatting straight into the buffer.
+/// Parses an integer the way Redis's string2ll does, accepting only the
+/// canonical form: an optional `-`, no `+`, no spaces and no leading zeros.
+fn parse_int(data: &[u8]) -> Option<i64> {
+    let (negative, digits) = match data.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        _ => (false, data),
+    };
+    match digits {
+        [] => return None,
+        [b'0'] if !negative => return Some(0),
+        [b'1'..=b'9', ..] => {}
+        _ => return None,
     }
-
-    fn from_string(data: &str) -> Result<Command, Error> {
-        match data.to_lowercase().as_str() {
-            "ping" => Ok(Command::Ping),
-            _ => Err(Error::Argument(format!("not implemented: {}", data))),
+    // Accumulating downwards reaches i64::MIN without overflowing.
+    let mut value: i64 = 0;
+    for &digit in digits {
+        if !digit.is_ascii_digit() {
+            return None;
         }
+        value = value
+            .checked_mul(10)?
+            .checked_sub(i64::from(digit - b'0'))?;
     }
-
-    fn from_array(data: Vec<Value>) -> Result<Command, Error> {
-        if data.is_empty() {
-            return Err(Error::Argument("empty command".to_owned()));
-        }
-
-        match &data[0] {
-            Value::String(command) => match command.to_lowercase().as_str() {
-                "ping" => Ok(Command::Ping),
-                "echo" => Command::echo(data),
-                "get" => Command::get(data),
-                "set" => Command::set(data, None),
-                _ => Err(Error::Argument(format!("not implemented: {}", command))),
-            },
-            _ => Err(Error::Argument("wrong argument type".to_owned())),
-        }
-    }
-
-    fn echo(mut data: Vec<Value>) -> Result<Command, Error> {
-        if let Some(Value::String(data)) = data.pop() {
-            Ok(Command::Echo(data))
-        } else {
-            Err(Error::Argument("ECHO: wrong argument type".to_owned()))
-        }
-    }
-
-    fn get(mut data: Vec<Value>) -> Result<Command, Error> {
-        if let Some(Value::String(data)) = data.pop() {
-            Ok(Command::Get(data))
-        } else {
-            Err(Error::Argument("GET: wrong argument type".to_owned()))
-        }
-    }
-
-    fn set(mut data: Vec<Value>, expiry: Option<std::time::Instant>) -> Result<Command, Error> {
-        if data.len() < 3 {
-            return Err(Error::Argument(
-                format! {"not enough arguments for set: {}", data.len()},
-            ));
-        }
-        if data.len() > 3 {
-            return Command::set_with_flags(data);
-        }
-
-        let value = data.pop().unwrap();
-        if let Value::String(name) = data.pop().unwrap() {
-            Ok(Command::Set(name, value, expiry))
-        } else {
-            Err(Error::Argument("SET: wrong argument type".to_owned()))
-        }
-    }
-
-    fn set_with_flags(mut data: Vec<Value>) -> Result<Command, Error> {
-        let arg = data.pop().unwrap();
-        if let Value::String(flag) = data.pop().unwrap() {
-            match flag.to_lowercase().as_str() {
-                "px" => {
-                    let duration = if let Value::Int(duration) = arg {
-                        duration.try_into()?
-                    } else if let Value::String(duration) = arg {
-                        duration.parse::<u64>().unwrap()
-                    } else {
-                        return Err(Error::Argument("SET: wrong argument type".to_owned()));
-                    };
-                    let expiry =
-                        std::time::Instant::now() + std::time::Duration::from_millis(duration);
-                    Command::set(data, Some(expiry))
-                }
-                _ => Err(Error::Argument(format!(
-                    "SET: flag not implemented: {}",
-                    flag
-                ))),
-            }
-        } else {
-            Err(Error::Argument("SET: wrong argument type".to_owned()))
-        }
+    if negative {
+        Some(value)
+    } else {
+        value.checked_neg()
     }
 }
 
-pub struct StoredValue {
-    value: Value,
-    expiry: Option<std::time::Instant>,
+fn encode_header(out: &mut Vec<u8>, kind: u8, len: usize) {
+    out.push(kind);
+    let _ = write!(out, "{}\r\n", len);
 }
 
-impl StoredValue {
-    fn expired(&self) -> bool {
-        if let Some(expiry) = self.expiry {
-            return expiry < std::time::Instant::now();
-        }
-        false
-    }
-}
+/// How much of an array reply is buffered before it is written out.
+const REPLY_CHUNK_SIZE: usize = 16 * 1024;
+
+/// How often the background task looks for expired keys, like Redis' `hz`.
+const EXPIRE_CYCLE_INTERVAL: Duration = Duration::from_millis(100);
 
 pub struct Server {
     storage: Storage,
+    busy_reply_threshold: Duration,
+    appendonly: bool,
 }
 
 impl Server {
-    pub fn new() -> Server {
-        let storage = Arc::new(Mutex::new(std::collections::HashMap::new()));
+    /// Sets up the dataset, loading it from the RDB file if there is one.
+    pub fn new(config: &Config) -> Result<Server, String> {
+        let mut store = Store::new(
+            config.databases,
+            config.notify_keyspace_events,
+            Persistence::new(config),
+        );
+        persistence::load(&mut store)?;
+        let storage = Arc::new(Mutex::new(store));
         {
             let storage = storage.clone();
             tokio::spawn(async move {
                 Server::gc(storage).await;
             });
         }
-        Server { storage }
+        Ok(Server {
+            storage,
+            busy_reply_threshold: config.busy_reply_threshold,
+            appendonly: config.appendonly,
+        })
     }
 
     pub fn worker<R>(&self, stream: R) -> Worker<R>
@@ -214,17 +354,30 @@ impl Server {
         Worker {
             stream,
             storage: self.storage.clone(),
+            db: 0,
+            subscriptions: Subscriptions::new(),
+            transaction: None,
+            watches: Watches::new(),
+            busy_reply_threshold: self.busy_reply_threshold,
+            appendonly: self.appendonly,
         }
     }
 
     async fn gc(storage: Storage) {
         loop {
-            storage.lock().await.retain(|_, ref mut v| !v.expired());
+            tokio::time::delay_for(EXPIRE_CYCLE_INTERVAL).await;
+            let mut storage = storage.lock().await;
+            storage.expire_cycle();
+            storage.publish_events();
+            persistence::check_rules(&mut storage);
+            persistence::check_rewrite(&mut storage);
+            // Writes held back for an fsync go out once it is done.
+            storage.persistence().write_aof();
         }
     }
 }
 
-type Storage = Arc<Mutex<std::collections::HashMap<String, StoredValue>>>;
+type Storage = Arc<Mutex<Store>>;
 
 pub struct Worker<R>
 where
@@ -235,6 +388,15 @@ where
 {
     stream: R,
     storage: Storage,
+    /// The database selected with SELECT.
+    db: usize,
+    subscriptions: Subscriptions,
+    /// The commands queued since MULTI, until EXEC or DISCARD.
+    transaction: Option<Transaction>,
+    watches: Watches,
+    busy_reply_threshold: Duration,
+    /// Whether write commands keep their arguments, for the AOF.
+    appendonly: bool,
 }
 
 impl<R> Worker<R>
@@ -245,42 +407,309 @@ where
         + std::marker::Unpin,
 {
     pub async fn run(mut self) -> Result<(), Error> {
+        let result = self.serve().await;
+        self.reset().await;
+        result
+    }
+
+    /// Answers commands and writes out the messages pushed meanwhile, until
+    /// the connection fails or closes.
+    async fn serve(&mut self) -> Result<(), Error> {
         loop {
-            self.process_message().await?;
+            // Waiting for input rather than reading it keeps a message that
+            // arrives halfway through a command from cutting it short.
+            tokio::select! {
+                readable = Worker::readable(&mut self.stream) => {
+                    readable?;
+                    if !self.process_message().await? {
+                        return Ok(());
+                    }
+                }
+                Some(message) = self.subscriptions.messages.recv() => {
+                    self.send_response(&message).await?;
+                }
+            }
         }
     }
 
-    pub async fn process_message(&mut self) -> Result<(), Error> {
+    /// Answers one command, returning whether the connection stays open.
+    pub async fn process_message(&mut self) -> Result<bool, Error> {
         let message = self.read_message().await?;
-        let command = Command::from_value(message)?;
-        let response = match command {
-            Command::Ping => Value::String("PONG".to_string()),
-            Command::Echo(data) => Value::String(data),
-            Command::Get(name) => self.get_value(&name).await,
-            Command::Set(name, value, expiry) => {
-                self.storage
-                    .lock()
-                    .await
-                    .insert(name, StoredValue { value, expiry });
-                Value::String("OK".to_string())
+        // Every connection speaks RESP2, which cannot tell messages pushed
+        // to subscribers from replies, so subscribing limits the commands.
+        let subscribed = !self.subscriptions.is_empty();
+        let name = command::name(&message);
+        let arguments = if self.appendonly && command::is_write(&name) {
+            Some(aof::arguments(&message))
+        } else {
+            None
+        };
+        let response = match Command::from_value(message) {
+            Ok(command) if subscribed && !command.allowed_subscribed() => {
+                Err(Error::Subscribed(name))
+            }
+            // These two must not wait for the lock a busy script holds.
+            Ok(Command::Script(ScriptCommand::Kill))
+            | Ok(Command::Function(FunctionCommand::Kill))
+                if self.transaction.is_none() =>
+            {
+                scripting::kill()
+            }
+            Ok(Command::Server(ServerCommand::Shutdown { save: Some(false) })) => {
+                server::shutdown()
+            }
+            Ok(command)
+                if !matches!(command, Command::Quit)
+                    && scripting::busy(self.busy_reply_threshold) =>
+            {
+                Err(self.abort(Error::Busy))
+            }
+            Ok(Command::Transaction(command)) => self.transact(command).await,
+            Ok(Command::Quit) => {
+                self.send_response(&Value::ok()).await?;
+                return Ok(false);
+            }
+            Ok(Command::Reset) => {
+                self.reset().await;
+                Ok(Value::Status("RESET".to_owned()))
+            }
+            Ok(Command::Subscribe(_)) if self.transaction.is_some() => Err(self.abort(
+                Error::Argument("Command not allowed inside a transaction".to_owned()),
+            )),
+            Ok(Command::Subscribe(command)) => {
+                self.subscribe(command).await?;
+                return Ok(true);
+            }
+            Ok(Command::Ping(message)) if subscribed => Ok(Value::array(vec![
+                Value::bulk("pong"),
+                Value::String(message.unwrap_or_default()),
+            ])),
+            Ok(command) => match &mut self.transaction {
+                Some(transaction) => {
+                    transaction.queue(command, arguments);
+                    Ok(Value::Status("QUEUED".to_owned()))
+                }
+                None => self.execute(command, arguments).await,
+            },
+            Err(e) => Err(self.abort(e)),
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(Error::Io(e)) => return Err(Error::Io(e)),
+            Err(e) => Value::Error(e.to_string()),
+        };
+        self.send_response(&response).await?;
+        Ok(true)
+    }
+
+    /// Puts the connection back in the state it started in.
+    async fn reset(&mut self) {
+        self.transaction = None;
+        self.db = 0;
+        if !self.subscriptions.is_empty() || !self.watches.is_empty() {
+            let mut storage = self.storage.lock().await;
+            self.subscriptions.clear(storage.pubsub());
+            self.watches.clear(&mut storage);
+        }
+    }
+
+    async fn transact(&mut self, command: TransactionCommand) -> Result<Value, Error> {
+        match command {
+            TransactionCommand::Multi => {
+                if self.transaction.is_some() {
+                    return Err(Error::Argument("MULTI calls can not be nested".to_owned()));
+                }
+                self.transaction = Some(Transaction::default());
+                Ok(Value::ok())
+            }
+            TransactionCommand::Exec => match self.transaction.take() {
+                Some(transaction) => {
+                    let mut storage = self.storage.lock().await;
+                    let dirty = self.watches.dirty(&storage);
+                    self.watches.clear(&mut storage);
+                    if transaction.aborted() {
+                        return Err(Error::ExecAbort);
+                    }
+                    if dirty {
+                        return Ok(Value::NilArray);
+                    }
+                    Ok(transaction.exec(&mut storage, &mut self.db))
+                }
+                None => Err(Error::Argument("EXEC without MULTI".to_owned())),
+            },
+            TransactionCommand::Discard => match self.transaction.take() {
+                Some(_) => {
+                    self.watches.clear(&mut *self.storage.lock().await);
+                    Ok(Value::ok())
+                }
+                None => Err(Error::Argument("DISCARD without MULTI".to_owned())),
+            },
+            TransactionCommand::Watch(keys) => {
+                if self.transaction.is_some() {
+                    return Err(self.abort(Error::Argument(
+                        "WATCH inside MULTI is not allowed".to_owned(),
+                    )));
+                }
+                let mut storage = self.storage.lock().await;
+                self.watches.watch(&mut storage, self.db, keys);
+                Ok(Value::ok())
             }
+            TransactionCommand::Unwatch => match &mut self.transaction {
+                Some(transaction) => {
+                    transaction.queue(Command::Transaction(TransactionCommand::Unwatch), None);
+                    Ok(Value::Status("QUEUED".to_owned()))
+                }
+                None => {
+                    self.watches.clear(&mut *self.storage.lock().await);
+                    Ok(Value::ok())
+                }
+            },
+        }
+    }
+
+    /// Fails the transaction being queued, if any, for a command it rejected.
+    fn abort(&mut self, error: Error) -> Error {
+        if let Some(transaction) = &mut self.transaction {
+            transaction.abort();
         }
-        .to_string();
-        self.send_response(&response).await
+        error
     }
 
-    async fn get_value(&self, name: &str) -> Value {
-        if let Some(value) = self.storage.lock().await.get(name) {
-            if value.expired() {
-                return Value::Nil;
+    /// Runs a command, with its arguments if they go to the AOF.
+    async fn execute(
+        &mut self,
+        command: Command,
+        arguments: Option<Vec<Vec<u8>>>,
+    ) -> Result<Value, Error> {
+        let command = match command {
+            Command::Block(command) if command.operation.waits() => {
+                return self.block(command, arguments).await
+            }
+            command => command,
+        };
+        let mut storage = self.storage.lock().await;
+        let db = &mut self.db;
+        let response = match command {
+            // Other connections are answered meanwhile, if only with BUSY.
+            Command::Script(_) | Command::Function(_) => {
+                tokio::task::block_in_place(|| aof::execute(&mut storage, db, command, arguments))
             }
-            return value.value.clone();
+            command => aof::execute(&mut storage, db, command, arguments),
+        };
+        storage.serve_blocked();
+        storage.publish_events();
+        storage.persistence().write_aof();
+        response
+    }
+
+    /// Runs a blocking command, waiting for a write to serve it if it cannot
+    /// be served right away.
+    async fn block(
+        &mut self,
+        mut command: BlockingCommand,
+        arguments: Option<Vec<Vec<u8>>>,
+    ) -> Result<Value, Error> {
+        let timeout_reply = command.operation.timeout_reply();
+        let timeout = command.timeout;
+        let (id, mut reply) = {
+            let mut storage = self.storage.lock().await;
+            let served = command
+                .operation
+                .try_execute(storage.database(self.db), false)?;
+            if let Some(response) = served {
+                if let Some(arguments) = arguments {
+                    storage
+                        .persistence()
+                        .propagate(self.db, arguments, &response);
+                }
+                storage.serve_blocked();
+                storage.publish_events();
+                storage.persistence().write_aof();
+                return Ok(response);
+            }
+            storage.block(self.db, command.operation, arguments)
+        };
+        let received = {
+            let wait = async {
+                match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, &mut reply).await.ok(),
+                    None => Some((&mut reply).await),
+                }
+            };
+            tokio::select! {
+                received = wait => received,
+                _ = Worker::closed(&mut self.stream) => {
+                    self.storage.lock().await.unblock(id);
+                    return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+                }
+            }
+        };
+        if let Some(Ok(response)) = received {
+            return response;
         }
-        Value::Nil
+        // A write may have served the command just before it was given up.
+        let mut storage = self.storage.lock().await;
+        if storage.unblock(id) {
+            return Ok(timeout_reply);
+        }
+        reply.try_recv().unwrap_or(Ok(timeout_reply))
     }
 
-    async fn send_response(&mut self, response: &str) -> Result<(), Error> {
-        self.stream.write_all(response.as_bytes()).await?;
+    /// Changes the subscriptions of the connection, confirming each change
+    /// before any message the change lets through.
+    async fn subscribe(&mut self, command: SubscribeCommand) -> Result<(), Error> {
+        let confirmations = {
+            let mut storage = self.storage.lock().await;
+            command.execute(storage.pubsub(), &mut self.subscriptions)
+        };
+        for confirmation in &confirmations {
+            self.send_response(confirmation).await?;
+        }
+        Ok(())
+    }
+
+    /// Completes once there is input to read, or the peer closed the
+    /// connection.
+    async fn readable(stream: &mut R) -> io::Result<()> {
+        tokio::future::poll_fn(|cx| Pin::new(&mut *stream).poll_fill_buf(cx).map_ok(|_| ())).await
+    }
+
+    /// Completes once the peer has closed the connection. Commands sent
+    /// meanwhile stay buffered until the blocked one is answered.
+    async fn closed(stream: &mut R) {
+        let eof = tokio::future::poll_fn(|cx| {
+            Pin::new(&mut *stream)
+                .poll_fill_buf(cx)
+                .map(|buf| buf.map_or(true, <[u8]>::is_empty))
+        });
+        if !eof.await {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Writes the response out in chunks. Arrays are walked with an explicit
+    /// stack rather than recursively, so large and deeply nested replies are
+    /// neither encoded whole up front nor bounded by the call stack.
+    async fn send_response(&mut self, response: &Value) -> Result<(), Error> {
+        let mut buf = vec![];
+        let mut pending = vec![std::slice::from_ref(response).iter()];
+        while let Some(values) = pending.last_mut() {
+            match values.next() {
+                Some(Value::Array(size, data)) => {
+                    encode_header(&mut buf, b'*', *size);
+                    pending.push(data.iter());
+                }
+                Some(value) => value.encode(&mut buf),
+                None => {
+                    pending.pop();
+                }
+            }
+            if buf.len() >= REPLY_CHUNK_SIZE {
+                self.stream.write_all(&buf).await?;
+                buf.clear();
+            }
+        }
+        self.stream.write_all(&buf).await?;
         self.stream.flush().await?;
         Ok(())
     }
@@ -303,7 +732,7 @@ where
             }
             '$' => {
                 let size = self.read_num::<i64>().await?;
-                if size > 0 {
+                if size >= 0 {
                     let result = self.read_fixed_string(size.try_into()?).await?;
                     Ok(Value::String(result))
                 } else {
@@ -338,24 +767,21 @@ where
             .unwrap())
     }
 
-    async fn read_fixed_string(&mut self, size: usize) -> io::Result<String> {
+    async fn read_fixed_string(&mut self, size: usize) -> io::Result<Vec<u8>> {
         let mut result = vec![0; size];
         self.stream.read_exact(&mut result).await?;
-        let result = result.iter().map(|b| *b as char).collect::<String>();
 
         let mut buf = vec![];
         self.stream.read_until(b'\n', &mut buf).await?;
         Ok(result)
     }
 
-    async fn read_simple_string(&mut self) -> io::Result<String> {
+    async fn read_simple_string(&mut self) -> io::Result<Vec<u8>> {
         let mut buf = vec![];
         self.stream.read_until(b'\n', &mut buf).await?;
-        Ok(buf
-            .iter()
-            .map(|b| *b as char)
-            .collect::<String>()
-            .trim()
-            .to_string())
+        while buf.last().map_or(false, u8::is_ascii_whitespace) {
+            buf.pop();
+        }
+        Ok(buf)
     }
 }
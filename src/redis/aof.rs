@@ -0,0 +1,815 @@
+//! The append-only file: the write commands that changed the dataset, in
+//! RESP as clients send them, replayed at startup to rebuild it. It is made
+//! of several files in the AOF directory, as listed in its manifest: a base
+//! file, and incremental files with the writes since.
+//!
+//! Connections keep the arguments of a write command as they came, and hand
+//! them over once it ran and changed something. Commands whose effect
+//! depends on when or by chance they ran are logged as one that does the
+//! same every time: relative TTLs become deadlines, SPOP becomes SREM of the
+//! members it popped, and XADD names the ID it generated. Scripts and
+//! transactions log the writes they made, as a MULTI/EXEC block.
+//!
+//! How soon what is written reaches the disk is up to the fsync policy.
+//! With `everysec` a task fsyncs the file once a second on the blocking
+//! pool, and writes wait for an fsync still under way, for a while.
+//!
+//! BGREWRITEAOF writes a new base file of the dataset as it was when the
+//! rewrite started, in the background, as an RDB or as the fewest commands
+//! that rebuild it. Writes made meanwhile go to a new incremental file,
+//! which is all that is left of the old ones once the new base is in place.
+
+use super::command::Command;
+use super::float;
+use super::manifest::{Manifest, Part};
+use super::persistence;
+use super::rdb::{self, Snapshot};
+use super::stats::{self, STATS};
+use super::storage::{self, Data, Store};
+use super::{Error, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+/// How long writes may wait for a background fsync before they go ahead
+/// regardless.
+const MAX_POSTPONE_MILLIS: i64 = 2000;
+
+/// How many elements a rewritten RPUSH, SADD, HSET or ZADD adds at most.
+const ITEMS_PER_COMMAND: usize = 64;
+
+/// When what is written to the file is fsynced.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Fsync {
+    /// After every write, before the reply.
+    Always,
+    /// Once a second, in the background.
+    EverySec,
+    /// Whenever the operating system sees fit.
+    No,
+}
+
+impl Fsync {
+    pub fn parse(value: &str) -> Result<Fsync, String> {
+        match value.to_lowercase().as_str() {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::EverySec),
+            "no" => Ok(Fsync::No),
+            _ => Err("argument(s) must be one of the following: always, everysec, no".to_owned()),
+        }
+    }
+}
+
+pub struct Aof {
+    dir: PathBuf,
+    manifest: Manifest,
+    /// The incremental file written to, the last in the manifest.
+    path: PathBuf,
+    file: File,
+    fsync: Fsync,
+    /// Shared with the task fsyncing the file every second.
+    syncing: Arc<Syncing>,
+    /// The database of the last command written, so that a SELECT comes
+    /// before any for another one.
+    db: Option<usize>,
+    /// The commands of the command running, written out once it finishes.
+    pending: Vec<(usize, Vec<Vec<u8>>)>,
+    /// What is yet to be written to the file.
+    buffer: Vec<u8>,
+    /// Since when writing has been waiting for an fsync, in unix
+    /// milliseconds.
+    postponed: Option<i64>,
+    /// The writes that stopped waiting for an fsync.
+    delayed_fsyncs: u64,
+    /// How much there is in the file.
+    size: u64,
+    /// How much there is in the base file.
+    base_size: u64,
+    /// How much there is in the base file and the incremental files before
+    /// this one.
+    earlier_size: u64,
+    /// Whether the last write to the file succeeded.
+    last_write_ok: bool,
+}
+
+struct Syncing {
+    file: File,
+    /// Whether an fsync is under way.
+    running: AtomicBool,
+    /// Whether there were writes since the last fsync started.
+    written: AtomicBool,
+    /// Whether the last fsync succeeded.
+    last_ok: AtomicBool,
+}
+
+impl Aof {
+    /// Opens the last incremental file of the AOF in `dir` for appending,
+    /// adding one to the manifest if there is none.
+    pub fn open(dir: &Path, mut manifest: Manifest, fsync: Fsync) -> io::Result<Aof> {
+        if manifest.incrs.is_empty() {
+            let file = manifest.new_incr();
+            eprintln!("Creating AOF incr file {} on server start", file);
+            manifest.save(dir)?;
+        }
+        let size_of = |part: &Part| fs::metadata(dir.join(&part.file)).map_or(0, |m| m.len());
+        let base_size = manifest.base.as_ref().map_or(0, size_of);
+        let last = manifest.incrs.len() - 1;
+        let earlier_size = base_size + manifest.incrs[..last].iter().map(size_of).sum::<u64>();
+        let path = dir.join(&manifest.incrs[last].file);
+        let (file, syncing) = open_incr(&path, fsync)?;
+        Ok(Aof {
+            dir: dir.to_owned(),
+            manifest,
+            path,
+            size: file.metadata()?.len(),
+            base_size,
+            earlier_size,
+            file,
+            fsync,
+            syncing,
+            db: None,
+            pending: vec![],
+            buffer: vec![],
+            postponed: None,
+            delayed_fsyncs: 0,
+            last_write_ok: true,
+        })
+    }
+
+    /// Whether the last write, and the last fsync, succeeded.
+    pub fn last_write_ok(&self) -> bool {
+        self.last_write_ok && self.syncing.last_ok.load(Ordering::SeqCst)
+    }
+
+    /// The fields of INFO about the file.
+    pub fn info(&self) -> Vec<(&'static str, String)> {
+        vec![
+            (
+                "aof_current_size",
+                (self.earlier_size + self.size).to_string(),
+            ),
+            ("aof_base_size", self.base_size.to_string()),
+            ("aof_buffer_length", self.buffer.len().to_string()),
+            (
+                "aof_pending_bio_fsync",
+                (self.syncing.running.load(Ordering::SeqCst) as u8).to_string(),
+            ),
+            ("aof_delayed_fsync", self.delayed_fsyncs.to_string()),
+        ]
+    }
+
+    /// Takes a write command that ran against database `db` and answered
+    /// `reply`.
+    pub fn feed(&mut self, db: usize, command: Vec<Vec<u8>>, reply: &Value) {
+        self.pending.push((db, rewrite(command, reply)));
+    }
+
+    /// Writes out the commands fed since the last time, as a transaction
+    /// if there are several, along with those held back before.
+    pub fn write(&mut self) {
+        self.encode_pending();
+        if self.buffer.is_empty() {
+            return;
+        }
+        if self.fsync == Fsync::EverySec && self.syncing.running.load(Ordering::SeqCst) {
+            let now = storage::unix_millis();
+            match self.postponed {
+                None => {
+                    self.postponed = Some(now);
+                    return;
+                }
+                Some(since) if now - since < MAX_POSTPONE_MILLIS => return,
+                Some(_) => {
+                    self.delayed_fsyncs += 1;
+                    eprintln!(
+                        "Asynchronous AOF fsync is taking too long (disk is busy?). Writing the \
+                         AOF buffer without waiting for fsync to complete, this may slow down \
+                         Redis."
+                    );
+                }
+            }
+        }
+        self.postponed = None;
+        if let Err(e) = self.file.write_all(&self.buffer) {
+            eprintln!(
+                "Error writing to the AOF file {}: {}",
+                self.path.display(),
+                e
+            );
+            if self.fsync == Fsync::Always {
+                eprintln!(
+                    "Can't recover from AOF write error when the AOF fsync policy is 'always'. \
+                     Exiting..."
+                );
+                std::process::exit(1);
+            }
+            // What made it is taken back, to be written again in full.
+            let _ = self.file.set_len(self.size);
+            self.last_write_ok = false;
+            return;
+        }
+        self.size += self.buffer.len() as u64;
+        self.buffer.clear();
+        self.last_write_ok = true;
+        match self.fsync {
+            Fsync::Always => {
+                if let Err(e) = self.file.sync_data() {
+                    eprintln!("Can't persist the AOF file to disk: {}, exiting...", e);
+                    std::process::exit(1);
+                }
+            }
+            Fsync::EverySec => self.syncing.written.store(true, Ordering::SeqCst),
+            Fsync::No => {}
+        }
+    }
+
+    fn encode_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let pending = std::mem::take(&mut self.pending);
+        encode_block(&mut self.buffer, &mut self.db, &pending);
+    }
+
+    /// Moves writes on to a new incremental file, for a rewrite to start.
+    pub fn start_rewrite(&mut self) -> io::Result<()> {
+        let previous = self.manifest.clone();
+        let file = self.manifest.new_incr();
+        let path = self.dir.join(&file);
+        let opened = open_incr(&path, self.fsync)
+            .and_then(|opened| self.manifest.save(&self.dir).map(|()| opened));
+        let (file, syncing) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                let _ = fs::remove_file(&path);
+                self.manifest = previous;
+                return Err(e);
+            }
+        };
+        self.write();
+        let old = std::mem::replace(&mut self.file, file);
+        if self.fsync != Fsync::No {
+            tokio::task::spawn_blocking(move || old.sync_data());
+        }
+        self.syncing = syncing;
+        self.path = path;
+        self.earlier_size += self.size;
+        self.size = 0;
+        self.db = None;
+        Ok(())
+    }
+
+    /// Puts the base file written by the rewrite at `temp` in place, in
+    /// RDB format if `rdb`, leaving only the incremental file written to.
+    pub fn finish_rewrite(&mut self, temp: &Path, rdb: bool) -> io::Result<()> {
+        let base = install_base(&self.dir, &mut self.manifest, temp, rdb, true)?;
+        self.base_size = fs::metadata(base)?.len();
+        self.earlier_size = self.base_size;
+        Ok(())
+    }
+}
+
+/// Opens an incremental file for appending, with what fsyncs it in the
+/// background under `everysec`.
+fn open_incr(path: &Path, fsync: Fsync) -> io::Result<(File, Arc<Syncing>)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let syncing = Arc::new(Syncing {
+        file: file.try_clone()?,
+        running: AtomicBool::new(false),
+        written: AtomicBool::new(false),
+        last_ok: AtomicBool::new(true),
+    });
+    if fsync == Fsync::EverySec {
+        tokio::spawn(sync_every_second(Arc::downgrade(&syncing)));
+    }
+    Ok((file, syncing))
+}
+
+/// Renames the base file written at `temp` into place in the manifest of
+/// the AOF in `dir`, and deletes the files it replaces. Returns where it
+/// went.
+pub fn install_base(
+    dir: &Path,
+    manifest: &mut Manifest,
+    temp: &Path,
+    rdb: bool,
+    keep_last: bool,
+) -> io::Result<PathBuf> {
+    let previous = manifest.clone();
+    let file = manifest.new_base(rdb, keep_last);
+    let path = dir.join(&file);
+    if let Err(e) = fs::rename(temp, &path).and_then(|()| manifest.save(dir)) {
+        let _ = fs::remove_file(&path);
+        *manifest = previous;
+        return Err(e);
+    }
+    for part in manifest.history.drain(..) {
+        let _ = fs::remove_file(dir.join(&part.file));
+    }
+    // Best effort: with the files gone the history is empty either way.
+    let _ = manifest.save(dir);
+    Ok(path)
+}
+
+/// Encodes the commands of one command that ran, as a transaction if there
+/// are several, with a SELECT first if the database differs from `db`.
+fn encode_block(out: &mut Vec<u8>, db: &mut Option<usize>, commands: &[(usize, Vec<Vec<u8>>)]) {
+    let wrap = commands.len() > 1;
+    if wrap {
+        encode(out, &[b"MULTI".to_vec()]);
+    }
+    for (index, command) in commands {
+        if *db != Some(*index) {
+            encode(out, &[b"SELECT".to_vec(), index.to_string().into_bytes()]);
+            *db = Some(*index);
+        }
+        encode(out, command);
+    }
+    if wrap {
+        encode(out, &[b"EXEC".to_vec()]);
+    }
+}
+
+/// Fsyncs the file once a second if it was written to, until the file is
+/// closed.
+async fn sync_every_second(syncing: Weak<Syncing>) {
+    loop {
+        tokio::time::delay_for(Duration::from_secs(1)).await;
+        let syncing = match syncing.upgrade() {
+            Some(syncing) => syncing,
+            None => return,
+        };
+        if syncing.running.load(Ordering::SeqCst) || !syncing.written.swap(false, Ordering::SeqCst)
+        {
+            continue;
+        }
+        syncing.running.store(true, Ordering::SeqCst);
+        tokio::task::spawn_blocking(move || {
+            let result = syncing.file.sync_data();
+            if let Err(e) = &result {
+                eprintln!("Error syncing the AOF file: {}", e);
+            }
+            syncing.last_ok.store(result.is_ok(), Ordering::SeqCst);
+            syncing.running.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+fn encode(out: &mut Vec<u8>, command: &[Vec<u8>]) {
+    Value::array(command.iter().cloned().map(Value::String).collect()).encode(out);
+}
+
+/// Writes the commands that rebuild the snapshot to a new file at `path`.
+/// Streams, which no plain command recreates whole, are restored from
+/// their DUMP payload.
+pub fn write_rewrite(snapshot: &Snapshot, path: &Path) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut out = vec![];
+    for code in &snapshot.functions {
+        encode(
+            &mut out,
+            &[b"FUNCTION".to_vec(), b"LOAD".to_vec(), code.clone()],
+        );
+    }
+    for (index, entries) in snapshot.databases.iter().enumerate() {
+        if entries.is_empty() {
+            continue;
+        }
+        encode(
+            &mut out,
+            &[b"SELECT".to_vec(), index.to_string().into_bytes()],
+        );
+        for (key, stored) in entries {
+            rewrite_key(&mut out, key, &stored.data);
+            if let Some(expiry) = stored.expiry {
+                encode(
+                    &mut out,
+                    &[
+                        b"PEXPIREAT".to_vec(),
+                        key.clone(),
+                        expiry.to_string().into_bytes(),
+                    ],
+                );
+            }
+            file.write_all(&out)?;
+            out.clear();
+        }
+    }
+    file.write_all(&out)?;
+    file.into_inner()?.sync_all()
+}
+
+/// Encodes the commands that create `key` holding `data`.
+fn rewrite_key(out: &mut Vec<u8>, key: &[u8], data: &Data) {
+    let batches = |out: &mut Vec<u8>, name: &[u8], items: Vec<Vec<Vec<u8>>>| {
+        for chunk in items.chunks(ITEMS_PER_COMMAND) {
+            let mut command = vec![name.to_vec(), key.to_vec()];
+            command.extend(chunk.iter().flatten().cloned());
+            encode(out, &command);
+        }
+    };
+    match data {
+        Data::String(value) => encode(out, &[b"SET".to_vec(), key.to_vec(), value.clone()]),
+        Data::List(list) => {
+            let items = list.iter().map(|item| vec![item.clone()]).collect();
+            batches(out, b"RPUSH", items);
+        }
+        Data::Set(set) => {
+            let items = set.iter().map(|(member, _)| vec![member.clone()]).collect();
+            batches(out, b"SADD", items);
+        }
+        Data::ZSet(zset) => {
+            let items = zset
+                .iter()
+                .map(|(member, score)| vec![float::format(score).into_bytes(), member.clone()])
+                .collect();
+            batches(out, b"ZADD", items);
+        }
+        Data::Hash(hash) => {
+            let fields: Vec<_> = hash.iter_with_expiry().collect();
+            let items = fields
+                .iter()
+                .map(|(field, value, _)| vec![field.to_vec(), value.to_vec()])
+                .collect();
+            batches(out, b"HSET", items);
+            for (field, _, expiry) in fields {
+                if let Some(expiry) = expiry {
+                    encode(
+                        out,
+                        &[
+                            b"HPEXPIREAT".to_vec(),
+                            key.to_vec(),
+                            expiry.to_string().into_bytes(),
+                            b"FIELDS".to_vec(),
+                            b"1".to_vec(),
+                            field.to_vec(),
+                        ],
+                    );
+                }
+            }
+        }
+        Data::Stream(_) => encode(
+            out,
+            &[
+                b"RESTORE".to_vec(),
+                key.to_vec(),
+                b"0".to_vec(),
+                rdb::dump(data),
+            ],
+        ),
+    }
+}
+
+/// The arguments of a command as a client sent it.
+pub fn arguments(message: &Value) -> Vec<Vec<u8>> {
+    match message {
+        Value::Array(_, data) => data.iter().filter_map(|arg| arg.to_bytes().ok()).collect(),
+        _ => vec![],
+    }
+}
+
+/// Runs a command, handing `command`, its arguments if it is a write, to
+/// the AOF if it changed the dataset.
+pub fn execute(
+    store: &mut Store,
+    db: &mut usize,
+    command: Command,
+    arguments: Option<Vec<Vec<u8>>>,
+) -> Result<Value, Error> {
+    let started_in = *db;
+    let dirty = stats::read(&STATS.dirty);
+    let response = command.execute(store, db);
+    if let (Some(arguments), Ok(reply)) = (arguments, &response) {
+        if stats::read(&STATS.dirty) != dirty {
+            store.persistence().propagate(started_in, arguments, reply);
+        }
+    }
+    response
+}
+
+/// The command to log for one that ran and answered `reply`, doing the
+/// same whenever it is replayed.
+fn rewrite(mut command: Vec<Vec<u8>>, reply: &Value) -> Vec<Vec<u8>> {
+    let name = String::from_utf8_lossy(&command[0]).to_lowercase();
+    let now = storage::unix_millis();
+    let deadline = |arg: &[u8], unit: i64, relative: bool| {
+        int(arg).map(|time| {
+            let time = time.saturating_mul(unit);
+            let base = if relative { now } else { 0 };
+            time.saturating_add(base).to_string().into_bytes()
+        })
+    };
+    match name.as_str() {
+        "expire" | "pexpire" | "expireat" if command.len() >= 3 => {
+            let (unit, relative) = match name.as_str() {
+                "expire" => (1000, true),
+                "pexpire" => (1, true),
+                _ => (1000, false),
+            };
+            if let Some(at) = deadline(&command[2], unit, relative) {
+                command[0] = b"PEXPIREAT".to_vec();
+                command[2] = at;
+            }
+        }
+        "hexpire" | "hpexpire" | "hexpireat" if command.len() >= 3 => {
+            let (unit, relative) = match name.as_str() {
+                "hexpire" => (1000, true),
+                "hpexpire" => (1, true),
+                _ => (1000, false),
+            };
+            if let Some(at) = deadline(&command[2], unit, relative) {
+                command[0] = b"HPEXPIREAT".to_vec();
+                command[2] = at;
+            }
+        }
+        "setex" | "psetex" if command.len() == 4 => {
+            let unit = if name == "setex" { 1000 } else { 1 };
+            if let Some(at) = deadline(&command[2], unit, true) {
+                let value = command.pop().unwrap_or_default();
+                let key = command.swap_remove(1);
+                command = vec![b"SET".to_vec(), key, value, b"PXAT".to_vec(), at];
+            }
+        }
+        "set" => {
+            let mut i = 3;
+            while i + 1 < command.len() {
+                let option = String::from_utf8_lossy(&command[i]).to_lowercase();
+                let at = match option.as_str() {
+                    "ex" => deadline(&command[i + 1], 1000, true),
+                    "px" => deadline(&command[i + 1], 1, true),
+                    "exat" => deadline(&command[i + 1], 1000, false),
+                    _ => None,
+                };
+                if let Some(at) = at {
+                    command[i] = b"PXAT".to_vec();
+                    command[i + 1] = at;
+                    i += 1;
+                }
+                i += 1;
+            }
+        }
+        "getex" if command.len() >= 3 => {
+            let option = String::from_utf8_lossy(&command[2]).to_lowercase();
+            let at = match (option.as_str(), command.get(3)) {
+                ("ex", Some(time)) => deadline(time, 1000, true),
+                ("px", Some(time)) => deadline(time, 1, true),
+                ("exat", Some(time)) => deadline(time, 1000, false),
+                ("pxat", Some(time)) => deadline(time, 1, false),
+                _ => None,
+            };
+            match at {
+                Some(at) => command = vec![b"PEXPIREAT".to_vec(), command.swap_remove(1), at],
+                None if option == "persist" => {
+                    command = vec![b"PERSIST".to_vec(), command.swap_remove(1)]
+                }
+                None => {}
+            }
+        }
+        "restore" if command.len() >= 4 => {
+            let absolute = command[4..]
+                .iter()
+                .any(|arg| arg.eq_ignore_ascii_case(b"absttl"));
+            if !absolute && int(&command[2]).map_or(false, |ttl| ttl > 0) {
+                if let Some(at) = deadline(&command[2], 1, true) {
+                    command[2] = at;
+                    command.push(b"ABSTTL".to_vec());
+                }
+            }
+        }
+        "spop" if command.len() >= 2 => {
+            let members = match reply {
+                Value::String(member) => vec![member.clone()],
+                Value::Array(_, members) => members
+                    .iter()
+                    .filter_map(|member| member.to_bytes().ok())
+                    .collect(),
+                _ => return command,
+            };
+            let key = command.swap_remove(1);
+            command = vec![b"SREM".to_vec(), key];
+            command.extend(members);
+        }
+        "xadd" => {
+            if let (Some(index), Value::String(id)) = (xadd_id(&command), reply) {
+                command[index] = id.clone();
+            }
+        }
+        _ => {}
+    }
+    command
+}
+
+/// Where the ID is among the arguments of XADD, after its options.
+fn xadd_id(command: &[Vec<u8>]) -> Option<usize> {
+    let is = |i: usize, keyword: &str| {
+        command
+            .get(i)
+            .map_or(false, |arg| arg.eq_ignore_ascii_case(keyword.as_bytes()))
+    };
+    let mut i = 2;
+    if is(i, "nomkstream") {
+        i += 1;
+    }
+    if is(i, "maxlen") || is(i, "minid") {
+        i += 1;
+        if is(i, "=") || is(i, "~") {
+            i += 1;
+        }
+        i += 1;
+        if is(i, "limit") {
+            i += 2;
+        }
+    }
+    if i < command.len() {
+        Some(i)
+    } else {
+        None
+    }
+}
+
+fn int(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// Replays the AOF in `dir` into the store, returning its manifest, or
+/// None if there is none.
+pub fn load(store: &mut Store, dir: &Path, name: &str) -> Result<Option<Manifest>, String> {
+    let manifest = match Manifest::read(dir, name)? {
+        Some(manifest) => manifest,
+        None => return Ok(None),
+    };
+    let files: Vec<&Part> = manifest.files().collect();
+    for (i, part) in files.iter().enumerate() {
+        let path = dir.join(&part.file);
+        if !load_file(store, &path, i + 1 == files.len())? {
+            return Err(format!("The AOF file {} doesn't exist", path.display()));
+        }
+    }
+    Ok(Some(manifest))
+}
+
+/// Replays the file at `path` into the store, returning false if there is
+/// none. It may start with an RDB, as base files do. A last file cut
+/// short, as by a crash halfway through a write, loses its last command:
+/// the file is truncated to the commands before it.
+pub fn load_file(store: &mut Store, path: &Path, last: bool) -> Result<bool, String> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(format!("Can't read {}: {}", path.display(), e)),
+    };
+    storage::set_loading(true);
+    let replayed = load_data(store, &data);
+    storage::set_loading(false);
+    let valid = replayed?;
+    if valid < data.len() {
+        if !last {
+            return Err(format!(
+                "Unexpected end of file reading the append only file {}",
+                path.display()
+            ));
+        }
+        eprintln!(
+            "!!! Warning: short read while loading the AOF file {}!!!",
+            path.display()
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(valid as u64))
+            .map_err(|e| format!("Error truncating the AOF file: {}", e))?;
+        eprintln!("AOF loaded anyway because aof-load-truncated is enabled");
+    }
+    Ok(true)
+}
+
+fn load_data(store: &mut Store, data: &[u8]) -> Result<usize, String> {
+    let start = if data.starts_with(b"REDIS") {
+        let (snapshot, len) = rdb::read_prefix(data, store.databases().len())?;
+        persistence::restore(store, snapshot)?;
+        len
+    } else {
+        0
+    };
+    replay(store, data, start)
+}
+
+/// Runs the commands in `data`, returning how much of it held complete
+/// ones, from `start` on. Commands between MULTI and EXEC only run once
+/// EXEC is read.
+fn replay(store: &mut Store, data: &[u8], start: usize) -> Result<usize, String> {
+    let mut reader = Reader {
+        data,
+        offset: start,
+    };
+    let mut db = 0;
+    let mut transaction: Option<Vec<Vec<Vec<u8>>>> = None;
+    let mut valid = start;
+    while let Some(command) = reader.command()? {
+        let name = String::from_utf8_lossy(&command[0]).to_lowercase();
+        if transaction.is_none() && name == "multi" {
+            transaction = Some(vec![]);
+        } else if transaction.is_some() && name == "exec" {
+            for command in transaction.take().unwrap_or_default() {
+                run(store, &mut db, command)?;
+            }
+        } else if let Some(commands) = &mut transaction {
+            commands.push(command);
+        } else {
+            run(store, &mut db, command)?;
+        }
+        if transaction.is_none() {
+            valid = reader.offset;
+        }
+    }
+    // A transaction the file ends in the middle of is left out whole.
+    if transaction.is_some() {
+        eprintln!("Revert incomplete MULTI/EXEC transaction in AOF file");
+    }
+    Ok(valid)
+}
+
+fn run(store: &mut Store, db: &mut usize, arguments: Vec<Vec<u8>>) -> Result<(), String> {
+    let name = String::from_utf8_lossy(&arguments[0]).into_owned();
+    let message = Value::array(arguments.into_iter().map(Value::String).collect());
+    match Command::from_value(message) {
+        Ok(Command::Subscribe(_) | Command::Transaction(_) | Command::Quit | Command::Reset)
+        | Err(_) => Err(format!(
+            "Unknown command '{}' reading the append only file",
+            name
+        )),
+        Ok(command) => {
+            // Replies, errors among them, went to the client long ago.
+            let _ = command.execute(store, db);
+            Ok(())
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// The next command, or None at the end of the data or where it is cut
+    /// short.
+    fn command(&mut self) -> Result<Option<Vec<Vec<u8>>>, String> {
+        if self.offset == self.data.len() {
+            return Ok(None);
+        }
+        let count = match self.header(b'*')? {
+            Some(count) if count > 0 => count,
+            Some(_) => return Err(bad_format()),
+            None => return Ok(None),
+        };
+        let mut command = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            let len = match self.header(b'$')? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+            let end = self.offset + len;
+            if end + 2 > self.data.len() {
+                self.offset = self.data.len();
+                return Ok(None);
+            }
+            if &self.data[end..end + 2] != b"\r\n" {
+                return Err(bad_format());
+            }
+            command.push(self.data[self.offset..end].to_vec());
+            self.offset = end + 2;
+        }
+        Ok(Some(command))
+    }
+
+    /// A `*` or `$` line and the count it holds.
+    fn header(&mut self, kind: u8) -> Result<Option<usize>, String> {
+        let rest = &self.data[self.offset..];
+        let line = match rest.windows(2).position(|window| window == b"\r\n") {
+            Some(end) => &rest[..end],
+            None => {
+                self.offset = self.data.len();
+                return Ok(None);
+            }
+        };
+        if line.first() != Some(&kind) {
+            return Err(bad_format());
+        }
+        let count = std::str::from_utf8(&line[1..])
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .ok_or_else(bad_format)?;
+        self.offset += line.len() + 2;
+        Ok(Some(count))
+    }
+}
+
+fn bad_format() -> String {
+    "Bad file format reading the append only file (make a backup of your AOF file, then use \
+     ./redis-check-aof --fix <filename>)"
+        .to_owned()
+}
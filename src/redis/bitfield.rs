@@ -0,0 +1,581 @@
+//! The BITFIELD mini-language, addressing strings as integers of arbitrary
+//! width at arbitrary bit offsets.
+//!
+//! A command is parsed into a program of GET, SET and INCRBY operations, each
+//! carrying the OVERFLOW mode in effect where it appears, and only run once
+//! all of it parsed, so a bad argument anywhere changes nothing.
+
+use super::bitmaps::{self, bit, set_bit};
+use super::command::Arguments;
+use super::notify::Class;
+use super::storage::{Data, Database};
+use super::{Error, Value};
+
+/// An integer field: its signedness, width and first bit.
+#[derive(Clone, Copy)]
+struct Field {
+    signed: bool,
+    bits: u32,
+    offset: usize,
+}
+
+impl Field {
+    /// Parses a type such as `i16` or `u8`, then an offset, which counts
+    /// fields of that type rather than bits when prefixed with `#`.
+    fn parse(args: &mut Arguments) -> Result<Field, Error> {
+        let kind = args.next_string()?;
+        let signed = kind.starts_with('i');
+        let bits = match (kind.get(..1), kind.get(1..).map(str::parse::<u32>)) {
+            (Some("i"), Some(Ok(bits))) if (1..=64).contains(&bits) => bits,
+            (Some("u"), Some(Ok(bits))) if (1..=63).contains(&bits) => bits,
+            _ => {
+                return Err(Error::Argument(
+                    "Invalid bitfield type. Use something like i16 u8. \
+                     Note that u64 is not supported but i64 is."
+                        .to_owned(),
+                ))
+            }
+        };
+        let offset = args.next_string()?;
+        let offset = match offset.strip_prefix('#') {
+            Some(index) => index
+                .parse::<i64>()
+                .ok()
+                .and_then(|index| index.checked_mul(bits.into())),
+            None => offset.parse::<i64>().ok(),
+        };
+        let offset = bitmaps::check_offset(offset.ok_or_else(bitmaps::invalid_offset)?)?;
+        Ok(Field {
+            signed,
+            bits,
+            offset,
+        })
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed {
+            -(1 << (self.bits - 1))
+        } else {
+            0
+        }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed {
+            (1 << (self.bits - 1)) - 1
+        } else {
+            (1 << self.bits) - 1
+        }
+    }
+
+    /// The bit following the field.
+    fn end(&self) -> usize {
+        self.offset + self.bits as usize
+    }
+
+    /// The value of the field, where bits past the end of the string are
+    /// clear.
+    fn get(&self, data: &[u8]) -> i64 {
+        let mut value = 0u64;
+        for offset in self.offset..self.end() {
+            value = value << 1 | u64::from(bit(data, offset));
+        }
+        // Negative values carry their sign into the bits above the field.
+        if self.signed && self.bits < 64 && value >> (self.bits - 1) & 1 == 1 {
+            value |= !0 << self.bits;
+        }
+        value as i64
+    }
+
+    /// Stores the low bits of `value`; the string has to cover the field.
+    fn set(&self, data: &mut [u8], value: i64) {
+        for (index, offset) in (self.offset..self.end()).enumerate() {
+            let shift = self.bits as usize - 1 - index;
+            set_bit(data, offset, value as u64 >> shift & 1 == 1);
+        }
+    }
+
+    /// Fits `value` into the field as `overflow` says, None if it fails.
+    fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        if (self.min()..=self.max()).contains(&value) {
+            return Some(value as i64);
+        }
+        match overflow {
+            Overflow::Wrap => {
+                let wrapped = value & ((1 << self.bits) - 1);
+                if wrapped > self.max() {
+                    Some((wrapped - (1 << self.bits)) as i64)
+                } else {
+                    Some(wrapped as i64)
+                }
+            }
+            Overflow::Sat if value > self.max() => Some(self.max() as i64),
+            Overflow::Sat => Some(self.min() as i64),
+            Overflow::Fail => None,
+        }
+    }
+}
+
+/// What writes do with results out of the range of their field.
+#[derive(Clone, Copy)]
+enum Overflow {
+    /// Keep the low bits, wrapping around.
+    Wrap,
+    /// Clamp to the nearest representable value.
+    Sat,
+    /// Leave the field as it is and reply with nil.
+    Fail,
+}
+
+enum Operation {
+    Get(Field),
+    Set(Field, i64, Overflow),
+    IncrBy(Field, i64, Overflow),
+}
+
+pub struct Bitfield {
+    operations: Vec<Operation>,
+}
+
+impl Bitfield {
+    /// Parses the operations following the key, where `read_only` is for
+    /// BITFIELD_RO, which takes GET alone.
+    pub fn parse(args: &mut Arguments, read_only: bool) -> Result<Bitfield, Error> {
+        let mut operations = vec![];
+        let mut overflow = Overflow::Wrap;
+        while !args.is_empty() {
+            let name = args.next_string()?.to_lowercase();
+            let operation = match name.as_str() {
+                "get" if args.len() >= 2 => Operation::Get(Field::parse(args)?),
+                "set" | "incrby" if args.len() >= 3 => {
+                    let field = Field::parse(args)?;
+                    let value = args.next_int()?;
+                    if name == "set" {
+                        Operation::Set(field, value, overflow)
+                    } else {
+                        Operation::IncrBy(field, value, overflow)
+                    }
+                }
+                "overflow" if !args.is_empty() => {
+                    overflow = match args.next_string()?.to_lowercase().as_str() {
+                        "wrap" => Overflow::Wrap,
+                        "sat" => Overflow::Sat,
+                        "fail" => Overflow::Fail,
+                        _ => {
+                            return Err(Error::Argument(
+                                "Invalid OVERFLOW type specified".to_owned(),
+                            ))
+                        }
+                    };
+                    continue;
+                }
+                _ => return Err(Error::Syntax),
+            };
+            operations.push(operation);
+        }
+        let writes = operations
+            .iter()
+            .any(|operation| !matches!(operation, Operation::Get(_)));
+        if read_only && writes {
+            return Err(Error::Argument(
+                "BITFIELD_RO only supports the GET subcommand".to_owned(),
+            ));
+        }
+        Ok(Bitfield { operations })
+    }
+
+    /// The length the string needs to cover every written field.
+    fn write_len(&self) -> Option<usize> {
+        self.operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Get(_) => None,
+                Operation::Set(field, ..) | Operation::IncrBy(field, ..) => {
+                    Some((field.end() + 7) >> 3)
+                }
+            })
+            .max()
+    }
+
+    /// Runs the operations in order, replying with one result each.
+    pub fn execute(self, storage: &mut Database, key: &[u8]) -> Result<Value, Error> {
+        let len = match self.write_len() {
+            Some(len) => len,
+            None => {
+                // Only reads: a missing key reads as zeros and is not created.
+                let data = match storage.get(key) {
+                    Some(stored) => stored.string()?.as_slice(),
+                    None => &[],
+                };
+                return Ok(Value::array(
+                    self.operations
+                        .iter()
+                        .filter_map(|operation| match operation {
+                            Operation::Get(field) => Some(Value::Int(field.get(data))),
+                            _ => None,
+                        })
+                        .collect(),
+                ));
+            }
+        };
+        // Like Redis, the string grows to fit every write up front, even
+        // those that end up failing.
+        let data = storage
+            .get_or_insert_with(key, || Data::String(vec![]))
+            .string_mut()?;
+        if data.len() < len {
+            data.resize(len, 0);
+        }
+        let mut replies = vec![];
+        let mut changed = false;
+        for operation in self.operations {
+            let reply = match operation {
+                Operation::Get(field) => Value::Int(field.get(data)),
+                Operation::Set(field, value, overflow) => {
+                    let previous = field.get(data);
+                    // Unsigned fields take the value's two's complement bits.
+                    let value = if field.signed {
+                        i128::from(value)
+                    } else {
+                        i128::from(value as u64)
+                    };
+                    match field.fit(value, overflow) {
+                        Some(value) => {
+                            field.set(data, value);
+                            changed = true;
+                            Value::Int(previous)
+                        }
+                        None => Value::Nil,
+                    }
+                }
+                Operation::IncrBy(field, increment, overflow) => {
+                    let value = i128::from(field.get(data)) + i128::from(increment);
+                    match field.fit(value, overflow) {
+                        Some(value) => {
+                            field.set(data, value);
+                            changed = true;
+                            Value::Int(value)
+                        }
+                        None => Value::Nil,
+                    }
+                }
+            };
+            replies.push(reply);
+        }
+        if changed {
+            storage.notify(Class::String, "setbit", key);
+        }
+        Ok(Value::array(replies))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::command::Arguments;
+    use super::super::storage::Database;
+    use super::super::{Error, Value};
+    use super::{Bitfield, Field, Overflow};
+
+    fn arguments(args: &[&str]) -> Arguments {
+        let args = args
+            .iter()
+            .map(|arg| Value::String(arg.as_bytes().to_vec()))
+            .collect();
+        Arguments::new("bitfield".to_owned(), args)
+    }
+
+    fn field(kind: &str, offset: &str) -> Result<Field, Error> {
+        Field::parse(&mut arguments(&[kind, offset]))
+    }
+
+    /// Runs BITFIELD on `key` and returns its replies, None for nil.
+    fn bitfield(db: &mut Database, args: &[&str]) -> Vec<Option<i64>> {
+        let bitfield = Bitfield::parse(&mut arguments(args), false).unwrap();
+        match bitfield.execute(db, b"key").unwrap() {
+            Value::Array(_, replies) => replies
+                .into_iter()
+                .map(|reply| match reply {
+                    Value::Int(value) => Some(value),
+                    Value::Nil => None,
+                    _ => panic!("unexpected reply"),
+                })
+                .collect(),
+            _ => panic!("unexpected reply"),
+        }
+    }
+
+    fn invalid_type(result: Result<Field, Error>) -> bool {
+        matches!(result, Err(Error::Argument(message)) if message.starts_with("Invalid bitfield type"))
+    }
+
+    #[test]
+    fn parses_types() {
+        let i64_field = field("i64", "0").unwrap();
+        assert!(i64_field.signed && i64_field.bits == 64);
+        let u63 = field("u63", "0").unwrap();
+        assert!(!u63.signed && u63.bits == 63);
+        assert!(invalid_type(field("u64", "0")));
+        assert!(invalid_type(field("i65", "0")));
+        assert!(invalid_type(field("i0", "0")));
+        assert!(invalid_type(field("x8", "0")));
+        assert!(invalid_type(field("u", "0")));
+    }
+
+    #[test]
+    fn parses_offsets() {
+        assert_eq!(field("u8", "13").unwrap().offset, 13);
+        assert_eq!(field("u8", "#3").unwrap().offset, 24);
+        assert_eq!(field("i5", "#0").unwrap().offset, 0);
+        assert_eq!(field("i5", "#7").unwrap().offset, 35);
+        assert!(field("u8", "-1").is_err());
+        assert!(field("u8", "#-1").is_err());
+        assert!(field("u8", "x").is_err());
+        // Offsets address the 2^32 bits of the largest string.
+        assert_eq!(field("u1", "4294967295").unwrap().offset, 4_294_967_295);
+        assert!(field("u1", "4294967296").is_err());
+        assert_eq!(field("u8", "#536870911").unwrap().offset, 4_294_967_288);
+        assert!(field("u8", "#536870912").is_err());
+        assert!(field("i64", "#9223372036854775807").is_err());
+    }
+
+    #[test]
+    fn wide_fields_at_unaligned_offsets() {
+        let mut db = Database::default();
+        let replies = bitfield(
+            &mut db,
+            &[
+                "SET", "i64", "3", "-2", "GET", "i64", "3", "GET", "u63", "3",
+            ],
+        );
+        assert_eq!(replies, vec![Some(0), Some(-2), Some(i64::MAX)]);
+        let replies = bitfield(
+            &mut db,
+            &[
+                "SET",
+                "u63",
+                "5",
+                "9223372036854775807",
+                "GET",
+                "u63",
+                "5",
+                "GET",
+                "i64",
+                "5",
+            ],
+        );
+        assert_eq!(replies, vec![Some(i64::MAX - 3), Some(i64::MAX), Some(-2)]);
+        let replies = bitfield(
+            &mut db,
+            &["SET", "i64", "7", "-9223372036854775808", "GET", "i64", "7"],
+        );
+        assert_eq!(replies, vec![Some(-8), Some(i64::MIN)]);
+    }
+
+    #[test]
+    fn reads_past_the_end_are_zero() {
+        let mut db = Database::default();
+        assert_eq!(
+            bitfield(&mut db, &["GET", "u8", "0", "GET", "i64", "1000"]),
+            vec![Some(0), Some(0)]
+        );
+        assert!(db.get(b"key").is_none());
+        bitfield(&mut db, &["SET", "u8", "0", "255"]);
+        assert_eq!(
+            bitfield(
+                &mut db,
+                &["GET", "u16", "0", "GET", "i4", "4", "GET", "u8", "8"]
+            ),
+            vec![Some(0xff00), Some(-1), Some(0)]
+        );
+    }
+
+    #[test]
+    fn wrap() {
+        let mut db = Database::default();
+        let replies = bitfield(
+            &mut db,
+            &[
+                "SET", "u8", "0", "255", "INCRBY", "u8", "0", "10", "INCRBY", "u8", "0", "-20",
+            ],
+        );
+        assert_eq!(replies, vec![Some(0), Some(9), Some(245)]);
+        let replies = bitfield(
+            &mut db,
+            &[
+                "SET", "i8", "8", "127", "INCRBY", "i8", "8", "1", "INCRBY", "i8", "8", "-1",
+            ],
+        );
+        assert_eq!(replies, vec![Some(0), Some(-128), Some(127)]);
+        // Unsigned fields take the low bits of negative values.
+        assert_eq!(
+            bitfield(&mut db, &["SET", "u4", "16", "-1", "GET", "u4", "16"]),
+            vec![Some(0), Some(15)]
+        );
+        let replies = bitfield(
+            &mut db,
+            &[
+                "SET",
+                "i64",
+                "24",
+                "9223372036854775807",
+                "INCRBY",
+                "i64",
+                "24",
+                "1",
+            ],
+        );
+        assert_eq!(replies, vec![Some(0), Some(i64::MIN)]);
+        assert_eq!(
+            bitfield(&mut db, &["INCRBY", "i64", "24", "-1"]),
+            vec![Some(i64::MAX)]
+        );
+    }
+
+    #[test]
+    fn sat() {
+        let mut db = Database::default();
+        let replies = bitfield(
+            &mut db,
+            &[
+                "OVERFLOW", "SAT", "SET", "u8", "0", "300", "INCRBY", "u8", "0", "-500", "SET",
+                "i8", "8", "-200", "INCRBY", "i8", "8", "1000",
+            ],
+        );
+        assert_eq!(replies, vec![Some(0), Some(0), Some(0), Some(127)]);
+        let replies = bitfield(
+            &mut db,
+            &[
+                "OVERFLOW",
+                "SAT",
+                "SET",
+                "i64",
+                "16",
+                "-9223372036854775808",
+                "INCRBY",
+                "i64",
+                "16",
+                "-1",
+                "INCRBY",
+                "i64",
+                "16",
+                "9223372036854775807",
+                "INCRBY",
+                "i64",
+                "16",
+                "9223372036854775807",
+                "INCRBY",
+                "i64",
+                "16",
+                "1",
+            ],
+        );
+        assert_eq!(
+            replies,
+            vec![
+                Some(0),
+                Some(i64::MIN),
+                Some(-1),
+                Some(i64::MAX - 1),
+                Some(i64::MAX)
+            ]
+        );
+        let replies = bitfield(
+            &mut db,
+            &[
+                "OVERFLOW", "SAT", "SET", "u63", "80", "-1", "INCRBY", "u63", "80", "-5",
+            ],
+        );
+        assert_eq!(replies, vec![Some(0), Some(i64::MAX - 5)]);
+    }
+
+    #[test]
+    fn fail() {
+        let mut db = Database::default();
+        let replies = bitfield(
+            &mut db,
+            &[
+                "OVERFLOW", "FAIL", "SET", "u8", "0", "200", "INCRBY", "u8", "0", "100", "GET",
+                "u8", "0", "SET", "u8", "0", "256",
+            ],
+        );
+        assert_eq!(replies, vec![Some(0), None, Some(200), None]);
+        let replies = bitfield(
+            &mut db,
+            &[
+                "OVERFLOW", "FAIL", "SET", "i8", "8", "-128", "INCRBY", "i8", "8", "-1", "SET",
+                "i8", "8", "-129", "GET", "i8", "8",
+            ],
+        );
+        assert_eq!(replies, vec![Some(0), None, None, Some(-128)]);
+        let replies = bitfield(
+            &mut db,
+            &[
+                "OVERFLOW",
+                "FAIL",
+                "SET",
+                "i64",
+                "16",
+                "-9223372036854775808",
+                "INCRBY",
+                "i64",
+                "16",
+                "-1",
+                "GET",
+                "i64",
+                "16",
+            ],
+        );
+        assert_eq!(replies, vec![Some(0), None, Some(i64::MIN)]);
+        assert_eq!(
+            bitfield(&mut db, &["OVERFLOW", "FAIL", "SET", "u8", "80", "-1"]),
+            vec![None]
+        );
+    }
+
+    #[test]
+    fn overflow_applies_from_where_it_appears() {
+        let mut db = Database::default();
+        let replies = bitfield(
+            &mut db,
+            &[
+                "SET", "u2", "0", "3", "INCRBY", "u2", "0", "1", "OVERFLOW", "SAT", "INCRBY", "u2",
+                "0", "7", "OVERFLOW", "FAIL", "INCRBY", "u2", "0", "1",
+            ],
+        );
+        assert_eq!(replies, vec![Some(0), Some(0), Some(3), None]);
+    }
+
+    #[test]
+    fn fit_at_the_edges() {
+        let i64_field = field("i64", "0").unwrap();
+        let min = i128::from(i64::MIN);
+        assert_eq!(i64_field.fit(min, Overflow::Fail), Some(i64::MIN));
+        assert_eq!(i64_field.fit(min - 1, Overflow::Fail), None);
+        assert_eq!(i64_field.fit(min - 1, Overflow::Sat), Some(i64::MIN));
+        assert_eq!(i64_field.fit(min - 1, Overflow::Wrap), Some(i64::MAX));
+        let u63 = field("u63", "0").unwrap();
+        assert_eq!(u63.fit(-1, Overflow::Wrap), Some(i64::MAX));
+        assert_eq!(u63.fit(1 << 63, Overflow::Sat), Some(i64::MAX));
+        assert_eq!(u63.fit(1 << 63, Overflow::Wrap), Some(0));
+    }
+
+    #[test]
+    fn rejects_bad_programs() {
+        let parse =
+            |args: &[&str], read_only: bool| Bitfield::parse(&mut arguments(args), read_only);
+        assert!(matches!(parse(&["GET", "u8"], false), Err(Error::Syntax)));
+        assert!(matches!(
+            parse(&["FOO", "u8", "0"], false),
+            Err(Error::Syntax)
+        ));
+        assert!(matches!(
+            parse(&["OVERFLOW", "MAYBE"], false),
+            Err(Error::Argument(message)) if message == "Invalid OVERFLOW type specified"
+        ));
+        assert!(parse(&["GET", "u8", "0"], true).is_ok());
+        assert!(matches!(
+            parse(&["GET", "u8", "0", "SET", "u8", "0", "1"], true),
+            Err(Error::Argument(message)) if message == "BITFIELD_RO only supports the GET subcommand"
+        ));
+    }
+}
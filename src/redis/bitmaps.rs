@@ -0,0 +1,369 @@
+//! Commands treating strings as arrays of bits, the first bit being the most
+//! significant one of the first byte.
+
+use super::bitfield::Bitfield;
+use super::command::Arguments;
+use super::notify::Class;
+use super::storage::{Data, Database, StoredValue};
+use super::strings::MAX_STRING_LENGTH;
+use super::{Error, Value};
+use std::convert::TryInto;
+
+pub fn invalid_offset() -> Error {
+    Error::Argument("bit offset is not an integer or out of range".to_owned())
+}
+
+/// Checks that a bit offset addresses a bit within the largest string a
+/// write may produce.
+pub fn check_offset(offset: i64) -> Result<usize, Error> {
+    if offset < 0 || (offset as u64 >> 3) >= MAX_STRING_LENGTH as u64 {
+        return Err(invalid_offset());
+    }
+    Ok(offset as usize)
+}
+
+fn parse_offset(args: &mut Arguments) -> Result<usize, Error> {
+    check_offset(args.next_int().map_err(|_| invalid_offset())?)
+}
+
+/// The bit at `offset`, where bits past the end of the string are clear.
+pub fn bit(data: &[u8], offset: usize) -> bool {
+    data.get(offset >> 3)
+        .map_or(false, |byte| byte & (0x80 >> (offset & 7)) != 0)
+}
+
+/// Sets the bit at `offset`, which must be within the string.
+pub fn set_bit(data: &mut [u8], offset: usize, value: bool) {
+    let mask = 0x80 >> (offset & 7);
+    if value {
+        data[offset >> 3] |= mask;
+    } else {
+        data[offset >> 3] &= !mask;
+    }
+}
+
+/// Counts the set bits eight bytes at a time.
+fn popcount(data: &[u8]) -> usize {
+    let mut words = data.chunks_exact(8);
+    let mut count = 0;
+    for word in &mut words {
+        let word = u64::from_ne_bytes(word.try_into().expect("chunks are eight bytes"));
+        count += word.count_ones() as usize;
+    }
+    count
+        + words
+            .remainder()
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum::<usize>()
+}
+
+/// The set bits among bits `first..=last`.
+fn count_bits(data: &[u8], first: usize, last: usize) -> usize {
+    let (first_byte, last_byte) = (first >> 3, last >> 3);
+    let before = data[first_byte] & !(0xff >> (first & 7));
+    let after = data[last_byte] & !(0xff << (7 - (last & 7)));
+    popcount(&data[first_byte..=last_byte]) - (before.count_ones() + after.count_ones()) as usize
+}
+
+/// The first bit set to `bit` among bits `first..=last`.
+fn find_bit(data: &[u8], first: usize, last: usize, bit: bool) -> Option<usize> {
+    let (first_byte, last_byte) = (first >> 3, last >> 3);
+    let skip = if bit { 0 } else { 0xff };
+    let mut index = first_byte;
+    while index <= last_byte {
+        // Words with no bit of interest are skipped whole.
+        if index + 8 <= last_byte + 1 && data[index..index + 8].iter().all(|&byte| byte == skip) {
+            index += 8;
+            continue;
+        }
+        // Looking for a set bit either way, out of the range ones cleared.
+        let mut byte = data[index] ^ skip;
+        if index == first_byte {
+            byte &= 0xff >> (first & 7);
+        }
+        if index == last_byte {
+            byte &= 0xff << (7 - (last & 7));
+        }
+        if byte != 0 {
+            return Some(index * 8 + byte.leading_zeros() as usize);
+        }
+        index += 1;
+    }
+    None
+}
+
+/// The range of BITCOUNT and BITPOS, in bytes unless BIT is given. Negative
+/// indexes count from the end.
+#[derive(Clone, Copy)]
+pub struct BitRange {
+    start: i64,
+    /// The end of the string if not given.
+    end: Option<i64>,
+    bits: bool,
+}
+
+impl BitRange {
+    fn whole() -> BitRange {
+        BitRange {
+            start: 0,
+            end: None,
+            bits: false,
+        }
+    }
+
+    /// Reads the optional BYTE or BIT unit that ends a range.
+    fn parse_unit(&mut self, args: &mut Arguments) -> Result<(), Error> {
+        if !args.is_empty() {
+            self.bits = match args.next_string()?.to_lowercase().as_str() {
+                "bit" => true,
+                "byte" => false,
+                _ => return Err(Error::Syntax),
+            };
+        }
+        Ok(())
+    }
+
+    /// The first and last bit the range covers in a string of `len` bytes,
+    /// clamped to the string; None if it covers none.
+    fn resolve(&self, len: usize) -> Option<(usize, usize)> {
+        let total = if self.bits { len * 8 } else { len } as i64;
+        let mut start = self.start;
+        let mut end = self.end.unwrap_or(total - 1);
+        if start < 0 {
+            start += total;
+        }
+        if end < 0 {
+            end += total;
+        }
+        let start = start.max(0);
+        let end = end.max(0).min(total - 1);
+        if start > end {
+            return None;
+        }
+        let (start, end) = (start as usize, end as usize);
+        if self.bits {
+            Some((start, end))
+        } else {
+            Some((start * 8, end * 8 + 7))
+        }
+    }
+}
+
+/// The operations of BITOP.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BitOperation {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+/// Combines the sources byte by byte, each padded with zero bytes to the
+/// length of the longest.
+fn combine(operation: BitOperation, sources: &[&[u8]]) -> Vec<u8> {
+    let len = sources.iter().map(|source| source.len()).max().unwrap_or(0);
+    let mut result = sources.first().map_or(vec![], |source| source.to_vec());
+    result.resize(len, 0);
+    if operation == BitOperation::Not {
+        for byte in &mut result {
+            *byte = !*byte;
+        }
+        return result;
+    }
+    for source in &sources[1..] {
+        for (index, byte) in result.iter_mut().enumerate() {
+            let other = source.get(index).copied().unwrap_or(0);
+            match operation {
+                BitOperation::And => *byte &= other,
+                BitOperation::Or => *byte |= other,
+                BitOperation::Xor => *byte ^= other,
+                BitOperation::Not => unreachable!("NOT has a single source"),
+            }
+        }
+    }
+    result
+}
+
+pub enum BitmapCommand {
+    SetBit(Vec<u8>, usize, bool),
+    GetBit(Vec<u8>, usize),
+    BitCount(Vec<u8>, BitRange),
+    /// BITPOS: the first bit set to a value.
+    BitPos(Vec<u8>, bool, BitRange),
+    /// BITOP: stores the combination of the sources at a destination.
+    BitOp(BitOperation, Vec<u8>, Vec<Vec<u8>>),
+    Field(Vec<u8>, Bitfield),
+}
+
+impl BitmapCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<BitmapCommand>, Error> {
+        let command = match name {
+            "setbit" => {
+                let key = args.next_bytes()?;
+                let offset = parse_offset(args)?;
+                let value = match args.next_bytes()?.as_slice() {
+                    b"0" => false,
+                    b"1" => true,
+                    _ => {
+                        return Err(Error::Argument(
+                            "bit is not an integer or out of range".to_owned(),
+                        ))
+                    }
+                };
+                args.finish()?;
+                BitmapCommand::SetBit(key, offset, value)
+            }
+            "getbit" => {
+                let key = args.next_bytes()?;
+                let offset = parse_offset(args)?;
+                args.finish()?;
+                BitmapCommand::GetBit(key, offset)
+            }
+            "bitcount" => {
+                let key = args.next_bytes()?;
+                let mut range = BitRange::whole();
+                if !args.is_empty() {
+                    if !(2..=3).contains(&args.len()) {
+                        return Err(Error::Syntax);
+                    }
+                    range.start = args.next_int()?;
+                    range.end = Some(args.next_int()?);
+                    range.parse_unit(args)?;
+                }
+                BitmapCommand::BitCount(key, range)
+            }
+            "bitpos" => {
+                let key = args.next_bytes()?;
+                let bit = match args.next_int()? {
+                    0 => false,
+                    1 => true,
+                    _ => {
+                        return Err(Error::Argument(
+                            "The bit argument must be 1 or 0.".to_owned(),
+                        ))
+                    }
+                };
+                if args.len() > 3 {
+                    return Err(Error::Syntax);
+                }
+                let mut range = BitRange::whole();
+                if !args.is_empty() {
+                    range.start = args.next_int()?;
+                }
+                if !args.is_empty() {
+                    range.end = Some(args.next_int()?);
+                }
+                range.parse_unit(args)?;
+                BitmapCommand::BitPos(key, bit, range)
+            }
+            "bitop" => {
+                if args.len() < 3 {
+                    return Err(args.wrong_arity());
+                }
+                let operation = match args.next_string()?.to_lowercase().as_str() {
+                    "and" => BitOperation::And,
+                    "or" => BitOperation::Or,
+                    "xor" => BitOperation::Xor,
+                    "not" => BitOperation::Not,
+                    _ => return Err(Error::Syntax),
+                };
+                let destination = args.next_bytes()?;
+                let sources = args.rest()?;
+                if operation == BitOperation::Not && sources.len() != 1 {
+                    return Err(Error::Argument(
+                        "BITOP NOT must be called with a single source key.".to_owned(),
+                    ));
+                }
+                BitmapCommand::BitOp(operation, destination, sources)
+            }
+            "bitfield" | "bitfield_ro" => {
+                let key = args.next_bytes()?;
+                BitmapCommand::Field(key, Bitfield::parse(args, name == "bitfield_ro")?)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    pub fn execute(self, storage: &mut Database) -> Result<Value, Error> {
+        let response = match self {
+            BitmapCommand::SetBit(key, offset, value) => {
+                let data = storage
+                    .get_or_insert_with(&key, || Data::String(vec![]))
+                    .string_mut()?;
+                // Writing past the end zero-extends the string first.
+                let byte = offset >> 3;
+                if data.len() <= byte {
+                    data.resize(byte + 1, 0);
+                }
+                let previous = bit(data, offset);
+                set_bit(data, offset, value);
+                storage.notify(Class::String, "setbit", &key);
+                Value::Int(previous.into())
+            }
+            BitmapCommand::GetBit(key, offset) => match storage.get(&key) {
+                Some(stored) => Value::Int(bit(stored.string()?, offset).into()),
+                None => Value::Int(0),
+            },
+            BitmapCommand::BitCount(key, range) => {
+                let data = match storage.get(&key) {
+                    Some(stored) => stored.string()?,
+                    None => return Ok(Value::Int(0)),
+                };
+                // Like Redis, a range with both ends negative and reversed
+                // is empty even if clamping would make it cover the start.
+                if range.start < 0 && range.end.map_or(false, |end| end < 0 && range.start > end) {
+                    return Ok(Value::Int(0));
+                }
+                match range.resolve(data.len()) {
+                    Some((first, last)) => Value::Int(count_bits(data, first, last).try_into()?),
+                    None => Value::Int(0),
+                }
+            }
+            BitmapCommand::BitPos(key, bit, range) => {
+                // A missing key is taken as an endless run of clear bits.
+                let data = match storage.get(&key) {
+                    Some(stored) => stored.string()?,
+                    None => return Ok(Value::Int(if bit { -1 } else { 0 })),
+                };
+                let (first, last) = match range.resolve(data.len()) {
+                    Some(bits) => bits,
+                    None => return Ok(Value::Int(-1)),
+                };
+                match find_bit(data, first, last, bit) {
+                    Some(position) => Value::Int(position.try_into()?),
+                    // Without an end the string counts as padded with clear
+                    // bits, the first of which comes right after it.
+                    None if !bit && range.end.is_none() => Value::Int((last + 1).try_into()?),
+                    None => Value::Int(-1),
+                }
+            }
+            BitmapCommand::BitOp(operation, destination, keys) => {
+                // Missing sources are empty strings.
+                let result = {
+                    let sources = keys
+                        .iter()
+                        .map(|key| match storage.get(key) {
+                            Some(stored) => stored.string().map(Vec::as_slice),
+                            None => Ok(&[][..]),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    combine(operation, &sources)
+                };
+                let len = result.len().try_into()?;
+                if result.is_empty() {
+                    if storage.remove(&destination).is_some() {
+                        storage.notify(Class::Generic, "del", &destination);
+                    }
+                } else {
+                    storage.notify(Class::String, "set", &destination);
+                    storage.insert(destination, StoredValue::new(Data::String(result), None));
+                }
+                Value::Int(len)
+            }
+            BitmapCommand::Field(key, bitfield) => bitfield.execute(storage, &key)?,
+        };
+        Ok(response)
+    }
+}
@@ -0,0 +1,210 @@
+//! Commands that wait for their keys to be filled by other clients.
+//!
+//! A blocked client is parked in the [`Blocked`] registry of the store and
+//! queued on each key it waits for. Creating one of those keys, or adding
+//! to a stream, marks it ready, and once the command that did so has finished the queued clients
+//! are served in the order they blocked, before anyone else gets the lock.
+
+use super::command::Arguments;
+use super::float;
+use super::lists::BlockingListCommand;
+use super::storage::Database;
+use super::streams::BlockingStreamCommand;
+use super::zsets::BlockingZSetCommand;
+use super::{Error, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+pub type ClientId = u64;
+
+/// The outcome of a blocked command, handed over to its connection.
+pub type Reply = Result<Value, Error>;
+
+pub enum Blocking {
+    List(BlockingListCommand),
+    ZSet(BlockingZSetCommand),
+    Stream(BlockingStreamCommand),
+}
+
+impl Blocking {
+    pub fn keys(&self) -> &[Vec<u8>] {
+        match self {
+            Blocking::List(command) => command.keys(),
+            Blocking::ZSet(command) => command.keys(),
+            Blocking::Stream(command) => command.keys(),
+        }
+    }
+
+    /// Whether the command waits at all. XREAD only does with BLOCK, and
+    /// otherwise runs as if its timeout had elapsed.
+    pub fn waits(&self) -> bool {
+        match self {
+            Blocking::List(_) | Blocking::ZSet(_) => true,
+            Blocking::Stream(command) => command.waits(),
+        }
+    }
+
+    /// Runs the command if it can be served now, or returns None when it
+    /// still has to wait. Serving a client already blocked passes
+    /// `skip_other_types`, which passes over keys of another type rather
+    /// than failing on them, as Redis does.
+    pub fn try_execute(
+        &mut self,
+        storage: &mut Database,
+        skip_other_types: bool,
+    ) -> Result<Option<Value>, Error> {
+        match self {
+            Blocking::List(command) => command.try_execute(storage, skip_other_types),
+            Blocking::ZSet(command) => command.try_execute(storage, skip_other_types),
+            Blocking::Stream(command) => command.try_execute(storage, skip_other_types),
+        }
+    }
+
+    /// What the command answers when its timeout elapses.
+    pub fn timeout_reply(&self) -> Value {
+        match self {
+            Blocking::List(command) => command.timeout_reply(),
+            Blocking::ZSet(_) | Blocking::Stream(_) => Value::NilArray,
+        }
+    }
+}
+
+pub struct BlockingCommand {
+    pub operation: Blocking,
+    /// None blocks until the command is served.
+    pub timeout: Option<Duration>,
+}
+
+impl BlockingCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<BlockingCommand>, Error> {
+        if let Some(command) = BlockingListCommand::parse(name, args)? {
+            return Ok(Some(command));
+        }
+        if let Some(command) = BlockingZSetCommand::parse(name, args)? {
+            return Ok(Some(command));
+        }
+        BlockingStreamCommand::parse(name, args)
+    }
+}
+
+/// The value of a key as the type a command wants, or None for a key of
+/// another type that `skip` says to pass over.
+pub fn skip_other_type<T>(value: Result<T, Error>, skip: bool) -> Result<Option<T>, Error> {
+    match value {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::WrongType) if skip => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// A timeout in seconds, where zero means waiting forever.
+pub fn parse_timeout(args: &mut Arguments) -> Result<Option<Duration>, Error> {
+    let not_float = || Error::Argument("timeout is not a float or out of range".to_owned());
+    let seconds = float::parse(&args.next_bytes()?)
+        .filter(|seconds| seconds.is_finite())
+        .ok_or_else(not_float)?;
+    if seconds < 0.0 {
+        return Err(Error::Argument("timeout is negative".to_owned()));
+    }
+    if seconds == 0.0 {
+        return Ok(None);
+    }
+    Ok(Some(Duration::from_secs_f64(seconds)))
+}
+
+/// A timeout in milliseconds, as XREAD's BLOCK takes it, where zero means
+/// waiting forever.
+pub fn parse_timeout_millis(args: &mut Arguments) -> Result<Option<Duration>, Error> {
+    let millis = args
+        .next_int()
+        .map_err(|_| Error::Argument("timeout is not an integer or out of range".to_owned()))?;
+    if millis < 0 {
+        return Err(Error::Argument("timeout is negative".to_owned()));
+    }
+    if millis == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Duration::from_millis(millis as u64)))
+}
+
+/// A client [`Blocked::serve`] let go of.
+pub struct Unblocked {
+    /// The keys it was waiting on.
+    pub keys: Vec<Vec<u8>>,
+    /// The arguments of its command, if it was served and keeps them.
+    pub command: Option<Vec<Vec<u8>>>,
+}
+
+struct Client {
+    db: usize,
+    operation: Blocking,
+    reply: oneshot::Sender<Reply>,
+    /// The arguments of the command, for the AOF once it is served.
+    command: Option<Vec<Vec<u8>>>,
+}
+
+/// The clients currently blocked, by id.
+#[derive(Default)]
+pub struct Blocked {
+    clients: HashMap<ClientId, Client>,
+    next_id: ClientId,
+}
+
+impl Blocked {
+    /// Parks `operation`, which waits on keys of database `db`.
+    pub fn insert(
+        &mut self,
+        db: usize,
+        operation: Blocking,
+        command: Option<Vec<Vec<u8>>>,
+    ) -> (ClientId, oneshot::Receiver<Reply>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (reply, receiver) = oneshot::channel();
+        self.clients.insert(
+            id,
+            Client {
+                db,
+                operation,
+                reply,
+                command,
+            },
+        );
+        (id, receiver)
+    }
+
+    /// The database and keys the client waits on, if it is still blocked.
+    pub fn remove(&mut self, id: ClientId) -> Option<(usize, Vec<Vec<u8>>)> {
+        let client = self.clients.remove(&id)?;
+        Some((client.db, client.operation.keys().to_vec()))
+    }
+
+    /// Tries to serve the client against its database, returning it once it
+    /// is no longer blocked: served, failed, or gone. A key of the wrong
+    /// type keeps it waiting, for the key to change again.
+    pub fn serve(&mut self, id: ClientId, storage: &mut Database) -> Option<Unblocked> {
+        let client = self.clients.get_mut(&id)?;
+        let reply = if client.reply.is_closed() {
+            None
+        } else {
+            match client.operation.try_execute(storage, true) {
+                Ok(Some(response)) => Some(Ok(response)),
+                Ok(None) | Err(Error::WrongType) => return None,
+                Err(e) => Some(Err(e)),
+            }
+        };
+        let client = self.clients.remove(&id)?;
+        let keys = client.operation.keys().to_vec();
+        let served = matches!(reply, Some(Ok(_)));
+        if let Some(reply) = reply {
+            // Fails only if the connection went away meanwhile, taking the
+            // response with it either way.
+            let _ = client.reply.send(reply);
+        }
+        Some(Unblocked {
+            keys,
+            command: client.command.filter(|_| served),
+        })
+    }
+}
@@ -0,0 +1,453 @@
+use super::bitmaps::BitmapCommand;
+use super::blocking::BlockingCommand;
+use super::databases::DatabaseCommand;
+use super::functions::FunctionCommand;
+use super::geo::GeoCommand;
+use super::hashes::HashCommand;
+use super::hyperloglog::HyperLogLogCommand;
+use super::keys::KeyCommand;
+use super::lists::ListCommand;
+use super::pubsub::{PubSubCommand, SubscribeCommand};
+use super::scripting::ScriptCommand;
+use super::server::ServerCommand;
+use super::sets::SetCommand;
+use super::storage::Store;
+use super::streams::StreamCommand;
+use super::strings::StringCommand;
+use super::transaction::TransactionCommand;
+use super::zsets::ZSetCommand;
+use super::{Error, Value};
+
+/// Cursor over the arguments of a single command, following the command name.
+///
+/// Every accessor fails with the command's arity error when the arguments run
+/// out, so parsers can consume positionally and report mistakes uniformly.
+pub struct Arguments {
+    name: String,
+    args: std::vec::IntoIter<Value>,
+}
+
+impl Arguments {
+    pub fn new(name: String, args: Vec<Value>) -> Arguments {
+        Arguments {
+            name,
+            args: args.into_iter(),
+        }
+    }
+
+    /// Lowercased command name, as used in error replies.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.len() == 0
+    }
+
+    pub fn wrong_arity(&self) -> Error {
+        Error::WrongArity(self.name.clone())
+    }
+
+    pub fn next_value(&mut self) -> Result<Value, Error> {
+        let name = &self.name;
+        self.args
+            .next()
+            .ok_or_else(|| Error::WrongArity(name.clone()))
+    }
+
+    pub fn next_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        self.next_value()?.to_bytes()
+    }
+
+    /// Next argument as text, for option names and other keywords.
+    pub fn next_string(&mut self) -> Result<String, Error> {
+        Ok(String::from_utf8_lossy(&self.next_bytes()?).into_owned())
+    }
+
+    pub fn next_int(&mut self) -> Result<i64, Error> {
+        self.next_value()?.to_int()
+    }
+
+    pub fn next_float(&mut self) -> Result<f64, Error> {
+        self.next_value()?.to_float()
+    }
+
+    /// Consumes the next argument if it is `keyword`, in any case.
+    pub fn next_if(&mut self, keyword: &str) -> bool {
+        let matches = match self.args.as_slice().first() {
+            Some(arg) => arg
+                .to_bytes()
+                .map_or(false, |arg| arg.eq_ignore_ascii_case(keyword.as_bytes())),
+            None => false,
+        };
+        if matches {
+            self.args.next();
+        }
+        matches
+    }
+
+    /// A `numkeys` count followed by that many keys, the form shared by the
+    /// multi-key commands that take further arguments after the keys.
+    pub fn next_keys(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        let count = self.next_key_count()?;
+        self.take_keys(count)
+    }
+
+    /// As [`Arguments::next_keys`], for SINTERCARD, which words the error
+    /// for more keys than arguments as EVAL does.
+    pub fn next_counted_keys(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        let count = self.next_key_count()?;
+        if count > self.len() as i64 {
+            return Err(Error::Argument(
+                "Number of keys can't be greater than number of args".to_owned(),
+            ));
+        }
+        self.take_keys(count)
+    }
+
+    fn next_key_count(&mut self) -> Result<i64, Error> {
+        self.next_int()
+            .ok()
+            .filter(|&count| count > 0)
+            .ok_or_else(|| Error::Argument("numkeys should be greater than 0".to_owned()))
+    }
+
+    /// As [`Arguments::next_keys`], for the commands combining sorted sets,
+    /// which word the error for no keys after the command.
+    pub fn next_input_keys(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        let count = self.next_int()?;
+        if count < 1 {
+            return Err(Error::Argument(format!(
+                "at least 1 input key is needed for '{}' command",
+                self.name
+            )));
+        }
+        self.take_keys(count)
+    }
+
+    fn take_keys(&mut self, count: i64) -> Result<Vec<Vec<u8>>, Error> {
+        if count > self.len() as i64 {
+            return Err(Error::Syntax);
+        }
+        (0..count).map(|_| self.next_bytes()).collect()
+    }
+
+    /// Consumes everything that is left.
+    pub fn rest(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        self.args.by_ref().map(|arg| arg.to_bytes()).collect()
+    }
+
+    /// Fails with an arity error unless all arguments were consumed.
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self.wrong_arity())
+        }
+    }
+}
+
+pub enum Command {
+    Ping(Option<Vec<u8>>),
+    Echo(Vec<u8>),
+    String(StringCommand),
+    Bitmap(BitmapCommand),
+    HyperLogLog(HyperLogLogCommand),
+    Key(KeyCommand),
+    List(ListCommand),
+    Hash(HashCommand),
+    Set(SetCommand),
+    ZSet(ZSetCommand),
+    Geo(GeoCommand),
+    Stream(StreamCommand),
+    /// A command that may have to wait; connections run these themselves.
+    Block(BlockingCommand),
+    PubSub(PubSubCommand),
+    /// Changes the subscriptions of the connection, which runs these itself.
+    Subscribe(SubscribeCommand),
+    Database(DatabaseCommand),
+    Server(ServerCommand),
+    Script(ScriptCommand),
+    Function(FunctionCommand),
+    /// Starts or ends a transaction, which connections run themselves.
+    Transaction(TransactionCommand),
+    /// Closes the connection, which runs this itself.
+    Quit,
+    /// Returns the connection to its initial state, which it does itself.
+    Reset,
+}
+
+impl Command {
+    pub fn from_value(value: Value) -> Result<Command, Error> {
+        match value {
+            Value::Array(_, data) => Command::from_array(data),
+            Value::String(data) => Command::from_string(&String::from_utf8_lossy(&data)),
+            _ => Err(Error::Argument("wrong argument type".to_owned())),
+        }
+    }
+
+    fn from_string(data: &str) -> Result<Command, Error> {
+        match data.to_lowercase().as_str() {
+            "ping" => Ok(Command::Ping(None)),
+            _ => Err(Error::UnknownCommand(data.to_owned())),
+        }
+    }
+
+    fn from_array(data: Vec<Value>) -> Result<Command, Error> {
+        let mut data = data.into_iter();
+        let command = match data.next() {
+            Some(Value::String(command)) => String::from_utf8_lossy(&command).into_owned(),
+            Some(_) => return Err(Error::Argument("wrong argument type".to_owned())),
+            None => return Err(Error::Argument("empty command".to_owned())),
+        };
+        let name = command.to_lowercase();
+        let mut args = Arguments::new(name.clone(), data.collect());
+
+        let command = match name.as_str() {
+            "ping" => {
+                let message = if args.is_empty() {
+                    None
+                } else {
+                    Some(args.next_bytes()?)
+                };
+                args.finish()?;
+                Command::Ping(message)
+            }
+            "quit" => Command::Quit,
+            "reset" => {
+                args.finish()?;
+                Command::Reset
+            }
+            "echo" => {
+                let message = args.next_bytes()?;
+                args.finish()?;
+                Command::Echo(message)
+            }
+            _ => {
+                if let Some(command) = StringCommand::parse(&name, &mut args)? {
+                    Command::String(command)
+                } else if let Some(command) = BitmapCommand::parse(&name, &mut args)? {
+                    Command::Bitmap(command)
+                } else if let Some(command) = HyperLogLogCommand::parse(&name, &mut args)? {
+                    Command::HyperLogLog(command)
+                } else if let Some(command) = KeyCommand::parse(&name, &mut args)? {
+                    Command::Key(command)
+                } else if let Some(command) = ListCommand::parse(&name, &mut args)? {
+                    Command::List(command)
+                } else if let Some(command) = HashCommand::parse(&name, &mut args)? {
+                    Command::Hash(command)
+                } else if let Some(command) = SetCommand::parse(&name, &mut args)? {
+                    Command::Set(command)
+                } else if let Some(command) = ZSetCommand::parse(&name, &mut args)? {
+                    Command::ZSet(command)
+                } else if let Some(command) = GeoCommand::parse(&name, &mut args)? {
+                    Command::Geo(command)
+                } else if let Some(command) = StreamCommand::parse(&name, &mut args)? {
+                    Command::Stream(command)
+                } else if let Some(command) = BlockingCommand::parse(&name, &mut args)? {
+                    Command::Block(command)
+                } else if let Some(command) = PubSubCommand::parse(&name, &mut args)? {
+                    Command::PubSub(command)
+                } else if let Some(command) = SubscribeCommand::parse(&name, &mut args)? {
+                    Command::Subscribe(command)
+                } else if let Some(command) = DatabaseCommand::parse(&name, &mut args)? {
+                    Command::Database(command)
+                } else if let Some(command) = ServerCommand::parse(&name, &mut args)? {
+                    Command::Server(command)
+                } else if let Some(command) = ScriptCommand::parse(&name, &mut args)? {
+                    Command::Script(command)
+                } else if let Some(command) = FunctionCommand::parse(&name, &mut args)? {
+                    Command::Function(command)
+                } else if let Some(command) = TransactionCommand::parse(&name, &mut args)? {
+                    Command::Transaction(command)
+                } else {
+                    return Err(Error::UnknownCommand(command));
+                }
+            }
+        };
+        Ok(command)
+    }
+
+    /// Runs the command with `db` as the connection's selected database.
+    pub fn execute(self, store: &mut Store, db: &mut usize) -> Result<Value, Error> {
+        match self {
+            Command::Ping(None) => Ok(Value::Status("PONG".to_owned())),
+            Command::Ping(Some(message)) => Ok(Value::String(message)),
+            Command::Echo(data) => Ok(Value::String(data)),
+            Command::String(command) => command.execute(store.database(*db)),
+            Command::Bitmap(command) => command.execute(store.database(*db)),
+            Command::HyperLogLog(command) => command.execute(store.database(*db)),
+            Command::Key(command) => command.execute(store.database(*db)),
+            Command::List(command) => command.execute(store.database(*db)),
+            Command::Hash(command) => command.execute(store.database(*db)),
+            Command::Set(command) => command.execute(store.database(*db)),
+            Command::ZSet(command) => command.execute(store.database(*db)),
+            Command::Geo(command) => command.execute(store.database(*db)),
+            Command::Stream(command) => command.execute(store.database(*db)),
+            // Executed without waiting, as if the timeout had elapsed.
+            Command::Block(command) => {
+                let mut operation = command.operation;
+                Ok(operation
+                    .try_execute(store.database(*db), false)?
+                    .unwrap_or_else(|| operation.timeout_reply()))
+            }
+            Command::PubSub(command) => command.execute(store.pubsub()),
+            Command::Subscribe(_) => unreachable!("connections run subscription commands"),
+            Command::Database(command) => command.execute(store, db),
+            Command::Server(command) => command.execute(store),
+            Command::Script(command) => command.execute(store, db),
+            Command::Function(command) => command.execute(store, db),
+            // Queued in a transaction, the watches are gone by the time it runs.
+            Command::Transaction(TransactionCommand::Unwatch) => Ok(Value::ok()),
+            Command::Transaction(_) => unreachable!("connections run transactions"),
+            Command::Quit | Command::Reset => unreachable!("connections run QUIT and RESET"),
+        }
+    }
+
+    /// Whether a connection with subscriptions may run the command.
+    pub fn allowed_subscribed(&self) -> bool {
+        matches!(
+            self,
+            Command::Ping(_) | Command::Subscribe(_) | Command::Quit | Command::Reset
+        )
+    }
+
+    /// Whether a script may call the command.
+    pub fn allowed_in_script(&self) -> bool {
+        !matches!(
+            self,
+            Command::Subscribe(_)
+                | Command::Transaction(_)
+                | Command::Script(_)
+                | Command::Function(_)
+                | Command::Server(ServerCommand::Shutdown { .. })
+                | Command::Quit
+                | Command::Reset
+        )
+    }
+}
+
+/// The lowercased name of the command in a message, for errors about it.
+pub fn name(message: &Value) -> String {
+    let name = match message {
+        Value::Array(_, data) => match data.first() {
+            Some(Value::String(name)) => name,
+            _ => return String::new(),
+        },
+        Value::String(name) => name,
+        _ => return String::new(),
+    };
+    String::from_utf8_lossy(name).to_lowercase()
+}
+
+/// The commands that may change the dataset, sorted.
+const WRITE_COMMANDS: &[&str] = &[
+    "append",
+    "bitfield",
+    "bitop",
+    "blmove",
+    "blmpop",
+    "blpop",
+    "brpop",
+    "bzmpop",
+    "bzpopmax",
+    "bzpopmin",
+    "copy",
+    "decr",
+    "decrby",
+    "del",
+    "expire",
+    "expireat",
+    "flushall",
+    "flushdb",
+    "function",
+    "geoadd",
+    "geosearchstore",
+    "getdel",
+    "getex",
+    "getset",
+    "hdel",
+    "hexpire",
+    "hexpireat",
+    "hincrby",
+    "hincrbyfloat",
+    "hmset",
+    "hpersist",
+    "hpexpire",
+    "hpexpireat",
+    "hset",
+    "hsetnx",
+    "incr",
+    "incrby",
+    "incrbyfloat",
+    "linsert",
+    "lmove",
+    "lmpop",
+    "lpop",
+    "lpush",
+    "lpushx",
+    "lrem",
+    "lset",
+    "ltrim",
+    "move",
+    "mset",
+    "msetnx",
+    "persist",
+    "pexpire",
+    "pexpireat",
+    "pfadd",
+    "pfmerge",
+    "psetex",
+    "rename",
+    "renamenx",
+    "restore",
+    "rpop",
+    "rpoplpush",
+    "rpush",
+    "rpushx",
+    "sadd",
+    "sdiffstore",
+    "set",
+    "setbit",
+    "setex",
+    "setnx",
+    "setrange",
+    "sinterstore",
+    "smove",
+    "sort",
+    "spop",
+    "srem",
+    "sunionstore",
+    "swapdb",
+    "unlink",
+    "xack",
+    "xadd",
+    "xautoclaim",
+    "xclaim",
+    "xdel",
+    "xgroup",
+    "xreadgroup",
+    "xtrim",
+    "zadd",
+    "zdiffstore",
+    "zincrby",
+    "zinterstore",
+    "zmpop",
+    "zpopmax",
+    "zpopmin",
+    "zrangestore",
+    "zrem",
+    "zremrangebylex",
+    "zremrangebyrank",
+    "zremrangebyscore",
+    "zunionstore",
+];
+
+/// Whether the command of that lowercased name may change the dataset.
+pub fn is_write(name: &str) -> bool {
+    WRITE_COMMANDS.binary_search(&name).is_ok()
+}
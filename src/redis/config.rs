@@ -0,0 +1,146 @@
+//! Server settings, taken from `--name value` command-line arguments the
+//! way redis-server accepts them.
+
+use super::aof::Fsync;
+use super::notify::Flags;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub struct Config {
+    /// Number of logical databases, selectable with SELECT.
+    pub databases: usize,
+    /// The keyspace notifications to publish, none by default.
+    pub notify_keyspace_events: Flags,
+    /// How long a script runs before other connections get BUSY replies.
+    pub busy_reply_threshold: Duration,
+    /// The directory the RDB file goes in.
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    /// The rules for saving in the background: after how many seconds, if
+    /// at least how many changes were made.
+    pub save: Vec<(i64, u64)>,
+    /// Whether writes are logged to an append-only file, which is then
+    /// what the dataset is loaded from.
+    pub appendonly: bool,
+    /// The name of the append-only file, which its files are named after.
+    pub appendfilename: String,
+    /// The directory the files of the append-only file go in, in `dir`.
+    pub appenddirname: String,
+    pub appendfsync: Fsync,
+    /// Whether the base file of the append-only file is an RDB, rather than
+    /// commands.
+    pub aof_use_rdb_preamble: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            databases: 16,
+            notify_keyspace_events: Flags::default(),
+            busy_reply_threshold: Duration::from_millis(5000),
+            dir: PathBuf::from("."),
+            dbfilename: "dump.rdb".to_owned(),
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_owned(),
+            appenddirname: "appendonlydir".to_owned(),
+            appendfsync: Fsync::EverySec,
+            aof_use_rdb_preamble: true,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args<I>(args: I) -> Result<Config, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut config = Config::default();
+        // The first `save` replaces the default rules, later ones add to it.
+        let mut saves = false;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let name = arg.trim_start_matches("--").to_lowercase();
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value for '{}'", arg))?;
+            match name.as_str() {
+                "databases" => {
+                    config.databases = value
+                        .parse()
+                        .ok()
+                        .filter(|&databases| databases >= 1)
+                        .ok_or_else(|| format!("invalid number of databases '{}'", value))?;
+                }
+                "notify-keyspace-events" => {
+                    config.notify_keyspace_events = Flags::parse(&value)?;
+                }
+                "busy-reply-threshold" | "lua-time-limit" => {
+                    let millis = value
+                        .parse()
+                        .map_err(|_| format!("invalid busy reply threshold '{}'", value))?;
+                    config.busy_reply_threshold = Duration::from_millis(millis);
+                }
+                "dir" => {
+                    if !std::path::Path::new(&value).is_dir() {
+                        return Err(format!("can't chdir to '{}'", value));
+                    }
+                    config.dir = PathBuf::from(value);
+                }
+                "dbfilename" => {
+                    if value.contains('/') {
+                        return Err("dbfilename can't be a path, just a filename".to_owned());
+                    }
+                    config.dbfilename = value;
+                }
+                "save" => {
+                    if !saves {
+                        config.save.clear();
+                        saves = true;
+                    }
+                    config.save.extend(save_rules(&value)?);
+                }
+                "appendonly" => config.appendonly = yes_no(&value)?,
+                "appendfilename" => {
+                    if value.contains('/') {
+                        return Err("appendfilename can't be a path, just a filename".to_owned());
+                    }
+                    config.appendfilename = value;
+                }
+                "appenddirname" => {
+                    if value.contains('/') {
+                        return Err("appenddirname can't be a path, just a dirname".to_owned());
+                    }
+                    config.appenddirname = value;
+                }
+                "appendfsync" => config.appendfsync = Fsync::parse(&value)?,
+                "aof-use-rdb-preamble" => config.aof_use_rdb_preamble = yes_no(&value)?,
+                _ => return Err(format!("unknown option '{}'", arg)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn yes_no(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_owned()),
+    }
+}
+
+/// Parses `<seconds> <changes>` pairs; an empty string is no rules at all.
+fn save_rules(value: &str) -> Result<Vec<(i64, u64)>, String> {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    if words.len() % 2 != 0 {
+        return Err("Invalid save parameters".to_owned());
+    }
+    words
+        .chunks(2)
+        .map(|pair| match (pair[0].parse(), pair[1].parse()) {
+            (Ok(seconds), Ok(changes)) if seconds >= 0 => Ok((seconds, changes)),
+            _ => Err("Invalid save parameters".to_owned()),
+        })
+        .collect()
+}
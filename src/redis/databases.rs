@@ -0,0 +1,170 @@
+//! Commands that address whole databases, or databases other than the one
+//! the connection has selected.
+
+use super::command::Arguments;
+use super::notify::Class;
+use super::storage::{Database, Store};
+use super::{Error, Value};
+use std::convert::TryInto;
+
+#[derive(Default)]
+pub struct CopyOptions {
+    db: Option<i64>,
+    replace: bool,
+}
+
+pub enum DatabaseCommand {
+    Select(i64),
+    SwapDb(i64, i64),
+    Move(Vec<u8>, i64),
+    Copy(Vec<u8>, Vec<u8>, CopyOptions),
+    DbSize,
+    FlushDb(bool),
+    FlushAll(bool),
+}
+
+impl DatabaseCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<DatabaseCommand>, Error> {
+        let command = match name {
+            "select" => {
+                let index = args.next_int()?;
+                args.finish()?;
+                DatabaseCommand::Select(index)
+            }
+            "swapdb" => {
+                let first = args
+                    .next_int()
+                    .map_err(|_| Error::Argument("invalid first DB index".to_owned()))?;
+                let second = args
+                    .next_int()
+                    .map_err(|_| Error::Argument("invalid second DB index".to_owned()))?;
+                args.finish()?;
+                DatabaseCommand::SwapDb(first, second)
+            }
+            "move" => {
+                let key = args.next_bytes()?;
+                let index = args.next_int()?;
+                args.finish()?;
+                DatabaseCommand::Move(key, index)
+            }
+            "copy" => DatabaseCommand::copy(args)?,
+            "dbsize" => {
+                args.finish()?;
+                DatabaseCommand::DbSize
+            }
+            "flushdb" => DatabaseCommand::FlushDb(DatabaseCommand::flush_mode(args)?),
+            "flushall" => DatabaseCommand::FlushAll(DatabaseCommand::flush_mode(args)?),
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    fn copy(args: &mut Arguments) -> Result<DatabaseCommand, Error> {
+        let source = args.next_bytes()?;
+        let destination = args.next_bytes()?;
+        let mut options = CopyOptions::default();
+        while !args.is_empty() {
+            match args.next_string()?.to_lowercase().as_str() {
+                "replace" => options.replace = true,
+                "db" => options.db = Some(args.next_int()?),
+                _ => return Err(Error::Syntax),
+            }
+        }
+        Ok(DatabaseCommand::Copy(source, destination, options))
+    }
+
+    /// Whether a flush should free the old data in the background.
+    pub fn flush_mode(args: &mut Arguments) -> Result<bool, Error> {
+        if args.is_empty() {
+            return Ok(false);
+        }
+        let lazily = match args.next_string()?.to_lowercase().as_str() {
+            "async" => true,
+            "sync" => false,
+            _ => return Err(Error::Syntax),
+        };
+        if !args.is_empty() {
+            return Err(Error::Syntax);
+        }
+        Ok(lazily)
+    }
+
+    /// Runs against `store`, where `db` is the connection's selected
+    /// database and is updated by SELECT.
+    pub fn execute(self, store: &mut Store, db: &mut usize) -> Result<Value, Error> {
+        let response = match self {
+            DatabaseCommand::Select(index) => {
+                *db = store.index(index)?;
+                Value::ok()
+            }
+            DatabaseCommand::SwapDb(first, second) => {
+                let first = store.index(first)?;
+                let second = store.index(second)?;
+                store.swap(first, second);
+                Value::ok()
+            }
+            DatabaseCommand::Move(key, index) => {
+                let target = store.index(index)?;
+                if target == *db {
+                    return Err(Error::Argument(
+                        "source and destination objects are the same".to_owned(),
+                    ));
+                }
+                if !store.database(*db).contains(&key) || store.database(target).contains(&key) {
+                    return Ok(Value::Int(0));
+                }
+                if let Some(stored) = store.database(*db).remove(&key) {
+                    store
+                        .database(*db)
+                        .notify(Class::Generic, "move_from", &key);
+                    store
+                        .database(target)
+                        .notify(Class::Generic, "move_to", &key);
+                    store.database(target).insert(key, stored);
+                }
+                Value::Int(1)
+            }
+            DatabaseCommand::Copy(source, destination, options) => {
+                let target = match options.db {
+                    Some(index) => store.index(index)?,
+                    None => *db,
+                };
+                if target == *db && source == destination {
+                    return Err(Error::Argument(
+                        "source and destination objects are the same".to_owned(),
+                    ));
+                }
+                let stored = match store.database(*db).get(&source) {
+                    Some(stored) => stored.clone(),
+                    None => return Ok(Value::Int(0)),
+                };
+                let target = store.database(target);
+                if !options.replace && target.contains(&destination) {
+                    return Ok(Value::Int(0));
+                }
+                target.notify(Class::Generic, "copy_to", &destination);
+                target.insert(destination, stored);
+                Value::Int(1)
+            }
+            DatabaseCommand::DbSize => Value::Int(store.database(*db).len().try_into()?),
+            DatabaseCommand::FlushDb(lazily) => {
+                let old = store.database(*db).flush();
+                free(vec![old], lazily);
+                Value::ok()
+            }
+            DatabaseCommand::FlushAll(lazily) => {
+                free(store.take_all(), lazily);
+                Value::ok()
+            }
+        };
+        Ok(response)
+    }
+}
+
+/// Drops flushed databases, on the blocking pool when asked to so that a
+/// huge keyspace does not stall other clients.
+fn free(databases: Vec<Database>, lazily: bool) {
+    if lazily {
+        tokio::task::spawn_blocking(move || drop(databases));
+    }
+}
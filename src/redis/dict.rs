@@ -0,0 +1,226 @@
+//! Chained hash table with power-of-two bucket counts.
+//!
+//! Unlike `std::collections::HashMap` the bucket layout is exposed through
+//! [`Dict::scan`], which walks buckets with Redis' reverse binary cursor: an
+//! element present for the whole iteration is reported at least once even if
+//! the table grows or shrinks between calls.
+
+use super::random;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+const INITIAL_SIZE: usize = 4;
+
+#[derive(Clone)]
+pub struct Dict<K, V> {
+    buckets: Vec<Vec<(K, V)>>,
+    len: usize,
+    /// Upper bound on the length of any chain; reset on resize.
+    longest_chain: usize,
+    hasher: RandomState,
+}
+
+impl<K, V> Default for Dict<K, V> {
+    fn default() -> Self {
+        Dict {
+            buckets: vec![],
+            len: 0,
+            longest_chain: 0,
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Dict<K, V> {
+    pub fn new() -> Self {
+        Dict::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn bucket<Q>(&self, key: &Q) -> usize
+    where
+        Q: Hash + ?Sized,
+    {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        (hasher.finish() as usize) & (self.buckets.len() - 1)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.buckets.is_empty() {
+            return None;
+        }
+        self.buckets[self.bucket(key)]
+            .iter()
+            .find(|(k, _)| k.borrow() == key)
+            .map(|(_, v)| v)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.buckets.is_empty() {
+            return None;
+        }
+        let index = self.bucket(key);
+        self.buckets[index]
+            .iter_mut()
+            .find(|(k, _)| k.borrow() == key)
+            .map(|(_, v)| v)
+    }
+
+    /// Inserts or replaces, returning the previous value.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(current) = self.get_mut(&key) {
+            return Some(std::mem::replace(current, value));
+        }
+        if self.len >= self.buckets.len() {
+            self.resize((self.buckets.len() * 2).max(INITIAL_SIZE));
+        }
+        let index = self.bucket(&key);
+        self.buckets[index].push((key, value));
+        self.longest_chain = self.longest_chain.max(self.buckets[index].len());
+        self.len += 1;
+        None
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.remove_entry(key).map(|(_, v)| v)
+    }
+
+    pub fn remove_entry<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.buckets.is_empty() {
+            return None;
+        }
+        let index = self.bucket(key);
+        let bucket = &mut self.buckets[index];
+        let position = bucket.iter().position(|(k, _)| k.borrow() == key)?;
+        let entry = bucket.swap_remove(position);
+        self.len -= 1;
+        self.shrink_if_sparse();
+        Some(entry)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.iter().map(|(k, v)| (k, v)))
+    }
+
+    /// A uniformly random element. Draws a bucket and a position up to the
+    /// longest chain and retries on empty slots, so short chains are not
+    /// favoured; the load factor bounds keep the expected retries small.
+    pub fn random_entry(&self) -> Option<(&K, &V)> {
+        if self.is_empty() {
+            return None;
+        }
+        loop {
+            let bucket = &self.buckets[random::below(self.buckets.len())];
+            if let Some((key, value)) = bucket.get(random::below(self.longest_chain)) {
+                return Some((key, value));
+            }
+        }
+    }
+
+    /// Up to `count` distinct random elements, in no particular order.
+    /// Sampling with rejection is cheap while the sample is small compared to
+    /// the table; larger samples shuffle a copy of all the entries instead.
+    pub fn sample(&self, count: usize) -> Vec<(&K, &V)> {
+        if count >= self.len {
+            return self.iter().collect();
+        }
+        if count.saturating_mul(3) > self.len {
+            let mut entries: Vec<_> = self.iter().collect();
+            for i in 0..count {
+                let j = i + random::below(entries.len() - i);
+                entries.swap(i, j);
+            }
+            entries.truncate(count);
+            return entries;
+        }
+        let mut picked = Dict::new();
+        let mut entries = vec![];
+        while entries.len() < count {
+            let (key, value) = self.random_entry().expect("table is not empty");
+            if picked.insert(key, ()).is_none() {
+                entries.push((key, value));
+            }
+        }
+        entries
+    }
+
+    /// Reports every element of the bucket addressed by `cursor` and returns
+    /// the cursor of the next bucket, or 0 once the whole table was visited.
+    pub fn scan<F>(&self, cursor: u64, mut f: F) -> u64
+    where
+        F: FnMut(&K, &V),
+    {
+        if self.buckets.is_empty() {
+            return 0;
+        }
+        let mask = (self.buckets.len() - 1) as u64;
+        for (k, v) in &self.buckets[(cursor & mask) as usize] {
+            f(k, v);
+        }
+        // Increment the reversed cursor, so that the high bits move first and
+        // buckets split or merged by a resize are never skipped.
+        let cursor = (cursor | !mask).reverse_bits().wrapping_add(1);
+        cursor.reverse_bits()
+    }
+
+    fn shrink_if_sparse(&mut self) {
+        if self.buckets.len() > INITIAL_SIZE && self.len * 10 < self.buckets.len() {
+            self.resize(self.len.next_power_of_two().max(INITIAL_SIZE));
+        }
+    }
+
+    fn resize(&mut self, size: usize) {
+        let old = std::mem::replace(&mut self.buckets, (0..size).map(|_| vec![]).collect());
+        for (key, value) in old.into_iter().flatten() {
+            let index = self.bucket(&key);
+            self.buckets[index].push((key, value));
+        }
+        self.longest_chain = self.buckets.iter().map(Vec::len).max().unwrap_or(0);
+    }
+}
+
+impl<K: Hash + Eq, V> std::iter::FromIterator<(K, V)> for Dict<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut dict = Dict::new();
+        for (key, value) in iter {
+            dict.insert(key, value);
+        }
+        dict
+    }
+}
+
+impl<K, V> IntoIterator for Dict<K, V> {
+    type Item = (K, V);
+    type IntoIter = std::iter::Flatten<std::vec::IntoIter<Vec<(K, V)>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.buckets.into_iter().flatten()
+    }
+}
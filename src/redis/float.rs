@@ -0,0 +1,66 @@
+//! Parsing and formatting of doubles the way Redis replies with them.
+
+/// Parses a client-supplied double. Infinities are accepted (`inf`, `+inf`,
+/// `-inf`), NaN and out-of-range literals are not.
+pub fn parse(data: &[u8]) -> Option<f64> {
+    let text = std::str::from_utf8(data).ok()?;
+    let value = match text.to_lowercase().as_str() {
+        "inf" | "+inf" | "infinity" | "+infinity" => f64::INFINITY,
+        "-inf" | "-infinity" => f64::NEG_INFINITY,
+        _ => text.parse::<f64>().ok().filter(|value| value.is_finite())?,
+    };
+    Some(value)
+}
+
+/// Shortest representation that round-trips, switching to exponent notation
+/// for very large or very small magnitudes. Used for scores.
+pub fn format(value: f64) -> String {
+    if value.is_infinite() {
+        return if value > 0.0 { "inf" } else { "-inf" }.to_owned();
+    }
+    let magnitude = value.abs();
+    if magnitude != 0.0 && !(1e-5..1e17).contains(&magnitude) {
+        return exponent(value);
+    }
+    format!("{}", value)
+}
+
+/// Plain decimal notation with no exponent and no trailing zeros, as
+/// produced by INCRBYFLOAT.
+pub fn format_human(value: f64) -> String {
+    if value.is_infinite() {
+        return format(value);
+    }
+    // Display never switches to exponent notation and trims trailing zeros
+    // on its own; only negative zero needs normalizing.
+    if value == 0.0 {
+        return "0".to_owned();
+    }
+    format!("{}", value)
+}
+
+/// Seventeen decimals with trailing zeros trimmed, the way Redis prints long
+/// doubles for humans, as in GEOPOS replies.
+pub fn format_fixed(value: f64) -> String {
+    let formatted = format!("{:.17}", value);
+    let formatted = formatted.trim_end_matches('0').trim_end_matches('.');
+    if formatted == "-0" {
+        return "0".to_owned();
+    }
+    formatted.to_owned()
+}
+
+/// C-style `%g` exponent form, e.g. `1.5e-07` or `1e+21`.
+fn exponent(value: f64) -> String {
+    let formatted = format!("{:e}", value);
+    let (mantissa, exponent) = match formatted.find('e') {
+        Some(pos) => formatted.split_at(pos),
+        None => return formatted,
+    };
+    let exponent = &exponent[1..];
+    let (sign, digits) = match exponent.strip_prefix('-') {
+        Some(digits) => ('-', digits),
+        None => ('+', exponent),
+    };
+    format!("{}e{}{:0>2}", mantissa, sign, digits)
+}
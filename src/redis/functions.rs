@@ -0,0 +1,633 @@
+//! Functions: libraries of Lua code loaded with FUNCTION LOAD, naming the
+//! functions FCALL calls with `redis.register_function`.
+//!
+//! Lua values cannot outlive the interpreter that made them, so a library
+//! keeps its parsed code, and each FCALL runs it again in a fresh interpreter
+//! to call one of the functions it registers.
+
+use super::command::Arguments;
+use super::databases::DatabaseCommand;
+use super::glob;
+use super::lua::{self, Host, Interpreter};
+use super::scripting::{self, Inputs, ScriptHost};
+use super::stats::{self, STATS};
+use super::storage::Store;
+use super::{Error, Value};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The name libraries go by in error messages.
+const CHUNK: &str = "user_function";
+
+/// How long loading a library may take to run its code.
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+const FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+pub enum FunctionCommand {
+    Load {
+        replace: bool,
+        code: Vec<u8>,
+    },
+    Call {
+        name: String,
+        inputs: Inputs,
+        read_only: bool,
+    },
+    List {
+        pattern: Option<Vec<u8>>,
+        with_code: bool,
+    },
+    Delete(String),
+    /// Deletes every library, freeing them in the background if set.
+    Flush(bool),
+    Kill,
+    Help,
+}
+
+impl FunctionCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<FunctionCommand>, Error> {
+        let command = match name {
+            "fcall" | "fcall_ro" => {
+                let function = args.next_string()?;
+                let inputs = Inputs::parse(args)?;
+                FunctionCommand::Call {
+                    name: function,
+                    inputs,
+                    read_only: name == "fcall_ro",
+                }
+            }
+            "function" => FunctionCommand::manage(args)?,
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    fn manage(args: &mut Arguments) -> Result<FunctionCommand, Error> {
+        let subcommand = args.next_string()?.to_lowercase();
+        let wrong_arity = || Error::WrongArity(format!("function|{}", subcommand));
+        let command = match subcommand.as_str() {
+            "load" => {
+                let mut replace = false;
+                while args.len() > 1 {
+                    let option = args.next_string()?;
+                    if !option.eq_ignore_ascii_case("replace") {
+                        return Err(Error::Argument(format!("Unknown option given: {}", option)));
+                    }
+                    replace = true;
+                }
+                let code = args.next_bytes().map_err(|_| wrong_arity())?;
+                FunctionCommand::Load { replace, code }
+            }
+            "list" => {
+                let mut pattern = None;
+                let mut with_code = false;
+                while !args.is_empty() {
+                    let option = args.next_string()?;
+                    match option.to_lowercase().as_str() {
+                        "withcode" if !with_code => with_code = true,
+                        "libraryname" if pattern.is_none() => {
+                            pattern = Some(args.next_bytes().map_err(|_| {
+                                Error::Argument("library name argument was not given".to_owned())
+                            })?);
+                        }
+                        "libraryname" => {
+                            return Err(Error::Argument(
+                                "library name argument was given multiple times.".to_owned(),
+                            ))
+                        }
+                        _ => return Err(Error::Argument(format!("Unknown argument {}", option))),
+                    }
+                }
+                FunctionCommand::List { pattern, with_code }
+            }
+            "delete" => {
+                let name = args.next_string().map_err(|_| wrong_arity())?;
+                args.finish().map_err(|_| wrong_arity())?;
+                FunctionCommand::Delete(name)
+            }
+            "flush" => FunctionCommand::Flush(DatabaseCommand::flush_mode(args)?),
+            "kill" => {
+                args.finish().map_err(|_| wrong_arity())?;
+                FunctionCommand::Kill
+            }
+            "help" => {
+                args.finish().map_err(|_| wrong_arity())?;
+                FunctionCommand::Help
+            }
+            _ => {
+                return Err(Error::Argument(format!(
+                    "unknown subcommand '{}'. Try FUNCTION HELP.",
+                    subcommand
+                )))
+            }
+        };
+        Ok(command)
+    }
+
+    pub fn execute(self, store: &mut Store, db: &mut usize) -> Result<Value, Error> {
+        match self {
+            FunctionCommand::Load { replace, code } => {
+                let name = store.functions().load(code, replace)?;
+                stats::increment(&STATS.dirty, 1);
+                Ok(Value::String(name.into_bytes()))
+            }
+            FunctionCommand::Call {
+                name,
+                inputs,
+                read_only,
+            } => call(store, *db, &name, inputs, read_only),
+            FunctionCommand::List { pattern, with_code } => {
+                Ok(store.functions().list(pattern.as_deref(), with_code))
+            }
+            FunctionCommand::Delete(name) => {
+                store.functions().delete(&name)?;
+                stats::increment(&STATS.dirty, 1);
+                Ok(Value::ok())
+            }
+            FunctionCommand::Flush(lazily) => {
+                let functions = std::mem::take(store.functions());
+                stats::increment(&STATS.dirty, 1);
+                if lazily {
+                    tokio::task::spawn_blocking(move || drop(functions));
+                }
+                Ok(Value::ok())
+            }
+            FunctionCommand::Kill => scripting::kill(),
+            FunctionCommand::Help => Ok(help()),
+        }
+    }
+}
+
+/// A function as its library registered it.
+struct Function {
+    description: Option<Vec<u8>>,
+    flags: Vec<&'static str>,
+}
+
+struct Library {
+    /// The code as loaded, metadata line included.
+    code: Vec<u8>,
+    chunk: Arc<lua::Chunk>,
+    functions: BTreeMap<String, Function>,
+}
+
+/// The libraries loaded, by name.
+#[derive(Default)]
+pub struct Functions {
+    libraries: BTreeMap<String, Library>,
+    /// The library of each function, by function name.
+    functions: HashMap<String, String>,
+}
+
+impl Functions {
+    /// Loads a library, replacing the one of the same name if `replace`,
+    /// and returns its name.
+    pub fn load(&mut self, code: Vec<u8>, replace: bool) -> Result<String, Error> {
+        let (name, header) = metadata(&code)?;
+        if !replace && self.libraries.contains_key(&name) {
+            return Err(Error::Argument(format!(
+                "Library '{}' already exists",
+                name
+            )));
+        }
+        // The metadata line is left out but for its newline, which keeps
+        // the line numbers in error messages right.
+        let chunk = lua::parse(&code[header..], CHUNK)
+            .map_err(|message| Error::Argument(format!("Error compiling function: {}", message)))?;
+        let functions = {
+            let mut host = LoadHost {
+                deadline: Instant::now() + LOAD_TIMEOUT,
+            };
+            let mut interpreter = Interpreter::new(CHUNK);
+            interpreter.set_host(&mut host);
+            let registrations = register(&mut interpreter, &chunk).map_err(|error| {
+                Error::Argument(format!(
+                    "Error registering functions: {}",
+                    String::from_utf8_lossy(&error.value.to_display())
+                ))
+            })?;
+            registrations
+                .into_iter()
+                .map(|registration| (registration.name, registration.function))
+                .collect::<BTreeMap<_, _>>()
+        };
+        if functions.is_empty() {
+            return Err(Error::Argument("No functions registered".to_owned()));
+        }
+        for function in functions.keys() {
+            match self.functions.get(function) {
+                Some(library) if *library != name => {
+                    return Err(Error::Argument(format!(
+                        "Function {} already exists",
+                        function
+                    )))
+                }
+                _ => {}
+            }
+        }
+
+        let _ = self.delete(&name);
+        for function in functions.keys() {
+            self.functions.insert(function.clone(), name.clone());
+        }
+        let library = Library {
+            code,
+            chunk: Arc::new(chunk),
+            functions,
+        };
+        self.libraries.insert(name.clone(), library);
+        Ok(name)
+    }
+
+    fn delete(&mut self, name: &str) -> Result<(), Error> {
+        let library = self
+            .libraries
+            .remove(name)
+            .ok_or_else(|| Error::Argument("Library not found".to_owned()))?;
+        for function in library.functions.keys() {
+            self.functions.remove(function);
+        }
+        Ok(())
+    }
+
+    /// The code of the library registering the function, if there is one,
+    /// and whether the function may write.
+    fn lookup(&self, function: &str) -> Option<(Arc<lua::Chunk>, bool)> {
+        let library = &self.libraries[self.functions.get(function)?];
+        let writes = !library.functions[function].flags.contains(&"no-writes");
+        Some((library.chunk.clone(), writes))
+    }
+
+    /// The code of every library, as RDB files keep them.
+    pub fn codes(&self) -> Vec<Vec<u8>> {
+        self.libraries
+            .values()
+            .map(|library| library.code.clone())
+            .collect()
+    }
+
+    fn list(&self, pattern: Option<&[u8]>, with_code: bool) -> Value {
+        let libraries = self
+            .libraries
+            .iter()
+            .filter(|(name, _)| {
+                pattern.map_or(true, |pattern| glob::matches(pattern, name.as_bytes()))
+            })
+            .map(|(name, library)| {
+                let functions = library.functions.iter().map(|(name, function)| {
+                    let flags = function
+                        .flags
+                        .iter()
+                        .map(|flag| Value::Status((*flag).to_owned()));
+                    Value::Map(vec![
+                        (Value::bulk("name"), Value::bulk(name)),
+                        (
+                            Value::bulk("description"),
+                            function
+                                .description
+                                .clone()
+                                .map_or(Value::Nil, Value::String),
+                        ),
+                        (Value::bulk("flags"), Value::array(flags.collect())),
+                    ])
+                });
+                let mut entries = vec![
+                    (Value::bulk("library_name"), Value::bulk(name)),
+                    (Value::bulk("engine"), Value::bulk("LUA")),
+                    (Value::bulk("functions"), Value::array(functions.collect())),
+                ];
+                if with_code {
+                    entries.push((
+                        Value::bulk("library_code"),
+                        Value::String(library.code.clone()),
+                    ));
+                }
+                Value::Map(entries)
+            });
+        Value::array(libraries.collect())
+    }
+}
+
+/// Reads the `#!lua name=<library>` line libraries start with, returning
+/// the library name and where the line ends.
+fn metadata(code: &[u8]) -> Result<(String, usize), Error> {
+    if !code.starts_with(b"#!") {
+        return Err(Error::Argument("Missing library metadata".to_owned()));
+    }
+    let end = code.iter().position(|&c| c == b'\n').unwrap_or(code.len());
+    let line = String::from_utf8_lossy(&code[2..end]);
+    let mut parts = line.split(' ').filter(|part| !part.is_empty());
+    let engine = parts.next().unwrap_or("");
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(Error::Argument(format!("Engine '{}' not found", engine)));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(_) if name.is_some() => {
+                return Err(Error::Argument(
+                    "Invalid metadata value, name argument was given multiple times".to_owned(),
+                ))
+            }
+            Some(value) => name = Some(value.to_owned()),
+            None => {
+                return Err(Error::Argument(format!(
+                    "Invalid metadata value given: {}",
+                    part
+                )))
+            }
+        }
+    }
+    let name = name.ok_or_else(|| Error::Argument("Library name was not given".to_owned()))?;
+    if !valid_name(name.as_bytes()) {
+        return Err(Error::Argument(
+            "Library names can only contain letters, numbers, or underscores(_) and must be at \
+             least one character long"
+                .to_owned(),
+        ));
+    }
+    Ok((name, end))
+}
+
+fn valid_name(name: &[u8]) -> bool {
+    !name.is_empty() && name.iter().all(|&c| c.is_ascii_alphanumeric() || c == b'_')
+}
+
+/// Calls a function for FCALL with the keys and arguments as its two
+/// parameters. FCALL_RO calls only functions flagged `no-writes`, which
+/// FCALL holds to that as well.
+fn call(
+    store: &mut Store,
+    db: usize,
+    name: &str,
+    inputs: Inputs,
+    read_only: bool,
+) -> Result<Value, Error> {
+    let (chunk, writes) = store
+        .functions()
+        .lookup(name)
+        .ok_or_else(|| Error::Argument("Function not found".to_owned()))?;
+    if read_only && writes {
+        return Err(Error::Argument(
+            "Can not execute a script with write flag using *_ro command.".to_owned(),
+        ));
+    }
+    let mut host = ScriptHost::new(store, db, !writes);
+    let mut interpreter = Interpreter::new(CHUNK);
+    interpreter.set_host(&mut host);
+    let keys = scripting::strings(&mut interpreter, inputs.keys);
+    let args = scripting::strings(&mut interpreter, inputs.args);
+    let result = scripting::running(|| {
+        let registrations = register(&mut interpreter, &chunk)?;
+        let callback = registrations
+            .into_iter()
+            .find(|registration| registration.name == name)
+            .map(|registration| registration.callback)
+            .unwrap_or_default();
+        interpreter.call(&callback, vec![keys, args])
+    });
+    match result {
+        Ok(values) => Ok(scripting::to_reply(
+            values.first().unwrap_or(&lua::Value::Nil),
+            0,
+        )),
+        Err(error) => Err(Error::Script(scripting::error_message(&error, name, CHUNK))),
+    }
+}
+
+struct Registration {
+    name: String,
+    callback: lua::Value,
+    function: Function,
+}
+
+/// Runs the code of a library for the functions it registers, keeping
+/// `redis.call` and `redis.pcall` out of its reach meanwhile.
+fn register(
+    interpreter: &mut Interpreter,
+    chunk: &lua::Chunk,
+) -> Result<Vec<Registration>, lua::Error> {
+    scripting::open_redis(interpreter);
+    let redis = match interpreter.globals.borrow().get_str("redis") {
+        lua::Value::Table(redis) => redis,
+        _ => unreachable!(),
+    };
+    let registrations = Rc::new(RefCell::new(vec![]));
+    let (call, pcall) = {
+        let mut redis = redis.borrow_mut();
+        let call = redis.get_str("call");
+        let pcall = redis.get_str("pcall");
+        redis.set_str("call", lua::Value::Nil);
+        redis.set_str("pcall", lua::Value::Nil);
+        redis.set_str(
+            "register_function",
+            register_function(registrations.clone()),
+        );
+        (call, pcall)
+    };
+    scripting::protect_globals(interpreter);
+    interpreter.run(chunk, vec![])?;
+
+    let mut redis = redis.borrow_mut();
+    redis.set_str("call", call);
+    redis.set_str("pcall", pcall);
+    redis.set_str(
+        "register_function",
+        lua::Value::builtin(|interpreter, _| {
+            Err(interpreter
+                .error("redis.register_function can only be called on FUNCTION LOAD command"))
+        }),
+    );
+    let registrations = registrations.take();
+    Ok(registrations)
+}
+
+fn register_function(registrations: Rc<RefCell<Vec<Registration>>>) -> lua::Value {
+    lua::Value::builtin(move |interpreter, args| {
+        let registration = registration(interpreter, &args)?;
+        if !valid_name(registration.name.as_bytes()) {
+            return Err(interpreter.error(
+                "Function names can only contain letters, numbers, or underscores(_) and must \
+                 be at least one character long",
+            ));
+        }
+        let mut registrations = registrations.borrow_mut();
+        if registrations
+            .iter()
+            .any(|registered| registered.name == registration.name)
+        {
+            return Err(interpreter.error("Function already exists in the library"));
+        }
+        registrations.push(registration);
+        Ok(vec![])
+    })
+}
+
+/// Reads the arguments of `redis.register_function`: a name and a callback,
+/// or a table of them along with flags and a description.
+fn registration(
+    interpreter: &Interpreter,
+    args: &[lua::Value],
+) -> Result<Registration, lua::Error> {
+    let named = match args {
+        [lua::Value::Table(named)] => named.clone(),
+        [_] => {
+            return Err(interpreter.error(
+                "calling redis.register_function with a single argument is only applicable to \
+                 Lua table (representing named arguments).",
+            ))
+        }
+        [name, callback] => {
+            let name = match name {
+                lua::Value::String(name) => String::from_utf8_lossy(name).into_owned(),
+                _ => {
+                    return Err(interpreter
+                        .error("first argument to redis.register_function must be a string"))
+                }
+            };
+            if !matches!(callback, lua::Value::Function(_)) {
+                return Err(interpreter
+                    .error("second argument to redis.register_function must be a function"));
+            }
+            return Ok(Registration {
+                name,
+                callback: callback.clone(),
+                function: Function {
+                    description: None,
+                    flags: vec![],
+                },
+            });
+        }
+        _ => return Err(interpreter.error("wrong number of arguments to redis.register_function")),
+    };
+
+    let mut name = None;
+    let mut callback = None;
+    let mut function = Function {
+        description: None,
+        flags: vec![],
+    };
+    let mut key = lua::Value::Nil;
+    while let Ok(Some((next, value))) = named.borrow().next(&key) {
+        let argument = next.to_bytes().unwrap_or_default();
+        match (&*argument, &value) {
+            (b"function_name", lua::Value::String(value)) => {
+                name = Some(String::from_utf8_lossy(value).into_owned());
+            }
+            (b"function_name", _) => {
+                return Err(interpreter.error(
+                    "function_name argument given to redis.register_function must be a string",
+                ))
+            }
+            (b"callback", lua::Value::Function(_)) => callback = Some(value.clone()),
+            (b"callback", _) => {
+                return Err(interpreter.error(
+                    "callback argument given to redis.register_function must be a function",
+                ))
+            }
+            (b"description", lua::Value::String(value)) => {
+                function.description = Some(value.to_vec());
+            }
+            (b"description", _) => {
+                return Err(interpreter.error(
+                    "description argument given to redis.register_function must be a string",
+                ))
+            }
+            (b"flags", lua::Value::Table(flags)) => {
+                let flags = flags.borrow();
+                for i in 1..=flags.len() {
+                    let flag = flags.get(&lua::Value::Number(i as f64)).to_bytes();
+                    let flag = FLAGS
+                        .iter()
+                        .find(|known| flag.as_deref() == Some(known.as_bytes()))
+                        .ok_or_else(|| interpreter.error("unknown flag given"))?;
+                    function.flags.push(flag);
+                }
+            }
+            (b"flags", _) => {
+                return Err(interpreter.error(
+                    "flags argument to redis.register_function must be a table representing \
+                     function flags",
+                ))
+            }
+            _ => return Err(interpreter.error("unknown argument given to redis.register_function")),
+        }
+        key = next;
+    }
+    let name = name.ok_or_else(|| {
+        interpreter.error("redis.register_function must get a function name argument")
+    })?;
+    let callback = callback
+        .ok_or_else(|| interpreter.error("redis.register_function must get a callback argument"))?;
+    Ok(Registration {
+        name,
+        callback,
+        function,
+    })
+}
+
+/// Stands in for the host while a library loads, only to stop its code
+/// once it runs for too long.
+struct LoadHost {
+    deadline: Instant,
+}
+
+impl Host for LoadHost {
+    fn call(
+        &mut self,
+        interpreter: &mut Interpreter,
+        _: Vec<lua::Value>,
+        _: bool,
+    ) -> Result<lua::Value, lua::Error> {
+        Err(interpreter.error("commands cannot be called while loading a library"))
+    }
+
+    fn interrupt(&mut self) -> Option<lua::Value> {
+        if Instant::now() >= self.deadline {
+            Some(lua::Value::string(b"FUNCTION LOAD timeout"))
+        } else {
+            None
+        }
+    }
+}
+
+fn help() -> Value {
+    Value::array(
+        [
+            "FUNCTION <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+            "LOAD [REPLACE] <FUNCTION CODE>",
+            "    Create a new library with the given library name and code.",
+            "DELETE <LIBRARY NAME>",
+            "    Delete the given library.",
+            "LIST [LIBRARYNAME PATTERN] [WITHCODE]",
+            "    Return general information on all the libraries:",
+            "    * Library name",
+            "    * The engine used to run the Library",
+            "    * Functions list",
+            "    * Library code (if WITHCODE is given)",
+            "    It also possible to get only function that matches a pattern using LIBRARYNAME argument.",
+            "KILL",
+            "    Kill the current running function.",
+            "FLUSH [ASYNC|SYNC]",
+            "    Delete all the libraries.",
+            "    Valid modes are:",
+            "    * ASYNC: Asynchronously flush the libraries.",
+            "    * SYNC: Synchronously flush the libraries.",
+            "HELP",
+            "    Print this help.",
+        ]
+        .iter()
+        .map(|line| Value::Status((*line).to_owned()))
+        .collect(),
+    )
+}
@@ -0,0 +1,453 @@
+//! Geospatial indexes: sorted sets whose scores are the geohashes of their
+//! members' positions.
+
+use super::command::Arguments;
+use super::float;
+use super::geohash::{self, Shape};
+use super::ranges::ScoreRange;
+use super::storage::Database;
+use super::zsets::{self, AddOptions, ZSet, ZSetCommand};
+use super::{Error, Value};
+use std::cmp::Ordering;
+
+/// Parses a longitude and latitude pair, which has to be within the area
+/// geohashes cover.
+fn parse_position(args: &mut Arguments) -> Result<(f64, f64), Error> {
+    let longitude = args.next_float()?;
+    let latitude = args.next_float()?;
+    if !(geohash::LONGITUDE_MIN..=geohash::LONGITUDE_MAX).contains(&longitude)
+        || !(geohash::LATITUDE_MIN..=geohash::LATITUDE_MAX).contains(&latitude)
+    {
+        return Err(Error::Argument(format!(
+            "invalid longitude,latitude pair {:.6},{:.6}",
+            longitude, latitude
+        )));
+    }
+    Ok((longitude, latitude))
+}
+
+/// Parses a distance unit into the meters it stands for.
+fn parse_unit(args: &mut Arguments) -> Result<f64, Error> {
+    match args.next_string()?.to_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(Error::Argument(
+            "unsupported unit provided. please use M, KM, FT, MI".to_owned(),
+        )),
+    }
+}
+
+/// The position of a member, decoded from its score.
+fn position(zset: &ZSet, member: &[u8]) -> Option<(f64, f64)> {
+    zset.score(member)
+        .map(|score| geohash::decode(score as u64))
+}
+
+fn distance_reply(meters: f64, unit: f64) -> Value {
+    Value::String(format!("{:.4}", meters / unit).into_bytes())
+}
+
+/// Parses a distance that must not be negative, reporting a bad number with
+/// `invalid`.
+fn parse_distance(args: &mut Arguments, invalid: &str) -> Result<f64, Error> {
+    args.next_float()
+        .map_err(|_| Error::Argument(invalid.to_owned()))
+}
+
+enum Center {
+    Member(Vec<u8>),
+    Position(f64, f64),
+}
+
+/// The options of GEOSEARCH and GEOSEARCHSTORE.
+pub struct Search {
+    center: Center,
+    /// The shape with its sizes in meters.
+    shape: Shape,
+    /// Meters in the unit the sizes were given in, also used for distances
+    /// in the reply.
+    unit: f64,
+    /// Sorted by distance, farthest first if set.
+    order: Option<bool>,
+    /// At most so many results, or all if zero.
+    count: usize,
+    /// Any results up to the count rather than the nearest ones.
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    /// Stores distances rather than geohashes as scores.
+    store_dist: bool,
+}
+
+/// A member within the searched shape.
+struct Found<'a> {
+    member: &'a Vec<u8>,
+    score: f64,
+    longitude: f64,
+    latitude: f64,
+    distance: f64,
+}
+
+impl Search {
+    /// Parses the options of a search, which stores its results if `store`
+    /// is set.
+    fn parse(args: &mut Arguments, store: bool) -> Result<Search, Error> {
+        let mut center = None;
+        let mut shape = None;
+        let mut unit = 1.0;
+        let mut search = Search {
+            center: Center::Position(0.0, 0.0),
+            shape: Shape::Radius(0.0),
+            unit: 1.0,
+            order: None,
+            count: 0,
+            any: false,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+            store_dist: false,
+        };
+        while !args.is_empty() {
+            let option = args.next_string()?.to_lowercase();
+            match option.as_str() {
+                "withdist" => search.with_dist = true,
+                "withhash" => search.with_hash = true,
+                "withcoord" => search.with_coord = true,
+                "any" => search.any = true,
+                "asc" => search.order = Some(false),
+                "desc" => search.order = Some(true),
+                "count" if !args.is_empty() => {
+                    let count = args.next_int()?;
+                    if count <= 0 {
+                        return Err(Error::Argument("COUNT must be > 0".to_owned()));
+                    }
+                    search.count = count as usize;
+                }
+                "storedist" if store => search.store_dist = true,
+                "frommember"
+                    if !args.is_empty() && !matches!(center, Some(Center::Position(..))) =>
+                {
+                    center = Some(Center::Member(args.next_bytes()?));
+                }
+                "fromlonlat" if args.len() >= 2 && !matches!(center, Some(Center::Member(_))) => {
+                    let (longitude, latitude) = parse_position(args)?;
+                    center = Some(Center::Position(longitude, latitude));
+                }
+                "byradius" if args.len() >= 2 && !matches!(shape, Some(Shape::Box(..))) => {
+                    let radius = parse_distance(args, "need numeric radius")?;
+                    if radius < 0.0 {
+                        return Err(Error::Argument("radius cannot be negative".to_owned()));
+                    }
+                    unit = parse_unit(args)?;
+                    shape = Some(Shape::Radius(radius));
+                }
+                "bybox" if args.len() >= 3 && !matches!(shape, Some(Shape::Radius(_))) => {
+                    let width = parse_distance(args, "need numeric width")?;
+                    let height = parse_distance(args, "need numeric height")?;
+                    if width < 0.0 || height < 0.0 {
+                        return Err(Error::Argument(
+                            "height or width cannot be negative".to_owned(),
+                        ));
+                    }
+                    unit = parse_unit(args)?;
+                    shape = Some(Shape::Box(width, height));
+                }
+                _ => return Err(Error::Syntax),
+            }
+        }
+        if store && (search.with_dist || search.with_hash || search.with_coord) {
+            return Err(Error::Argument(format!(
+                "{} is not compatible with WITHDIST, WITHHASH and WITHCOORD options",
+                args.name().to_uppercase()
+            )));
+        }
+        search.center = center.ok_or_else(|| {
+            Error::Argument(format!(
+                "exactly one of FROMMEMBER or FROMLONLAT can be specified for {}",
+                args.name()
+            ))
+        })?;
+        search.shape = match shape.ok_or_else(|| {
+            Error::Argument(format!(
+                "exactly one of BYRADIUS and BYBOX can be specified for {}",
+                args.name()
+            ))
+        })? {
+            Shape::Radius(radius) => Shape::Radius(radius * unit),
+            Shape::Box(width, height) => Shape::Box(width * unit, height * unit),
+        };
+        search.unit = unit;
+        if search.any && search.count == 0 {
+            return Err(Error::Argument(
+                "the ANY argument requires COUNT argument".to_owned(),
+            ));
+        }
+        // The nearest ones are only known once all are sorted.
+        if search.count != 0 && search.order.is_none() && !search.any {
+            search.order = Some(false);
+        }
+        Ok(search)
+    }
+
+    /// The members within the shape, in the order the cells around the
+    /// center were searched unless sorted.
+    fn run<'a>(&self, zset: &'a ZSet) -> Result<Vec<Found<'a>>, Error> {
+        let (longitude, latitude) = match &self.center {
+            Center::Member(member) => position(zset, member).ok_or_else(|| {
+                Error::Argument("could not decode requested zset member".to_owned())
+            })?,
+            &Center::Position(longitude, latitude) => (longitude, latitude),
+        };
+        // With ANY the search stops as soon as there are enough.
+        let limit = if self.any { self.count } else { 0 };
+        let full = |found: &Vec<Found>| limit != 0 && found.len() >= limit;
+        let cells = geohash::search_cells(longitude, latitude, self.shape);
+        let mut found = vec![];
+        let mut last = 0;
+        for (index, &cell) in cells.iter().enumerate() {
+            // Huge shapes make neighbors wrap around into the same cells,
+            // which Redis only notices when they come one after another.
+            if last != 0 && cell == cells[last] {
+                continue;
+            }
+            if full(&found) {
+                break;
+            }
+            let scores = cell.scores();
+            let range = ScoreRange::half_open(scores.start as f64, scores.end as f64);
+            for (member, score) in zset.range(zset.score_ranks(&range)) {
+                let (member_longitude, member_latitude) = geohash::decode(score as u64);
+                let distance =
+                    self.shape
+                        .distance(member_longitude, member_latitude, longitude, latitude);
+                if let Some(distance) = distance {
+                    found.push(Found {
+                        member,
+                        score,
+                        longitude: member_longitude,
+                        latitude: member_latitude,
+                        distance,
+                    });
+                }
+                if full(&found) {
+                    break;
+                }
+            }
+            last = index;
+        }
+        if let Some(descending) = self.order {
+            found.sort_by(|a, b| {
+                let order = a
+                    .distance
+                    .partial_cmp(&b.distance)
+                    .unwrap_or(Ordering::Equal);
+                if descending {
+                    order.reverse()
+                } else {
+                    order
+                }
+            });
+        }
+        if self.count != 0 {
+            found.truncate(self.count);
+        }
+        Ok(found)
+    }
+
+    fn reply(&self, found: Vec<Found>) -> Value {
+        let with_options = self.with_dist || self.with_hash || self.with_coord;
+        Value::array(
+            found
+                .into_iter()
+                .map(|found| {
+                    let member = Value::String(found.member.clone());
+                    if !with_options {
+                        return member;
+                    }
+                    let mut reply = vec![member];
+                    if self.with_dist {
+                        reply.push(distance_reply(found.distance, self.unit));
+                    }
+                    if self.with_hash {
+                        reply.push(Value::Int(found.score as i64));
+                    }
+                    if self.with_coord {
+                        reply.push(Value::array(vec![
+                            Value::String(float::format_fixed(found.longitude).into_bytes()),
+                            Value::String(float::format_fixed(found.latitude).into_bytes()),
+                        ]));
+                    }
+                    Value::array(reply)
+                })
+                .collect(),
+        )
+    }
+}
+
+pub enum GeoCommand {
+    /// GEOADD, carried out as the ZADD it amounts to.
+    Add(ZSetCommand),
+    Pos(Vec<u8>, Vec<Vec<u8>>),
+    /// GEODIST: the distance between two members, in a unit of so many
+    /// meters.
+    Dist(Vec<u8>, Vec<u8>, Vec<u8>, f64),
+    Hash(Vec<u8>, Vec<Vec<u8>>),
+    Search(Vec<u8>, Search),
+    /// GEOSEARCHSTORE: the destination, then the key searched.
+    SearchStore(Vec<u8>, Vec<u8>, Search),
+}
+
+impl GeoCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<GeoCommand>, Error> {
+        let command = match name {
+            "geoadd" => {
+                let key = args.next_bytes()?;
+                if args.len() < 3 {
+                    return Err(args.wrong_arity());
+                }
+                let (mut nx, mut xx, mut ch) = (false, false, false);
+                loop {
+                    if args.next_if("nx") {
+                        nx = true;
+                    } else if args.next_if("xx") {
+                        xx = true;
+                    } else if args.next_if("ch") {
+                        ch = true;
+                    } else {
+                        break;
+                    }
+                }
+                if args.is_empty() || args.len() % 3 != 0 || (nx && xx) {
+                    return Err(Error::Syntax);
+                }
+                let mut pairs = vec![];
+                while !args.is_empty() {
+                    let (longitude, latitude) = parse_position(args)?;
+                    let score = geohash::encode(longitude, latitude) as f64;
+                    pairs.push((score, args.next_bytes()?));
+                }
+                GeoCommand::Add(ZSetCommand::Add(key, AddOptions::geo(nx, xx, ch), pairs))
+            }
+            "geopos" | "geohash" => {
+                let key = args.next_bytes()?;
+                let members = args.rest()?;
+                if name == "geopos" {
+                    GeoCommand::Pos(key, members)
+                } else {
+                    GeoCommand::Hash(key, members)
+                }
+            }
+            "geodist" => {
+                let key = args.next_bytes()?;
+                let first = args.next_bytes()?;
+                let second = args.next_bytes()?;
+                let unit = match args.len() {
+                    0 => 1.0,
+                    1 => parse_unit(args)?,
+                    _ => return Err(Error::Syntax),
+                };
+                GeoCommand::Dist(key, first, second, unit)
+            }
+            "geosearch" => {
+                let key = args.next_bytes()?;
+                if args.len() < 5 {
+                    return Err(args.wrong_arity());
+                }
+                GeoCommand::Search(key, Search::parse(args, false)?)
+            }
+            "geosearchstore" => {
+                let destination = args.next_bytes()?;
+                let key = args.next_bytes()?;
+                if args.len() < 5 {
+                    return Err(args.wrong_arity());
+                }
+                GeoCommand::SearchStore(destination, key, Search::parse(args, true)?)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    pub fn execute(self, storage: &mut Database) -> Result<Value, Error> {
+        let response = match self {
+            GeoCommand::Add(command) => command.execute(storage)?,
+            GeoCommand::Pos(key, members) => {
+                let zset = match storage.get(&key) {
+                    Some(stored) => Some(stored.zset()?),
+                    None => None,
+                };
+                Value::array(
+                    members
+                        .iter()
+                        .map(
+                            |member| match zset.and_then(|zset| position(zset, member)) {
+                                Some((longitude, latitude)) => Value::array(vec![
+                                    Value::String(float::format_fixed(longitude).into_bytes()),
+                                    Value::String(float::format_fixed(latitude).into_bytes()),
+                                ]),
+                                None => Value::NilArray,
+                            },
+                        )
+                        .collect(),
+                )
+            }
+            GeoCommand::Dist(key, first, second, unit) => {
+                let zset = match storage.get(&key) {
+                    Some(stored) => stored.zset()?,
+                    None => return Ok(Value::Nil),
+                };
+                match (position(zset, &first), position(zset, &second)) {
+                    (Some((longitude1, latitude1)), Some((longitude2, latitude2))) => {
+                        let meters =
+                            geohash::distance(longitude1, latitude1, longitude2, latitude2);
+                        distance_reply(meters, unit)
+                    }
+                    _ => Value::Nil,
+                }
+            }
+            GeoCommand::Hash(key, members) => {
+                let zset = match storage.get(&key) {
+                    Some(stored) => Some(stored.zset()?),
+                    None => None,
+                };
+                Value::array(
+                    members
+                        .iter()
+                        .map(
+                            |member| match zset.and_then(|zset| position(zset, member)) {
+                                Some((longitude, latitude)) => {
+                                    Value::bulk(&geohash::to_string(longitude, latitude))
+                                }
+                                None => Value::Nil,
+                            },
+                        )
+                        .collect(),
+                )
+            }
+            GeoCommand::Search(key, search) => match storage.get(&key) {
+                Some(stored) => search.reply(search.run(stored.zset()?)?),
+                None => Value::array(vec![]),
+            },
+            GeoCommand::SearchStore(destination, key, search) => {
+                let mut result = ZSet::new();
+                if let Some(stored) = storage.get(&key) {
+                    for found in search.run(stored.zset()?)? {
+                        let score = if search.store_dist {
+                            found.distance / search.unit
+                        } else {
+                            found.score
+                        };
+                        result.insert(found.member.clone(), score);
+                    }
+                }
+                let len = result.len() as i64;
+                zsets::store(storage, destination, result, "geosearchstore");
+                Value::Int(len)
+            }
+        };
+        Ok(response)
+    }
+}
@@ -0,0 +1,327 @@
+//! Geohashes as Redis computes them: longitude and latitude each scaled to
+//! 26 bits and interleaved into a 52 bit integer, which is exact as a double
+//! and so serves as the score of a sorted set member.
+//!
+//! Latitudes are limited to the range of the Web Mercator projection rather
+//! than the poles, so the scores differ from standard geohashes; those are
+//! only produced for GEOHASH replies.
+//!
+//! Searches look at the cell around their center and its eight neighbors, at
+//! a precision coarse enough for the cells to cover the searched shape; each
+//! cell is a range of scores.
+
+use std::ops::Range;
+
+pub const LONGITUDE_MIN: f64 = -180.0;
+pub const LONGITUDE_MAX: f64 = 180.0;
+pub const LATITUDE_MIN: f64 = -85.051_128_78;
+pub const LATITUDE_MAX: f64 = 85.051_128_78;
+/// Bits per coordinate.
+const STEP: u32 = 26;
+/// Half the circumference of the Earth along the equator, in Web Mercator
+/// meters.
+const MERCATOR_MAX: f64 = 20_037_726.37;
+/// Mean radius used by the haversine formula.
+const EARTH_RADIUS: f64 = 6_372_797.560_856;
+const ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Moves the bits of `value` to the even positions of the result.
+fn spread(value: u32) -> u64 {
+    let mut value = u64::from(value);
+    value = (value | value << 16) & 0x0000_ffff_0000_ffff;
+    value = (value | value << 8) & 0x00ff_00ff_00ff_00ff;
+    value = (value | value << 4) & 0x0f0f_0f0f_0f0f_0f0f;
+    value = (value | value << 2) & 0x3333_3333_3333_3333;
+    (value | value << 1) & 0x5555_5555_5555_5555
+}
+
+/// The inverse of `spread`, gathering the bits at even positions.
+fn squash(value: u64) -> u32 {
+    let mut value = value & 0x5555_5555_5555_5555;
+    value = (value | value >> 1) & 0x3333_3333_3333_3333;
+    value = (value | value >> 2) & 0x0f0f_0f0f_0f0f_0f0f;
+    value = (value | value >> 4) & 0x00ff_00ff_00ff_00ff;
+    value = (value | value >> 8) & 0x0000_ffff_0000_ffff;
+    (value | value >> 16) as u32
+}
+
+fn rad_deg(radians: f64) -> f64 {
+    radians / (std::f64::consts::PI / 180.0)
+}
+
+/// Latitudes take the even bits and longitudes the odd ones, so the most
+/// significant bit halves the longitude range.
+fn encode_in(longitude: f64, latitude: f64, latitude_min: f64, latitude_max: f64) -> u64 {
+    encode_step(longitude, latitude, latitude_min, latitude_max, STEP)
+}
+
+fn encode_step(
+    longitude: f64,
+    latitude: f64,
+    latitude_min: f64,
+    latitude_max: f64,
+    step: u32,
+) -> u64 {
+    let scale = f64::from(1u32 << step);
+    let latitude = (latitude - latitude_min) / (latitude_max - latitude_min) * scale;
+    let longitude = (longitude - LONGITUDE_MIN) / (LONGITUDE_MAX - LONGITUDE_MIN) * scale;
+    spread(latitude as u32) | spread(longitude as u32) << 1
+}
+
+/// The score of a position, which has to be within the valid ranges.
+pub fn encode(longitude: f64, latitude: f64) -> u64 {
+    encode_in(longitude, latitude, LATITUDE_MIN, LATITUDE_MAX)
+}
+
+/// The position at the center of the cell a score stands for.
+pub fn decode(bits: u64) -> (f64, f64) {
+    let area = Cell { bits, step: STEP }.area();
+    let center =
+        |(min, max): (f64, f64), low: f64, high: f64| ((min + max) / 2.0).max(low).min(high);
+    (
+        center(area.longitude, LONGITUDE_MIN, LONGITUDE_MAX),
+        center(area.latitude, LATITUDE_MIN, LATITUDE_MAX),
+    )
+}
+
+/// The bounds of a cell, as minimum and maximum pairs.
+struct Area {
+    longitude: (f64, f64),
+    latitude: (f64, f64),
+}
+
+/// A cell of the grid at a precision of `step` bits per coordinate.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    bits: u64,
+    step: u32,
+}
+
+impl Cell {
+    fn around(longitude: f64, latitude: f64, step: u32) -> Cell {
+        Cell {
+            bits: encode_step(longitude, latitude, LATITUDE_MIN, LATITUDE_MAX, step),
+            step,
+        }
+    }
+
+    fn area(self) -> Area {
+        let scale = f64::from(1u32 << self.step);
+        let bounds = |index: u32, min: f64, max: f64| {
+            (
+                min + f64::from(index) / scale * (max - min),
+                min + (f64::from(index) + 1.0) / scale * (max - min),
+            )
+        };
+        Area {
+            longitude: bounds(squash(self.bits >> 1), LONGITUDE_MIN, LONGITUDE_MAX),
+            latitude: bounds(squash(self.bits), LATITUDE_MIN, LATITUDE_MAX),
+        }
+    }
+
+    /// The cell so many cells east and north, wrapping around the grid.
+    fn moved(self, east: i8, north: i8) -> Cell {
+        let width = 64 - self.step * 2;
+        let mut longitude = self.bits & 0xaaaa_aaaa_aaaa_aaaa;
+        let mut latitude = self.bits & 0x5555_5555_5555_5555;
+        // Filling the bits of the other coordinate makes carries and
+        // borrows skip over them.
+        if east != 0 {
+            let others = 0x5555_5555_5555_5555 >> width;
+            longitude = if east > 0 {
+                longitude.wrapping_add(others + 1)
+            } else {
+                (longitude | others).wrapping_sub(others + 1)
+            };
+            longitude &= 0xaaaa_aaaa_aaaa_aaaa >> width;
+        }
+        if north != 0 {
+            let others = 0xaaaa_aaaa_aaaa_aaaa >> width;
+            latitude = if north > 0 {
+                latitude.wrapping_add(others + 1)
+            } else {
+                (latitude | others).wrapping_sub(others + 1)
+            };
+            latitude &= 0x5555_5555_5555_5555 >> width;
+        }
+        Cell {
+            bits: longitude | latitude,
+            step: self.step,
+        }
+    }
+
+    /// The scores of the positions within the cell.
+    pub fn scores(self) -> Range<u64> {
+        let shift = (STEP - self.step) * 2;
+        self.bits << shift..(self.bits + 1) << shift
+    }
+}
+
+/// The area a search covers around its center, in meters.
+#[derive(Clone, Copy)]
+pub enum Shape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+impl Shape {
+    /// The distance from the center of a position within the shape.
+    pub fn distance(
+        self,
+        longitude: f64,
+        latitude: f64,
+        center_longitude: f64,
+        center_latitude: f64,
+    ) -> Option<f64> {
+        match self {
+            Shape::Radius(radius) => {
+                let distance = distance(center_longitude, center_latitude, longitude, latitude);
+                Some(distance).filter(|&distance| distance <= radius)
+            }
+            Shape::Box(width, height) => {
+                if latitude_distance(latitude, center_latitude) > height / 2.0
+                    || distance(longitude, latitude, center_longitude, latitude) > width / 2.0
+                {
+                    return None;
+                }
+                Some(distance(
+                    center_longitude,
+                    center_latitude,
+                    longitude,
+                    latitude,
+                ))
+            }
+        }
+    }
+
+    /// The western, southern, eastern and northern bounds of the shape.
+    fn bounds(self, longitude: f64, latitude: f64) -> (f64, f64, f64, f64) {
+        let (width, height) = match self {
+            Shape::Radius(radius) => (radius, radius),
+            Shape::Box(width, height) => (width / 2.0, height / 2.0),
+        };
+        let latitude_delta = rad_deg(height / EARTH_RADIUS);
+        let longitude_delta =
+            |latitude: f64| rad_deg(width / EARTH_RADIUS / latitude.to_radians().cos());
+        // The side nearer the pole spans the most longitude.
+        let longitude_delta = if latitude < 0.0 {
+            longitude_delta(latitude - latitude_delta)
+        } else {
+            longitude_delta(latitude + latitude_delta)
+        };
+        (
+            longitude - longitude_delta,
+            latitude - latitude_delta,
+            longitude + longitude_delta,
+            latitude + latitude_delta,
+        )
+    }
+
+    /// The distance from the center to the farthest point of the shape.
+    fn reach(self) -> f64 {
+        match self {
+            Shape::Radius(radius) => radius,
+            Shape::Box(width, height) => ((width / 2.0).powi(2) + (height / 2.0).powi(2)).sqrt(),
+        }
+    }
+}
+
+/// The precision at which a cell is about as large as `reach` meters,
+/// coarser towards the poles where cells narrow.
+fn estimate_step(mut reach: f64, latitude: f64) -> u32 {
+    if reach == 0.0 {
+        return STEP;
+    }
+    let mut step: i32 = 1;
+    while reach < MERCATOR_MAX {
+        reach *= 2.0;
+        step += 1;
+    }
+    step -= 2;
+    if !(-66.0..=66.0).contains(&latitude) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&latitude) {
+            step -= 1;
+        }
+    }
+    step.max(1).min(STEP as i32) as u32
+}
+
+/// The cells to look in for positions within a shape: the one around the
+/// center, then its neighbors north, south, east, west, north east, north
+/// west, south east and south west, leaving out those the shape does not
+/// reach into.
+pub fn search_cells(longitude: f64, latitude: f64, shape: Shape) -> Vec<Cell> {
+    let (min_longitude, min_latitude, max_longitude, max_latitude) =
+        shape.bounds(longitude, latitude);
+    let mut step = estimate_step(shape.reach(), latitude);
+    let mut center = Cell::around(longitude, latitude, step);
+    // Near the edges of a cell, its neighbors may not reach far enough.
+    let too_small = center.moved(0, 1).area().latitude.1 < max_latitude
+        || center.moved(0, -1).area().latitude.0 > min_latitude
+        || center.moved(1, 0).area().longitude.1 < max_longitude
+        || center.moved(-1, 0).area().longitude.0 > min_longitude;
+    if step > 1 && too_small {
+        step -= 1;
+        center = Cell::around(longitude, latitude, step);
+    }
+    let area = center.area();
+    let (mut north, mut south, mut east, mut west) = (true, true, true, true);
+    if step >= 2 {
+        south = area.latitude.0 >= min_latitude;
+        north = area.latitude.1 <= max_latitude;
+        west = area.longitude.0 >= min_longitude;
+        east = area.longitude.1 <= max_longitude;
+    }
+    let neighbors = [
+        (0, 1, north),
+        (0, -1, south),
+        (1, 0, east),
+        (-1, 0, west),
+        (1, 1, north && east),
+        (-1, 1, north && west),
+        (1, -1, south && east),
+        (-1, -1, south && west),
+    ];
+    let mut cells = vec![center];
+    for &(east, north, wanted) in &neighbors {
+        if wanted {
+            cells.push(center.moved(east, north));
+        }
+    }
+    cells
+}
+
+/// The standard eleven character geohash of a position, its last character
+/// always `0` as only 52 bits are known.
+pub fn to_string(longitude: f64, latitude: f64) -> String {
+    let bits = encode_in(longitude, latitude, -90.0, 90.0);
+    (0..11)
+        .map(|i| {
+            let index = if i == 10 {
+                0
+            } else {
+                bits >> (52 - (i + 1) * 5) & 0x1f
+            };
+            char::from(ALPHABET[index as usize])
+        })
+        .collect()
+}
+
+/// The distance in meters between two latitudes along a meridian.
+fn latitude_distance(latitude1: f64, latitude2: f64) -> f64 {
+    EARTH_RADIUS * (latitude2.to_radians() - latitude1.to_radians()).abs()
+}
+
+/// The distance in meters between two positions along the surface.
+pub fn distance(longitude1: f64, latitude1: f64, longitude2: f64, latitude2: f64) -> f64 {
+    let v = ((longitude2.to_radians() - longitude1.to_radians()) / 2.0).sin();
+    // On the same meridian only latitudes differ.
+    if v == 0.0 {
+        return latitude_distance(latitude1, latitude2);
+    }
+    let (latitude1, latitude2) = (latitude1.to_radians(), latitude2.to_radians());
+    let u = ((latitude2 - latitude1) / 2.0).sin();
+    let a = u * u + latitude1.cos() * latitude2.cos() * v * v;
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
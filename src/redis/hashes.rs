@@ -0,0 +1,703 @@
+use super::command::Arguments;
+use super::dict::Dict;
+use super::float;
+use super::keys::{self, ExpireCondition, TtlQuery};
+use super::notify::Class;
+use super::scan::{self, ScanOptions, ScanTarget};
+use super::stats::{self, STATS};
+use super::storage::{self, Data, Database};
+use super::{Error, Value};
+use std::convert::TryInto;
+
+/// A field's value, with its deadline in unix milliseconds if it has one.
+#[derive(Clone)]
+struct Field {
+    value: Vec<u8>,
+    expiry: Option<i64>,
+}
+
+impl Field {
+    fn expired(&self, now: i64) -> bool {
+        self.expiry.map_or(false, |expiry| expiry < now)
+    }
+}
+
+/// The fields of a hash. Fields past their deadline are invisible to every
+/// accessor, and reclaimed by writes to them, [`Hash::purge`] and the active
+/// expiry cycle.
+#[derive(Clone, Default)]
+pub struct Hash {
+    fields: Dict<Vec<u8>, Field>,
+    /// No field expires before this; None while no field has a TTL.
+    next_expiry: Option<i64>,
+}
+
+impl Hash {
+    pub fn new() -> Hash {
+        Hash::default()
+    }
+
+    /// Whether some field may have expired by `now`.
+    pub fn needs_purge(&self, now: i64) -> bool {
+        self.next_expiry.map_or(false, |expiry| expiry < now)
+    }
+
+    /// Number of live fields.
+    pub fn len(&self) -> usize {
+        let now = storage::unix_millis();
+        if !self.needs_purge(now) {
+            return self.fields.len();
+        }
+        self.fields
+            .iter()
+            .filter(|(_, field)| !field.expired(now))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn live(&self, name: &[u8]) -> Option<&Field> {
+        let now = storage::unix_millis();
+        self.fields.get(name).filter(|field| !field.expired(now))
+    }
+
+    pub fn get(&self, name: &[u8]) -> Option<&Vec<u8>> {
+        self.live(name).map(|field| &field.value)
+    }
+
+    /// Sets a field, dropping any TTL it had, and returns the previous value.
+    pub fn insert(&mut self, name: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>> {
+        let now = storage::unix_millis();
+        self.fields
+            .insert(
+                name,
+                Field {
+                    value,
+                    expiry: None,
+                },
+            )
+            .filter(|field| !field.expired(now))
+            .map(|field| field.value)
+    }
+
+    /// Sets the value of a field, keeping its TTL if it is still live.
+    pub fn update(&mut self, name: Vec<u8>, value: Vec<u8>) {
+        let now = storage::unix_millis();
+        match self.fields.get_mut(&name) {
+            Some(field) if !field.expired(now) => field.value = value,
+            _ => {
+                self.insert(name, value);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, name: &[u8]) -> Option<Vec<u8>> {
+        let now = storage::unix_millis();
+        let field = self.fields.remove(name)?;
+        if field.expired(now) {
+            stats::increment(&STATS.expired_subkeys, 1);
+            return None;
+        }
+        Some(field.value)
+    }
+
+    /// The deadline of a live field, or None if there is no such field.
+    pub fn expiry(&self, name: &[u8]) -> Option<Option<i64>> {
+        self.live(name).map(|field| field.expiry)
+    }
+
+    /// Replaces the deadline of a live field; returns false if it is gone.
+    pub fn set_expiry(&mut self, name: &[u8], expiry: Option<i64>) -> bool {
+        let now = storage::unix_millis();
+        match self.fields.get_mut(name) {
+            Some(field) if !field.expired(now) => {
+                field.expiry = expiry;
+                if let Some(expiry) = expiry {
+                    self.next_expiry =
+                        Some(self.next_expiry.map_or(expiry, |next| next.min(expiry)));
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether any live field has a TTL.
+    pub fn has_expiries(&self) -> bool {
+        self.next_expiry.is_some()
+            && self
+                .iter_with_expiry()
+                .any(|(_, _, expiry)| expiry.is_some())
+    }
+
+    /// Live fields and values, in bucket order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)> {
+        self.iter_with_expiry()
+            .map(|(name, value, _)| (name, value))
+    }
+
+    pub fn iter_with_expiry(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>, Option<i64>)> {
+        let now = storage::unix_millis();
+        self.fields
+            .iter()
+            .filter(move |(_, field)| !field.expired(now))
+            .map(|(name, field)| (name, &field.value, field.expiry))
+    }
+
+    /// Visits the live fields of one bucket; see [`Dict::scan`].
+    pub fn scan<F>(&self, cursor: u64, mut f: F) -> u64
+    where
+        F: FnMut(&Vec<u8>, &Vec<u8>),
+    {
+        let now = storage::unix_millis();
+        self.fields.scan(cursor, |name, field| {
+            if !field.expired(now) {
+                f(name, &field.value)
+            }
+        })
+    }
+
+    /// A random field; expired ones may be drawn unless purged first.
+    pub fn random_entry(&self) -> Option<(&Vec<u8>, &Vec<u8>)> {
+        self.fields
+            .random_entry()
+            .map(|(name, field)| (name, &field.value))
+    }
+
+    /// Up to `count` distinct random fields; see [`Dict::sample`]. Expired
+    /// ones may be drawn unless purged first.
+    pub fn sample(&self, count: usize) -> Vec<(&Vec<u8>, &Vec<u8>)> {
+        self.fields
+            .sample(count)
+            .into_iter()
+            .map(|(name, field)| (name, &field.value))
+            .collect()
+    }
+
+    /// Deletes the expired fields, returning how many there were.
+    pub fn purge(&mut self) -> usize {
+        let now = storage::unix_millis();
+        if !self.needs_purge(now) {
+            return 0;
+        }
+        let expired: Vec<_> = self
+            .fields
+            .iter()
+            .filter(|(_, field)| field.expired(now))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &expired {
+            self.fields.remove(name);
+        }
+        self.next_expiry = self
+            .fields
+            .iter()
+            .filter_map(|(_, field)| field.expiry)
+            .min();
+        stats::increment(&STATS.expired_subkeys, expired.len() as u64);
+        expired.len()
+    }
+}
+
+pub enum HashCommand {
+    /// Sets field/value pairs, creating the hash if needed. With the flag
+    /// set it replies OK rather than the number of new fields, like HMSET.
+    Set(Vec<u8>, Vec<(Vec<u8>, Vec<u8>)>, bool),
+    /// Sets a field only if the hash does not have it yet.
+    SetNx(Vec<u8>, Vec<u8>, Vec<u8>),
+    Get(Vec<u8>, Vec<u8>),
+    MGet(Vec<u8>, Vec<Vec<u8>>),
+    IncrBy(Vec<u8>, Vec<u8>, i64),
+    IncrByFloat(Vec<u8>, Vec<u8>, f64),
+    Delete(Vec<u8>, Vec<Vec<u8>>),
+    Exists(Vec<u8>, Vec<u8>),
+    GetAll(Vec<u8>),
+    Keys(Vec<u8>),
+    Values(Vec<u8>),
+    Len(Vec<u8>),
+    Scan(Vec<u8>, u64, ScanOptions),
+    /// Samples fields: a single one without a count, distinct ones for a
+    /// positive count and possibly repeated ones for a negative count, with
+    /// their values when the flag is set.
+    RandomField(Vec<u8>, Option<i64>, bool),
+    /// Sets field deadlines in unix milliseconds, subject to a condition.
+    Expire(Vec<u8>, i64, Option<ExpireCondition>, Vec<Vec<u8>>),
+    Persist(Vec<u8>, Vec<Vec<u8>>),
+    Ttl(Vec<u8>, TtlQuery, Vec<Vec<u8>>),
+}
+
+impl HashCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<HashCommand>, Error> {
+        let command = match name {
+            "hset" | "hmset" => {
+                let key = args.next_bytes()?;
+                if args.is_empty() || args.len() % 2 != 0 {
+                    return Err(args.wrong_arity());
+                }
+                let mut pairs = vec![];
+                while !args.is_empty() {
+                    pairs.push((args.next_bytes()?, args.next_bytes()?));
+                }
+                HashCommand::Set(key, pairs, name == "hmset")
+            }
+            "hsetnx" => {
+                let key = args.next_bytes()?;
+                let field = args.next_bytes()?;
+                let value = args.next_bytes()?;
+                args.finish()?;
+                HashCommand::SetNx(key, field, value)
+            }
+            "hget" => {
+                let key = args.next_bytes()?;
+                let field = args.next_bytes()?;
+                args.finish()?;
+                HashCommand::Get(key, field)
+            }
+            "hincrby" => {
+                let key = args.next_bytes()?;
+                let field = args.next_bytes()?;
+                let increment = args.next_int()?;
+                args.finish()?;
+                HashCommand::IncrBy(key, field, increment)
+            }
+            "hincrbyfloat" => {
+                let key = args.next_bytes()?;
+                let field = args.next_bytes()?;
+                let increment = args.next_float()?;
+                args.finish()?;
+                HashCommand::IncrByFloat(key, field, increment)
+            }
+            "hmget" => {
+                let key = args.next_bytes()?;
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                HashCommand::MGet(key, args.rest()?)
+            }
+            "hdel" => {
+                let key = args.next_bytes()?;
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                HashCommand::Delete(key, args.rest()?)
+            }
+            "hexists" => {
+                let key = args.next_bytes()?;
+                let field = args.next_bytes()?;
+                args.finish()?;
+                HashCommand::Exists(key, field)
+            }
+            "hgetall" | "hkeys" | "hvals" | "hlen" => {
+                let key = args.next_bytes()?;
+                args.finish()?;
+                match name {
+                    "hgetall" => HashCommand::GetAll(key),
+                    "hkeys" => HashCommand::Keys(key),
+                    "hvals" => HashCommand::Values(key),
+                    _ => HashCommand::Len(key),
+                }
+            }
+            "hrandfield" => {
+                let key = args.next_bytes()?;
+                let count = if args.is_empty() {
+                    None
+                } else {
+                    Some(args.next_int()?)
+                };
+                let with_values = match args.len() {
+                    0 => false,
+                    1 if args.next_string()?.eq_ignore_ascii_case("withvalues") => true,
+                    _ => return Err(Error::Syntax),
+                };
+                HashCommand::RandomField(key, count, with_values)
+            }
+            "hexpire" | "hpexpire" | "hexpireat" | "hpexpireat" => {
+                let key = args.next_bytes()?;
+                let amount = args.next_int()?;
+                if amount < 0 {
+                    return Err(Error::InvalidExpire(name.to_owned()));
+                }
+                let deadline = keys::deadline(name, amount)?;
+                if deadline > MAX_FIELD_EXPIRY {
+                    return Err(Error::InvalidExpire(name.to_owned()));
+                }
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                let flag = args.next_string()?;
+                let condition = if flag.eq_ignore_ascii_case("fields") {
+                    None
+                } else {
+                    let condition = ExpireCondition::from_flag(&flag).ok_or_else(fields_missing)?;
+                    if !args.next_string()?.eq_ignore_ascii_case("fields") {
+                        return Err(fields_missing());
+                    }
+                    Some(condition)
+                };
+                HashCommand::Expire(key, deadline, condition, parse_fields(args)?)
+            }
+            "hpersist" => {
+                let key = args.next_bytes()?;
+                HashCommand::Persist(key, HashCommand::fields(args)?)
+            }
+            "httl" | "hpttl" | "hexpiretime" | "hpexpiretime" => {
+                let query = match name {
+                    "httl" => TtlQuery::Seconds,
+                    "hpttl" => TtlQuery::Millis,
+                    "hexpiretime" => TtlQuery::UnixSeconds,
+                    _ => TtlQuery::UnixMillis,
+                };
+                let key = args.next_bytes()?;
+                HashCommand::Ttl(key, query, HashCommand::fields(args)?)
+            }
+            "hscan" => {
+                let key = args.next_bytes()?;
+                let cursor = scan::parse_cursor(&args.next_bytes()?)?;
+                let options = ScanOptions::parse(args, ScanTarget::Hash)?;
+                HashCommand::Scan(key, cursor, options)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    /// The `FIELDS numfields field [field ...]` tail of the field TTL
+    /// commands.
+    fn fields(args: &mut Arguments) -> Result<Vec<Vec<u8>>, Error> {
+        if args.is_empty() {
+            return Err(args.wrong_arity());
+        }
+        if !args.next_string()?.eq_ignore_ascii_case("fields") {
+            return Err(fields_missing());
+        }
+        parse_fields(args)
+    }
+
+    pub fn execute(self, storage: &mut Database) -> Result<Value, Error> {
+        let response = match self {
+            HashCommand::Set(key, pairs, legacy) => {
+                let hash = storage
+                    .get_or_insert_with(&key, || Data::Hash(Hash::new()))
+                    .hash_mut()?;
+                let mut added = 0;
+                for (field, value) in pairs {
+                    if hash.insert(field, value).is_none() {
+                        added += 1;
+                    }
+                }
+                storage.notify(Class::Hash, "hset", &key);
+                if legacy {
+                    Value::ok()
+                } else {
+                    Value::Int(added)
+                }
+            }
+            HashCommand::SetNx(key, field, value) => {
+                let hash = storage
+                    .get_or_insert_with(&key, || Data::Hash(Hash::new()))
+                    .hash_mut()?;
+                if hash.get(&field).is_some() {
+                    Value::Int(0)
+                } else {
+                    hash.insert(field, value);
+                    storage.notify(Class::Hash, "hset", &key);
+                    Value::Int(1)
+                }
+            }
+            HashCommand::Get(key, field) => match storage.get(&key) {
+                Some(stored) => stored
+                    .hash()?
+                    .get(&field)
+                    .map_or(Value::Nil, |value| Value::String(value.clone())),
+                None => Value::Nil,
+            },
+            HashCommand::MGet(key, fields) => {
+                let hash = match storage.get(&key) {
+                    Some(stored) => Some(stored.hash()?),
+                    None => None,
+                };
+                Value::array(
+                    fields
+                        .iter()
+                        .map(|field| {
+                            hash.and_then(|hash| hash.get(field))
+                                .map_or(Value::Nil, |value| Value::String(value.clone()))
+                        })
+                        .collect(),
+                )
+            }
+            HashCommand::IncrBy(key, field, increment) => {
+                let current = match storage.peek(&key) {
+                    Some(stored) => match stored.hash()?.get(&field) {
+                        Some(value) => std::str::from_utf8(value)
+                            .ok()
+                            .and_then(|value| value.parse::<i64>().ok())
+                            .ok_or_else(|| {
+                                Error::Argument("hash value is not an integer".to_owned())
+                            })?,
+                        None => 0,
+                    },
+                    None => 0,
+                };
+                let result = current.checked_add(increment).ok_or(Error::Overflow)?;
+                storage
+                    .get_or_insert_with(&key, || Data::Hash(Hash::new()))
+                    .hash_mut()?
+                    .update(field, result.to_string().into_bytes());
+                storage.notify(Class::Hash, "hincrby", &key);
+                Value::Int(result)
+            }
+            HashCommand::IncrByFloat(key, field, increment) => {
+                let current = match storage.peek(&key) {
+                    Some(stored) => match stored.hash()?.get(&field) {
+                        Some(value) => float::parse(value).ok_or_else(|| {
+                            Error::Argument("hash value is not a float".to_owned())
+                        })?,
+                        None => 0.0,
+                    },
+                    None => 0.0,
+                };
+                let result = current + increment;
+                if !result.is_finite() {
+                    return Err(Error::Argument(
+                        "increment would produce NaN or Infinity".to_owned(),
+                    ));
+                }
+                let result = float::format_human(result).into_bytes();
+                storage
+                    .get_or_insert_with(&key, || Data::Hash(Hash::new()))
+                    .hash_mut()?
+                    .update(field, result.clone());
+                storage.notify(Class::Hash, "hincrbyfloat", &key);
+                Value::String(result)
+            }
+            HashCommand::Delete(key, fields) => {
+                let hash = match storage.get_mut(&key) {
+                    Some(stored) => stored.hash_mut()?,
+                    None => return Ok(Value::Int(0)),
+                };
+                let removed = fields
+                    .iter()
+                    .filter(|field| hash.remove(field.as_slice()).is_some())
+                    .count();
+                if removed > 0 {
+                    storage.notify(Class::Hash, "hdel", &key);
+                }
+                storage.remove_if_empty(&key);
+                Value::Int(removed.try_into()?)
+            }
+            HashCommand::Exists(key, field) => match storage.get(&key) {
+                Some(stored) => Value::Int(stored.hash()?.get(&field).is_some() as i64),
+                None => Value::Int(0),
+            },
+            HashCommand::GetAll(key) => match storage.get(&key) {
+                Some(stored) => Value::Map(
+                    stored
+                        .hash()?
+                        .iter()
+                        .map(|(field, value)| {
+                            (Value::String(field.clone()), Value::String(value.clone()))
+                        })
+                        .collect(),
+                ),
+                None => Value::Map(vec![]),
+            },
+            HashCommand::Keys(key) => match storage.get(&key) {
+                Some(stored) => Value::array(
+                    stored
+                        .hash()?
+                        .iter()
+                        .map(|(field, _)| Value::String(field.clone()))
+                        .collect(),
+                ),
+                None => Value::array(vec![]),
+            },
+            HashCommand::Values(key) => match storage.get(&key) {
+                Some(stored) => Value::array(
+                    stored
+                        .hash()?
+                        .iter()
+                        .map(|(_, value)| Value::String(value.clone()))
+                        .collect(),
+                ),
+                None => Value::array(vec![]),
+            },
+            HashCommand::Len(key) => match storage.get(&key) {
+                Some(stored) => Value::Int(stored.hash()?.len().try_into()?),
+                None => Value::Int(0),
+            },
+            HashCommand::Scan(key, cursor, options) => {
+                let hash = match storage.get(&key) {
+                    Some(stored) => stored.hash()?,
+                    None => return Ok(scan::scan_reply(0, vec![])),
+                };
+                let mut batch = vec![];
+                let cursor = options.scan(cursor, |cursor, visited| {
+                    hash.scan(cursor, |field, value| {
+                        *visited += 1;
+                        if options.matches(field) {
+                            batch.push(Value::String(field.clone()));
+                            if options.values() {
+                                batch.push(Value::String(value.clone()));
+                            }
+                        }
+                    })
+                });
+                scan::scan_reply(cursor, batch)
+            }
+            HashCommand::RandomField(key, count, with_values) => {
+                // Sampling draws from every field, so expired ones go first.
+                if let Some(stored) = storage.get_mut(&key) {
+                    if stored.hash_mut()?.purge() > 0 {
+                        storage.notify(Class::Hash, "hexpired", &key);
+                    }
+                }
+                storage.remove_if_empty(&key);
+                let hash = match storage.get(&key) {
+                    Some(stored) => stored.hash()?,
+                    None if count.is_some() => return Ok(Value::array(vec![])),
+                    None => return Ok(Value::Nil),
+                };
+                let count = match count {
+                    Some(count) => count,
+                    None => {
+                        return Ok(hash
+                            .random_entry()
+                            .map_or(Value::Nil, |(field, _)| Value::String(field.clone())))
+                    }
+                };
+                let entries = if count >= 0 {
+                    hash.sample(count.try_into()?)
+                } else {
+                    let count = count.unsigned_abs().try_into()?;
+                    (0..count).filter_map(|_| hash.random_entry()).collect()
+                };
+                let mut reply = vec![];
+                for (field, value) in entries {
+                    reply.push(Value::String(field.clone()));
+                    if with_values {
+                        reply.push(Value::String(value.clone()));
+                    }
+                }
+                Value::array(reply)
+            }
+            HashCommand::Expire(key, deadline, condition, fields) => {
+                let hash = match storage.get_mut(&key) {
+                    Some(stored) => stored.hash_mut()?,
+                    None => {
+                        return Ok(Value::array(
+                            fields.iter().map(|_| Value::Int(-2)).collect(),
+                        ))
+                    }
+                };
+                let now = storage::unix_millis();
+                let (mut expired, mut updated) = (false, false);
+                let mut replies = vec![];
+                for field in &fields {
+                    let reply = match hash.expiry(field) {
+                        None => -2,
+                        Some(current)
+                            if !condition
+                                .as_ref()
+                                .map_or(true, |condition| condition.allows(current, deadline)) =>
+                        {
+                            0
+                        }
+                        Some(_) if deadline <= now => {
+                            hash.remove(field);
+                            expired = true;
+                            2
+                        }
+                        Some(_) => {
+                            hash.set_expiry(field, Some(deadline));
+                            updated = true;
+                            1
+                        }
+                    };
+                    replies.push(Value::Int(reply));
+                }
+                if updated {
+                    storage.notify(Class::Hash, "hexpire", &key);
+                }
+                if expired {
+                    storage.notify(Class::Hash, "hexpired", &key);
+                }
+                storage.remove_if_empty(&key);
+                Value::array(replies)
+            }
+            HashCommand::Persist(key, fields) => {
+                let hash = match storage.get_mut(&key) {
+                    Some(stored) => stored.hash_mut()?,
+                    None => {
+                        return Ok(Value::array(
+                            fields.iter().map(|_| Value::Int(-2)).collect(),
+                        ))
+                    }
+                };
+                let replies: Vec<_> = fields
+                    .iter()
+                    .map(|field| match hash.expiry(field) {
+                        None => -2,
+                        Some(None) => -1,
+                        Some(Some(_)) => {
+                            hash.set_expiry(field, None);
+                            1
+                        }
+                    })
+                    .collect();
+                if replies.contains(&1) {
+                    storage.notify(Class::Hash, "hpersist", &key);
+                }
+                Value::array(replies.into_iter().map(Value::Int).collect())
+            }
+            HashCommand::Ttl(key, query, fields) => {
+                let hash = match storage.peek(&key) {
+                    Some(stored) => stored.hash()?,
+                    None => {
+                        return Ok(Value::array(
+                            fields.iter().map(|_| Value::Int(-2)).collect(),
+                        ))
+                    }
+                };
+                Value::array(
+                    fields
+                        .iter()
+                        .map(|field| {
+                            Value::Int(match hash.expiry(field) {
+                                None => -2,
+                                Some(None) => -1,
+                                Some(Some(expiry)) => query.reply(expiry),
+                            })
+                        })
+                        .collect(),
+                )
+            }
+        };
+        Ok(response)
+    }
+}
+
+/// Latest field deadline Redis accepts, in unix milliseconds.
+const MAX_FIELD_EXPIRY: i64 = (1 << 48) - 1;
+
+fn fields_missing() -> Error {
+    Error::Argument("Mandatory argument FIELDS is missing or not at the right position".to_owned())
+}
+
+/// The `numfields field [field ...]` list following FIELDS.
+fn parse_fields(args: &mut Arguments) -> Result<Vec<Vec<u8>>, Error> {
+    let count = args.next_int()?;
+    if count <= 0 {
+        return Err(Error::Argument(
+            "Parameter `numFields` should be greater than 0".to_owned(),
+        ));
+    }
+    if count != args.len() as i64 {
+        return Err(Error::Argument(
+            "The `numfields` parameter must match the number of arguments".to_owned(),
+        ));
+    }
+    args.rest()
+}
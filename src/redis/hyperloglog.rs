@@ -0,0 +1,524 @@
+//! HyperLogLog cardinality estimation, stored in strings laid out as Redis
+//! lays them out, so they can be moved between the two with GET and SET.
+//!
+//! A string starts with a 16 byte header: the magic `HYLL`, the encoding,
+//! three unused bytes and the last cardinality computed, little endian, its
+//! most significant bit set once a write makes it stale. The dense encoding
+//! follows with 2^14 registers of six bits, packed least significant first.
+//!
+//! New HyperLogLogs use the sparse encoding instead, a run-length encoding of
+//! the registers in three opcodes:
+//!
+//! * `00xxxxxx`: a run of `x + 1` zeros, up to 64;
+//! * `01xxxxxx yyyyyyyy`: a run of `xy + 1` zeros, up to 16384;
+//! * `1vvvvvxx`: a run of `x + 1` registers set to `v + 1`, up to four
+//!   registers and a value of 32.
+//!
+//! It is promoted to dense once a register exceeds 32 or it outgrows
+//! [`SPARSE_MAX_BYTES`]. Writes update the opcodes in place the way Redis
+//! does, so both produce the same strings.
+
+use super::command::Arguments;
+use super::notify::Class;
+use super::storage::{Data, Database, StoredValue};
+use super::{Error, Value};
+use std::convert::TryInto;
+
+const MAGIC: &[u8] = b"HYLL";
+const HEADER_LEN: usize = 16;
+/// Bits of the hash that select a register.
+const P: u32 = 14;
+/// Bits of the hash whose run of zeros is counted.
+const Q: u32 = 64 - P;
+const REGISTERS: usize = 1 << P;
+const REGISTER_BITS: usize = 6;
+const REGISTER_MAX: u8 = (1 << REGISTER_BITS) - 1;
+const DENSE_LEN: usize = HEADER_LEN + (REGISTERS * REGISTER_BITS + 7) / 8;
+const DENSE: u8 = 0;
+const SPARSE: u8 = 1;
+/// Longest sparse string before promotion, the default hll-sparse-max-bytes.
+const SPARSE_MAX_BYTES: usize = 3000;
+const ZERO_MAX_LEN: usize = 64;
+const XZERO_MAX_LEN: usize = 16384;
+const VAL_MAX_VALUE: u8 = 32;
+const VAL_MAX_LEN: usize = 4;
+/// Offset of the cached cardinality in the header.
+const CARD: usize = 8;
+const SEED: u64 = 0xadc8_3b19;
+
+/// MurmurHash64A, reading words little endian.
+fn murmur64a(data: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (data.len() as u64).wrapping_mul(M);
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        let mut k = u64::from_le_bytes(word.try_into().expect("chunks are eight bytes"));
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = words.remainder();
+    if !tail.is_empty() {
+        for (i, &byte) in tail.iter().enumerate() {
+            h ^= u64::from(byte) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// The register an element falls in and the value it bids for it: the
+/// position of the first set bit in the rest of its hash.
+fn register_of(element: &[u8]) -> (usize, u8) {
+    let hash = murmur64a(element, SEED);
+    let index = hash as usize & (REGISTERS - 1);
+    // The extra bit ends the run even if the hash is all zeros.
+    let rest = hash >> P | 1 << Q;
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+fn dense_get(registers: &[u8], index: usize) -> u8 {
+    let byte = index * REGISTER_BITS / 8;
+    let shift = (index * REGISTER_BITS) & 7;
+    // The last register sits in the last byte with no next one to read.
+    let next = registers.get(byte + 1).copied().unwrap_or(0);
+    let pair = u16::from(registers[byte]) | u16::from(next) << 8;
+    (pair >> shift) as u8 & REGISTER_MAX
+}
+
+fn dense_set(registers: &mut [u8], index: usize, value: u8) {
+    let byte = index * REGISTER_BITS / 8;
+    let shift = (index * REGISTER_BITS) & 7;
+    registers[byte] &= !(REGISTER_MAX << shift);
+    registers[byte] |= value << shift;
+    if shift > 8 - REGISTER_BITS {
+        registers[byte + 1] &= !(REGISTER_MAX >> (8 - shift));
+        registers[byte + 1] |= value >> (8 - shift);
+    }
+}
+
+/// An opcode of the sparse encoding.
+#[derive(Clone, Copy)]
+enum Opcode {
+    Zero(usize),
+    XZero(usize),
+    Val(u8, usize),
+}
+
+impl Opcode {
+    /// The opcode at the start of `ops`, None if there is none.
+    fn decode(ops: &[u8]) -> Option<Opcode> {
+        let byte = *ops.first()?;
+        let opcode = if byte & 0x80 != 0 {
+            Opcode::Val((byte >> 2 & 0x1f) + 1, usize::from(byte & 0x03) + 1)
+        } else if byte & 0x40 != 0 {
+            let run = usize::from(byte & 0x3f) << 8 | usize::from(*ops.get(1)?);
+            Opcode::XZero(run + 1)
+        } else {
+            Opcode::Zero(usize::from(byte) + 1)
+        };
+        Some(opcode)
+    }
+
+    /// A run of zeros, in the shortest opcode that holds it.
+    fn zeros(run: usize) -> Opcode {
+        if run > ZERO_MAX_LEN {
+            Opcode::XZero(run)
+        } else {
+            Opcode::Zero(run)
+        }
+    }
+
+    fn encode(self) -> Vec<u8> {
+        match self {
+            Opcode::Zero(run) => vec![(run - 1) as u8],
+            Opcode::XZero(run) => vec![0x40 | ((run - 1) >> 8) as u8, (run - 1) as u8],
+            Opcode::Val(value, run) => vec![0x80 | (value - 1) << 2 | (run - 1) as u8],
+        }
+    }
+
+    /// Bytes the opcode takes.
+    fn len(self) -> usize {
+        match self {
+            Opcode::XZero(_) => 2,
+            _ => 1,
+        }
+    }
+
+    /// Registers the opcode covers.
+    fn run(self) -> usize {
+        match self {
+            Opcode::Zero(run) | Opcode::XZero(run) | Opcode::Val(_, run) => run,
+        }
+    }
+}
+
+/// Calls `f` with the first register, length and value of every run of
+/// nonzero registers, checking the opcodes cover exactly every register.
+fn sparse_runs<F>(ops: &[u8], mut f: F) -> Result<(), Error>
+where
+    F: FnMut(usize, usize, u8),
+{
+    let (mut at, mut index) = (0, 0);
+    while at < ops.len() {
+        let opcode = Opcode::decode(&ops[at..]).ok_or(Error::CorruptHll)?;
+        if let Opcode::Val(value, run) = opcode {
+            if index + run > REGISTERS {
+                break;
+            }
+            f(index, run, value);
+        }
+        at += opcode.len();
+        index += opcode.run();
+    }
+    if index != REGISTERS {
+        return Err(Error::CorruptHll);
+    }
+    Ok(())
+}
+
+/// An empty HyperLogLog, its cache holding the valid cardinality of zero.
+fn create() -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.push(SPARSE);
+    data.resize(HEADER_LEN, 0);
+    for _ in 0..REGISTERS / XZERO_MAX_LEN {
+        data.extend(Opcode::XZero(XZERO_MAX_LEN).encode());
+    }
+    data
+}
+
+/// The HyperLogLog stored at a key.
+fn validate(stored: &StoredValue) -> Result<&Vec<u8>, Error> {
+    let data = stored.string()?;
+    let valid = data.len() >= HEADER_LEN
+        && &data[..4] == MAGIC
+        && match data[4] {
+            DENSE => data.len() == DENSE_LEN,
+            SPARSE => true,
+            _ => false,
+        };
+    if !valid {
+        return Err(Error::InvalidHll);
+    }
+    Ok(data)
+}
+
+/// Converts a sparse HyperLogLog to the dense encoding, keeping its cache.
+fn to_dense(data: &mut Vec<u8>) -> Result<(), Error> {
+    if data[4] == DENSE {
+        return Ok(());
+    }
+    let mut dense = data[..HEADER_LEN].to_vec();
+    dense[4] = DENSE;
+    dense.resize(DENSE_LEN, 0);
+    let registers = &mut dense[HEADER_LEN..];
+    sparse_runs(&data[HEADER_LEN..], |first, run, value| {
+        for index in first..first + run {
+            dense_set(registers, index, value);
+        }
+    })?;
+    *data = dense;
+    Ok(())
+}
+
+/// Raises a register to `count`; returns whether it was lower.
+fn set(data: &mut Vec<u8>, index: usize, count: u8) -> Result<bool, Error> {
+    if data[4] == SPARSE {
+        return sparse_set(data, index, count);
+    }
+    let registers = &mut data[HEADER_LEN..];
+    if dense_get(registers, index) >= count {
+        return Ok(false);
+    }
+    dense_set(registers, index, count);
+    Ok(true)
+}
+
+/// Raises a register of a sparse HyperLogLog, splitting the opcode that
+/// covers it, or promoting the whole to dense if the register no longer
+/// fits the encoding or the string grows too long.
+fn sparse_set(data: &mut Vec<u8>, index: usize, count: u8) -> Result<bool, Error> {
+    if count > VAL_MAX_VALUE {
+        return promote(data, index, count);
+    }
+    let (mut at, mut first, mut prev) = (HEADER_LEN, 0, None);
+    let opcode = loop {
+        let opcode = Opcode::decode(&data[at..]).ok_or(Error::CorruptHll)?;
+        if index < first + opcode.run() {
+            break opcode;
+        }
+        prev = Some(at);
+        at += opcode.len();
+        first += opcode.run();
+    };
+    match opcode {
+        Opcode::Val(value, _) if value >= count => return Ok(false),
+        // The opcode covers this register alone, so it is overwritten.
+        Opcode::Val(_, 1) | Opcode::Zero(1) => data[at] = Opcode::Val(count, 1).encode()[0],
+        _ => {
+            // Split into the registers before, this one and those after.
+            let last = first + opcode.run() - 1;
+            let around = |run| match opcode {
+                Opcode::Val(value, _) => Opcode::Val(value, run),
+                _ => Opcode::zeros(run),
+            };
+            let mut sequence = vec![];
+            if index != first {
+                sequence.extend(around(index - first).encode());
+            }
+            sequence.extend(Opcode::Val(count, 1).encode());
+            if index != last {
+                sequence.extend(around(last - index).encode());
+            }
+            if sequence.len() > opcode.len()
+                && data.len() + sequence.len() - opcode.len() > SPARSE_MAX_BYTES
+            {
+                return promote(data, index, count);
+            }
+            data.splice(at..at + opcode.len(), sequence);
+        }
+    }
+    merge_values(data, prev.unwrap_or(HEADER_LEN));
+    Ok(true)
+}
+
+/// Joins adjacent runs of the same value among the few opcodes from `at`,
+/// where a write may have left them apart.
+fn merge_values(data: &mut Vec<u8>, mut at: usize) {
+    let mut scan = 5;
+    while at < data.len() && scan > 0 {
+        scan -= 1;
+        let (value, run) = match Opcode::decode(&data[at..]) {
+            Some(Opcode::Val(value, run)) => (value, run),
+            Some(opcode) => {
+                at += opcode.len();
+                continue;
+            }
+            None => return,
+        };
+        if let Some(Opcode::Val(next, next_run)) = Opcode::decode(&data[at + 1..]) {
+            if next == value && run + next_run <= VAL_MAX_LEN {
+                data[at + 1] = Opcode::Val(value, run + next_run).encode()[0];
+                data.remove(at);
+                // The merged run may join the next one too.
+                continue;
+            }
+        }
+        at += 1;
+    }
+}
+
+fn promote(data: &mut Vec<u8>, index: usize, count: u8) -> Result<bool, Error> {
+    to_dense(data)?;
+    dense_set(&mut data[HEADER_LEN..], index, count);
+    Ok(true)
+}
+
+fn cached(data: &[u8]) -> Option<u64> {
+    let card = u64::from_le_bytes(data[CARD..HEADER_LEN].try_into().expect("eight bytes"));
+    if card >> 63 == 0 {
+        Some(card)
+    } else {
+        None
+    }
+}
+
+fn set_cached(data: &mut [u8], card: u64) {
+    data[CARD..HEADER_LEN].copy_from_slice(&card.to_le_bytes());
+}
+
+fn invalidate(data: &mut [u8]) {
+    data[HEADER_LEN - 1] |= 0x80;
+}
+
+/// Raises every register to the ones of `data`, where `max` has one byte a
+/// register.
+fn merge(max: &mut [u8], data: &[u8]) -> Result<(), Error> {
+    let registers = &data[HEADER_LEN..];
+    if data[4] == SPARSE {
+        return sparse_runs(registers, |first, run, value| {
+            for max in &mut max[first..first + run] {
+                *max = (*max).max(value);
+            }
+        });
+    }
+    for (index, max) in max.iter_mut().enumerate() {
+        *max = (*max).max(dense_get(registers, index));
+    }
+    Ok(())
+}
+
+/// Estimates the cardinality from one byte a register, with the estimator
+/// of Ertl's "New cardinality estimation algorithms for HyperLogLog
+/// sketches", as Redis does.
+fn estimate(registers: &[u8]) -> u64 {
+    let mut histogram = [0u32; Q as usize + 2];
+    for &register in registers {
+        histogram[usize::from(register)] += 1;
+    }
+    let m = REGISTERS as f64;
+    let mut z = m * tau((m - f64::from(histogram[Q as usize + 1])) / m);
+    for &count in histogram[1..=Q as usize].iter().rev() {
+        z += f64::from(count);
+        z *= 0.5;
+    }
+    z += m * sigma(f64::from(histogram[0]) / m);
+    (0.5 / std::f64::consts::LN_2 * m * m / z).round() as u64
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if previous == z {
+            return z / 3.0;
+        }
+    }
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if previous == z {
+            return z;
+        }
+    }
+}
+
+pub enum HyperLogLogCommand {
+    Add(Vec<u8>, Vec<Vec<u8>>),
+    Count(Vec<Vec<u8>>),
+    /// PFMERGE: the destination, which is merged too, then the sources.
+    Merge(Vec<u8>, Vec<Vec<u8>>),
+}
+
+impl HyperLogLogCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<HyperLogLogCommand>, Error> {
+        let command = match name {
+            "pfadd" => {
+                let key = args.next_bytes()?;
+                HyperLogLogCommand::Add(key, args.rest()?)
+            }
+            "pfcount" => {
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                HyperLogLogCommand::Count(args.rest()?)
+            }
+            "pfmerge" => {
+                let destination = args.next_bytes()?;
+                HyperLogLogCommand::Merge(destination, args.rest()?)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    pub fn execute(self, storage: &mut Database) -> Result<Value, Error> {
+        let response = match self {
+            HyperLogLogCommand::Add(key, elements) => {
+                let mut updated = false;
+                if let Some(stored) = storage.get(&key) {
+                    validate(stored)?;
+                } else {
+                    storage.insert(key.clone(), StoredValue::new(Data::String(create()), None));
+                    updated = true;
+                }
+                let data = storage
+                    .get_mut(&key)
+                    .expect("key was just checked")
+                    .string_mut()?;
+                for element in &elements {
+                    let (index, count) = register_of(element);
+                    updated |= set(data, index, count)?;
+                }
+                if updated {
+                    invalidate(data);
+                    storage.notify(Class::String, "pfadd", &key);
+                }
+                Value::Int(updated.into())
+            }
+            HyperLogLogCommand::Count(keys) if keys.len() == 1 => {
+                let key = &keys[0];
+                match storage.get(key) {
+                    Some(stored) => validate(stored)?,
+                    None => return Ok(Value::Int(0)),
+                };
+                let data = storage
+                    .get_mut(key)
+                    .expect("key was just checked")
+                    .string_mut()?;
+                let card = match cached(data) {
+                    Some(card) => card,
+                    None => {
+                        let mut registers = vec![0; REGISTERS];
+                        merge(&mut registers, data)?;
+                        let card = estimate(&registers);
+                        set_cached(data, card);
+                        card
+                    }
+                };
+                Value::Int(card.try_into()?)
+            }
+            // The union of several is counted without caching anything.
+            HyperLogLogCommand::Count(keys) => {
+                let mut registers = vec![0; REGISTERS];
+                for key in &keys {
+                    if let Some(stored) = storage.get(key) {
+                        merge(&mut registers, validate(stored)?)?;
+                    }
+                }
+                Value::Int(estimate(&registers).try_into()?)
+            }
+            HyperLogLogCommand::Merge(destination, sources) => {
+                let mut registers = vec![0; REGISTERS];
+                let mut dense = false;
+                for key in std::iter::once(&destination).chain(&sources) {
+                    if let Some(stored) = storage.get(key) {
+                        let data = validate(stored)?;
+                        dense |= data[4] == DENSE;
+                        merge(&mut registers, data)?;
+                    }
+                }
+                let data = storage
+                    .get_or_insert_with(&destination, || Data::String(create()))
+                    .string_mut()?;
+                // Any dense input makes for a dense result.
+                if dense {
+                    to_dense(data)?;
+                }
+                for (index, &register) in registers.iter().enumerate() {
+                    if register != 0 {
+                        set(data, index, register)?;
+                    }
+                }
+                invalidate(data);
+                storage.notify(Class::String, "pfadd", &destination);
+                Value::ok()
+            }
+        };
+        Ok(response)
+    }
+}
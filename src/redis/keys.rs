@@ -0,0 +1,458 @@
+use super::command::Arguments;
+use super::glob;
+use super::notify::Class;
+use super::rdb;
+use super::scan::{self, ScanOptions, ScanTarget};
+use super::sort::{self, SortOptions};
+use super::storage::{self, Database, StoredValue, ValueType};
+use super::{Error, Value};
+use std::convert::TryInto;
+
+pub enum ExpireCondition {
+    NoExpiry,
+    HasExpiry,
+    GreaterThan,
+    LessThan,
+}
+
+impl ExpireCondition {
+    /// A single NX, XX, GT or LT flag.
+    pub fn from_flag(flag: &str) -> Option<ExpireCondition> {
+        match flag.to_lowercase().as_str() {
+            "nx" => Some(ExpireCondition::NoExpiry),
+            "xx" => Some(ExpireCondition::HasExpiry),
+            "gt" => Some(ExpireCondition::GreaterThan),
+            "lt" => Some(ExpireCondition::LessThan),
+            _ => None,
+        }
+    }
+
+    /// Whether `deadline` may replace the `current` one; no deadline counts
+    /// as an infinite one.
+    pub fn allows(&self, current: Option<i64>, deadline: i64) -> bool {
+        match self {
+            ExpireCondition::NoExpiry => current.is_none(),
+            ExpireCondition::HasExpiry => current.is_some(),
+            ExpireCondition::GreaterThan => current.map_or(false, |c| deadline > c),
+            ExpireCondition::LessThan => current.map_or(true, |c| deadline < c),
+        }
+    }
+}
+
+pub enum TtlQuery {
+    Seconds,
+    Millis,
+    UnixSeconds,
+    UnixMillis,
+}
+
+impl TtlQuery {
+    /// Reports `expiry` in the unit the command asked for.
+    pub fn reply(&self, expiry: i64) -> i64 {
+        let remaining = (expiry - storage::unix_millis()).max(0);
+        match self {
+            TtlQuery::Seconds => (remaining + 500) / 1000,
+            TtlQuery::Millis => remaining,
+            TtlQuery::UnixSeconds => expiry / 1000,
+            TtlQuery::UnixMillis => expiry,
+        }
+    }
+}
+
+/// Turns the amount given to an EXPIRE-style command into a deadline in
+/// unix milliseconds. The command name tells the unit, and whether the
+/// amount is relative to now or absolute.
+pub fn deadline(name: &str, amount: i64) -> Result<i64, Error> {
+    let invalid = || Error::InvalidExpire(name.to_owned());
+    let millis = if name.starts_with("hp") || name.starts_with('p') {
+        amount
+    } else {
+        amount.checked_mul(1000).ok_or_else(invalid)?
+    };
+    if name.ends_with("at") {
+        Ok(millis)
+    } else {
+        storage::unix_millis()
+            .checked_add(millis)
+            .ok_or_else(invalid)
+    }
+}
+
+#[derive(Default)]
+pub struct RestoreOptions {
+    replace: bool,
+    absolute_ttl: bool,
+    idle_seconds: Option<i64>,
+    frequency: Option<u8>,
+}
+
+pub enum ObjectQuery {
+    Encoding,
+    RefCount,
+    IdleTime,
+    Freq,
+}
+
+pub enum KeyCommand {
+    Del(Vec<Vec<u8>>),
+    Expire(Vec<u8>, i64, Option<ExpireCondition>),
+    Ttl(Vec<u8>, TtlQuery),
+    Persist(Vec<u8>),
+    Type(Vec<u8>),
+    Rename(Vec<u8>, Vec<u8>, bool),
+    Object(ObjectQuery, Vec<u8>),
+    ObjectHelp,
+    Dump(Vec<u8>),
+    Restore(Vec<u8>, i64, Vec<u8>, RestoreOptions),
+    Keys(Vec<u8>),
+    RandomKey,
+    Scan(u64, ScanOptions),
+    Exists(Vec<Vec<u8>>),
+    Touch(Vec<Vec<u8>>),
+    Sort(Vec<u8>, SortOptions),
+    Unlink(Vec<Vec<u8>>),
+}
+
+impl KeyCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<KeyCommand>, Error> {
+        let command = match name {
+            "del" => KeyCommand::Del(KeyCommand::keys(args)?),
+            "exists" => KeyCommand::Exists(KeyCommand::keys(args)?),
+            "touch" => KeyCommand::Touch(KeyCommand::keys(args)?),
+            "sort" => {
+                let key = args.next_bytes()?;
+                KeyCommand::Sort(key, SortOptions::parse(args)?)
+            }
+            "unlink" => KeyCommand::Unlink(KeyCommand::keys(args)?),
+            "ttl" => KeyCommand::Ttl(KeyCommand::single_key(args)?, TtlQuery::Seconds),
+            "pttl" => KeyCommand::Ttl(KeyCommand::single_key(args)?, TtlQuery::Millis),
+            "expiretime" => KeyCommand::Ttl(KeyCommand::single_key(args)?, TtlQuery::UnixSeconds),
+            "pexpiretime" => KeyCommand::Ttl(KeyCommand::single_key(args)?, TtlQuery::UnixMillis),
+            "keys" => KeyCommand::Keys(KeyCommand::single_key(args)?),
+            "randomkey" => {
+                args.finish()?;
+                KeyCommand::RandomKey
+            }
+            "scan" => {
+                let cursor = scan::parse_cursor(&args.next_bytes()?)?;
+                let options = ScanOptions::parse(args, ScanTarget::Keyspace)?;
+                KeyCommand::Scan(cursor, options)
+            }
+            "object" => KeyCommand::object(args)?,
+            "dump" => KeyCommand::Dump(KeyCommand::single_key(args)?),
+            "restore" => KeyCommand::restore(args)?,
+            "persist" => KeyCommand::Persist(KeyCommand::single_key(args)?),
+            "type" => KeyCommand::Type(KeyCommand::single_key(args)?),
+            "rename" | "renamenx" => {
+                let source = args.next_bytes()?;
+                let destination = args.next_bytes()?;
+                args.finish()?;
+                KeyCommand::Rename(source, destination, name == "renamenx")
+            }
+            "expire" | "pexpire" | "expireat" | "pexpireat" => KeyCommand::expire(name, args)?,
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    fn single_key(args: &mut Arguments) -> Result<Vec<u8>, Error> {
+        let name = args.next_bytes()?;
+        args.finish()?;
+        Ok(name)
+    }
+
+    fn keys(args: &mut Arguments) -> Result<Vec<Vec<u8>>, Error> {
+        if args.is_empty() {
+            return Err(args.wrong_arity());
+        }
+        args.rest()
+    }
+
+    fn restore(args: &mut Arguments) -> Result<KeyCommand, Error> {
+        let key = args.next_bytes()?;
+        let ttl = args.next_int()?;
+        let payload = args.next_bytes()?;
+        let mut options = RestoreOptions::default();
+        while !args.is_empty() {
+            match args.next_string()?.to_lowercase().as_str() {
+                "replace" => options.replace = true,
+                "absttl" => options.absolute_ttl = true,
+                "idletime" if options.frequency.is_none() => {
+                    let idle = args.next_int()?;
+                    if idle < 0 {
+                        return Err(Error::Argument(
+                            "Invalid IDLETIME value, must be >= 0".to_owned(),
+                        ));
+                    }
+                    options.idle_seconds = Some(idle);
+                }
+                "freq" if options.idle_seconds.is_none() => {
+                    let frequency = args.next_int()?.try_into().map_err(|_| {
+                        Error::Argument("Invalid FREQ value, must be >= 0 and <= 255".to_owned())
+                    })?;
+                    options.frequency = Some(frequency);
+                }
+                _ => return Err(Error::Syntax),
+            }
+        }
+        if ttl < 0 {
+            return Err(Error::Argument(
+                "Invalid TTL value, must be >= 0".to_owned(),
+            ));
+        }
+        Ok(KeyCommand::Restore(key, ttl, payload, options))
+    }
+
+    fn object(args: &mut Arguments) -> Result<KeyCommand, Error> {
+        let subcommand = args.next_string()?.to_lowercase();
+        let query = match subcommand.as_str() {
+            "help" => {
+                args.finish()?;
+                return Ok(KeyCommand::ObjectHelp);
+            }
+            "encoding" => ObjectQuery::Encoding,
+            "refcount" => ObjectQuery::RefCount,
+            "idletime" => ObjectQuery::IdleTime,
+            "freq" => ObjectQuery::Freq,
+            _ => {
+                return Err(Error::Argument(format!(
+                    "unknown subcommand '{}'. Try OBJECT HELP.",
+                    subcommand
+                )))
+            }
+        };
+        if args.len() != 1 {
+            return Err(Error::WrongArity(format!("object|{}", subcommand)));
+        }
+        Ok(KeyCommand::Object(query, args.next_bytes()?))
+    }
+
+    fn expire(name: &str, args: &mut Arguments) -> Result<KeyCommand, Error> {
+        let key = args.next_bytes()?;
+        let deadline = deadline(name, args.next_int()?)?;
+
+        let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+        while !args.is_empty() {
+            let flag = args.next_string()?;
+            match flag.to_lowercase().as_str() {
+                "nx" => nx = true,
+                "xx" => xx = true,
+                "gt" => gt = true,
+                "lt" => lt = true,
+                _ => return Err(Error::Argument(format!("Unsupported option {}", flag))),
+            }
+        }
+        if nx && (xx || gt || lt) {
+            return Err(Error::Argument(
+                "NX and XX, GT or LT options at the same time are not compatible".to_owned(),
+            ));
+        }
+        if gt && lt {
+            return Err(Error::Argument(
+                "GT and LT options at the same time are not compatible".to_owned(),
+            ));
+        }
+        let condition = if nx {
+            Some(ExpireCondition::NoExpiry)
+        } else if gt {
+            Some(ExpireCondition::GreaterThan)
+        } else if lt {
+            Some(ExpireCondition::LessThan)
+        } else if xx {
+            Some(ExpireCondition::HasExpiry)
+        } else {
+            None
+        };
+        Ok(KeyCommand::Expire(key, deadline, condition))
+    }
+
+    pub fn execute(self, storage: &mut Database) -> Result<Value, Error> {
+        let response = match self {
+            KeyCommand::Del(names) => {
+                let removed = names
+                    .iter()
+                    .filter(|name| {
+                        let removed = storage.remove(name).is_some();
+                        if removed {
+                            storage.notify(Class::Generic, "del", name);
+                        }
+                        removed
+                    })
+                    .count();
+                Value::Int(removed.try_into()?)
+            }
+            KeyCommand::Expire(name, expiry, condition) => {
+                let current = match storage.peek(&name) {
+                    Some(stored) => stored.expiry,
+                    None => return Ok(Value::Int(0)),
+                };
+                let allowed = condition.map_or(true, |condition| condition.allows(current, expiry));
+                if !allowed {
+                    return Ok(Value::Int(0));
+                }
+                if storage::already_expired(expiry) {
+                    storage.remove(&name);
+                    storage.notify(Class::Generic, "del", &name);
+                } else {
+                    storage.set_expiry(&name, Some(expiry));
+                    storage.notify(Class::Generic, "expire", &name);
+                }
+                Value::Int(1)
+            }
+            KeyCommand::Ttl(name, query) => {
+                let expiry = match storage.peek(&name) {
+                    Some(stored) => stored.expiry,
+                    None => return Ok(Value::Int(-2)),
+                };
+                let expiry = match expiry {
+                    Some(expiry) => expiry,
+                    None => return Ok(Value::Int(-1)),
+                };
+                Value::Int(query.reply(expiry))
+            }
+            KeyCommand::Keys(pattern) => Value::array(
+                storage
+                    .keys()
+                    .filter(|key| glob::matches(&pattern, key))
+                    .map(|key| Value::String(key.clone()))
+                    .collect(),
+            ),
+            KeyCommand::RandomKey => storage.random_key().map_or(Value::Nil, Value::String),
+            KeyCommand::Scan(cursor, options) => {
+                let mut keys = vec![];
+                let cursor = options.scan(cursor, |cursor, visited| {
+                    storage.scan(cursor, |key, stored| {
+                        *visited += 1;
+                        if options.wants_type(stored.value_type()) && options.matches(key) {
+                            keys.push(Value::String(key.to_vec()));
+                        }
+                    })
+                });
+                scan::scan_reply(cursor, keys)
+            }
+            KeyCommand::Persist(name) => {
+                let volatile = storage
+                    .peek(&name)
+                    .map_or(false, |stored| stored.expiry.is_some());
+                if volatile {
+                    storage.set_expiry(&name, None);
+                    storage.notify(Class::Generic, "persist", &name);
+                }
+                Value::Int(volatile.into())
+            }
+            KeyCommand::Type(name) => {
+                let value_type = storage.peek(&name).map(|stored| stored.value_type());
+                Value::Status(value_type.map_or("none", ValueType::name).to_owned())
+            }
+            KeyCommand::Rename(source, destination, only_new) => {
+                if !storage.contains(&source) {
+                    return Err(Error::Argument("no such key".to_owned()));
+                }
+                if only_new && storage.contains(&destination) {
+                    return Ok(Value::Int(0));
+                }
+                if source != destination {
+                    if let Some(stored) = storage.remove(&source) {
+                        storage.notify(Class::Generic, "rename_from", &source);
+                        storage.notify(Class::Generic, "rename_to", &destination);
+                        storage.insert(destination, stored);
+                    }
+                }
+                if only_new {
+                    Value::Int(1)
+                } else {
+                    Value::ok()
+                }
+            }
+            KeyCommand::Object(query, name) => match storage.peek(&name) {
+                Some(stored) => match query {
+                    ObjectQuery::Encoding => Value::bulk(stored.encoding()),
+                    ObjectQuery::RefCount => Value::Int(1),
+                    ObjectQuery::IdleTime => Value::Int(stored.access.idle_seconds()),
+                    ObjectQuery::Freq => Value::Int(stored.access.frequency().into()),
+                },
+                None => Value::Nil,
+            },
+            KeyCommand::ObjectHelp => Value::array(
+                [
+                    "OBJECT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                    "ENCODING <key>",
+                    "    Return the kind of internal representation used in order to store the value",
+                    "    associated with a <key>.",
+                    "FREQ <key>",
+                    "    Return the access frequency index of the <key>. The returned integer is",
+                    "    proportional to the logarithm of the recent access frequency of the key.",
+                    "IDLETIME <key>",
+                    "    Return the idle time of the <key>, that is the approximated number of",
+                    "    seconds elapsed since the last access to the key.",
+                    "REFCOUNT <key>",
+                    "    Return the number of references of the value associated with the specified",
+                    "    <key>.",
+                    "HELP",
+                    "    Print this help.",
+                ]
+                .iter()
+                .map(|line| Value::Status((*line).to_owned()))
+                .collect(),
+            ),
+            KeyCommand::Dump(name) => match storage.get(&name) {
+                Some(stored) => Value::String(rdb::dump(&stored.data)),
+                None => Value::Nil,
+            },
+            KeyCommand::Restore(name, ttl, payload, options) => {
+                if !options.replace && storage.contains(&name) {
+                    return Err(Error::BusyKey);
+                }
+                let data = rdb::restore(&payload)?;
+                let expiry = match ttl {
+                    0 => None,
+                    ttl if options.absolute_ttl => Some(ttl),
+                    ttl => Some(
+                        storage::unix_millis()
+                            .checked_add(ttl)
+                            .ok_or_else(|| Error::InvalidExpire("restore".to_owned()))?,
+                    ),
+                };
+                // A deadline already in the past still replaces, but leaves
+                // nothing behind.
+                if expiry.map_or(false, storage::already_expired) {
+                    if storage.remove(&name).is_some() {
+                        storage.notify(Class::Generic, "del", &name);
+                    }
+                } else {
+                    let stored = StoredValue::new(data, expiry);
+                    stored.access.set(options.idle_seconds, options.frequency);
+                    storage.notify(Class::Generic, "restore", &name);
+                    storage.insert(name, stored);
+                }
+                Value::ok()
+            }
+            KeyCommand::Exists(names) => {
+                let existing = names.iter().filter(|name| storage.contains(name)).count();
+                Value::Int(existing.try_into()?)
+            }
+            KeyCommand::Touch(names) => {
+                // Looking a key up is what records the access.
+                let touched = names.iter().filter(|name| storage.get(name).is_some()).count();
+                Value::Int(touched.try_into()?)
+            }
+            KeyCommand::Sort(key, options) => sort::sort(storage, &key, options)?,
+            KeyCommand::Unlink(names) => {
+                let removed: Vec<_> = names
+                    .iter()
+                    .filter_map(|name| {
+                        let removed = storage.remove(name);
+                        if removed.is_some() {
+                            storage.notify(Class::Generic, "del", name);
+                        }
+                        removed
+                    })
+                    .collect();
+                let count = removed.len().try_into()?;
+                storage::free_lazily(removed);
+                Value::Int(count)
+            }
+        };
+        Ok(response)
+    }
+}
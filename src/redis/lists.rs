@@ -0,0 +1,693 @@
+use super::blocking::{self, Blocking, BlockingCommand};
+use super::command::Arguments;
+use super::notify::Class;
+use super::ranges;
+use super::storage::{Data, Database};
+use super::{Error, Value};
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
+/// The end of a list a command operates on.
+#[derive(Clone, Copy)]
+pub enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    /// Parses a LEFT or RIGHT argument.
+    fn parse(args: &mut Arguments) -> Result<End, Error> {
+        match args.next_string()?.to_lowercase().as_str() {
+            "left" => Ok(End::Left),
+            "right" => Ok(End::Right),
+            _ => Err(Error::Syntax),
+        }
+    }
+
+    /// The keyspace events of pushing and popping at this end.
+    fn events(self) -> (&'static str, &'static str) {
+        match self {
+            End::Left => ("lpush", "lpop"),
+            End::Right => ("rpush", "rpop"),
+        }
+    }
+}
+
+pub enum ListCommand {
+    /// Pushes the values one by one; with the flag set only onto an
+    /// existing list.
+    Push(Vec<u8>, End, Vec<Vec<u8>>, bool),
+    /// Pops one element, or up to a count of them as an array.
+    Pop(Vec<u8>, End, Option<usize>),
+    Range(Vec<u8>, i64, i64),
+    Len(Vec<u8>),
+    Index(Vec<u8>, i64),
+    Set(Vec<u8>, i64, Vec<u8>),
+    /// Inserts the element after the pivot when the flag is set, before it
+    /// otherwise.
+    Insert(Vec<u8>, bool, Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>, i64, Vec<u8>),
+    Trim(Vec<u8>, i64, i64),
+    /// Pops from one end of the source and pushes onto an end of the
+    /// destination, which may be the same list.
+    Move(Vec<u8>, Vec<u8>, End, End),
+    Position(Vec<u8>, Vec<u8>, PositionOptions),
+    /// Pops up to a count of elements from the first non-empty list.
+    MPop(Vec<Vec<u8>>, End, usize),
+}
+
+pub struct PositionOptions {
+    /// Which match to start from, counting from the tail when negative.
+    rank: i64,
+    /// None replies with a single index, zero with all of them.
+    count: Option<usize>,
+    /// How many elements to compare at most, zero for all of them.
+    max_len: usize,
+}
+
+impl PositionOptions {
+    fn parse(args: &mut Arguments) -> Result<PositionOptions, Error> {
+        let mut options = PositionOptions {
+            rank: 1,
+            count: None,
+            max_len: 0,
+        };
+        while !args.is_empty() {
+            let option = args.next_string()?.to_lowercase();
+            if args.is_empty() {
+                return Err(Error::Syntax);
+            }
+            match option.as_str() {
+                "rank" => {
+                    options.rank = args.next_int()?;
+                    // Redis takes the rank between -LONG_MAX and LONG_MAX.
+                    if options.rank == i64::MIN {
+                        return Err(Error::Argument(
+                            "value is out of range, value must between -9223372036854775807 \
+                             and 9223372036854775807"
+                                .to_owned(),
+                        ));
+                    }
+                    if options.rank == 0 {
+                        return Err(Error::Argument(
+                            "RANK can't be zero: use 1 to start from the first match, 2 from \
+                             the second ... or use negative to start from the end of the list"
+                                .to_owned(),
+                        ));
+                    }
+                }
+                "count" => {
+                    options.count = Some(
+                        args.next_int()?
+                            .try_into()
+                            .map_err(|_| Error::Argument("COUNT can't be negative".to_owned()))?,
+                    );
+                }
+                "maxlen" => {
+                    options.max_len = args
+                        .next_int()?
+                        .try_into()
+                        .map_err(|_| Error::Argument("MAXLEN can't be negative".to_owned()))?;
+                }
+                _ => return Err(Error::Syntax),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// List commands that wait for an element when there is none.
+pub enum BlockingListCommand {
+    /// Pops one element from the first non-empty list.
+    Pop(Vec<Vec<u8>>, End),
+    /// Pops up to a count of elements from the first non-empty list.
+    MPop(Vec<Vec<u8>>, End, usize),
+    /// Like [`ListCommand::Move`], with the source as its only key.
+    Move(Vec<Vec<u8>>, Vec<u8>, End, End),
+}
+
+impl BlockingListCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<BlockingCommand>, Error> {
+        let (operation, timeout) = match name {
+            "blpop" | "brpop" => {
+                let end = if name == "blpop" {
+                    End::Left
+                } else {
+                    End::Right
+                };
+                if args.len() < 2 {
+                    return Err(args.wrong_arity());
+                }
+                let mut keys = vec![];
+                while args.len() > 1 {
+                    keys.push(args.next_bytes()?);
+                }
+                let timeout = blocking::parse_timeout(args)?;
+                (BlockingListCommand::Pop(keys, end), timeout)
+            }
+            "blmpop" => {
+                let timeout = blocking::parse_timeout(args)?;
+                let (keys, end, count) = parse_mpop(args)?;
+                (BlockingListCommand::MPop(keys, end, count), timeout)
+            }
+            "blmove" => {
+                let source = args.next_bytes()?;
+                let destination = args.next_bytes()?;
+                let from = End::parse(args)?;
+                let to = End::parse(args)?;
+                let timeout = blocking::parse_timeout(args)?;
+                args.finish()?;
+                (
+                    BlockingListCommand::Move(vec![source], destination, from, to),
+                    timeout,
+                )
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(BlockingCommand {
+            operation: Blocking::List(operation),
+            timeout,
+        }))
+    }
+
+    pub fn keys(&self) -> &[Vec<u8>] {
+        match self {
+            BlockingListCommand::Pop(keys, _) => keys,
+            BlockingListCommand::MPop(keys, _, _) => keys,
+            BlockingListCommand::Move(source, _, _, _) => source,
+        }
+    }
+
+    pub fn try_execute(
+        &self,
+        storage: &mut Database,
+        skip_other_types: bool,
+    ) -> Result<Option<Value>, Error> {
+        match self {
+            BlockingListCommand::Pop(keys, end) => {
+                for key in keys {
+                    let list = match storage.get_mut(key) {
+                        Some(stored) => {
+                            blocking::skip_other_type(stored.list_mut(), skip_other_types)?
+                        }
+                        None => continue,
+                    };
+                    let value = match list {
+                        Some(list) => pop(list, *end),
+                        None => continue,
+                    };
+                    if value.is_some() {
+                        storage.notify(Class::List, end.events().1, key);
+                    }
+                    storage.remove_if_empty(key);
+                    if let Some(value) = value {
+                        return Ok(Some(Value::array(vec![
+                            Value::String(key.clone()),
+                            Value::String(value),
+                        ])));
+                    }
+                }
+                Ok(None)
+            }
+            BlockingListCommand::MPop(keys, end, count) => {
+                mpop(storage, keys, *end, *count, skip_other_types)
+            }
+            BlockingListCommand::Move(source, destination, from, to) => {
+                let is_list = storage
+                    .peek(&source[0])
+                    .map(|stored| stored.list().map(drop));
+                if let Some(is_list) = is_list {
+                    if blocking::skip_other_type(is_list, skip_other_types)?.is_none() {
+                        return Ok(None);
+                    }
+                }
+                Ok(move_element(storage, &source[0], destination, *from, *to)?.map(Value::String))
+            }
+        }
+    }
+
+    pub fn timeout_reply(&self) -> Value {
+        match self {
+            BlockingListCommand::Move(..) => Value::Nil,
+            _ => Value::NilArray,
+        }
+    }
+}
+
+impl ListCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<ListCommand>, Error> {
+        let command = match name {
+            "lpush" | "rpush" | "lpushx" | "rpushx" => {
+                let end = if name.starts_with('l') {
+                    End::Left
+                } else {
+                    End::Right
+                };
+                let key = args.next_bytes()?;
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                ListCommand::Push(key, end, args.rest()?, name.ends_with('x'))
+            }
+            "lpop" | "rpop" => {
+                let end = if name == "lpop" {
+                    End::Left
+                } else {
+                    End::Right
+                };
+                let key = args.next_bytes()?;
+                let count = if args.is_empty() {
+                    None
+                } else {
+                    Some(parse_count(args)?)
+                };
+                args.finish()?;
+                ListCommand::Pop(key, end, count)
+            }
+            "lrange" => {
+                let key = args.next_bytes()?;
+                let start = args.next_int()?;
+                let stop = args.next_int()?;
+                args.finish()?;
+                ListCommand::Range(key, start, stop)
+            }
+            "llen" => {
+                let key = args.next_bytes()?;
+                args.finish()?;
+                ListCommand::Len(key)
+            }
+            "lindex" => {
+                let key = args.next_bytes()?;
+                let index = args.next_int()?;
+                args.finish()?;
+                ListCommand::Index(key, index)
+            }
+            "lset" => {
+                let key = args.next_bytes()?;
+                let index = args.next_int()?;
+                let value = args.next_bytes()?;
+                args.finish()?;
+                ListCommand::Set(key, index, value)
+            }
+            "linsert" => {
+                let key = args.next_bytes()?;
+                let after = match args.next_string()?.to_lowercase().as_str() {
+                    "before" => false,
+                    "after" => true,
+                    _ => return Err(Error::Syntax),
+                };
+                let pivot = args.next_bytes()?;
+                let value = args.next_bytes()?;
+                args.finish()?;
+                ListCommand::Insert(key, after, pivot, value)
+            }
+            "lrem" => {
+                let key = args.next_bytes()?;
+                let count = args.next_int()?;
+                let value = args.next_bytes()?;
+                args.finish()?;
+                ListCommand::Remove(key, count, value)
+            }
+            "ltrim" => {
+                let key = args.next_bytes()?;
+                let start = args.next_int()?;
+                let stop = args.next_int()?;
+                args.finish()?;
+                ListCommand::Trim(key, start, stop)
+            }
+            "rpoplpush" => {
+                let source = args.next_bytes()?;
+                let destination = args.next_bytes()?;
+                args.finish()?;
+                ListCommand::Move(source, destination, End::Right, End::Left)
+            }
+            "lmove" => {
+                let source = args.next_bytes()?;
+                let destination = args.next_bytes()?;
+                let from = End::parse(args)?;
+                let to = End::parse(args)?;
+                args.finish()?;
+                ListCommand::Move(source, destination, from, to)
+            }
+            "lpos" => {
+                let key = args.next_bytes()?;
+                let element = args.next_bytes()?;
+                let options = PositionOptions::parse(args)?;
+                ListCommand::Position(key, element, options)
+            }
+            "lmpop" => {
+                let (keys, end, count) = parse_mpop(args)?;
+                ListCommand::MPop(keys, end, count)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    pub fn execute(self, storage: &mut Database) -> Result<Value, Error> {
+        let response = match self {
+            ListCommand::Push(key, end, values, only_existing) => {
+                if only_existing && !storage.contains(&key) {
+                    return Ok(Value::Int(0));
+                }
+                let list = storage
+                    .get_or_insert_with(&key, || Data::List(VecDeque::new()))
+                    .list_mut()?;
+                for value in values {
+                    push(list, end, value);
+                }
+                let len = list.len().try_into()?;
+                storage.notify(Class::List, end.events().0, &key);
+                Value::Int(len)
+            }
+            ListCommand::Pop(key, end, count) => {
+                let list = match storage.get_mut(&key) {
+                    Some(stored) => stored.list_mut()?,
+                    None if count.is_some() => return Ok(Value::NilArray),
+                    None => return Ok(Value::Nil),
+                };
+                let (response, popped) = match count {
+                    Some(count) => {
+                        let count = count.min(list.len());
+                        let values = (0..count)
+                            .filter_map(|_| pop(list, end))
+                            .map(Value::String)
+                            .collect();
+                        (Value::array(values), count > 0)
+                    }
+                    None => match pop(list, end) {
+                        Some(value) => (Value::String(value), true),
+                        None => (Value::Nil, false),
+                    },
+                };
+                if popped {
+                    storage.notify(Class::List, end.events().1, &key);
+                }
+                storage.remove_if_empty(&key);
+                response
+            }
+            ListCommand::Range(key, start, stop) => match storage.get(&key) {
+                Some(stored) => {
+                    let list = stored.list()?;
+                    match ranges::indexes(start, stop, list.len()) {
+                        // Only the requested window is copied out.
+                        Some((start, stop)) => Value::array(
+                            list.range(start..=stop)
+                                .map(|item| Value::String(item.clone()))
+                                .collect(),
+                        ),
+                        None => Value::array(vec![]),
+                    }
+                }
+                None => Value::array(vec![]),
+            },
+            ListCommand::Len(key) => match storage.get(&key) {
+                Some(stored) => Value::Int(stored.list()?.len().try_into()?),
+                None => Value::Int(0),
+            },
+            ListCommand::Index(key, index) => match storage.get(&key) {
+                Some(stored) => {
+                    let list = stored.list()?;
+                    index_of(index, list.len())
+                        .and_then(|index| list.get(index))
+                        .map_or(Value::Nil, |item| Value::String(item.clone()))
+                }
+                None => Value::Nil,
+            },
+            ListCommand::Set(key, index, value) => {
+                let list = match storage.get_mut(&key) {
+                    Some(stored) => stored.list_mut()?,
+                    None => return Err(Error::Argument("no such key".to_owned())),
+                };
+                let index = index_of(index, list.len())
+                    .ok_or_else(|| Error::Argument("index out of range".to_owned()))?;
+                list[index] = value;
+                storage.notify(Class::List, "lset", &key);
+                Value::ok()
+            }
+            ListCommand::Insert(key, after, pivot, value) => {
+                let list = match storage.get_mut(&key) {
+                    Some(stored) => stored.list_mut()?,
+                    None => return Ok(Value::Int(0)),
+                };
+                match list.iter().position(|item| *item == pivot) {
+                    Some(position) => {
+                        list.insert(position + after as usize, value);
+                        let len = list.len().try_into()?;
+                        storage.notify(Class::List, "linsert", &key);
+                        Value::Int(len)
+                    }
+                    None => Value::Int(-1),
+                }
+            }
+            ListCommand::Remove(key, count, value) => {
+                let list = match storage.get_mut(&key) {
+                    Some(stored) => stored.list_mut()?,
+                    None => return Ok(Value::Int(0)),
+                };
+                let removed = remove(list, count, &value);
+                if removed > 0 {
+                    storage.notify(Class::List, "lrem", &key);
+                }
+                storage.remove_if_empty(&key);
+                Value::Int(removed.try_into()?)
+            }
+            ListCommand::Trim(key, start, stop) => {
+                let list = match storage.get_mut(&key) {
+                    Some(stored) => stored.list_mut()?,
+                    None => return Ok(Value::ok()),
+                };
+                match ranges::indexes(start, stop, list.len()) {
+                    Some((start, stop)) => {
+                        list.truncate(stop + 1);
+                        list.drain(..start);
+                    }
+                    None => list.clear(),
+                }
+                storage.notify(Class::List, "ltrim", &key);
+                storage.remove_if_empty(&key);
+                Value::ok()
+            }
+            ListCommand::Move(source, destination, from, to) => {
+                move_element(storage, &source, &destination, from, to)?
+                    .map_or(Value::Nil, Value::String)
+            }
+            ListCommand::Position(key, element, options) => {
+                let matches = match storage.get(&key) {
+                    Some(stored) => positions(stored.list()?, &element, &options)?,
+                    None => vec![],
+                };
+                match options.count {
+                    Some(_) => Value::array(matches.into_iter().map(Value::Int).collect()),
+                    None => matches
+                        .first()
+                        .map_or(Value::Nil, |&index| Value::Int(index)),
+                }
+            }
+            ListCommand::MPop(keys, end, count) => {
+                mpop(storage, &keys, end, count, false)?.unwrap_or(Value::NilArray)
+            }
+        };
+        Ok(response)
+    }
+}
+
+/// Indexes of the elements equal to `element` that LPOS reports, in the
+/// order they were found.
+fn positions(
+    list: &VecDeque<Vec<u8>>,
+    element: &[u8],
+    options: &PositionOptions,
+) -> Result<Vec<i64>, Error> {
+    let len = list.len();
+    let limit = if options.max_len == 0 {
+        len
+    } else {
+        options.max_len.min(len)
+    };
+    let wanted = match options.count {
+        Some(0) => usize::MAX,
+        Some(count) => count,
+        None => 1,
+    };
+    let mut skip = options.rank.unsigned_abs() - 1;
+    let mut matches = vec![];
+    for i in 0..limit {
+        let index = if options.rank > 0 { i } else { len - 1 - i };
+        if list[index] != element {
+            continue;
+        }
+        if skip > 0 {
+            skip -= 1;
+            continue;
+        }
+        matches.push(index.try_into()?);
+        if matches.len() == wanted {
+            break;
+        }
+    }
+    Ok(matches)
+}
+
+/// The `numkeys key [key ...] LEFT|RIGHT [COUNT count]` tail of the
+/// multi-key pops.
+fn parse_mpop(args: &mut Arguments) -> Result<(Vec<Vec<u8>>, End, usize), Error> {
+    let keys = args.next_keys()?;
+    let end = End::parse(args)?;
+    Ok((keys, end, parse_mpop_count(args)?))
+}
+
+/// The optional `COUNT count` ending the multi-key pops of lists and sorted
+/// sets, one by default.
+pub fn parse_mpop_count(args: &mut Arguments) -> Result<usize, Error> {
+    let mut count = None;
+    while !args.is_empty() {
+        match args.next_string()?.to_lowercase().as_str() {
+            "count" if count.is_none() && !args.is_empty() => {
+                let value = args
+                    .next_int()
+                    .ok()
+                    .filter(|&value| value > 0)
+                    .and_then(|value| value.try_into().ok())
+                    .ok_or_else(|| Error::Argument("count should be greater than 0".to_owned()))?;
+                count = Some(value);
+            }
+            _ => return Err(Error::Syntax),
+        }
+    }
+    Ok(count.unwrap_or(1))
+}
+
+/// Pops up to `count` elements from the first of `keys` holding a list,
+/// replying with the key and the elements.
+fn mpop(
+    storage: &mut Database,
+    keys: &[Vec<u8>],
+    end: End,
+    count: usize,
+    skip_other_types: bool,
+) -> Result<Option<Value>, Error> {
+    for key in keys {
+        let list = match storage.get_mut(key) {
+            Some(stored) => blocking::skip_other_type(stored.list_mut(), skip_other_types)?,
+            None => None,
+        };
+        let list = match list {
+            Some(list) => list,
+            None => continue,
+        };
+        let count = count.min(list.len());
+        let values = (0..count)
+            .filter_map(|_| pop(list, end))
+            .map(Value::String)
+            .collect();
+        if count > 0 {
+            storage.notify(Class::List, end.events().1, key);
+        }
+        storage.remove_if_empty(key);
+        return Ok(Some(Value::array(vec![
+            Value::String(key.clone()),
+            Value::array(values),
+        ])));
+    }
+    Ok(None)
+}
+
+/// Moves one element between lists, returning it. Both types are checked
+/// before anything changes, and the source is only dropped after the push so
+/// rotating a single-element list keeps the key and its expiry.
+fn move_element(
+    storage: &mut Database,
+    source: &[u8],
+    destination: &[u8],
+    from: End,
+    to: End,
+) -> Result<Option<Vec<u8>>, Error> {
+    match storage.peek(source) {
+        Some(stored) => stored.list()?,
+        None => return Ok(None),
+    };
+    if let Some(stored) = storage.peek(destination) {
+        stored.list()?;
+    }
+    let value = match storage.get_mut(source) {
+        Some(stored) => pop(stored.list_mut()?, from),
+        None => None,
+    };
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let list = storage
+        .get_or_insert_with(destination, || Data::List(VecDeque::new()))
+        .list_mut()?;
+    push(list, to, value.clone());
+    storage.notify(Class::List, to.events().0, destination);
+    storage.notify(Class::List, from.events().1, source);
+    storage.remove_if_empty(source);
+    Ok(Some(value))
+}
+
+/// Resolves a possibly negative index against a list of `len` elements.
+fn index_of(index: i64, len: usize) -> Option<usize> {
+    let len = len as i64;
+    let index = if index < 0 { len + index } else { index };
+    if (0..len).contains(&index) {
+        Some(index as usize)
+    } else {
+        None
+    }
+}
+
+/// Removes up to `count` occurrences of `value`, scanning from the tail
+/// when `count` is negative; zero removes them all.
+fn remove(list: &mut VecDeque<Vec<u8>>, count: i64, value: &[u8]) -> usize {
+    let limit = if count == 0 {
+        usize::MAX
+    } else {
+        count.unsigned_abs().try_into().unwrap_or(usize::MAX)
+    };
+    let mut removed = 0;
+    if count >= 0 {
+        let mut i = 0;
+        while i < list.len() && removed < limit {
+            if list[i] == value {
+                list.remove(i);
+                removed += 1;
+            } else {
+                i += 1;
+            }
+        }
+    } else {
+        let mut i = list.len();
+        while i > 0 && removed < limit {
+            i -= 1;
+            if list[i] == value {
+                list.remove(i);
+                removed += 1;
+            }
+        }
+    }
+    removed
+}
+
+/// A COUNT argument, which may be zero but not negative.
+fn parse_count(args: &mut Arguments) -> Result<usize, Error> {
+    args.next_int()?
+        .try_into()
+        .map_err(|_| Error::Argument("value is out of range, must be positive".to_owned()))
+}
+
+fn push(list: &mut VecDeque<Vec<u8>>, end: End, value: Vec<u8>) {
+    match end {
+        End::Left => list.push_front(value),
+        End::Right => list.push_back(value),
+    }
+}
+
+fn pop(list: &mut VecDeque<Vec<u8>>, end: End) -> Option<Vec<u8>> {
+    match end {
+        End::Left => list.pop_front(),
+        End::Right => list.pop_back(),
+    }
+}
@@ -0,0 +1,22 @@
+//! A Lua 5.1 interpreter for scripts, walking the syntax tree of each chunk.
+//!
+//! Parsed chunks hold no Lua values, so they can be cached and shared
+//! between connections. Everything a script creates lives in the interpreter
+//! made for that run and goes with it.
+
+mod interpreter;
+mod lexer;
+mod number;
+mod parser;
+mod pattern;
+mod stdlib;
+mod value;
+
+pub use interpreter::{Host, Interpreter};
+pub use number::format_g;
+pub use parser::{parse, Chunk};
+pub use value::{Error, Table, Value};
+
+/// The stack the threads running scripts need, deep enough for the nesting
+/// of calls and syntax the interpreter allows.
+pub const STACK_SIZE: usize = 64 * 1024 * 1024;
@@ -0,0 +1,764 @@
+//! Runs syntax trees.
+
+use super::parser::{BinaryOp, Call, Chunk, Expr, Field, Proto, Stmt, Target, UnaryOp, Upvalue};
+use super::stdlib;
+use super::value::{Cell, Closure, Error, Function, Key, Table, TableRef, Value};
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+
+/// How deeply calls may nest, which bounds the native stack the recursive
+/// walk takes.
+const MAX_CALLS: usize = 1000;
+
+/// How many calls and loop iterations go by between asking the host whether
+/// to stop the script.
+const STEPS_PER_CHECK: u32 = 1024;
+
+/// What the interpreter runs scripts for, reached by the functions it adds
+/// to the libraries.
+pub trait Host {
+    /// Runs a command for `redis.call`, or `redis.pcall` if `protected`, given
+    /// its name and arguments.
+    fn call(
+        &mut self,
+        interpreter: &mut Interpreter,
+        args: Vec<Value>,
+        protected: bool,
+    ) -> Result<Value, Error>;
+
+    /// The error to stop the script with, if it is to stop.
+    fn interrupt(&mut self) -> Option<Value>;
+}
+
+pub struct Interpreter<'a> {
+    pub globals: TableRef,
+    /// The string library, where strings look up methods.
+    pub strings: TableRef,
+    host: Option<&'a mut dyn Host>,
+    chunk: String,
+    line: u32,
+    depth: usize,
+    steps: u32,
+    /// The error the host stopped the script with, raised again at every
+    /// step so that pcall cannot carry on past it.
+    interrupted: Option<Value>,
+    tables: Heap<Table>,
+    cells: Heap<Value>,
+}
+
+/// The tables or captured variables made while running, which go on
+/// referencing each other in cycles unless cleared once the run is over.
+struct Heap<T> {
+    objects: Vec<Weak<RefCell<T>>>,
+    limit: usize,
+}
+
+impl<T: Default> Heap<T> {
+    fn new() -> Heap<T> {
+        Heap {
+            objects: vec![],
+            limit: 64,
+        }
+    }
+
+    fn track(&mut self, object: &Rc<RefCell<T>>) {
+        if self.objects.len() >= self.limit {
+            self.objects.retain(|object| object.strong_count() > 0);
+            self.limit = (self.objects.len() * 2).max(64);
+        }
+        self.objects.push(Rc::downgrade(object));
+    }
+
+    fn clear(&mut self) {
+        for object in self.objects.drain(..) {
+            if let Some(object) = object.upgrade() {
+                let contents = std::mem::take(&mut *object.borrow_mut());
+                drop(contents);
+            }
+        }
+    }
+}
+
+enum Flow {
+    Normal,
+    Break,
+    Return(Vec<Value>),
+}
+
+struct Frame<'f> {
+    slots: Vec<Cell>,
+    upvalues: &'f [Cell],
+    varargs: Vec<Value>,
+}
+
+/// Where an assignment stores its value.
+enum Place<'t> {
+    Local(usize),
+    Upvalue(usize),
+    Global(&'t str),
+    Index(Value, Value),
+}
+
+fn cell(value: Value) -> Cell {
+    Rc::new(RefCell::new(value))
+}
+
+/// How error messages name the variable an expression reads, if it does.
+fn describe(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Global(name, _) => Some(format!("global '{}'", name)),
+        Expr::Local(_, name) => Some(format!("local '{}'", name)),
+        Expr::Upvalue(_, name) => Some(format!("upvalue '{}'", name)),
+        Expr::Index(_, key, _) => match &**key {
+            Expr::String(key) => Some(format!("field '{}'", String::from_utf8_lossy(key))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The `name` field of a metatable, if the value has one.
+pub fn metamethod(value: &Value, name: &str) -> Option<Value> {
+    match value {
+        Value::Table(table) => {
+            let metatable = table.borrow().metatable.clone()?;
+            let method = metatable.borrow().get_str(name);
+            Some(method).filter(|method| !method.is_nil())
+        }
+        _ => None,
+    }
+}
+
+impl<'a> Interpreter<'a> {
+    /// An interpreter with the standard libraries loaded, naming `chunk` in
+    /// error messages.
+    pub fn new(chunk: &str) -> Interpreter<'a> {
+        let mut interpreter = Interpreter {
+            globals: Rc::new(RefCell::new(Table::new())),
+            strings: Rc::new(RefCell::new(Table::new())),
+            host: None,
+            chunk: chunk.to_owned(),
+            line: 0,
+            depth: 0,
+            steps: 0,
+            interrupted: None,
+            tables: Heap::new(),
+            cells: Heap::new(),
+        };
+        stdlib::open(&mut interpreter);
+        interpreter
+    }
+
+    /// Makes a table value, to be cleared along with the interpreter.
+    pub fn new_table(&mut self, table: Table) -> Value {
+        let table = Rc::new(RefCell::new(table));
+        self.tables.track(&table);
+        Value::Table(table)
+    }
+
+    pub fn set_host(&mut self, host: &'a mut dyn Host) {
+        self.host = Some(host);
+    }
+
+    /// Runs `redis.call` or `redis.pcall` through the host.
+    pub fn call_host(&mut self, args: Vec<Value>, protected: bool) -> Result<Value, Error> {
+        let host = match self.host.take() {
+            Some(host) => host,
+            None => return Err(self.error("no host to run commands")),
+        };
+        let result = host.call(self, args, protected);
+        self.host = Some(host);
+        result
+    }
+
+    /// An error raised at the current line, its message prefixed with the
+    /// position as Lua does.
+    pub fn error<S: AsRef<str>>(&self, message: S) -> Error {
+        let message = format!("{}:{}: {}", self.chunk, self.line, message.as_ref());
+        Error {
+            value: Value::string(message.as_bytes()),
+            line: self.line,
+        }
+    }
+
+    /// An error raising `value` as is.
+    pub fn raise(&self, value: Value) -> Error {
+        Error {
+            value,
+            line: self.line,
+        }
+    }
+
+    /// Runs a chunk with `args` as its `...`.
+    pub fn run(&mut self, chunk: &Chunk, args: Vec<Value>) -> Result<Vec<Value>, Error> {
+        let closure = Rc::new(Closure {
+            proto: chunk.proto.clone(),
+            upvalues: vec![],
+        });
+        self.call(&Value::Function(Function::Lua(closure)), args)
+    }
+
+    pub fn call(&mut self, function: &Value, mut args: Vec<Value>) -> Result<Vec<Value>, Error> {
+        if self.depth >= MAX_CALLS {
+            return Err(self.error("stack overflow"));
+        }
+        self.step()?;
+        let line = self.line;
+        self.depth += 1;
+        let result = match function {
+            Value::Function(Function::Builtin(builtin)) => builtin.clone()(self, args),
+            Value::Function(Function::Lua(closure)) => self.call_closure(&closure.clone(), args),
+            _ => match metamethod(function, "__call") {
+                Some(handler) => {
+                    args.insert(0, function.clone());
+                    self.call(&handler, args)
+                }
+                None => {
+                    Err(self.error(format!("attempt to call a {} value", function.type_name())))
+                }
+            },
+        };
+        self.depth -= 1;
+        if result.is_ok() {
+            self.line = line;
+        }
+        result
+    }
+
+    /// Counts a call or loop iteration, checking with the host every so often.
+    fn step(&mut self) -> Result<(), Error> {
+        if self.interrupted.is_none() {
+            self.steps = self.steps.wrapping_add(1);
+            if self.steps % STEPS_PER_CHECK != 0 {
+                return Ok(());
+            }
+            self.interrupted = self.host.as_mut().and_then(|host| host.interrupt());
+        }
+        match &self.interrupted {
+            Some(value) => Err(self.raise(value.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn call_closure(&mut self, closure: &Closure, args: Vec<Value>) -> Result<Vec<Value>, Error> {
+        let proto = &closure.proto;
+        let mut args = args.into_iter();
+        let mut slots = Vec::with_capacity(proto.slots);
+        for _ in 0..proto.params {
+            slots.push(cell(args.next().unwrap_or(Value::Nil)));
+        }
+        while slots.len() < proto.slots {
+            slots.push(cell(Value::Nil));
+        }
+        let varargs = if proto.vararg { args.collect() } else { vec![] };
+        let mut frame = Frame {
+            slots,
+            upvalues: &closure.upvalues,
+            varargs,
+        };
+        match self.exec_block(&proto.body, &mut frame)? {
+            Flow::Return(values) => Ok(values),
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Indexes `object`, following `__index` metamethods.
+    pub fn index(&mut self, object: &Value, key: &Value) -> Result<Value, Error> {
+        match object {
+            Value::Table(table) => {
+                let value = table.borrow().get(key);
+                if !value.is_nil() {
+                    return Ok(value);
+                }
+                match metamethod(object, "__index") {
+                    Some(handler @ Value::Function(_)) => {
+                        let values = self.call(&handler, vec![object.clone(), key.clone()])?;
+                        Ok(values.into_iter().next().unwrap_or(Value::Nil))
+                    }
+                    Some(handler) => self.index(&handler, key),
+                    None => Ok(Value::Nil),
+                }
+            }
+            Value::String(_) => Ok(self.strings.borrow().get(key)),
+            _ => Err(self.error(format!("attempt to index a {} value", object.type_name()))),
+        }
+    }
+
+    /// Assigns to a field of `object`, following `__newindex` metamethods.
+    pub fn set_index(&mut self, object: &Value, key: Value, value: Value) -> Result<(), Error> {
+        let table = match object {
+            Value::Table(table) => table,
+            _ => return Err(self.error(format!("attempt to index a {} value", object.type_name()))),
+        };
+        if table.borrow().readonly {
+            return Err(self.error("Attempt to modify a readonly table"));
+        }
+        let present = !table.borrow().get(&key).is_nil();
+        match metamethod(object, "__newindex").filter(|_| !present) {
+            Some(handler @ Value::Function(_)) => {
+                self.call(&handler, vec![object.clone(), key, value])?;
+                Ok(())
+            }
+            Some(handler) => self.set_index(&handler, key, value),
+            None => {
+                let result = table.borrow_mut().set(key, value);
+                result.map_err(|message| self.error(message))
+            }
+        }
+    }
+
+    /// `tostring`, honouring `__tostring`.
+    pub fn tostring(&mut self, value: &Value) -> Result<Value, Error> {
+        if let Some(handler) = metamethod(value, "__tostring") {
+            let values = self.call(&handler, vec![value.clone()])?;
+            let result = values.into_iter().next().unwrap_or(Value::Nil);
+            return match result {
+                Value::String(_) => Ok(result),
+                _ => Err(self.error("'__tostring' must return a string")),
+            };
+        }
+        Ok(Value::string(&value.to_display()))
+    }
+
+    /// The `<` operator.
+    pub fn less_than(&self, a: &Value, b: &Value) -> Result<bool, Error> {
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => Ok(a < b),
+            (Value::String(a), Value::String(b)) => Ok(a < b),
+            _ => Err(self.compare_error(a, b)),
+        }
+    }
+
+    fn less_equal(&self, a: &Value, b: &Value) -> Result<bool, Error> {
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => Ok(a <= b),
+            (Value::String(a), Value::String(b)) => Ok(a <= b),
+            _ => Err(self.compare_error(a, b)),
+        }
+    }
+
+    fn compare_error(&self, a: &Value, b: &Value) -> Error {
+        if a.type_name() == b.type_name() {
+            self.error(format!("attempt to compare two {} values", a.type_name()))
+        } else {
+            self.error(format!(
+                "attempt to compare {} with {}",
+                a.type_name(),
+                b.type_name()
+            ))
+        }
+    }
+
+    fn operand_error(&self, action: &str, value: &Value, expr: &Expr) -> Error {
+        match describe(expr) {
+            Some(name) => self.error(format!(
+                "attempt to {} {} (a {} value)",
+                action,
+                name,
+                value.type_name()
+            )),
+            None => self.error(format!(
+                "attempt to {} a {} value",
+                action,
+                value.type_name()
+            )),
+        }
+    }
+
+    fn exec_block(&mut self, block: &[Stmt], frame: &mut Frame) -> Result<Flow, Error> {
+        for statement in block {
+            match self.exec(statement, frame)? {
+                Flow::Normal => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Runs a loop body, telling whether the loop goes on or how it ends.
+    fn exec_loop(&mut self, body: &[Stmt], frame: &mut Frame) -> Result<Option<Flow>, Error> {
+        self.step()?;
+        match self.exec_block(body, frame)? {
+            Flow::Normal => Ok(None),
+            Flow::Break => Ok(Some(Flow::Normal)),
+            flow => Ok(Some(flow)),
+        }
+    }
+
+    fn exec(&mut self, statement: &Stmt, frame: &mut Frame) -> Result<Flow, Error> {
+        match statement {
+            Stmt::Local(slots, exprs) => {
+                let mut values = self.eval_list(exprs, frame)?.into_iter();
+                for &slot in slots {
+                    frame.slots[slot] = cell(values.next().unwrap_or(Value::Nil));
+                }
+            }
+            Stmt::LocalFunction(slot, proto) => {
+                frame.slots[*slot] = cell(Value::Nil);
+                let function = self.closure(proto, frame);
+                *frame.slots[*slot].borrow_mut() = function;
+            }
+            Stmt::Assign(targets, exprs, line) => {
+                let mut places = Vec::with_capacity(targets.len());
+                for target in targets {
+                    let place = match target {
+                        Target::Local(slot) => Place::Local(*slot),
+                        Target::Upvalue(index) => Place::Upvalue(*index),
+                        Target::Global(name) => Place::Global(name),
+                        Target::Index(object, key) => {
+                            let value = self.eval(object, frame)?;
+                            if !matches!(value, Value::Table(_)) {
+                                self.line = *line;
+                                return Err(self.operand_error("index", &value, object));
+                            }
+                            Place::Index(value, self.eval(key, frame)?)
+                        }
+                    };
+                    places.push(place);
+                }
+                let mut values = self.eval_list(exprs, frame)?.into_iter();
+                self.line = *line;
+                for place in places {
+                    let value = values.next().unwrap_or(Value::Nil);
+                    match place {
+                        Place::Local(slot) => *frame.slots[slot].borrow_mut() = value,
+                        Place::Upvalue(index) => *frame.upvalues[index].borrow_mut() = value,
+                        Place::Global(name) => {
+                            let globals = Value::Table(self.globals.clone());
+                            self.set_index(&globals, Value::string(name.as_bytes()), value)?;
+                        }
+                        Place::Index(object, key) => self.set_index(&object, key, value)?,
+                    }
+                }
+            }
+            Stmt::Call(call) => {
+                self.eval_call(call, frame)?;
+            }
+            Stmt::Do(body) => return self.exec_block(body, frame),
+            Stmt::While(condition, body) => {
+                while self.eval(condition, frame)?.truthy() {
+                    if let Some(flow) = self.exec_loop(body, frame)? {
+                        return Ok(flow);
+                    }
+                }
+            }
+            Stmt::Repeat(body, condition) => loop {
+                if let Some(flow) = self.exec_loop(body, frame)? {
+                    return Ok(flow);
+                }
+                if self.eval(condition, frame)?.truthy() {
+                    break;
+                }
+            },
+            Stmt::If(branches, otherwise) => {
+                for (condition, body) in branches {
+                    if self.eval(condition, frame)?.truthy() {
+                        return self.exec_block(body, frame);
+                    }
+                }
+                if let Some(body) = otherwise {
+                    return self.exec_block(body, frame);
+                }
+            }
+            Stmt::NumericFor {
+                slot,
+                start,
+                limit,
+                step,
+                body,
+                line,
+            } => {
+                let start = self.eval(start, frame)?.to_number();
+                let limit = self.eval(limit, frame)?.to_number();
+                let step = match step {
+                    Some(step) => self.eval(step, frame)?.to_number(),
+                    None => Some(1.0),
+                };
+                self.line = *line;
+                let mut i =
+                    start.ok_or_else(|| self.error("'for' initial value must be a number"))?;
+                let limit = limit.ok_or_else(|| self.error("'for' limit must be a number"))?;
+                let step = step.ok_or_else(|| self.error("'for' step must be a number"))?;
+                while (step > 0.0 && i <= limit) || (step <= 0.0 && i >= limit) {
+                    frame.slots[*slot] = cell(Value::Number(i));
+                    if let Some(flow) = self.exec_loop(body, frame)? {
+                        return Ok(flow);
+                    }
+                    i += step;
+                }
+            }
+            Stmt::GenericFor {
+                slots,
+                exprs,
+                body,
+                line,
+            } => {
+                let mut values = self.eval_list(exprs, frame)?.into_iter();
+                let function = values.next().unwrap_or(Value::Nil);
+                let state = values.next().unwrap_or(Value::Nil);
+                let mut control = values.next().unwrap_or(Value::Nil);
+                loop {
+                    self.line = *line;
+                    let mut results = self
+                        .call(&function, vec![state.clone(), control.clone()])?
+                        .into_iter();
+                    let first = results.next().unwrap_or(Value::Nil);
+                    if first.is_nil() {
+                        break;
+                    }
+                    control = first.clone();
+                    frame.slots[slots[0]] = cell(first);
+                    for &slot in &slots[1..] {
+                        frame.slots[slot] = cell(results.next().unwrap_or(Value::Nil));
+                    }
+                    if let Some(flow) = self.exec_loop(body, frame)? {
+                        return Ok(flow);
+                    }
+                }
+            }
+            Stmt::Return(exprs) => return Ok(Flow::Return(self.eval_list(exprs, frame)?)),
+            Stmt::Break => return Ok(Flow::Break),
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn closure(&mut self, proto: &Arc<Proto>, frame: &Frame) -> Value {
+        let mut upvalues = Vec::with_capacity(proto.upvalues.len());
+        for upvalue in &proto.upvalues {
+            upvalues.push(match upvalue {
+                Upvalue::Local(slot) => {
+                    let cell = frame.slots[*slot].clone();
+                    self.cells.track(&cell);
+                    cell
+                }
+                Upvalue::Upvalue(index) => frame.upvalues[*index].clone(),
+            });
+        }
+        Value::Function(Function::Lua(Rc::new(Closure {
+            proto: proto.clone(),
+            upvalues,
+        })))
+    }
+
+    /// Evaluates expressions to a list of values, the last one contributing
+    /// all of its values.
+    fn eval_list(&mut self, exprs: &[Expr], frame: &mut Frame) -> Result<Vec<Value>, Error> {
+        let mut values = Vec::with_capacity(exprs.len());
+        if let Some((last, exprs)) = exprs.split_last() {
+            for expr in exprs {
+                values.push(self.eval(expr, frame)?);
+            }
+            values.extend(self.eval_multi(last, frame)?);
+        }
+        Ok(values)
+    }
+
+    fn eval_multi(&mut self, expr: &Expr, frame: &mut Frame) -> Result<Vec<Value>, Error> {
+        match expr {
+            Expr::Call(call) => self.eval_call(call, frame),
+            Expr::VarArgs => Ok(frame.varargs.clone()),
+            expr => Ok(vec![self.eval(expr, frame)?]),
+        }
+    }
+
+    fn eval_call(&mut self, call: &Call, frame: &mut Frame) -> Result<Vec<Value>, Error> {
+        let object = self.eval(&call.function, frame)?;
+        let (function, mut args) = match &call.method {
+            Some(method) => {
+                if !matches!(object, Value::Table(_) | Value::String(_)) {
+                    self.line = call.line;
+                    return Err(self.operand_error("index", &object, &call.function));
+                }
+                self.line = call.line;
+                let function = self.index(&object, &Value::string(method.as_bytes()))?;
+                (function, vec![object])
+            }
+            None => (object, vec![]),
+        };
+        args.extend(self.eval_list(&call.args, frame)?);
+        self.line = call.line;
+        let callable =
+            matches!(function, Value::Function(_)) || metamethod(&function, "__call").is_some();
+        if !callable {
+            return Err(match &call.method {
+                Some(method) => self.error(format!(
+                    "attempt to call method '{}' (a {} value)",
+                    method,
+                    function.type_name()
+                )),
+                None => self.operand_error("call", &function, &call.function),
+            });
+        }
+        self.call(&function, args)
+    }
+
+    fn eval(&mut self, expr: &Expr, frame: &mut Frame) -> Result<Value, Error> {
+        let value = match expr {
+            Expr::Nil => Value::Nil,
+            Expr::True => Value::Boolean(true),
+            Expr::False => Value::Boolean(false),
+            Expr::VarArgs => frame.varargs.first().cloned().unwrap_or(Value::Nil),
+            Expr::Number(n) => Value::Number(*n),
+            Expr::String(s) => Value::string(s),
+            Expr::Function(proto) => self.closure(proto, frame),
+            Expr::Table(fields, line) => self.table(fields, *line, frame)?,
+            Expr::Local(slot, _) => frame.slots[*slot].borrow().clone(),
+            Expr::Upvalue(index, _) => frame.upvalues[*index].borrow().clone(),
+            Expr::Global(name, line) => {
+                let key = Value::string(name.as_bytes());
+                let value = self.globals.borrow().get(&key);
+                if value.is_nil() {
+                    self.line = *line;
+                    let globals = Value::Table(self.globals.clone());
+                    self.index(&globals, &key)?
+                } else {
+                    value
+                }
+            }
+            Expr::Index(object, key, line) => {
+                let value = self.eval(object, frame)?;
+                let key = self.eval(key, frame)?;
+                self.line = *line;
+                if !matches!(value, Value::Table(_) | Value::String(_)) {
+                    return Err(self.operand_error("index", &value, object));
+                }
+                self.index(&value, &key)?
+            }
+            Expr::Call(call) => self
+                .eval_call(call, frame)?
+                .into_iter()
+                .next()
+                .unwrap_or(Value::Nil),
+            Expr::Paren(expr) => self.eval(expr, frame)?,
+            Expr::And(left, right) => {
+                let value = self.eval(left, frame)?;
+                if value.truthy() {
+                    self.eval(right, frame)?
+                } else {
+                    value
+                }
+            }
+            Expr::Or(left, right) => {
+                let value = self.eval(left, frame)?;
+                if value.truthy() {
+                    value
+                } else {
+                    self.eval(right, frame)?
+                }
+            }
+            Expr::Unary(op, operand, line) => {
+                let value = self.eval(operand, frame)?;
+                self.line = *line;
+                match op {
+                    UnaryOp::Not => Value::Boolean(!value.truthy()),
+                    UnaryOp::Neg => match value.to_number() {
+                        Some(n) => Value::Number(-n),
+                        None => {
+                            return Err(self.operand_error(
+                                "perform arithmetic on",
+                                &value,
+                                operand,
+                            ))
+                        }
+                    },
+                    UnaryOp::Len => match &value {
+                        Value::String(s) => Value::Number(s.len() as f64),
+                        Value::Table(table) => Value::Number(table.borrow().len() as f64),
+                        _ => return Err(self.operand_error("get length of", &value, operand)),
+                    },
+                }
+            }
+            Expr::Binary(op, left, right, line) => {
+                let a = self.eval(left, frame)?;
+                let b = self.eval(right, frame)?;
+                self.line = *line;
+                self.binary(*op, a, b, left, right)?
+            }
+        };
+        Ok(value)
+    }
+
+    fn binary(
+        &mut self,
+        op: BinaryOp,
+        a: Value,
+        b: Value,
+        left: &Expr,
+        right: &Expr,
+    ) -> Result<Value, Error> {
+        let value = match op {
+            BinaryOp::Eq => Value::Boolean(a.raw_equals(&b)),
+            BinaryOp::Ne => Value::Boolean(!a.raw_equals(&b)),
+            BinaryOp::Lt => Value::Boolean(self.less_than(&a, &b)?),
+            BinaryOp::Le => Value::Boolean(self.less_equal(&a, &b)?),
+            BinaryOp::Gt => Value::Boolean(self.less_than(&b, &a)?),
+            BinaryOp::Ge => Value::Boolean(self.less_equal(&b, &a)?),
+            BinaryOp::Concat => match (a.to_bytes(), b.to_bytes()) {
+                (Some(a), Some(b)) => {
+                    let mut joined = Vec::with_capacity(a.len() + b.len());
+                    joined.extend_from_slice(&a);
+                    joined.extend_from_slice(&b);
+                    Value::String(Rc::from(joined))
+                }
+                (None, _) => return Err(self.operand_error("concatenate", &a, left)),
+                _ => return Err(self.operand_error("concatenate", &b, right)),
+            },
+            _ => {
+                let (x, y) = match (a.to_number(), b.to_number()) {
+                    (Some(x), Some(y)) => (x, y),
+                    (None, _) => return Err(self.operand_error("perform arithmetic on", &a, left)),
+                    _ => return Err(self.operand_error("perform arithmetic on", &b, right)),
+                };
+                Value::Number(match op {
+                    BinaryOp::Add => x + y,
+                    BinaryOp::Sub => x - y,
+                    BinaryOp::Mul => x * y,
+                    BinaryOp::Div => x / y,
+                    BinaryOp::Mod => x - (x / y).floor() * y,
+                    _ => x.powf(y),
+                })
+            }
+        };
+        Ok(value)
+    }
+
+    fn table(&mut self, fields: &[Field], line: u32, frame: &mut Frame) -> Result<Value, Error> {
+        let mut table = Table::new();
+        let mut position = 1.0;
+        for (i, field) in fields.iter().enumerate() {
+            match field {
+                Field::Positional(expr) => {
+                    let values = if i + 1 == fields.len() {
+                        self.eval_multi(expr, frame)?
+                    } else {
+                        vec![self.eval(expr, frame)?]
+                    };
+                    for value in values {
+                        let _ = table.set(Value::Number(position), value);
+                        position += 1.0;
+                    }
+                }
+                Field::Named(key, value) => {
+                    let key = self.eval(key, frame)?;
+                    let value = self.eval(value, frame)?;
+                    if let Err(message) = Key::new(&key) {
+                        self.line = line;
+                        return Err(self.error(message));
+                    }
+                    let _ = table.set(key, value);
+                }
+            }
+        }
+        Ok(self.new_table(table))
+    }
+}
+
+impl<'a> Drop for Interpreter<'a> {
+    fn drop(&mut self) {
+        self.tables.clear();
+        self.cells.clear();
+        std::mem::take(&mut *self.globals.borrow_mut());
+        std::mem::take(&mut *self.strings.borrow_mut());
+    }
+}
@@ -0,0 +1,410 @@
+//! Splits Lua source into tokens.
+
+use super::number;
+
+#[derive(Clone, PartialEq)]
+pub enum Token {
+    Name(String),
+    String(Vec<u8>),
+    Number(f64),
+    And,
+    Break,
+    Do,
+    Else,
+    ElseIf,
+    End,
+    False,
+    For,
+    Function,
+    If,
+    In,
+    Local,
+    Nil,
+    Not,
+    Or,
+    Repeat,
+    Return,
+    Then,
+    True,
+    Until,
+    While,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Caret,
+    Hash,
+    Eq,
+    Ne,
+    Le,
+    Ge,
+    Lt,
+    Gt,
+    Assign,
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    Semicolon,
+    Colon,
+    Comma,
+    Dot,
+    Concat,
+    Dots,
+    Eof,
+}
+
+fn keyword(name: &str) -> Option<Token> {
+    let token = match name {
+        "and" => Token::And,
+        "break" => Token::Break,
+        "do" => Token::Do,
+        "else" => Token::Else,
+        "elseif" => Token::ElseIf,
+        "end" => Token::End,
+        "false" => Token::False,
+        "for" => Token::For,
+        "function" => Token::Function,
+        "if" => Token::If,
+        "in" => Token::In,
+        "local" => Token::Local,
+        "nil" => Token::Nil,
+        "not" => Token::Not,
+        "or" => Token::Or,
+        "repeat" => Token::Repeat,
+        "return" => Token::Return,
+        "then" => Token::Then,
+        "true" => Token::True,
+        "until" => Token::Until,
+        "while" => Token::While,
+        _ => return None,
+    };
+    Some(token)
+}
+
+/// A token and where it was found, for error messages.
+pub struct Lexeme {
+    pub token: Token,
+    pub line: u32,
+    start: usize,
+    end: usize,
+}
+
+pub struct Lexer<'a> {
+    source: &'a [u8],
+    chunk: &'a str,
+    pos: usize,
+    line: u32,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a [u8], chunk: &'a str) -> Lexer<'a> {
+        let mut lexer = Lexer {
+            source,
+            chunk,
+            pos: 0,
+            line: 1,
+        };
+        // A first line starting with `#` is skipped, as by the standalone
+        // interpreter.
+        if source.starts_with(b"#") {
+            while lexer.peek().map_or(false, |c| c != b'\n') {
+                lexer.pos += 1;
+            }
+        }
+        lexer
+    }
+
+    /// The text of a lexeme, as error messages quote it.
+    pub fn text(&self, lexeme: &Lexeme) -> String {
+        match lexeme.token {
+            Token::Eof => "<eof>".to_owned(),
+            _ => String::from_utf8_lossy(&self.source[lexeme.start..lexeme.end]).into_owned(),
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.source.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.source.get(self.pos + offset).copied()
+    }
+
+    fn error(&self, message: &str, start: usize) -> String {
+        let near = String::from_utf8_lossy(&self.source[start..self.pos.min(self.source.len())]);
+        format!("{}:{}: {} near '{}'", self.chunk, self.line, message, near)
+    }
+
+    fn newline(&mut self) {
+        let c = self.source[self.pos];
+        self.pos += 1;
+        // \r\n and \n\r count as one line break.
+        if let Some(next) = self.peek() {
+            if (next == b'\n' || next == b'\r') && next != c {
+                self.pos += 1;
+            }
+        }
+        self.line += 1;
+    }
+
+    pub fn next(&mut self) -> Result<Lexeme, String> {
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => {
+                    return Ok(Lexeme {
+                        token: Token::Eof,
+                        line: self.line,
+                        start: self.pos,
+                        end: self.pos,
+                    })
+                }
+            };
+            match c {
+                b'\n' | b'\r' => self.newline(),
+                b' ' | b'\t' | b'\x0b' | b'\x0c' => self.pos += 1,
+                b'-' if self.peek_at(1) == Some(b'-') => self.comment()?,
+                _ => break,
+            }
+        }
+        let start = self.pos;
+        let line = self.line;
+        let token = self.token()?;
+        Ok(Lexeme {
+            token,
+            line,
+            start,
+            end: self.pos,
+        })
+    }
+
+    fn comment(&mut self) -> Result<(), String> {
+        self.pos += 2;
+        if self.peek() == Some(b'[') {
+            let start = self.pos;
+            if let Some(level) = self.long_bracket() {
+                self.long_string(level, start)?;
+                return Ok(());
+            }
+            self.pos = start;
+        }
+        while self.peek().map_or(false, |c| c != b'\n' && c != b'\r') {
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    /// Consumes the opening `[`, `[=[` and so on of a long string, returning
+    /// its level.
+    fn long_bracket(&mut self) -> Option<usize> {
+        let mut level = 0;
+        let mut pos = self.pos + 1;
+        while self.source.get(pos) == Some(&b'=') {
+            level += 1;
+            pos += 1;
+        }
+        if self.source.get(pos) == Some(&b'[') {
+            self.pos = pos + 1;
+            Some(level)
+        } else {
+            None
+        }
+    }
+
+    fn long_string(&mut self, level: usize, start: usize) -> Result<Vec<u8>, String> {
+        // A line break right after the opening bracket is dropped.
+        if let Some(b'\n') | Some(b'\r') = self.peek() {
+            self.newline();
+        }
+        let mut value = vec![];
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unfinished long string", start)),
+                Some(b']') => {
+                    let mut pos = self.pos + 1;
+                    let mut closing = 0;
+                    while self.source.get(pos) == Some(&b'=') {
+                        closing += 1;
+                        pos += 1;
+                    }
+                    if closing == level && self.source.get(pos) == Some(&b']') {
+                        self.pos = pos + 1;
+                        return Ok(value);
+                    }
+                    value.push(b']');
+                    self.pos += 1;
+                }
+                Some(b'\n') | Some(b'\r') => {
+                    self.newline();
+                    value.push(b'\n');
+                }
+                Some(c) => {
+                    value.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn token(&mut self) -> Result<Token, String> {
+        let start = self.pos;
+        let c = self.source[self.pos];
+        let two = |lexer: &mut Lexer, next: u8, double: Token, single: Token| {
+            if lexer.peek_at(1) == Some(next) {
+                lexer.pos += 2;
+                double
+            } else {
+                lexer.pos += 1;
+                single
+            }
+        };
+        let token = match c {
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                while self
+                    .peek()
+                    .map_or(false, |c| c.is_ascii_alphanumeric() || c == b'_')
+                {
+                    self.pos += 1;
+                }
+                let name = String::from_utf8_lossy(&self.source[start..self.pos]).into_owned();
+                keyword(&name).unwrap_or(Token::Name(name))
+            }
+            b'0'..=b'9' => self.number()?,
+            b'.' if self.peek_at(1).map_or(false, |c| c.is_ascii_digit()) => self.number()?,
+            b'"' | b'\'' => Token::String(self.string(c)?),
+            b'[' => match self.long_bracket() {
+                Some(level) => Token::String(self.long_string(level, start)?),
+                None if self.peek_at(1) == Some(b'=') => {
+                    self.pos += 1;
+                    return Err(self.error("invalid long string delimiter", start));
+                }
+                None => {
+                    self.pos += 1;
+                    Token::LeftBracket
+                }
+            },
+            b'=' => two(self, b'=', Token::Eq, Token::Assign),
+            b'<' => two(self, b'=', Token::Le, Token::Lt),
+            b'>' => two(self, b'=', Token::Ge, Token::Gt),
+            b'~' if self.peek_at(1) == Some(b'=') => {
+                self.pos += 2;
+                Token::Ne
+            }
+            b'.' => {
+                if self.peek_at(1) == Some(b'.') {
+                    if self.peek_at(2) == Some(b'.') {
+                        self.pos += 3;
+                        Token::Dots
+                    } else {
+                        self.pos += 2;
+                        Token::Concat
+                    }
+                } else {
+                    self.pos += 1;
+                    Token::Dot
+                }
+            }
+            _ => {
+                self.pos += 1;
+                match c {
+                    b'+' => Token::Plus,
+                    b'-' => Token::Minus,
+                    b'*' => Token::Star,
+                    b'/' => Token::Slash,
+                    b'%' => Token::Percent,
+                    b'^' => Token::Caret,
+                    b'#' => Token::Hash,
+                    b'(' => Token::LeftParen,
+                    b')' => Token::RightParen,
+                    b'{' => Token::LeftBrace,
+                    b'}' => Token::RightBrace,
+                    b']' => Token::RightBracket,
+                    b';' => Token::Semicolon,
+                    b':' => Token::Colon,
+                    b',' => Token::Comma,
+                    _ => return Err(self.error("unexpected symbol", start)),
+                }
+            }
+        };
+        Ok(token)
+    }
+
+    fn number(&mut self) -> Result<Token, String> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            let exponent_sign = (c == b'+' || c == b'-')
+                && matches!(self.source[self.pos - 1], b'e' | b'E')
+                && !self.source[start..self.pos].starts_with(b"0x")
+                && !self.source[start..self.pos].starts_with(b"0X");
+            if c.is_ascii_alphanumeric() || c == b'.' || c == b'_' || exponent_sign {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        match number::parse(&self.source[start..self.pos]) {
+            Some(value) => Ok(Token::Number(value)),
+            None => Err(self.error("malformed number", start)),
+        }
+    }
+
+    fn string(&mut self, quote: u8) -> Result<Vec<u8>, String> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut value = vec![];
+        loop {
+            let c = match self.peek() {
+                None | Some(b'\n') | Some(b'\r') => {
+                    return Err(self.error("unfinished string", start))
+                }
+                Some(c) => c,
+            };
+            self.pos += 1;
+            if c == quote {
+                return Ok(value);
+            }
+            if c != b'\\' {
+                value.push(c);
+                continue;
+            }
+            let escaped = match self.peek() {
+                None => return Err(self.error("unfinished string", start)),
+                Some(c) => c,
+            };
+            match escaped {
+                b'n' => value.push(b'\n'),
+                b't' => value.push(b'\t'),
+                b'r' => value.push(b'\r'),
+                b'a' => value.push(7),
+                b'b' => value.push(8),
+                b'f' => value.push(12),
+                b'v' => value.push(11),
+                b'\n' | b'\r' => {
+                    self.newline();
+                    value.push(b'\n');
+                    continue;
+                }
+                b'0'..=b'9' => {
+                    let mut code = 0u32;
+                    let mut digits = 0;
+                    while digits < 3 && self.peek().map_or(false, |c| c.is_ascii_digit()) {
+                        code = code * 10 + u32::from(self.source[self.pos] - b'0');
+                        self.pos += 1;
+                        digits += 1;
+                    }
+                    if code > 255 {
+                        return Err(self.error("escape sequence too large", start));
+                    }
+                    value.push(code as u8);
+                    continue;
+                }
+                c => value.push(c),
+            }
+            self.pos += 1;
+        }
+    }
+}
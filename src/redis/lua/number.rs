@@ -0,0 +1,129 @@
+//! Conversions between Lua numbers and text, following the C library calls
+//! Lua 5.1 makes.
+
+/// Parses a numeral as `tonumber` does: decimal with an optional exponent,
+/// or hexadecimal with a `0x` prefix, surrounded by optional whitespace.
+pub fn parse(text: &[u8]) -> Option<f64> {
+    let text = std::str::from_utf8(text).ok()?;
+    let text = text.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        if hex.is_empty() || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        hex.bytes().fold(0.0, |value, c| {
+            value * 16.0 + f64::from((c as char).to_digit(16).unwrap_or(0))
+        })
+    } else {
+        // Rust also parses words like `inf`, which strtod would take but
+        // Lua numerals cannot spell.
+        let valid = digits.bytes().any(|c| c.is_ascii_digit())
+            && digits
+                .bytes()
+                .all(|c| c.is_ascii_digit() || matches!(c, b'.' | b'e' | b'E' | b'+' | b'-'));
+        if !valid {
+            return None;
+        }
+        digits.parse::<f64>().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// A number as `tostring` writes it, with `%.14g`.
+pub fn to_string(value: f64) -> String {
+    format_g(value, 14, false, false)
+}
+
+/// C's `%e`, with at least two exponent digits.
+pub fn format_e(value: f64, precision: usize, upper: bool) -> String {
+    if !value.is_finite() {
+        return non_finite(value, upper);
+    }
+    let formatted = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = formatted.split_at(formatted.find('e').unwrap_or(0));
+    let exponent: i32 = exponent[1..].parse().unwrap_or(0);
+    let sign = if exponent < 0 { '-' } else { '+' };
+    let e = if upper { 'E' } else { 'e' };
+    format!("{}{}{}{:02}", mantissa, e, sign, exponent.abs())
+}
+
+/// C's `%f`.
+pub fn format_f(value: f64, precision: usize) -> String {
+    if !value.is_finite() {
+        return non_finite(value, false);
+    }
+    format!("{:.*}", precision, value)
+}
+
+/// C's `%g`: `precision` significant digits, switching to exponent form for
+/// large and small magnitudes, with trailing zeros dropped unless `alternate`.
+pub fn format_g(value: f64, precision: usize, upper: bool, alternate: bool) -> String {
+    if !value.is_finite() {
+        return non_finite(value, upper);
+    }
+    let precision = precision.max(1);
+    if value == 0.0 {
+        let zero = if value.is_sign_negative() { "-0" } else { "0" };
+        if alternate && precision > 1 {
+            return format!("{}.{}", zero, "0".repeat(precision - 1));
+        }
+        return zero.to_owned();
+    }
+    // The exponent after rounding to the precision decides the form.
+    let exponent = {
+        let formatted = format!("{:.*e}", precision - 1, value);
+        formatted[formatted.find('e').unwrap_or(0) + 1..]
+            .parse::<i32>()
+            .unwrap_or(0)
+    };
+    if exponent < -4 || exponent >= precision as i32 {
+        let formatted = format_e(value, precision - 1, upper);
+        if alternate {
+            return formatted;
+        }
+        let split = formatted
+            .find(|c| c == 'e' || c == 'E')
+            .unwrap_or(formatted.len());
+        let (mantissa, exponent) = formatted.split_at(split);
+        return format!("{}{}", trim_zeros(mantissa), exponent);
+    }
+    let formatted = format!("{:.*}", (precision as i32 - 1 - exponent) as usize, value);
+    if alternate {
+        formatted
+    } else {
+        trim_zeros(&formatted).to_owned()
+    }
+}
+
+fn trim_zeros(formatted: &str) -> &str {
+    if formatted.contains('.') {
+        formatted.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        formatted
+    }
+}
+
+fn non_finite(value: f64, upper: bool) -> String {
+    let text = if value.is_nan() {
+        if value.is_sign_negative() {
+            "-nan"
+        } else {
+            "nan"
+        }
+    } else if value > 0.0 {
+        "inf"
+    } else {
+        "-inf"
+    };
+    if upper {
+        text.to_uppercase()
+    } else {
+        text.to_owned()
+    }
+}
@@ -0,0 +1,908 @@
+//! Parses tokens into a syntax tree, resolving every variable to a slot of
+//! its function's frame, an upvalue captured from an enclosing function, or
+//! a global.
+
+use super::lexer::{Lexeme, Lexer, Token};
+use std::sync::Arc;
+
+/// How deeply syntax may nest before parsing gives up, which keeps the tree
+/// shallow enough to walk recursively.
+const MAX_NESTING: usize = 200;
+
+#[derive(Clone, Copy)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    Len,
+}
+
+pub enum Expr {
+    Nil,
+    True,
+    False,
+    VarArgs,
+    Number(f64),
+    String(Vec<u8>),
+    Function(Arc<Proto>),
+    Table(Vec<Field>, u32),
+    Local(usize, String),
+    Upvalue(usize, String),
+    Global(String, u32),
+    Index(Box<Expr>, Box<Expr>, u32),
+    Call(Box<Call>),
+    /// Parentheses, which cut multiple values down to one.
+    Paren(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>, u32),
+    Unary(UnaryOp, Box<Expr>, u32),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+pub enum Field {
+    Positional(Expr),
+    Named(Expr, Expr),
+}
+
+pub struct Call {
+    pub function: Expr,
+    /// The name of a method called with `:`, on `function` as the object.
+    pub method: Option<String>,
+    pub args: Vec<Expr>,
+    pub line: u32,
+}
+
+pub enum Target {
+    Local(usize),
+    Upvalue(usize),
+    Global(String),
+    Index(Expr, Expr),
+}
+
+pub enum Stmt {
+    Local(Vec<usize>, Vec<Expr>),
+    LocalFunction(usize, Arc<Proto>),
+    Assign(Vec<Target>, Vec<Expr>, u32),
+    Call(Call),
+    Do(Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    Repeat(Vec<Stmt>, Expr),
+    If(Vec<(Expr, Vec<Stmt>)>, Option<Vec<Stmt>>),
+    NumericFor {
+        slot: usize,
+        start: Expr,
+        limit: Expr,
+        step: Option<Expr>,
+        body: Vec<Stmt>,
+        line: u32,
+    },
+    GenericFor {
+        slots: Vec<usize>,
+        exprs: Vec<Expr>,
+        body: Vec<Stmt>,
+        line: u32,
+    },
+    Return(Vec<Expr>),
+    Break,
+}
+
+/// Where a function finds a variable of an enclosing one.
+pub enum Upvalue {
+    Local(usize),
+    Upvalue(usize),
+}
+
+/// A function as written, shared by the closures made of it.
+pub struct Proto {
+    pub params: usize,
+    pub vararg: bool,
+    pub slots: usize,
+    pub upvalues: Vec<Upvalue>,
+    pub body: Vec<Stmt>,
+}
+
+/// A parsed chunk, the function its source is the body of.
+pub struct Chunk {
+    pub proto: Arc<Proto>,
+}
+
+struct FunctionState {
+    /// The locals in scope, innermost last, with their slots.
+    active: Vec<(String, usize)>,
+    /// How many locals were in scope when each open block began.
+    blocks: Vec<usize>,
+    slots: usize,
+    upvalues: Vec<(String, Upvalue)>,
+    vararg: bool,
+    loops: usize,
+}
+
+impl FunctionState {
+    fn new(vararg: bool) -> FunctionState {
+        FunctionState {
+            active: vec![],
+            blocks: vec![],
+            slots: 0,
+            upvalues: vec![],
+            vararg,
+            loops: 0,
+        }
+    }
+}
+
+enum Variable {
+    Local(usize),
+    Upvalue(usize),
+}
+
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+    chunk: &'a str,
+    current: Lexeme,
+    ahead: Option<Lexeme>,
+    functions: Vec<FunctionState>,
+    nesting: usize,
+}
+
+/// Parses `source`, naming it `chunk` in error messages.
+pub fn parse(source: &[u8], chunk: &str) -> Result<Chunk, String> {
+    let mut lexer = Lexer::new(source, chunk);
+    let current = lexer.next()?;
+    let mut parser = Parser {
+        lexer,
+        chunk,
+        current,
+        ahead: None,
+        functions: vec![FunctionState::new(true)],
+        nesting: 0,
+    };
+    let body = parser.block()?;
+    if parser.current.token != Token::Eof {
+        return Err(parser.error_near("'<eof>' expected"));
+    }
+    let state = parser
+        .functions
+        .pop()
+        .unwrap_or_else(|| FunctionState::new(true));
+    Ok(Chunk {
+        proto: Arc::new(Proto {
+            params: 0,
+            vararg: true,
+            slots: state.slots,
+            upvalues: vec![],
+            body,
+        }),
+    })
+}
+
+fn binary_op(token: &Token) -> Option<(BinaryOp, u8, u8)> {
+    // Left and right priorities, as in lparser.c.
+    let op = match token {
+        Token::Plus => (BinaryOp::Add, 6, 6),
+        Token::Minus => (BinaryOp::Sub, 6, 6),
+        Token::Star => (BinaryOp::Mul, 7, 7),
+        Token::Slash => (BinaryOp::Div, 7, 7),
+        Token::Percent => (BinaryOp::Mod, 7, 7),
+        Token::Caret => (BinaryOp::Pow, 10, 9),
+        Token::Concat => (BinaryOp::Concat, 5, 4),
+        Token::Eq => (BinaryOp::Eq, 3, 3),
+        Token::Ne => (BinaryOp::Ne, 3, 3),
+        Token::Lt => (BinaryOp::Lt, 3, 3),
+        Token::Le => (BinaryOp::Le, 3, 3),
+        Token::Gt => (BinaryOp::Gt, 3, 3),
+        Token::Ge => (BinaryOp::Ge, 3, 3),
+        _ => return None,
+    };
+    Some(op)
+}
+
+const UNARY_PRIORITY: u8 = 8;
+
+fn token_name(token: &Token) -> &'static str {
+    match token {
+        Token::And => "and",
+        Token::Break => "break",
+        Token::Do => "do",
+        Token::Else => "else",
+        Token::ElseIf => "elseif",
+        Token::End => "end",
+        Token::For => "for",
+        Token::Function => "function",
+        Token::If => "if",
+        Token::In => "in",
+        Token::Local => "local",
+        Token::Repeat => "repeat",
+        Token::Return => "return",
+        Token::Then => "then",
+        Token::Until => "until",
+        Token::While => "while",
+        Token::Assign => "=",
+        Token::LeftParen => "(",
+        Token::RightParen => ")",
+        Token::LeftBrace => "{",
+        Token::RightBrace => "}",
+        Token::LeftBracket => "[",
+        Token::RightBracket => "]",
+        Token::Comma => ",",
+        Token::Dot => ".",
+        Token::Eof => "<eof>",
+        _ => "?",
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn advance(&mut self) -> Result<Lexeme, String> {
+        let next = match self.ahead.take() {
+            Some(next) => next,
+            None => self.lexer.next()?,
+        };
+        Ok(std::mem::replace(&mut self.current, next))
+    }
+
+    fn peek_ahead(&mut self) -> Result<&Token, String> {
+        if self.ahead.is_none() {
+            self.ahead = Some(self.lexer.next()?);
+        }
+        Ok(self
+            .ahead
+            .as_ref()
+            .map_or(&Token::Eof, |lexeme| &lexeme.token))
+    }
+
+    fn line(&self) -> u32 {
+        self.current.line
+    }
+
+    fn error_near(&self, message: &str) -> String {
+        format!(
+            "{}:{}: {} near '{}'",
+            self.chunk,
+            self.current.line,
+            message,
+            self.lexer.text(&self.current)
+        )
+    }
+
+    fn check(&self, token: Token) -> Result<(), String> {
+        if self.current.token == token {
+            Ok(())
+        } else {
+            Err(self.error_near(&format!("'{}' expected", token_name(&token))))
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), String> {
+        self.check(token)?;
+        self.advance()?;
+        Ok(())
+    }
+
+    /// Expects the token closing what `opening` started on `line`.
+    fn expect_closing(&mut self, token: Token, opening: Token, line: u32) -> Result<(), String> {
+        if self.current.token == token {
+            self.advance()?;
+            return Ok(());
+        }
+        if line == self.line() {
+            return self.expect(token);
+        }
+        Err(self.error_near(&format!(
+            "'{}' expected (to close '{}' at line {})",
+            token_name(&token),
+            token_name(&opening),
+            line
+        )))
+    }
+
+    fn accept(&mut self, token: Token) -> Result<bool, String> {
+        if self.current.token == token {
+            self.advance()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match &self.current.token {
+            Token::Name(name) => {
+                let name = name.clone();
+                self.advance()?;
+                Ok(name)
+            }
+            _ => Err(self.error_near("<name> expected")),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), String> {
+        self.nesting += 1;
+        if self.nesting > MAX_NESTING {
+            return Err(self.error_near("chunk has too many syntax levels"));
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.nesting -= 1;
+    }
+
+    fn function(&mut self) -> &mut FunctionState {
+        let last = self.functions.len() - 1;
+        &mut self.functions[last]
+    }
+
+    fn open_block(&mut self) {
+        let function = self.function();
+        let active = function.active.len();
+        function.blocks.push(active);
+    }
+
+    fn close_block(&mut self) {
+        let function = self.function();
+        let active = function.blocks.pop().unwrap_or(0);
+        function.active.truncate(active);
+    }
+
+    fn declare(&mut self, name: String) -> usize {
+        let function = self.function();
+        let slot = function.active.len();
+        function.active.push((name, slot));
+        function.slots = function.slots.max(slot + 1);
+        slot
+    }
+
+    fn find(&mut self, level: usize, name: &str) -> Option<Variable> {
+        let function = &self.functions[level];
+        if let Some((_, slot)) = function
+            .active
+            .iter()
+            .rev()
+            .find(|(local, _)| local == name)
+        {
+            return Some(Variable::Local(*slot));
+        }
+        if let Some(index) = function
+            .upvalues
+            .iter()
+            .position(|(upvalue, _)| upvalue == name)
+        {
+            return Some(Variable::Upvalue(index));
+        }
+        if level == 0 {
+            return None;
+        }
+        let upvalue = match self.find(level - 1, name)? {
+            Variable::Local(slot) => Upvalue::Local(slot),
+            Variable::Upvalue(index) => Upvalue::Upvalue(index),
+        };
+        let upvalues = &mut self.functions[level].upvalues;
+        upvalues.push((name.to_owned(), upvalue));
+        Some(Variable::Upvalue(upvalues.len() - 1))
+    }
+
+    fn variable(&mut self, name: String, line: u32) -> Expr {
+        match self.find(self.functions.len() - 1, &name) {
+            Some(Variable::Local(slot)) => Expr::Local(slot, name),
+            Some(Variable::Upvalue(index)) => Expr::Upvalue(index, name),
+            None => Expr::Global(name, line),
+        }
+    }
+
+    fn block_follows(&self) -> bool {
+        matches!(
+            self.current.token,
+            Token::Else | Token::ElseIf | Token::End | Token::Until | Token::Eof
+        )
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, String> {
+        self.enter()?;
+        let mut statements = vec![];
+        while !self.block_follows() {
+            let last = matches!(self.current.token, Token::Return | Token::Break);
+            statements.push(self.statement()?);
+            self.accept(Token::Semicolon)?;
+            if last {
+                break;
+            }
+        }
+        self.leave();
+        Ok(statements)
+    }
+
+    /// A block with its own scope.
+    fn scoped_block(&mut self) -> Result<Vec<Stmt>, String> {
+        self.open_block();
+        let block = self.block();
+        self.close_block();
+        block
+    }
+
+    fn statement(&mut self) -> Result<Stmt, String> {
+        let line = self.line();
+        match self.current.token {
+            Token::If => self.if_statement(line),
+            Token::While => {
+                self.advance()?;
+                let condition = self.expr()?;
+                self.expect(Token::Do)?;
+                let body = self.loop_body()?;
+                self.expect_closing(Token::End, Token::While, line)?;
+                Ok(Stmt::While(condition, body))
+            }
+            Token::Do => {
+                self.advance()?;
+                let body = self.scoped_block()?;
+                self.expect_closing(Token::End, Token::Do, line)?;
+                Ok(Stmt::Do(body))
+            }
+            Token::For => self.for_statement(line),
+            Token::Repeat => {
+                self.advance()?;
+                // The condition sees the locals of the body.
+                self.open_block();
+                self.function().loops += 1;
+                let body = self.block();
+                self.function().loops -= 1;
+                let body = body?;
+                self.expect_closing(Token::Until, Token::Repeat, line)?;
+                let condition = self.expr();
+                self.close_block();
+                Ok(Stmt::Repeat(body, condition?))
+            }
+            Token::Function => {
+                self.advance()?;
+                let name_line = self.line();
+                let name = self.name()?;
+                let mut target = self.variable(name, name_line);
+                let mut method = false;
+                while matches!(self.current.token, Token::Dot | Token::Colon) {
+                    method = self.current.token == Token::Colon;
+                    let key_line = self.line();
+                    self.advance()?;
+                    let key = self.name()?;
+                    target = Expr::Index(
+                        Box::new(target),
+                        Box::new(Expr::String(key.into_bytes())),
+                        key_line,
+                    );
+                    if method {
+                        break;
+                    }
+                }
+                let proto = self.body(method, line)?;
+                let target = self.target(target)?;
+                Ok(Stmt::Assign(
+                    vec![target],
+                    vec![Expr::Function(proto)],
+                    line,
+                ))
+            }
+            Token::Local => {
+                self.advance()?;
+                if self.accept(Token::Function)? {
+                    let name = self.name()?;
+                    // Declared first, so the function can call itself.
+                    let slot = self.declare(name);
+                    let proto = self.body(false, line)?;
+                    return Ok(Stmt::LocalFunction(slot, proto));
+                }
+                let mut names = vec![self.name()?];
+                while self.accept(Token::Comma)? {
+                    names.push(self.name()?);
+                }
+                let exprs = if self.accept(Token::Assign)? {
+                    self.expr_list()?
+                } else {
+                    vec![]
+                };
+                let slots = names.into_iter().map(|name| self.declare(name)).collect();
+                Ok(Stmt::Local(slots, exprs))
+            }
+            Token::Return => {
+                self.advance()?;
+                let exprs = if self.block_follows() || self.current.token == Token::Semicolon {
+                    vec![]
+                } else {
+                    self.expr_list()?
+                };
+                Ok(Stmt::Return(exprs))
+            }
+            Token::Break => {
+                if self.function().loops == 0 {
+                    return Err(self.error_near("no loop to break"));
+                }
+                self.advance()?;
+                Ok(Stmt::Break)
+            }
+            _ => self.expr_statement(line),
+        }
+    }
+
+    fn loop_body(&mut self) -> Result<Vec<Stmt>, String> {
+        self.function().loops += 1;
+        let body = self.scoped_block();
+        self.function().loops -= 1;
+        body
+    }
+
+    fn if_statement(&mut self, line: u32) -> Result<Stmt, String> {
+        let mut branches = vec![];
+        let mut otherwise = None;
+        loop {
+            // `if` or `elseif`.
+            self.advance()?;
+            let condition = self.expr()?;
+            self.expect(Token::Then)?;
+            let body = self.scoped_block()?;
+            branches.push((condition, body));
+            match self.current.token {
+                Token::ElseIf => continue,
+                Token::Else => {
+                    self.advance()?;
+                    otherwise = Some(self.scoped_block()?);
+                    self.expect_closing(Token::End, Token::If, line)?;
+                    break;
+                }
+                _ => {
+                    self.expect_closing(Token::End, Token::If, line)?;
+                    break;
+                }
+            }
+        }
+        Ok(Stmt::If(branches, otherwise))
+    }
+
+    fn for_statement(&mut self, line: u32) -> Result<Stmt, String> {
+        self.advance()?;
+        let first = self.name()?;
+        match self.current.token {
+            Token::Assign => {
+                self.advance()?;
+                let start = self.expr()?;
+                self.expect(Token::Comma)?;
+                let limit = self.expr()?;
+                let step = if self.accept(Token::Comma)? {
+                    Some(self.expr()?)
+                } else {
+                    None
+                };
+                self.expect(Token::Do)?;
+                self.open_block();
+                let slot = self.declare(first);
+                let body = self.loop_body();
+                self.close_block();
+                let body = body?;
+                self.expect_closing(Token::End, Token::For, line)?;
+                Ok(Stmt::NumericFor {
+                    slot,
+                    start,
+                    limit,
+                    step,
+                    body,
+                    line,
+                })
+            }
+            Token::Comma | Token::In => {
+                let mut names = vec![first];
+                while self.accept(Token::Comma)? {
+                    names.push(self.name()?);
+                }
+                self.expect(Token::In)?;
+                let exprs = self.expr_list()?;
+                self.expect(Token::Do)?;
+                self.open_block();
+                let slots = names.into_iter().map(|name| self.declare(name)).collect();
+                let body = self.loop_body();
+                self.close_block();
+                let body = body?;
+                self.expect_closing(Token::End, Token::For, line)?;
+                Ok(Stmt::GenericFor {
+                    slots,
+                    exprs,
+                    body,
+                    line,
+                })
+            }
+            _ => Err(self.error_near("'=' or 'in' expected")),
+        }
+    }
+
+    fn expr_statement(&mut self, line: u32) -> Result<Stmt, String> {
+        let expr = self.suffixed_expr()?;
+        if matches!(self.current.token, Token::Assign | Token::Comma) {
+            let mut targets = vec![self.target(expr)?];
+            while self.accept(Token::Comma)? {
+                let expr = self.suffixed_expr()?;
+                targets.push(self.target(expr)?);
+            }
+            self.expect(Token::Assign)?;
+            let exprs = self.expr_list()?;
+            return Ok(Stmt::Assign(targets, exprs, line));
+        }
+        match expr {
+            Expr::Call(call) => Ok(Stmt::Call(*call)),
+            _ => Err(self.error_near("syntax error")),
+        }
+    }
+
+    fn target(&self, expr: Expr) -> Result<Target, String> {
+        match expr {
+            Expr::Local(slot, _) => Ok(Target::Local(slot)),
+            Expr::Upvalue(index, _) => Ok(Target::Upvalue(index)),
+            Expr::Global(name, _) => Ok(Target::Global(name)),
+            Expr::Index(table, key, _) => Ok(Target::Index(*table, *key)),
+            _ => Err(self.error_near("syntax error")),
+        }
+    }
+
+    /// Parses parameters and body of a function, `self` first for methods.
+    fn body(&mut self, method: bool, line: u32) -> Result<Arc<Proto>, String> {
+        self.functions.push(FunctionState::new(false));
+        self.open_block();
+        let result = self.function_rest(method, line);
+        let state = self
+            .functions
+            .pop()
+            .unwrap_or_else(|| FunctionState::new(false));
+        let (params, body) = result?;
+        Ok(Arc::new(Proto {
+            params,
+            vararg: state.vararg,
+            slots: state.slots,
+            upvalues: state
+                .upvalues
+                .into_iter()
+                .map(|(_, upvalue)| upvalue)
+                .collect(),
+            body,
+        }))
+    }
+
+    fn function_rest(&mut self, method: bool, line: u32) -> Result<(usize, Vec<Stmt>), String> {
+        let mut params = 0;
+        if method {
+            self.declare("self".to_owned());
+            params += 1;
+        }
+        self.expect(Token::LeftParen)?;
+        if self.current.token != Token::RightParen {
+            loop {
+                if self.accept(Token::Dots)? {
+                    self.function().vararg = true;
+                    break;
+                }
+                let name = self.name()?;
+                self.declare(name);
+                params += 1;
+                if !self.accept(Token::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.expect(Token::RightParen)?;
+        let body = self.block()?;
+        self.expect_closing(Token::End, Token::Function, line)?;
+        Ok((params, body))
+    }
+
+    fn expr_list(&mut self) -> Result<Vec<Expr>, String> {
+        let mut exprs = vec![self.expr()?];
+        while self.accept(Token::Comma)? {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.subexpr(0)
+    }
+
+    /// Parses operators binding tighter than `limit`, as lparser.c does.
+    fn subexpr(&mut self, limit: u8) -> Result<Expr, String> {
+        self.enter()?;
+        let line = self.line();
+        let unary = match self.current.token {
+            Token::Not => Some(UnaryOp::Not),
+            Token::Minus => Some(UnaryOp::Neg),
+            Token::Hash => Some(UnaryOp::Len),
+            _ => None,
+        };
+        let mut left = match unary {
+            Some(op) => {
+                self.advance()?;
+                let operand = self.subexpr(UNARY_PRIORITY)?;
+                match (op, operand) {
+                    (UnaryOp::Neg, Expr::Number(n)) => Expr::Number(-n),
+                    (op, operand) => Expr::Unary(op, Box::new(operand), line),
+                }
+            }
+            None => self.simple_expr()?,
+        };
+        loop {
+            // `and` and `or` get nodes of their own as they short-circuit.
+            let (op, left_priority, right_priority) = match self.current.token {
+                Token::And => (None, 2, 2),
+                Token::Or => (None, 1, 1),
+                ref token => match binary_op(token) {
+                    Some((op, left, right)) => (Some(op), left, right),
+                    None => break,
+                },
+            };
+            if left_priority <= limit {
+                break;
+            }
+            let line = self.line();
+            let and = self.current.token == Token::And;
+            self.advance()?;
+            let right = Box::new(self.subexpr(right_priority)?);
+            let operand = Box::new(left);
+            left = match op {
+                Some(op) => Expr::Binary(op, operand, right, line),
+                None if and => Expr::And(operand, right),
+                None => Expr::Or(operand, right),
+            };
+        }
+        self.leave();
+        Ok(left)
+    }
+
+    fn simple_expr(&mut self) -> Result<Expr, String> {
+        let line = self.line();
+        let expr = match &self.current.token {
+            Token::Number(n) => Expr::Number(*n),
+            Token::String(s) => Expr::String(s.clone()),
+            Token::Nil => Expr::Nil,
+            Token::True => Expr::True,
+            Token::False => Expr::False,
+            Token::Dots => {
+                if !self.function().vararg {
+                    return Err(self.error_near("cannot use '...' outside a vararg function"));
+                }
+                Expr::VarArgs
+            }
+            Token::LeftBrace => return self.table(),
+            Token::Function => {
+                self.advance()?;
+                return Ok(Expr::Function(self.body(false, line)?));
+            }
+            _ => return self.suffixed_expr(),
+        };
+        self.advance()?;
+        Ok(expr)
+    }
+
+    fn primary_expr(&mut self) -> Result<Expr, String> {
+        let line = self.line();
+        match &self.current.token {
+            Token::Name(_) => {
+                let name = self.name()?;
+                Ok(self.variable(name, line))
+            }
+            Token::LeftParen => {
+                self.advance()?;
+                let expr = self.expr()?;
+                self.expect_closing(Token::RightParen, Token::LeftParen, line)?;
+                Ok(Expr::Paren(Box::new(expr)))
+            }
+            _ => Err(self.error_near("unexpected symbol")),
+        }
+    }
+
+    fn suffixed_expr(&mut self) -> Result<Expr, String> {
+        self.enter()?;
+        let mut expr = self.primary_expr()?;
+        loop {
+            let line = self.line();
+            match self.current.token {
+                Token::Dot => {
+                    self.advance()?;
+                    let key = self.name()?;
+                    expr = Expr::Index(
+                        Box::new(expr),
+                        Box::new(Expr::String(key.into_bytes())),
+                        line,
+                    );
+                }
+                Token::LeftBracket => {
+                    self.advance()?;
+                    let key = self.expr()?;
+                    self.expect(Token::RightBracket)?;
+                    expr = Expr::Index(Box::new(expr), Box::new(key), line);
+                }
+                Token::Colon => {
+                    self.advance()?;
+                    let method = self.name()?;
+                    let args = self.call_args()?;
+                    expr = Expr::Call(Box::new(Call {
+                        function: expr,
+                        method: Some(method),
+                        args,
+                        line,
+                    }));
+                }
+                Token::LeftParen | Token::String(_) | Token::LeftBrace => {
+                    let args = self.call_args()?;
+                    expr = Expr::Call(Box::new(Call {
+                        function: expr,
+                        method: None,
+                        args,
+                        line,
+                    }));
+                }
+                _ => break,
+            }
+        }
+        self.leave();
+        Ok(expr)
+    }
+
+    fn call_args(&mut self) -> Result<Vec<Expr>, String> {
+        let line = self.line();
+        match &self.current.token {
+            Token::String(s) => {
+                let arg = Expr::String(s.clone());
+                self.advance()?;
+                Ok(vec![arg])
+            }
+            Token::LeftBrace => Ok(vec![self.table()?]),
+            Token::LeftParen => {
+                self.advance()?;
+                if self.accept(Token::RightParen)? {
+                    return Ok(vec![]);
+                }
+                let args = self.expr_list()?;
+                self.expect_closing(Token::RightParen, Token::LeftParen, line)?;
+                Ok(args)
+            }
+            _ => Err(self.error_near("function arguments expected")),
+        }
+    }
+
+    fn table(&mut self) -> Result<Expr, String> {
+        let line = self.line();
+        self.expect(Token::LeftBrace)?;
+        let mut fields = vec![];
+        while self.current.token != Token::RightBrace {
+            let named = matches!(self.current.token, Token::Name(_))
+                && self.peek_ahead()? == &Token::Assign;
+            let field = match &self.current.token {
+                Token::LeftBracket => {
+                    self.advance()?;
+                    let key = self.expr()?;
+                    self.expect(Token::RightBracket)?;
+                    self.expect(Token::Assign)?;
+                    Field::Named(key, self.expr()?)
+                }
+                Token::Name(_) if named => {
+                    let key = self.name()?;
+                    self.advance()?;
+                    Field::Named(Expr::String(key.into_bytes()), self.expr()?)
+                }
+                _ => Field::Positional(self.expr()?),
+            };
+            fields.push(field);
+            if !self.accept(Token::Comma)? && !self.accept(Token::Semicolon)? {
+                break;
+            }
+        }
+        self.expect_closing(Token::RightBrace, Token::LeftBrace, line)?;
+        Ok(Expr::Table(fields, line))
+    }
+}
@@ -0,0 +1,353 @@
+//! Lua patterns, as `string.find`, `match`, `gmatch` and `gsub` use them,
+//! after the backtracking matcher of Lua 5.1.
+
+const MAX_CAPTURES: usize = 32;
+/// How deeply matching may recurse, which bounds the native stack it takes.
+const MAX_DEPTH: usize = 200;
+const UNFINISHED: isize = -1;
+const POSITION: isize = -2;
+
+/// What `find` and friends look for patterns in: the characters after which
+/// a pattern is more than a plain string.
+pub const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+pub enum Capture {
+    String(usize, usize),
+    /// A `()` capture, the position it matched at counting from 1.
+    Position(usize),
+}
+
+pub struct Matcher<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    level: usize,
+    captures: [(usize, isize); MAX_CAPTURES],
+    depth: usize,
+}
+
+fn match_class(c: u8, class: u8) -> bool {
+    let matched = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => c.is_ascii_whitespace() || c == 0x0b,
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        b'z' => c == 0,
+        _ => return class == c,
+    };
+    if class.is_ascii_uppercase() {
+        !matched
+    } else {
+        matched
+    }
+}
+
+impl<'a> Matcher<'a> {
+    pub fn new(src: &'a [u8], pat: &'a [u8]) -> Matcher<'a> {
+        Matcher {
+            src,
+            pat,
+            level: 0,
+            captures: [(0, 0); MAX_CAPTURES],
+            depth: 0,
+        }
+    }
+
+    /// Matches the pattern from `p` against the subject from `s`, returning
+    /// where the match ends.
+    pub fn find(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        self.level = 0;
+        self.depth = 0;
+        self.matches(s, p)
+    }
+
+    /// The captures of the last match from `s` to `e`, or the whole match if
+    /// the pattern has none.
+    pub fn captures(&self, s: usize, e: usize, whole: bool) -> Result<Vec<Capture>, String> {
+        let count = if self.level == 0 && whole {
+            1
+        } else {
+            self.level
+        };
+        (0..count).map(|i| self.capture(i, s, e)).collect()
+    }
+
+    pub fn capture(&self, i: usize, s: usize, e: usize) -> Result<Capture, String> {
+        if i >= self.level {
+            return if i == 0 {
+                Ok(Capture::String(s, e))
+            } else {
+                Err("invalid capture index".to_owned())
+            };
+        }
+        let (start, len) = self.captures[i];
+        match len {
+            UNFINISHED => Err("unfinished capture".to_owned()),
+            POSITION => Ok(Capture::Position(start + 1)),
+            len => Ok(Capture::String(start, start + len as usize)),
+        }
+    }
+
+    fn pat_at(&self, p: usize) -> u8 {
+        self.pat.get(p).copied().unwrap_or(0)
+    }
+
+    fn class_end(&self, mut p: usize) -> Result<usize, String> {
+        let c = self.pat_at(p);
+        p += 1;
+        match c {
+            b'%' => {
+                if p >= self.pat.len() {
+                    return Err("malformed pattern (ends with '%')".to_owned());
+                }
+                Ok(p + 1)
+            }
+            b'[' => {
+                if self.pat_at(p) == b'^' {
+                    p += 1;
+                }
+                loop {
+                    if p >= self.pat.len() {
+                        return Err("malformed pattern (missing ']')".to_owned());
+                    }
+                    let c = self.pat[p];
+                    p += 1;
+                    if c == b'%' && p < self.pat.len() {
+                        p += 1;
+                    }
+                    if self.pat_at(p) == b']' {
+                        return Ok(p + 1);
+                    }
+                }
+            }
+            _ => Ok(p),
+        }
+    }
+
+    /// Whether `c` is in the set from `p`, at its `[`, to `end`, at its `]`.
+    fn match_bracket(&self, c: u8, mut p: usize, end: usize) -> bool {
+        let mut found = true;
+        if self.pat_at(p + 1) == b'^' {
+            found = false;
+            p += 1;
+        }
+        p += 1;
+        while p < end {
+            if self.pat[p] == b'%' {
+                p += 1;
+                if match_class(c, self.pat_at(p)) {
+                    return found;
+                }
+            } else if self.pat_at(p + 1) == b'-' && p + 2 < end {
+                if self.pat[p] <= c && c <= self.pat[p + 2] {
+                    return found;
+                }
+                p += 2;
+            } else if self.pat[p] == c {
+                return found;
+            }
+            p += 1;
+        }
+        !found
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let c = match self.src.get(s) {
+            Some(&c) => c,
+            None => return false,
+        };
+        match self.pat[p] {
+            b'.' => true,
+            b'%' => match_class(c, self.pat_at(p + 1)),
+            b'[' => self.match_bracket(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    fn matches(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err("pattern too complex".to_owned());
+        }
+        self.depth += 1;
+        let result = self.match_item(s, p);
+        self.depth -= 1;
+        result
+    }
+
+    fn match_item(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+        loop {
+            if p >= self.pat.len() {
+                return Ok(Some(s));
+            }
+            match self.pat[p] {
+                b'(' => {
+                    return if self.pat_at(p + 1) == b')' {
+                        self.start_capture(s, p + 2, POSITION)
+                    } else {
+                        self.start_capture(s, p + 1, UNFINISHED)
+                    };
+                }
+                b')' => return self.end_capture(s, p + 1),
+                b'%' if self.pat_at(p + 1) == b'b' => {
+                    s = match self.match_balance(s, p + 2)? {
+                        Some(s) => s,
+                        None => return Ok(None),
+                    };
+                    p += 4;
+                    continue;
+                }
+                b'%' if self.pat_at(p + 1) == b'f' => {
+                    p += 2;
+                    if self.pat_at(p) != b'[' {
+                        return Err("missing '[' after '%f' in pattern".to_owned());
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).copied().unwrap_or(0);
+                    if self.match_bracket(previous, p, ep - 1)
+                        || !self.match_bracket(current, p, ep - 1)
+                    {
+                        return Ok(None);
+                    }
+                    p = ep;
+                    continue;
+                }
+                b'%' if self.pat_at(p + 1).is_ascii_digit() => {
+                    s = match self.match_capture(s, self.pat[p + 1])? {
+                        Some(s) => s,
+                        None => return Ok(None),
+                    };
+                    p += 2;
+                    continue;
+                }
+                b'$' if p + 1 == self.pat.len() => {
+                    return Ok(if s == self.src.len() { Some(s) } else { None });
+                }
+                _ => {}
+            }
+            let ep = self.class_end(p)?;
+            let matched = self.single_match(s, p, ep);
+            match self.pat_at(ep) {
+                b'?' => {
+                    if matched {
+                        if let Some(end) = self.matches(s + 1, ep + 1)? {
+                            return Ok(Some(end));
+                        }
+                    }
+                    p = ep + 1;
+                }
+                b'*' => return self.max_expand(s, p, ep),
+                b'+' => {
+                    return if matched {
+                        self.max_expand(s + 1, p, ep)
+                    } else {
+                        Ok(None)
+                    };
+                }
+                b'-' => return self.min_expand(s, p, ep),
+                _ => {
+                    if !matched {
+                        return Ok(None);
+                    }
+                    s += 1;
+                    p = ep;
+                }
+            }
+        }
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        let mut count = 0;
+        while self.single_match(s + count, p, ep) {
+            count += 1;
+        }
+        loop {
+            if let Some(end) = self.matches(s + count, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(end) = self.matches(s, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if !self.single_match(s, p, ep) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: isize) -> Result<Option<usize>, String> {
+        if self.level >= MAX_CAPTURES {
+            return Err("too many captures".to_owned());
+        }
+        self.captures[self.level] = (s, what);
+        self.level += 1;
+        let result = self.matches(s, p)?;
+        if result.is_none() {
+            self.level -= 1;
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let open = (0..self.level)
+            .rev()
+            .find(|&i| self.captures[i].1 == UNFINISHED)
+            .ok_or_else(|| "invalid pattern capture".to_owned())?;
+        self.captures[open].1 = (s - self.captures[open].0) as isize;
+        let result = self.matches(s, p)?;
+        if result.is_none() {
+            self.captures[open].1 = UNFINISHED;
+        }
+        Ok(result)
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if p + 1 >= self.pat.len() {
+            return Err("unbalanced pattern".to_owned());
+        }
+        let (open, close) = (self.pat[p], self.pat[p + 1]);
+        if self.src.get(s) != Some(&open) {
+            return Ok(None);
+        }
+        let mut depth = 1;
+        for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == open {
+                depth += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    fn match_capture(&self, s: usize, digit: u8) -> Result<Option<usize>, String> {
+        let i = (digit as usize).wrapping_sub(b'1' as usize);
+        if i >= self.level || self.captures[i].1 == UNFINISHED {
+            return Err("invalid capture index".to_owned());
+        }
+        let (start, len) = self.captures[i];
+        let len = len.max(0) as usize;
+        let captured = &self.src[start..start + len];
+        if self.src[s..].starts_with(captured) {
+            Ok(Some(s + len))
+        } else {
+            Ok(None)
+        }
+    }
+}
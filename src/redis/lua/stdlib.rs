@@ -0,0 +1,1076 @@
+//! The parts of the Lua 5.1 standard library scripts get: the base functions
+//! and the string, table and math libraries.
+
+use super::interpreter::Interpreter;
+use super::number;
+use super::pattern::{Capture, Matcher, SPECIALS};
+use super::value::{Error, Table, TableRef, Value};
+use std::cell::Cell;
+use std::rc::Rc;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Strings longer than this are refused rather than allocated.
+const MAX_STRING: usize = 512 * 1024 * 1024;
+
+fn arg(args: &[Value], n: usize) -> Value {
+    args.get(n).cloned().unwrap_or(Value::Nil)
+}
+
+fn bad_argument(interpreter: &Interpreter, n: usize, name: &str, message: &str) -> Error {
+    interpreter.error(format!(
+        "bad argument #{} to '{}' ({})",
+        n + 1,
+        name,
+        message
+    ))
+}
+
+fn type_error(
+    interpreter: &Interpreter,
+    args: &[Value],
+    n: usize,
+    name: &str,
+    expected: &str,
+) -> Error {
+    let got = args.get(n).map_or("no value", Value::type_name);
+    let message = format!("{} expected, got {}", expected, got);
+    bad_argument(interpreter, n, name, &message)
+}
+
+fn check_any(interpreter: &Interpreter, args: &[Value], n: usize, name: &str) -> Result<Value> {
+    match args.get(n) {
+        Some(value) => Ok(value.clone()),
+        None => Err(bad_argument(interpreter, n, name, "value expected")),
+    }
+}
+
+fn check_table(
+    interpreter: &Interpreter,
+    args: &[Value],
+    n: usize,
+    name: &str,
+) -> Result<TableRef> {
+    match args.get(n) {
+        Some(Value::Table(table)) => Ok(table.clone()),
+        _ => Err(type_error(interpreter, args, n, name, "table")),
+    }
+}
+
+fn check_number(interpreter: &Interpreter, args: &[Value], n: usize, name: &str) -> Result<f64> {
+    args.get(n)
+        .and_then(Value::to_number)
+        .ok_or_else(|| type_error(interpreter, args, n, name, "number"))
+}
+
+fn check_integer(interpreter: &Interpreter, args: &[Value], n: usize, name: &str) -> Result<i64> {
+    check_number(interpreter, args, n, name).map(|n| n as i64)
+}
+
+fn opt_integer(
+    interpreter: &Interpreter,
+    args: &[Value],
+    n: usize,
+    name: &str,
+    default: i64,
+) -> Result<i64> {
+    match args.get(n) {
+        None | Some(Value::Nil) => Ok(default),
+        _ => check_integer(interpreter, args, n, name),
+    }
+}
+
+fn check_string(
+    interpreter: &Interpreter,
+    args: &[Value],
+    n: usize,
+    name: &str,
+) -> Result<Rc<[u8]>> {
+    args.get(n)
+        .and_then(Value::to_bytes)
+        .ok_or_else(|| type_error(interpreter, args, n, name, "string"))
+}
+
+/// A position counting from the end of a string of `len` if negative.
+fn relative(position: i64, len: usize) -> i64 {
+    if position < 0 {
+        len as i64 + position + 1
+    } else {
+        position
+    }
+}
+
+fn register<F>(table: &mut Table, name: &str, f: F)
+where
+    F: Fn(&mut Interpreter, Vec<Value>) -> Result<Vec<Value>> + 'static,
+{
+    table.set_str(name, Value::builtin(f));
+}
+
+pub fn open(interpreter: &mut Interpreter) {
+    let mut globals = Table::new();
+    base(&mut globals);
+    let mut string = Table::new();
+    strings(&mut string);
+    let mut table = Table::new();
+    tables(&mut table);
+    let mut math = Table::new();
+    maths(&mut math);
+
+    *interpreter.strings.borrow_mut() = string;
+    globals.set_str("string", Value::Table(interpreter.strings.clone()));
+    globals.set_str("table", interpreter.new_table(table));
+    globals.set_str("math", interpreter.new_table(math));
+    globals.set_str("_G", Value::Table(interpreter.globals.clone()));
+    globals.set_str("_VERSION", Value::string(b"Lua 5.1"));
+    *interpreter.globals.borrow_mut() = globals;
+}
+
+fn next(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Vec<Value>> {
+    let table = check_table(interpreter, &args, 0, "next")?;
+    let result = table.borrow().next(&arg(&args, 1));
+    match result {
+        Ok(Some((key, value))) => Ok(vec![key, value]),
+        Ok(None) => Ok(vec![Value::Nil]),
+        Err(()) => Err(interpreter.error("invalid key to 'next'")),
+    }
+}
+
+fn base(globals: &mut Table) {
+    register(globals, "assert", |interpreter, args| {
+        if arg(&args, 0).truthy() {
+            return Ok(args);
+        }
+        let message = match args.get(1) {
+            Some(message) if !message.is_nil() => {
+                check_string(interpreter, &args, 1, "assert")?.to_vec()
+            }
+            _ => b"assertion failed!".to_vec(),
+        };
+        Err(interpreter.error(String::from_utf8_lossy(&message)))
+    });
+    register(globals, "error", |interpreter, args| {
+        let value = arg(&args, 0);
+        let level = opt_integer(interpreter, &args, 1, "error", 1)?;
+        match &value {
+            Value::String(message) if level > 0 => {
+                Err(interpreter.error(String::from_utf8_lossy(message)))
+            }
+            _ => Err(interpreter.raise(value)),
+        }
+    });
+    register(globals, "getmetatable", |interpreter, args| {
+        let value = check_any(interpreter, &args, 0, "getmetatable")?;
+        let metatable = match &value {
+            Value::Table(table) => table.borrow().metatable.clone(),
+            _ => None,
+        };
+        Ok(vec![match metatable {
+            Some(metatable) => {
+                let protected = metatable.borrow().get_str("__metatable");
+                if protected.is_nil() {
+                    Value::Table(metatable)
+                } else {
+                    protected
+                }
+            }
+            None => Value::Nil,
+        }])
+    });
+    register(globals, "setmetatable", |interpreter, args| {
+        let table = check_table(interpreter, &args, 0, "setmetatable")?;
+        let metatable = match arg(&args, 1) {
+            Value::Nil => None,
+            Value::Table(metatable) => Some(metatable),
+            _ => {
+                return Err(type_error(
+                    interpreter,
+                    &args,
+                    1,
+                    "setmetatable",
+                    "nil or table",
+                ))
+            }
+        };
+        if table.borrow().readonly {
+            return Err(interpreter.error("Attempt to modify a readonly table"));
+        }
+        let protected = match &table.borrow().metatable {
+            Some(current) => !current.borrow().get_str("__metatable").is_nil(),
+            None => false,
+        };
+        if protected {
+            return Err(interpreter.error("cannot change a protected metatable"));
+        }
+        table.borrow_mut().metatable = metatable;
+        Ok(vec![Value::Table(table)])
+    });
+    register(globals, "ipairs", |interpreter, args| {
+        let table = check_table(interpreter, &args, 0, "ipairs")?;
+        let iterator = Value::builtin(|interpreter, args| {
+            let table = check_table(interpreter, &args, 0, "ipairs")?;
+            let i = check_number(interpreter, &args, 1, "ipairs")? + 1.0;
+            let value = table.borrow().get(&Value::Number(i));
+            if value.is_nil() {
+                Ok(vec![Value::Nil])
+            } else {
+                Ok(vec![Value::Number(i), value])
+            }
+        });
+        Ok(vec![iterator, Value::Table(table), Value::Number(0.0)])
+    });
+    register(globals, "pairs", |interpreter, args| {
+        let table = check_table(interpreter, &args, 0, "pairs")?;
+        Ok(vec![Value::builtin(next), Value::Table(table), Value::Nil])
+    });
+    register(globals, "next", next);
+    register(globals, "pcall", |interpreter, mut args| {
+        let function = check_any(interpreter, &args, 0, "pcall")?;
+        args.remove(0);
+        match interpreter.call(&function, args) {
+            Ok(mut values) => {
+                values.insert(0, Value::Boolean(true));
+                Ok(values)
+            }
+            Err(error) => Ok(vec![Value::Boolean(false), error.value]),
+        }
+    });
+    register(globals, "xpcall", |interpreter, args| {
+        let function = arg(&args, 0);
+        let handler = arg(&args, 1);
+        match interpreter.call(&function, vec![]) {
+            Ok(mut values) => {
+                values.insert(0, Value::Boolean(true));
+                Ok(values)
+            }
+            Err(error) => {
+                let mut values = interpreter.call(&handler, vec![error.value])?;
+                values.truncate(1);
+                values.insert(0, Value::Boolean(false));
+                Ok(values)
+            }
+        }
+    });
+    register(globals, "print", |interpreter, args| {
+        let mut line = vec![];
+        for (i, value) in args.iter().enumerate() {
+            if i > 0 {
+                line.push(b'\t');
+            }
+            if let Value::String(s) = interpreter.tostring(value)? {
+                line.extend_from_slice(&s);
+            }
+        }
+        println!("{}", String::from_utf8_lossy(&line));
+        Ok(vec![])
+    });
+    register(globals, "rawequal", |interpreter, args| {
+        let a = check_any(interpreter, &args, 0, "rawequal")?;
+        let b = check_any(interpreter, &args, 1, "rawequal")?;
+        Ok(vec![Value::Boolean(a.raw_equals(&b))])
+    });
+    register(globals, "rawget", |interpreter, args| {
+        let table = check_table(interpreter, &args, 0, "rawget")?;
+        let value = table.borrow().get(&arg(&args, 1));
+        Ok(vec![value])
+    });
+    register(globals, "rawset", |interpreter, args| {
+        let table = check_table(interpreter, &args, 0, "rawset")?;
+        if table.borrow().readonly {
+            return Err(interpreter.error("Attempt to modify a readonly table"));
+        }
+        let result = table.borrow_mut().set(arg(&args, 1), arg(&args, 2));
+        result.map_err(|message| interpreter.error(message))?;
+        Ok(vec![Value::Table(table)])
+    });
+    register(globals, "select", |interpreter, mut args| {
+        if let Some(Value::String(s)) = args.first() {
+            if &**s == b"#" {
+                return Ok(vec![Value::Number((args.len() - 1) as f64)]);
+            }
+        }
+        let n = check_integer(interpreter, &args, 0, "select")?;
+        let count = args.len() as i64 - 1;
+        let start = if n < 0 { count + n } else { n - 1 };
+        if start < 0 {
+            return Err(bad_argument(interpreter, 0, "select", "index out of range"));
+        }
+        Ok(args.split_off((start + 1).min(args.len() as i64) as usize))
+    });
+    register(globals, "tonumber", |interpreter, args| {
+        let value = check_any(interpreter, &args, 0, "tonumber")?;
+        let base = opt_integer(interpreter, &args, 1, "tonumber", 10)?;
+        if base == 10 {
+            return Ok(vec![value.to_number().map_or(Value::Nil, Value::Number)]);
+        }
+        if !(2..=36).contains(&base) {
+            return Err(bad_argument(
+                interpreter,
+                1,
+                "tonumber",
+                "base out of range",
+            ));
+        }
+        let digits = check_string(interpreter, &args, 0, "tonumber")?;
+        let text = String::from_utf8_lossy(&digits);
+        let text = text.trim();
+        let parsed = match text.strip_prefix('-') {
+            Some(digits) => u64::from_str_radix(digits, base as u32).map(|n| -(n as f64)),
+            None => u64::from_str_radix(text, base as u32).map(|n| n as f64),
+        };
+        Ok(vec![parsed.map_or(Value::Nil, Value::Number)])
+    });
+    register(globals, "tostring", |interpreter, args| {
+        let value = check_any(interpreter, &args, 0, "tostring")?;
+        Ok(vec![interpreter.tostring(&value)?])
+    });
+    register(globals, "type", |interpreter, args| {
+        let value = check_any(interpreter, &args, 0, "type")?;
+        Ok(vec![Value::string(value.type_name().as_bytes())])
+    });
+    register(globals, "unpack", unpack);
+    register(globals, "collectgarbage", |_, _| {
+        Ok(vec![Value::Number(0.0)])
+    });
+}
+
+fn unpack(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Vec<Value>> {
+    let table = check_table(interpreter, &args, 0, "unpack")?;
+    let len = table.borrow().len() as i64;
+    let start = opt_integer(interpreter, &args, 1, "unpack", 1)?;
+    let end = opt_integer(interpreter, &args, 2, "unpack", len)?;
+    if start > end {
+        return Ok(vec![]);
+    }
+    if end - start >= 8000 {
+        return Err(interpreter.error("too many results to unpack"));
+    }
+    let table = table.borrow();
+    Ok((start..=end)
+        .map(|i| table.get(&Value::Number(i as f64)))
+        .collect())
+}
+
+fn capture_value(src: &Rc<[u8]>, capture: Capture) -> Value {
+    match capture {
+        Capture::String(start, end) => Value::string(&src[start..end]),
+        Capture::Position(position) => Value::Number(position as f64),
+    }
+}
+
+/// `string.find` and `string.match`.
+fn find(interpreter: &mut Interpreter, args: Vec<Value>, find: bool) -> Result<Vec<Value>> {
+    let name = if find { "find" } else { "match" };
+    let src = check_string(interpreter, &args, 0, name)?;
+    let pat = check_string(interpreter, &args, 1, name)?;
+    let init = relative(opt_integer(interpreter, &args, 2, name, 1)?, src.len()) - 1;
+    let init = init.max(0).min(src.len() as i64) as usize;
+    let plain = arg(&args, 3).truthy() || !pat.iter().any(|c| SPECIALS.contains(c));
+    if find && plain {
+        let found = if pat.is_empty() {
+            Some(init)
+        } else {
+            src[init..]
+                .windows(pat.len())
+                .position(|window| window == &*pat)
+                .map(|position| init + position)
+        };
+        return Ok(match found {
+            Some(start) => vec![
+                Value::Number((start + 1) as f64),
+                Value::Number((start + pat.len()) as f64),
+            ],
+            None => vec![Value::Nil],
+        });
+    }
+    let anchor = pat.first() == Some(&b'^');
+    let p = if anchor { 1 } else { 0 };
+    let mut matcher = Matcher::new(&src, &pat);
+    let mut s = init;
+    loop {
+        let found = matcher
+            .find(s, p)
+            .map_err(|message| interpreter.error(message))?;
+        if let Some(end) = found {
+            let captures = matcher
+                .captures(s, end, !find)
+                .map_err(|message| interpreter.error(message))?;
+            let mut values = vec![];
+            if find {
+                values.push(Value::Number((s + 1) as f64));
+                values.push(Value::Number(end as f64));
+            }
+            values.extend(captures.into_iter().map(|c| capture_value(&src, c)));
+            return Ok(values);
+        }
+        s += 1;
+        if anchor || s > src.len() {
+            return Ok(vec![Value::Nil]);
+        }
+    }
+}
+
+fn gmatch(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Vec<Value>> {
+    let src = check_string(interpreter, &args, 0, "gmatch")?;
+    let pat = check_string(interpreter, &args, 1, "gmatch")?;
+    let position = Cell::new(0);
+    let iterator = Value::builtin(move |interpreter, _| {
+        let mut matcher = Matcher::new(&src, &pat);
+        let mut s = position.get();
+        while s <= src.len() {
+            let found = matcher
+                .find(s, 0)
+                .map_err(|message| interpreter.error(message))?;
+            if let Some(end) = found {
+                position.set(if end == s { end + 1 } else { end });
+                let captures = matcher
+                    .captures(s, end, true)
+                    .map_err(|message| interpreter.error(message))?;
+                return Ok(captures
+                    .into_iter()
+                    .map(|c| capture_value(&src, c))
+                    .collect());
+            }
+            s += 1;
+        }
+        position.set(s);
+        Ok(vec![Value::Nil])
+    });
+    Ok(vec![iterator])
+}
+
+fn gsub(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Vec<Value>> {
+    let src = check_string(interpreter, &args, 0, "gsub")?;
+    let pat = check_string(interpreter, &args, 1, "gsub")?;
+    let replacement = arg(&args, 2);
+    if !matches!(
+        replacement,
+        Value::Number(_) | Value::String(_) | Value::Table(_) | Value::Function(_)
+    ) {
+        return Err(type_error(
+            interpreter,
+            &args,
+            2,
+            "gsub",
+            "string/function/table",
+        ));
+    }
+    let max = opt_integer(interpreter, &args, 3, "gsub", src.len() as i64 + 1)?;
+    let anchor = pat.first() == Some(&b'^');
+    let p = if anchor { 1 } else { 0 };
+    let mut matcher = Matcher::new(&src, &pat);
+    let mut out = vec![];
+    let mut s = 0;
+    let mut count = 0;
+    while count < max {
+        let found = matcher
+            .find(s, p)
+            .map_err(|message| interpreter.error(message))?;
+        if let Some(end) = found {
+            count += 1;
+            let whole = &src[s..end];
+            let value = match &replacement {
+                Value::Function(_) => {
+                    let captures = matcher
+                        .captures(s, end, true)
+                        .map_err(|message| interpreter.error(message))?;
+                    let captures = captures.into_iter().map(|c| capture_value(&src, c));
+                    let values = interpreter.call(&replacement, captures.collect())?;
+                    values.into_iter().next().unwrap_or(Value::Nil)
+                }
+                Value::Table(_) => {
+                    let capture = matcher
+                        .capture(0, s, end)
+                        .map_err(|message| interpreter.error(message))?;
+                    interpreter.index(&replacement, &capture_value(&src, capture))?
+                }
+                _ => {
+                    let template = replacement.to_bytes().unwrap_or_else(|| Rc::from(&b""[..]));
+                    let mut i = 0;
+                    while i < template.len() {
+                        let c = template[i];
+                        i += 1;
+                        if c != b'%' || i == template.len() {
+                            out.push(c);
+                            continue;
+                        }
+                        let d = template[i];
+                        i += 1;
+                        if !d.is_ascii_digit() {
+                            out.push(d);
+                        } else if d == b'0' {
+                            out.extend_from_slice(whole);
+                        } else {
+                            let capture = matcher
+                                .capture((d - b'1') as usize, s, end)
+                                .map_err(|message| interpreter.error(message))?;
+                            out.extend_from_slice(
+                                &capture_value(&src, capture).to_bytes().unwrap_or_default(),
+                            );
+                        }
+                    }
+                    Value::Boolean(false)
+                }
+            };
+            match value {
+                Value::Nil | Value::Boolean(false) => {
+                    if !matches!(replacement, Value::String(_) | Value::Number(_)) {
+                        out.extend_from_slice(whole);
+                    }
+                }
+                Value::String(_) | Value::Number(_) => {
+                    out.extend_from_slice(&value.to_bytes().unwrap_or_default())
+                }
+                value => {
+                    return Err(interpreter.error(format!(
+                        "invalid replacement value (a {})",
+                        value.type_name()
+                    )))
+                }
+            }
+        }
+        match found {
+            Some(end) if end > s => s = end,
+            _ if s < src.len() => {
+                out.push(src[s]);
+                s += 1;
+            }
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    out.extend_from_slice(&src[s.min(src.len())..]);
+    Ok(vec![
+        Value::String(Rc::from(out)),
+        Value::Number(count as f64),
+    ])
+}
+
+/// The flags, width and precision of a `string.format` conversion.
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    fn pad(&self, prefix: &str, body: &[u8], zeros: bool, out: &mut Vec<u8>) {
+        let fill = self.width.saturating_sub(prefix.len() + body.len());
+        if self.left {
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend_from_slice(body);
+            out.extend(std::iter::repeat(b' ').take(fill));
+        } else if self.zero && zeros {
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend(std::iter::repeat(b'0').take(fill));
+            out.extend_from_slice(body);
+        } else {
+            out.extend(std::iter::repeat(b' ').take(fill));
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend_from_slice(body);
+        }
+    }
+}
+
+fn format(interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Vec<Value>> {
+    let template = check_string(interpreter, &args, 0, "format")?;
+    let mut out = vec![];
+    let mut n = 0;
+    let mut i = 0;
+    while i < template.len() {
+        let c = template[i];
+        i += 1;
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        if template.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+        let mut spec = Spec::default();
+        let start = i;
+        while let Some(&flag) = template.get(i) {
+            match flag {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                b'0' => spec.zero = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        if i - start > 5 {
+            return Err(interpreter.error("invalid format (repeated flags)"));
+        }
+        let digits = |i: &mut usize| {
+            let mut value = 0;
+            let mut count = 0;
+            while count < 2 && template.get(*i).map_or(false, u8::is_ascii_digit) {
+                value = value * 10 + (template[*i] - b'0') as usize;
+                *i += 1;
+                count += 1;
+            }
+            value
+        };
+        spec.width = digits(&mut i);
+        if template.get(i) == Some(&b'.') {
+            i += 1;
+            spec.precision = Some(digits(&mut i));
+        }
+        if template.get(i).map_or(false, u8::is_ascii_digit) {
+            return Err(interpreter.error("invalid format (width or precision too long)"));
+        }
+        let conversion = template.get(i).copied().unwrap_or(0);
+        i += 1;
+        n += 1;
+        match conversion {
+            b'c' => {
+                let code = check_number(interpreter, &args, n, "format")? as i64;
+                spec.pad("", &[code as u8], false, &mut out);
+            }
+            b'd' | b'i' => {
+                let value = check_number(interpreter, &args, n, "format")? as i64;
+                let mut digits = value.unsigned_abs().to_string();
+                if let Some(precision) = spec.precision {
+                    if precision == 0 && value == 0 {
+                        digits.clear();
+                    }
+                    while digits.len() < precision {
+                        digits.insert(0, '0');
+                    }
+                }
+                let sign = spec.sign(value < 0);
+                spec.pad(sign, digits.as_bytes(), spec.precision.is_none(), &mut out);
+            }
+            b'o' | b'u' | b'x' | b'X' => {
+                let value = check_number(interpreter, &args, n, "format")? as i64 as u64;
+                let mut digits = match conversion {
+                    b'o' => format!("{:o}", value),
+                    b'u' => value.to_string(),
+                    b'x' => format!("{:x}", value),
+                    _ => format!("{:X}", value),
+                };
+                if let Some(precision) = spec.precision {
+                    if precision == 0 && value == 0 {
+                        digits.clear();
+                    }
+                    while digits.len() < precision {
+                        digits.insert(0, '0');
+                    }
+                }
+                let prefix = match conversion {
+                    b'o' if spec.alternate && !digits.starts_with('0') => "0",
+                    b'x' if spec.alternate && value != 0 => "0x",
+                    b'X' if spec.alternate && value != 0 => "0X",
+                    _ => "",
+                };
+                spec.pad(
+                    prefix,
+                    digits.as_bytes(),
+                    spec.precision.is_none(),
+                    &mut out,
+                );
+            }
+            b'e' | b'E' | b'f' | b'g' | b'G' => {
+                let value = check_number(interpreter, &args, n, "format")?;
+                let precision = spec.precision.unwrap_or(6);
+                let magnitude = value.abs();
+                let mut body = match conversion {
+                    b'e' | b'E' => number::format_e(magnitude, precision, conversion == b'E'),
+                    b'f' => number::format_f(magnitude, precision),
+                    _ => number::format_g(magnitude, precision, conversion == b'G', spec.alternate),
+                };
+                if spec.alternate && precision == 0 && !body.contains('.') && value.is_finite() {
+                    if let Some(e) = body.find(|c| c == 'e' || c == 'E') {
+                        body.insert(e, '.');
+                    } else {
+                        body.push('.');
+                    }
+                }
+                let sign = spec.sign(value.is_sign_negative() && !value.is_nan());
+                spec.pad(sign, body.as_bytes(), value.is_finite(), &mut out);
+            }
+            b'q' => {
+                let s = check_string(interpreter, &args, n, "format")?;
+                out.push(b'"');
+                for &c in s.iter() {
+                    match c {
+                        b'"' | b'\\' | b'\n' => {
+                            out.push(b'\\');
+                            out.push(c);
+                        }
+                        b'\r' => out.extend_from_slice(b"\\r"),
+                        0 => out.extend_from_slice(b"\\000"),
+                        c => out.push(c),
+                    }
+                }
+                out.push(b'"');
+            }
+            b's' => {
+                let s = check_string(interpreter, &args, n, "format")?;
+                let len = spec
+                    .precision
+                    .map_or(s.len(), |precision| precision.min(s.len()));
+                spec.pad("", &s[..len], false, &mut out);
+            }
+            c => {
+                return Err(
+                    interpreter.error(format!("invalid option '%{}' to 'format'", c as char))
+                )
+            }
+        }
+    }
+    Ok(vec![Value::String(Rc::from(out))])
+}
+
+fn strings(string: &mut Table) {
+    register(string, "len", |interpreter, args| {
+        let s = check_string(interpreter, &args, 0, "len")?;
+        Ok(vec![Value::Number(s.len() as f64)])
+    });
+    register(string, "sub", |interpreter, args| {
+        let s = check_string(interpreter, &args, 0, "sub")?;
+        let start = relative(opt_integer(interpreter, &args, 1, "sub", 1)?, s.len()).max(1);
+        let end = relative(opt_integer(interpreter, &args, 2, "sub", -1)?, s.len());
+        let end = end.min(s.len() as i64);
+        if start > end {
+            return Ok(vec![Value::string(b"")]);
+        }
+        Ok(vec![Value::string(&s[start as usize - 1..end as usize])])
+    });
+    register(string, "upper", |interpreter, args| {
+        let s = check_string(interpreter, &args, 0, "upper")?;
+        Ok(vec![Value::string(&s.to_ascii_uppercase())])
+    });
+    register(string, "lower", |interpreter, args| {
+        let s = check_string(interpreter, &args, 0, "lower")?;
+        Ok(vec![Value::string(&s.to_ascii_lowercase())])
+    });
+    register(string, "rep", |interpreter, args| {
+        let s = check_string(interpreter, &args, 0, "rep")?;
+        let n = check_integer(interpreter, &args, 1, "rep")?.max(0) as usize;
+        if s.len().saturating_mul(n) > MAX_STRING {
+            return Err(interpreter.error("resulting string too large"));
+        }
+        Ok(vec![Value::String(Rc::from(s.repeat(n)))])
+    });
+    register(string, "reverse", |interpreter, args| {
+        let s = check_string(interpreter, &args, 0, "reverse")?;
+        let mut reversed = s.to_vec();
+        reversed.reverse();
+        Ok(vec![Value::String(Rc::from(reversed))])
+    });
+    register(string, "byte", |interpreter, args| {
+        let s = check_string(interpreter, &args, 0, "byte")?;
+        let start = relative(opt_integer(interpreter, &args, 1, "byte", 1)?, s.len());
+        let end = relative(opt_integer(interpreter, &args, 2, "byte", start)?, s.len());
+        let start = start.max(1);
+        let end = end.min(s.len() as i64);
+        if start > end {
+            return Ok(vec![]);
+        }
+        Ok(s[start as usize - 1..end as usize]
+            .iter()
+            .map(|&c| Value::Number(f64::from(c)))
+            .collect())
+    });
+    register(string, "char", |interpreter, args| {
+        let mut s = Vec::with_capacity(args.len());
+        for n in 0..args.len() {
+            let code = check_integer(interpreter, &args, n, "char")?;
+            if !(0..=255).contains(&code) {
+                return Err(bad_argument(interpreter, n, "char", "invalid value"));
+            }
+            s.push(code as u8);
+        }
+        Ok(vec![Value::String(Rc::from(s))])
+    });
+    register(string, "format", format);
+    register(string, "find", |interpreter, args| {
+        find(interpreter, args, true)
+    });
+    register(string, "match", |interpreter, args| {
+        find(interpreter, args, false)
+    });
+    register(string, "gmatch", gmatch);
+    register(string, "gsub", gsub);
+}
+
+/// Sorts with a comparison that can fail, merging runs so that a comparator
+/// that is not a strict order cannot take it out of bounds.
+fn merge_sort<F>(values: &mut Vec<Value>, less: &mut F) -> Result<()>
+where
+    F: FnMut(&Value, &Value) -> Result<bool>,
+{
+    if values.len() <= 1 {
+        return Ok(());
+    }
+    let mut right = values.split_off(values.len() / 2);
+    merge_sort(values, less)?;
+    merge_sort(&mut right, less)?;
+    let left = std::mem::take(values);
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        if less(b, a)? {
+            values.extend(right.next());
+        } else {
+            values.extend(left.next());
+        }
+    }
+    values.extend(left);
+    values.extend(right);
+    Ok(())
+}
+
+fn tables(table: &mut Table) {
+    register(table, "insert", |interpreter, args| {
+        let table = check_table(interpreter, &args, 0, "insert")?;
+        let len = table.borrow().len();
+        let (position, value) = match args.len() {
+            2 => (len + 1, arg(&args, 1)),
+            3 => {
+                let position = check_integer(interpreter, &args, 1, "insert")?;
+                (position.max(0) as usize, arg(&args, 2))
+            }
+            _ => return Err(interpreter.error("wrong number of arguments to 'insert'")),
+        };
+        let object = Value::Table(table.clone());
+        let mut i = len;
+        while i >= position && i > 0 {
+            let moved = table.borrow().get(&Value::Number(i as f64));
+            interpreter.set_index(&object, Value::Number((i + 1) as f64), moved)?;
+            i -= 1;
+        }
+        interpreter.set_index(&object, Value::Number(position as f64), value)?;
+        Ok(vec![])
+    });
+    register(table, "remove", |interpreter, args| {
+        let table = check_table(interpreter, &args, 0, "remove")?;
+        let len = table.borrow().len() as i64;
+        let position = opt_integer(interpreter, &args, 1, "remove", len)?;
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let object = Value::Table(table.clone());
+        let removed = table.borrow().get(&Value::Number(position as f64));
+        for i in position..len {
+            let moved = table.borrow().get(&Value::Number((i + 1) as f64));
+            interpreter.set_index(&object, Value::Number(i as f64), moved)?;
+        }
+        interpreter.set_index(&object, Value::Number(len as f64), Value::Nil)?;
+        Ok(vec![removed])
+    });
+    register(table, "concat", |interpreter, args| {
+        let table = check_table(interpreter, &args, 0, "concat")?;
+        let separator = match args.get(1) {
+            None | Some(Value::Nil) => Rc::from(&b""[..]),
+            _ => check_string(interpreter, &args, 1, "concat")?,
+        };
+        let len = table.borrow().len() as i64;
+        let start = opt_integer(interpreter, &args, 2, "concat", 1)?;
+        let end = opt_integer(interpreter, &args, 3, "concat", len)?;
+        let mut out = vec![];
+        for i in start..=end {
+            let value = table.borrow().get(&Value::Number(i as f64));
+            match value.to_bytes() {
+                Some(s) => out.extend_from_slice(&s),
+                None => {
+                    return Err(interpreter.error(format!(
+                        "invalid value (at index {}) in table for 'concat'",
+                        i
+                    )))
+                }
+            }
+            if i < end {
+                out.extend_from_slice(&separator);
+            }
+        }
+        Ok(vec![Value::String(Rc::from(out))])
+    });
+    register(table, "sort", |interpreter, args| {
+        let table = check_table(interpreter, &args, 0, "sort")?;
+        let comparator = arg(&args, 1);
+        if !matches!(comparator, Value::Nil | Value::Function(_)) {
+            return Err(type_error(interpreter, &args, 1, "sort", "function"));
+        }
+        let len = table.borrow().len();
+        let mut values: Vec<Value> = (1..=len)
+            .map(|i| table.borrow().get(&Value::Number(i as f64)))
+            .collect();
+        merge_sort(&mut values, &mut |a, b| match &comparator {
+            Value::Nil => interpreter.less_than(a, b),
+            comparator => {
+                let result = interpreter.call(comparator, vec![a.clone(), b.clone()])?;
+                Ok(result.first().map_or(false, Value::truthy))
+            }
+        })?;
+        let object = Value::Table(table);
+        for (i, value) in values.into_iter().enumerate() {
+            interpreter.set_index(&object, Value::Number((i + 1) as f64), value)?;
+        }
+        Ok(vec![])
+    });
+    register(table, "getn", |interpreter, args| {
+        let table = check_table(interpreter, &args, 0, "getn")?;
+        let len = table.borrow().len();
+        Ok(vec![Value::Number(len as f64)])
+    });
+    register(table, "maxn", |interpreter, args| {
+        let table = check_table(interpreter, &args, 0, "maxn")?;
+        let max = table.borrow().maxn();
+        Ok(vec![Value::Number(max)])
+    });
+}
+
+fn unary(math: &mut Table, name: &'static str, f: fn(f64) -> f64) {
+    register(math, name, move |interpreter, args| {
+        let x = check_number(interpreter, &args, 0, name)?;
+        Ok(vec![Value::Number(f(x))])
+    });
+}
+
+/// The `rand48` generator Redis seeds the same way for every script, so that
+/// scripts stay deterministic.
+struct Rand48(Cell<u64>);
+
+impl Rand48 {
+    const MAX: u64 = (1 << 31) - 1;
+
+    fn seed(&self, seed: u64) {
+        self.0.set(((seed & 0xffff_ffff) << 16) | 0x330e);
+    }
+
+    fn next(&self) -> f64 {
+        let state = (self.0.get().wrapping_mul(0x5_deec_e66d) + 0xb) & ((1 << 48) - 1);
+        self.0.set(state);
+        ((state >> 17) % Rand48::MAX) as f64 / Rand48::MAX as f64
+    }
+}
+
+fn maths(math: &mut Table) {
+    unary(math, "abs", f64::abs);
+    unary(math, "ceil", f64::ceil);
+    unary(math, "floor", f64::floor);
+    unary(math, "sqrt", f64::sqrt);
+    unary(math, "sin", f64::sin);
+    unary(math, "cos", f64::cos);
+    unary(math, "tan", f64::tan);
+    unary(math, "asin", f64::asin);
+    unary(math, "acos", f64::acos);
+    unary(math, "atan", f64::atan);
+    unary(math, "sinh", f64::sinh);
+    unary(math, "cosh", f64::cosh);
+    unary(math, "tanh", f64::tanh);
+    unary(math, "exp", f64::exp);
+    unary(math, "log", f64::ln);
+    unary(math, "log10", f64::log10);
+    unary(math, "deg", f64::to_degrees);
+    unary(math, "rad", f64::to_radians);
+    register(math, "atan2", |interpreter, args| {
+        let y = check_number(interpreter, &args, 0, "atan2")?;
+        let x = check_number(interpreter, &args, 1, "atan2")?;
+        Ok(vec![Value::Number(y.atan2(x))])
+    });
+    register(math, "pow", |interpreter, args| {
+        let x = check_number(interpreter, &args, 0, "pow")?;
+        let y = check_number(interpreter, &args, 1, "pow")?;
+        Ok(vec![Value::Number(x.powf(y))])
+    });
+    register(math, "fmod", |interpreter, args| {
+        let x = check_number(interpreter, &args, 0, "fmod")?;
+        let y = check_number(interpreter, &args, 1, "fmod")?;
+        Ok(vec![Value::Number(x % y)])
+    });
+    register(math, "modf", |interpreter, args| {
+        let x = check_number(interpreter, &args, 0, "modf")?;
+        Ok(vec![Value::Number(x.trunc()), Value::Number(x.fract())])
+    });
+    register(math, "frexp", |interpreter, args| {
+        let x = check_number(interpreter, &args, 0, "frexp")?;
+        if x == 0.0 || !x.is_finite() {
+            return Ok(vec![Value::Number(x), Value::Number(0.0)]);
+        }
+        let exponent = x.abs().log2().floor() + 1.0;
+        let mut mantissa = x / 2f64.powf(exponent);
+        let mut exponent = exponent;
+        // Rounding in log2 can leave the mantissa just outside [0.5, 1).
+        if mantissa.abs() >= 1.0 {
+            mantissa /= 2.0;
+            exponent += 1.0;
+        } else if mantissa.abs() < 0.5 {
+            mantissa *= 2.0;
+            exponent -= 1.0;
+        }
+        Ok(vec![Value::Number(mantissa), Value::Number(exponent)])
+    });
+    register(math, "ldexp", |interpreter, args| {
+        let m = check_number(interpreter, &args, 0, "ldexp")?;
+        let e = check_integer(interpreter, &args, 1, "ldexp")?;
+        Ok(vec![Value::Number(m * 2f64.powi(e as i32))])
+    });
+    let extreme = |math: &mut Table, name: &'static str, max: bool| {
+        register(math, name, move |interpreter, args| {
+            let mut result = check_number(interpreter, &args, 0, name)?;
+            for n in 1..args.len() {
+                let x = check_number(interpreter, &args, n, name)?;
+                if (max && x > result) || (!max && x < result) {
+                    result = x;
+                }
+            }
+            Ok(vec![Value::Number(result)])
+        });
+    };
+    extreme(math, "max", true);
+    extreme(math, "min", false);
+    math.set_str("huge", Value::Number(f64::INFINITY));
+    math.set_str("pi", Value::Number(std::f64::consts::PI));
+
+    let random = Rc::new(Rand48(Cell::new(0)));
+    random.seed(0);
+    let generator = random.clone();
+    register(math, "random", move |interpreter, args| {
+        let r = generator.next();
+        let (low, high) = match args.len() {
+            0 => return Ok(vec![Value::Number(r)]),
+            1 => (1.0, check_integer(interpreter, &args, 0, "random")? as f64),
+            2 => (
+                check_integer(interpreter, &args, 0, "random")? as f64,
+                check_integer(interpreter, &args, 1, "random")? as f64,
+            ),
+            _ => return Err(interpreter.error("wrong number of arguments")),
+        };
+        if low > high {
+            let n = if args.len() == 1 { 0 } else { 1 };
+            return Err(bad_argument(interpreter, n, "random", "interval is empty"));
+        }
+        Ok(vec![Value::Number((r * (high - low + 1.0)).floor() + low)])
+    });
+    register(math, "randomseed", move |interpreter, args| {
+        let seed = check_integer(interpreter, &args, 0, "randomseed")?;
+        random.seed(seed as u64);
+        Ok(vec![])
+    });
+}
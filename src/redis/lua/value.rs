@@ -0,0 +1,444 @@
+//! Lua values and tables.
+
+use super::interpreter::Interpreter;
+use super::number;
+use super::parser::Proto;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(Rc<[u8]>),
+    Table(TableRef),
+    Function(Function),
+}
+
+pub type TableRef = Rc<RefCell<Table>>;
+
+/// A variable, shared between the frame declaring it and the closures that
+/// capture it.
+pub type Cell = Rc<RefCell<Value>>;
+
+pub type Builtin = dyn Fn(&mut Interpreter, Vec<Value>) -> Result<Vec<Value>, Error>;
+
+#[derive(Clone)]
+pub enum Function {
+    Lua(Rc<Closure>),
+    Builtin(Rc<Builtin>),
+}
+
+pub struct Closure {
+    pub proto: Arc<Proto>,
+    pub upvalues: Vec<Cell>,
+}
+
+impl Drop for Closure {
+    fn drop(&mut self) {
+        let values = self
+            .upvalues
+            .drain(..)
+            .filter_map(|cell| Rc::try_unwrap(cell).ok())
+            .map(RefCell::into_inner)
+            .collect();
+        release(values);
+    }
+}
+
+/// Frees values a level at a time rather than recursively, so that long
+/// chains of tables or closures cannot overflow the stack.
+fn release(mut pending: Vec<Value>) {
+    while let Some(value) = pending.pop() {
+        match value {
+            Value::Table(table) => {
+                if let Ok(table) = Rc::try_unwrap(table) {
+                    pending.extend(table.into_inner().take_values());
+                }
+            }
+            Value::Function(Function::Lua(closure)) => {
+                if let Ok(mut closure) = Rc::try_unwrap(closure) {
+                    for cell in closure.upvalues.drain(..) {
+                        if let Ok(cell) = Rc::try_unwrap(cell) {
+                            pending.push(cell.into_inner());
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A raised error: any value, usually a message, and the line it was raised
+/// at.
+pub struct Error {
+    pub value: Value,
+    pub line: u32,
+}
+
+impl Default for Value {
+    fn default() -> Value {
+        Value::Nil
+    }
+}
+
+impl Value {
+    pub fn string(data: &[u8]) -> Value {
+        Value::String(Rc::from(data))
+    }
+
+    pub fn builtin<F>(f: F) -> Value
+    where
+        F: Fn(&mut Interpreter, Vec<Value>) -> Result<Vec<Value>, Error> + 'static,
+    {
+        Value::Function(Function::Builtin(Rc::new(f)))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    /// The number the value stands for in arithmetic, converting strings.
+    pub fn to_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::String(s) => number::parse(s),
+            _ => None,
+        }
+    }
+
+    /// The string the value stands for in concatenation, converting numbers.
+    pub fn to_bytes(&self) -> Option<Rc<[u8]>> {
+        match self {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(Rc::from(number::to_string(*n).as_bytes())),
+            _ => None,
+        }
+    }
+
+    /// Raw equality, comparing tables and functions by identity.
+    pub fn raw_equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => a.ptr() == b.ptr(),
+            _ => false,
+        }
+    }
+
+    /// What `tostring` makes of the value, short of metamethods.
+    pub fn to_display(&self) -> Vec<u8> {
+        match self {
+            Value::Nil => b"nil".to_vec(),
+            Value::Boolean(b) => b.to_string().into_bytes(),
+            Value::Number(n) => number::to_string(*n).into_bytes(),
+            Value::String(s) => s.to_vec(),
+            Value::Table(t) => format!("table: {:p}", Rc::as_ptr(t)).into_bytes(),
+            Value::Function(f) => format!("function: {:#x}", f.ptr()).into_bytes(),
+        }
+    }
+}
+
+impl Function {
+    fn ptr(&self) -> usize {
+        match self {
+            Function::Lua(closure) => Rc::as_ptr(closure) as usize,
+            Function::Builtin(builtin) => Rc::as_ptr(builtin) as *const u8 as usize,
+        }
+    }
+}
+
+/// A value usable as a table key: anything but nil and NaN, with numbers
+/// compared by value and tables and functions by identity.
+#[derive(Clone)]
+pub enum Key {
+    Boolean(bool),
+    Number(f64),
+    String(Rc<[u8]>),
+    Table(TableRef),
+    Function(Function),
+}
+
+impl Key {
+    /// The key for `value`, or an error message if it cannot be one.
+    pub fn new(value: &Value) -> Result<Key, &'static str> {
+        let key = match value {
+            Value::Nil => return Err("table index is nil"),
+            Value::Number(n) if n.is_nan() => return Err("table index is NaN"),
+            // Negative zero is the same key as zero.
+            Value::Number(n) => Key::Number(if *n == 0.0 { 0.0 } else { *n }),
+            Value::Boolean(b) => Key::Boolean(*b),
+            Value::String(s) => Key::String(s.clone()),
+            Value::Table(t) => Key::Table(t.clone()),
+            Value::Function(f) => Key::Function(f.clone()),
+        };
+        Ok(key)
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            Key::Boolean(b) => Value::Boolean(*b),
+            Key::Number(n) => Value::Number(*n),
+            Key::String(s) => Value::String(s.clone()),
+            Key::Table(t) => Value::Table(t.clone()),
+            Key::Function(f) => Value::Function(f.clone()),
+        }
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.to_value().raw_equals(&other.to_value())
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Key::Boolean(b) => b.hash(state),
+            Key::Number(n) => n.to_bits().hash(state),
+            Key::String(s) => s.hash(state),
+            Key::Table(t) => (Rc::as_ptr(t) as usize).hash(state),
+            Key::Function(f) => f.ptr().hash(state),
+        }
+    }
+}
+
+/// The index into the array part that key `value` would take, if it is a
+/// positive integer.
+fn array_index(value: &Value) -> Option<usize> {
+    match value {
+        Value::Number(n) if *n >= 1.0 && n.fract() == 0.0 && *n <= usize::MAX as f64 => {
+            Some(*n as usize - 1)
+        }
+        _ => None,
+    }
+}
+
+/// A table, keeping keys 1 to n in an array part and the others in order of
+/// insertion, so that `next` can continue from any key.
+///
+/// Keys set to nil stay in place until the next new key is inserted, which
+/// is what allows clearing fields while traversing a table.
+#[derive(Default)]
+pub struct Table {
+    array: Vec<Value>,
+    entries: Vec<(Key, Value)>,
+    index: HashMap<Key, usize>,
+    dead: usize,
+    pub metatable: Option<TableRef>,
+    /// Set on the tables scripts may not change, such as the globals.
+    pub readonly: bool,
+}
+
+impl Table {
+    pub fn new() -> Table {
+        Table::default()
+    }
+
+    /// A table holding `values` at keys 1 to n.
+    pub fn array(values: Vec<Value>) -> Table {
+        let mut table = Table::new();
+        for value in values {
+            table.push(value);
+        }
+        table
+    }
+
+    pub fn get(&self, key: &Value) -> Value {
+        if let Some(index) = array_index(key) {
+            if let Some(value) = self.array.get(index) {
+                return value.clone();
+            }
+        }
+        match Key::new(key) {
+            Ok(key) => self.get_key(&key),
+            Err(_) => Value::Nil,
+        }
+    }
+
+    fn get_key(&self, key: &Key) -> Value {
+        match self.index.get(key) {
+            Some(&position) => self.entries[position].1.clone(),
+            None => Value::Nil,
+        }
+    }
+
+    pub fn get_str(&self, key: &str) -> Value {
+        self.get_key(&Key::String(Rc::from(key.as_bytes())))
+    }
+
+    pub fn set_str(&mut self, key: &str, value: Value) {
+        let _ = self.set(Value::string(key.as_bytes()), value);
+    }
+
+    /// Sets `key`, failing with a message for nil and NaN keys.
+    pub fn set(&mut self, key: Value, value: Value) -> Result<(), &'static str> {
+        let key = Key::new(&key)?;
+        if let Key::Number(n) = key {
+            if let Some(index) = array_index(&Value::Number(n)) {
+                if index < self.array.len() {
+                    self.array[index] = value;
+                    while self.array.last().map_or(false, Value::is_nil) {
+                        self.array.pop();
+                    }
+                    return Ok(());
+                }
+                if index == self.array.len() && !self.index.contains_key(&key) {
+                    if !value.is_nil() {
+                        self.push(value);
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        match self.index.get(&key) {
+            Some(&position) => {
+                let slot = &mut self.entries[position].1;
+                match (slot.is_nil(), value.is_nil()) {
+                    (false, true) => self.dead += 1,
+                    (true, false) => self.dead -= 1,
+                    _ => {}
+                }
+                *slot = value;
+            }
+            None if value.is_nil() => {}
+            None => {
+                if self.dead > 0 && self.dead * 2 >= self.entries.len() {
+                    self.compact();
+                }
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends at key n + 1, moving the keys that follow out of the hash part.
+    fn push(&mut self, value: Value) {
+        self.array.push(value);
+        loop {
+            let next = Key::Number((self.array.len() + 1) as f64);
+            let position = match self.index.get(&next) {
+                Some(&position) if !self.entries[position].1.is_nil() => position,
+                _ => break,
+            };
+            let value = std::mem::replace(&mut self.entries[position].1, Value::Nil);
+            self.dead += 1;
+            self.array.push(value);
+        }
+    }
+
+    fn compact(&mut self) {
+        self.entries.retain(|(_, value)| !value.is_nil());
+        self.index = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(position, (key, _))| (key.clone(), position))
+            .collect();
+        self.dead = 0;
+    }
+
+    /// The length operator: a border of the table.
+    pub fn len(&self) -> usize {
+        self.array.len()
+    }
+
+    /// The entry after `key` in traversal order, or None past the last one.
+    /// Fails if `key` is not in the table.
+    pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, ()> {
+        let (mut index, mut position) = match key {
+            Value::Nil => (0, 0),
+            key => {
+                let array = array_index(key);
+                let position = Key::new(key)
+                    .ok()
+                    .and_then(|key| self.index.get(&key).copied());
+                match (array, position) {
+                    (Some(index), _) if index < self.array.len() => (index + 1, 0),
+                    (_, Some(position)) => (self.array.len(), position + 1),
+                    // The array part shrinks as its last keys are cleared
+                    // during a traversal.
+                    (Some(_), None) => (self.array.len(), 0),
+                    (None, None) => return Err(()),
+                }
+            }
+        };
+        while index < self.array.len() {
+            if !self.array[index].is_nil() {
+                return Ok(Some((
+                    Value::Number((index + 1) as f64),
+                    self.array[index].clone(),
+                )));
+            }
+            index += 1;
+        }
+        while position < self.entries.len() {
+            let (key, value) = &self.entries[position];
+            if !value.is_nil() {
+                return Ok(Some((key.to_value(), value.clone())));
+            }
+            position += 1;
+        }
+        Ok(None)
+    }
+
+    /// Empties the table, returning what it held.
+    fn take_values(&mut self) -> Vec<Value> {
+        self.index.clear();
+        let mut values = std::mem::take(&mut self.array);
+        for (key, value) in self.entries.drain(..) {
+            values.push(key.to_value());
+            values.push(value);
+        }
+        values.extend(self.metatable.take().map(Value::Table));
+        values
+    }
+
+    /// The largest positive integer key, as `table.maxn` has it.
+    pub fn maxn(&self) -> f64 {
+        let mut max = self.array.len() as f64;
+        for (key, value) in &self.entries {
+            if let Key::Number(n) = key {
+                if !value.is_nil() && *n > max {
+                    max = *n;
+                }
+            }
+        }
+        max
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if !self.array.is_empty() || !self.entries.is_empty() || self.metatable.is_some() {
+            release(self.take_values());
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! LZF, the compression RDB applies to long strings.
+
+/// Expands `input` into exactly `len` bytes, or returns None if the stream
+/// is malformed.
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    // `len` comes from the payload, so it is not trusted for preallocation.
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = usize::from(input[i]);
+        i += 1;
+        if ctrl < 32 {
+            // A run of ctrl + 1 literal bytes.
+            let literal = input.get(i..i + ctrl + 1)?;
+            out.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            // A back reference, which may overlap its own output.
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += usize::from(*input.get(i)?);
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1f) << 8) + usize::from(*input.get(i)?) + 1;
+            i += 1;
+            let start = out.len().checked_sub(offset)?;
+            for k in 0..run + 2 {
+                let byte = out[start + k];
+                out.push(byte);
+            }
+        }
+        if out.len() > len {
+            return None;
+        }
+    }
+    if out.len() != len {
+        return None;
+    }
+    Some(out)
+}
+
+/// The longest literal run, and the farthest and longest back references,
+/// the format can express.
+const MAX_LITERAL: usize = 32;
+const MAX_OFFSET: usize = 1 << 13;
+const MAX_REFERENCE: usize = 264;
+
+const HASH_BITS: u32 = 14;
+
+/// Compresses `input`, or returns None if that takes more than `max`
+/// bytes.
+pub fn compress(input: &[u8], max: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(max);
+    // Where each hash of three bytes was last seen, plus one.
+    let mut table = vec![0; 1 << HASH_BITS];
+    let mut literal = 0;
+    let mut i = 0;
+    while i + 2 < input.len() {
+        let triple =
+            u32::from(input[i]) << 16 | u32::from(input[i + 1]) << 8 | u32::from(input[i + 2]);
+        let hash = (triple.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize;
+        let candidate = table[hash];
+        table[hash] = i + 1;
+        if candidate > 0
+            && i - candidate < MAX_OFFSET
+            && input[candidate - 1..candidate + 2] == input[i..i + 3]
+        {
+            let from = candidate - 1;
+            let longest = MAX_REFERENCE.min(input.len() - i);
+            let mut len = 3;
+            while len < longest && input[from + len] == input[i + len] {
+                len += 1;
+            }
+            push_literals(&mut out, &input[literal..i]);
+            let offset = i - from - 1;
+            let run = len - 2;
+            if run < 7 {
+                out.push((run << 5 | offset >> 8) as u8);
+            } else {
+                out.push((7 << 5 | offset >> 8) as u8);
+                out.push((run - 7) as u8);
+            }
+            out.push(offset as u8);
+            i += len;
+            literal = i;
+        } else {
+            i += 1;
+        }
+        if out.len() > max {
+            return None;
+        }
+    }
+    push_literals(&mut out, &input[literal..]);
+    if out.len() > max {
+        return None;
+    }
+    Some(out)
+}
+
+fn push_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for run in literals.chunks(MAX_LITERAL) {
+        out.push((run.len() - 1) as u8);
+        out.extend_from_slice(run);
+    }
+}
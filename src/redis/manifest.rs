@@ -0,0 +1,189 @@
+//! The manifest of a multi-part AOF: which files in the AOF directory make
+//! up the dataset and in what order. There is a base file, an RDB or a
+//! command stream written by the last rewrite, and incremental files of
+//! the writes since, newest last. Files the last rewrite replaced stay
+//! listed as history until they are deleted.
+//!
+//! Each line reads `file <name> seq <n> type <b|h|i>`, as Redis writes it.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone)]
+pub struct Manifest {
+    /// The AOF name the files are named after, `appendfilename`.
+    name: String,
+    pub base: Option<Part>,
+    pub incrs: Vec<Part>,
+    pub history: Vec<Part>,
+    /// The highest sequence numbers given out, for the next files.
+    base_seq: u64,
+    incr_seq: u64,
+}
+
+#[derive(Clone)]
+pub struct Part {
+    pub file: String,
+    pub seq: u64,
+}
+
+impl Manifest {
+    pub fn new(name: &str) -> Manifest {
+        Manifest {
+            name: name.to_owned(),
+            base: None,
+            incrs: vec![],
+            history: vec![],
+            base_seq: 0,
+            incr_seq: 0,
+        }
+    }
+
+    /// Where the manifest of the AOF `name` goes, in `dir`.
+    pub fn path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{}.manifest", name))
+    }
+
+    /// Reads the manifest of the AOF `name` from `dir`, if there is one.
+    pub fn read(dir: &Path, name: &str) -> Result<Option<Manifest>, String> {
+        let path = Manifest::path(dir, name);
+        match fs::read_to_string(&path) {
+            Ok(text) => Manifest::parse(name, &text).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!(
+                "Fail to read the AOF manifest file {}: {}",
+                path.display(),
+                e
+            )),
+        }
+    }
+
+    fn parse(name: &str, text: &str) -> Result<Manifest, String> {
+        let invalid = || "Invalid AOF manifest file format".to_owned();
+        let mut manifest = Manifest::new(name);
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.len() % 2 != 0 {
+                return Err(invalid());
+            }
+            let (mut file, mut seq, mut kind) = (None, None, None);
+            for pair in words.chunks(2) {
+                match pair[0] {
+                    "file" => file = Some(pair[1]),
+                    "seq" => seq = pair[1].parse::<u64>().ok(),
+                    "type" => kind = Some(pair[1]),
+                    // Keys of later versions are skipped.
+                    _ => {}
+                }
+            }
+            let (file, seq, kind) = match (file, seq, kind) {
+                (Some(file), Some(seq), Some(kind)) if !file.contains('/') => (file, seq, kind),
+                _ => return Err(invalid()),
+            };
+            let part = Part {
+                file: file.to_owned(),
+                seq,
+            };
+            match kind {
+                "b" => {
+                    if manifest.base.is_some() {
+                        return Err("Found duplicate base file information".to_owned());
+                    }
+                    manifest.base_seq = seq;
+                    manifest.base = Some(part);
+                }
+                "h" => manifest.history.push(part),
+                "i" => {
+                    if seq <= manifest.incr_seq {
+                        return Err("Found a non-monotonic sequence number".to_owned());
+                    }
+                    manifest.incr_seq = seq;
+                    manifest.incrs.push(part);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        if manifest.base.is_none() && manifest.incrs.is_empty() {
+            return Err("Found an empty AOF manifest".to_owned());
+        }
+        Ok(manifest)
+    }
+
+    /// Writes the manifest to a temporary file first and renames it into
+    /// place, so the one in `dir` is always complete.
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        let parts = self
+            .base
+            .iter()
+            .map(|part| (part, 'b'))
+            .chain(self.history.iter().map(|part| (part, 'h')))
+            .chain(self.incrs.iter().map(|part| (part, 'i')));
+        for (part, kind) in parts {
+            text.push_str(&format!(
+                "file {} seq {} type {}\n",
+                part.file, part.seq, kind
+            ));
+        }
+        let temp = dir.join(format!("temp-{}.manifest", self.name));
+        let mut file = File::create(&temp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, Manifest::path(dir, &self.name))
+    }
+
+    /// Adds a new incremental file, to be written to from now on, and
+    /// returns its name.
+    pub fn new_incr(&mut self) -> String {
+        self.incr_seq += 1;
+        let file = format!("{}.{}.incr.aof", self.name, self.incr_seq);
+        self.incrs.push(Part {
+            file: file.clone(),
+            seq: self.incr_seq,
+        });
+        file
+    }
+
+    /// Makes a new base file the one the dataset starts from, in RDB format
+    /// or as commands, and returns its name. The old base and the
+    /// incremental files it covers, all but the newest one if `keep_last`,
+    /// become history.
+    pub fn new_base(&mut self, rdb: bool, keep_last: bool) -> String {
+        self.base_seq += 1;
+        let file = format!(
+            "{}.{}.base.{}",
+            self.name,
+            self.base_seq,
+            if rdb { "rdb" } else { "aof" }
+        );
+        let kept = if keep_last { self.incrs.pop() } else { None };
+        self.history.extend(self.base.take());
+        self.history.append(&mut self.incrs);
+        self.incrs.extend(kept);
+        self.base = Some(Part {
+            file: file.clone(),
+            seq: self.base_seq,
+        });
+        file
+    }
+
+    /// Makes an existing file, the old single-file AOF moved into the
+    /// directory, the base.
+    pub fn set_base(&mut self, file: &str) {
+        self.base_seq += 1;
+        self.base = Some(Part {
+            file: file.to_owned(),
+            seq: self.base_seq,
+        });
+    }
+
+    /// The files of the dataset, the base first.
+    pub fn files(&self) -> impl Iterator<Item = &Part> {
+        self.base.iter().chain(self.incrs.iter())
+    }
+}
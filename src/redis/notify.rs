@@ -0,0 +1,154 @@
+//! Keyspace notifications: events about changes to keys, published on the
+//! `__keyspace@<db>__:<key>` and `__keyevent@<db>__:<event>` channels.
+//!
+//! Commands report every change through [`Database::notify`], which queues
+//! the event if `notify-keyspace-events` enables its class. The queue is
+//! published once the command has finished, so subscribers never see an
+//! event before the change it describes.
+//!
+//! [`Database::notify`]: super::storage::Database::notify
+
+use super::pubsub::PubSub;
+use std::cell::RefCell;
+
+/// The kinds of events, each enabled by a character of the flags.
+#[derive(Clone, Copy)]
+pub enum Class {
+    /// Commands that work on keys of any type, such as DEL or EXPIRE.
+    Generic,
+    String,
+    List,
+    Set,
+    Hash,
+    ZSet,
+    Stream,
+    /// Keys deleted because their TTL ran out.
+    Expired,
+    /// Lookups of keys that do not exist.
+    KeyMiss,
+    /// Keys added to a database.
+    New,
+}
+
+const KEYSPACE: u16 = 1 << 0;
+const KEYEVENT: u16 = 1 << 1;
+const GENERIC: u16 = 1 << 2;
+const STRING: u16 = 1 << 3;
+const LIST: u16 = 1 << 4;
+const SET: u16 = 1 << 5;
+const HASH: u16 = 1 << 6;
+const ZSET: u16 = 1 << 7;
+const EXPIRED: u16 = 1 << 8;
+const EVICTED: u16 = 1 << 9;
+const STREAM: u16 = 1 << 10;
+const KEY_MISS: u16 = 1 << 11;
+const MODULE: u16 = 1 << 12;
+const NEW: u16 = 1 << 13;
+/// What `A` stands for: every class but key misses and new keys.
+const ALL: u16 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM | MODULE;
+
+impl Class {
+    fn flag(self) -> u16 {
+        match self {
+            Class::Generic => GENERIC,
+            Class::String => STRING,
+            Class::List => LIST,
+            Class::Set => SET,
+            Class::Hash => HASH,
+            Class::ZSet => ZSET,
+            Class::Stream => STREAM,
+            Class::Expired => EXPIRED,
+            Class::KeyMiss => KEY_MISS,
+            Class::New => NEW,
+        }
+    }
+}
+
+/// The classes of events to publish, and on which channels.
+#[derive(Clone, Copy, Default)]
+pub struct Flags(u16);
+
+impl Flags {
+    /// Parses a flag string such as `KEA` or `Kx`.
+    pub fn parse(flags: &str) -> Result<Flags, String> {
+        let mut parsed = 0;
+        for flag in flags.chars() {
+            parsed |= match flag {
+                'A' => ALL,
+                'g' => GENERIC,
+                '$' => STRING,
+                'l' => LIST,
+                's' => SET,
+                'h' => HASH,
+                'z' => ZSET,
+                'x' => EXPIRED,
+                'e' => EVICTED,
+                'K' => KEYSPACE,
+                'E' => KEYEVENT,
+                't' => STREAM,
+                'm' => KEY_MISS,
+                'd' => MODULE,
+                'n' => NEW,
+                _ => return Err("Invalid event class character. Use 'Ag$lshzxeKEtmdn'.".to_owned()),
+            };
+        }
+        Ok(Flags(parsed))
+    }
+
+    /// Whether events of `class` are published on either kind of channel.
+    fn enabled(self, class: Class) -> bool {
+        self.0 & class.flag() != 0 && self.0 & (KEYSPACE | KEYEVENT) != 0
+    }
+}
+
+struct Event {
+    name: &'static str,
+    key: Vec<u8>,
+}
+
+/// The events of a database waiting to be published.
+#[derive(Default)]
+pub struct Events {
+    flags: Flags,
+    // Lookups take the database by shared reference and still report misses.
+    pending: RefCell<Vec<Event>>,
+}
+
+impl Events {
+    pub fn new(flags: Flags) -> Events {
+        Events {
+            flags,
+            pending: RefCell::default(),
+        }
+    }
+
+    pub fn enabled(&self, class: Class) -> bool {
+        self.flags.enabled(class)
+    }
+
+    /// Queues `event` about `key` if its class is enabled.
+    pub fn push(&self, class: Class, name: &'static str, key: &[u8]) {
+        if self.enabled(class) {
+            self.pending.borrow_mut().push(Event {
+                name,
+                key: key.to_vec(),
+            });
+        }
+    }
+
+    /// Publishes the queued events of database `db`.
+    pub fn publish(&self, db: usize, pubsub: &mut PubSub) {
+        let pending = self.pending.replace(vec![]);
+        for event in pending {
+            if self.flags.0 & KEYSPACE != 0 {
+                let mut channel = format!("__keyspace@{}__:", db).into_bytes();
+                channel.extend_from_slice(&event.key);
+                pubsub.publish(&channel, event.name.as_bytes());
+            }
+            if self.flags.0 & KEYEVENT != 0 {
+                let channel = format!("__keyevent@{}__:{}", db, event.name);
+                pubsub.publish(channel.as_bytes(), &event.key);
+            }
+        }
+    }
+}
@@ -0,0 +1,537 @@
+//! Snapshots of the dataset in an RDB file. SAVE writes one with the store
+//! locked throughout; BGSAVE copies the dataset under the lock and writes
+//! the copy out on the blocking pool while commands carry on. The server
+//! loads the file back at startup, before it takes connections, and saves
+//! in the background on its own once the changes made meet a save rule.
+//!
+//! With the append-only file on, the dataset is loaded from that instead,
+//! and every write that goes through is added to it. BGREWRITEAOF writes
+//! a new base file for it from a copy of the dataset, again on the blocking
+//! pool.
+
+use super::aof::{self, Aof, Fsync};
+use super::config::Config;
+use super::manifest::Manifest;
+use super::rdb::{self, Snapshot};
+use super::stats::{self, STATS};
+use super::storage::{self, Store};
+use super::{Error, Value};
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::oneshot;
+
+/// How long after a failed background save the rules may start another.
+const RETRY_DELAY_SECONDS: i64 = 5;
+
+pub struct Persistence {
+    /// Where the RDB file goes.
+    path: PathBuf,
+    /// Seconds and changes, as in [`Config::save`].
+    rules: Vec<(i64, u64)>,
+    /// Shared with the background save under way, which reports back.
+    status: Arc<Mutex<Status>>,
+    /// The directory the files of the AOF go in.
+    aof_dir: PathBuf,
+    /// The name of the AOF, which its files are named after.
+    aof_name: String,
+    appendonly: bool,
+    /// Whether rewrites write the base file as an RDB.
+    aof_preamble: bool,
+    appendfsync: Fsync,
+    /// Open once the dataset is loaded, if the AOF is on.
+    aof: Option<Aof>,
+    /// The AOF rewrite under way.
+    rewrite: Option<Rewrite>,
+    last_rewrite_ok: bool,
+    /// How long the last AOF rewrite took, in seconds.
+    last_rewrite_seconds: Option<i64>,
+    rewrites: u64,
+}
+
+struct Rewrite {
+    /// When it started, in unix milliseconds.
+    started: i64,
+    /// Where the new base file is written, to be renamed into place.
+    temp: PathBuf,
+    done: oneshot::Receiver<io::Result<()>>,
+}
+
+/// How saving went so far, for the save rules, LASTSAVE and INFO.
+struct Status {
+    /// When the background save under way started, in unix milliseconds.
+    saving: Option<i64>,
+    /// The changes counter as of the last successful save.
+    saved_changes: u64,
+    /// When the last successful save happened, in unix seconds.
+    last_save: i64,
+    saves: u64,
+    /// When the last background save started, in unix seconds.
+    last_attempt: i64,
+    last_background_ok: bool,
+    /// How long the last background save took, in seconds.
+    last_background_seconds: Option<i64>,
+}
+
+impl Status {
+    /// Records a successful save of the dataset as of `changes`.
+    fn saved(&mut self, changes: u64) {
+        self.saved_changes = changes;
+        self.last_save = unix_seconds();
+        self.saves += 1;
+    }
+}
+
+impl Persistence {
+    pub fn new(config: &Config) -> Persistence {
+        Persistence {
+            path: config.dir.join(&config.dbfilename),
+            rules: config.save.clone(),
+            status: Arc::new(Mutex::new(Status {
+                saving: None,
+                saved_changes: 0,
+                last_save: unix_seconds(),
+                saves: 0,
+                last_attempt: 0,
+                last_background_ok: true,
+                last_background_seconds: None,
+            })),
+            aof_dir: config.dir.join(&config.appenddirname),
+            aof_name: config.appendfilename.clone(),
+            appendonly: config.appendonly,
+            aof_preamble: config.aof_use_rdb_preamble,
+            appendfsync: config.appendfsync,
+            aof: None,
+            rewrite: None,
+            last_rewrite_ok: true,
+            last_rewrite_seconds: None,
+            rewrites: 0,
+        }
+    }
+
+    /// Hands a write command that changed the dataset to the AOF, if it
+    /// is on.
+    pub fn propagate(&mut self, db: usize, command: Vec<Vec<u8>>, reply: &Value) {
+        if let Some(aof) = &mut self.aof {
+            aof.feed(db, command, reply);
+        }
+    }
+
+    /// Writes the commands propagated by the command that just ran to the
+    /// AOF, if it is on.
+    pub fn write_aof(&mut self) {
+        if let Some(aof) = &mut self.aof {
+            aof.write();
+        }
+    }
+
+    /// Whether writes go to the AOF, and so must keep their arguments.
+    pub fn appendonly(&self) -> bool {
+        self.aof.is_some()
+    }
+
+    /// Whether saving is configured at all, which SHUTDOWN goes by.
+    pub fn has_rules(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// When the last successful save happened, in unix seconds.
+    pub fn last_save(&self) -> i64 {
+        self.status().last_save
+    }
+
+    /// The fields of the persistence section of INFO.
+    pub fn info(&self) -> Vec<(&'static str, String)> {
+        let status = self.status();
+        let now = storage::unix_millis();
+        let mut fields = vec![
+            ("loading", "0".to_owned()),
+            (
+                "rdb_changes_since_last_save",
+                (stats::read(&STATS.dirty) - status.saved_changes).to_string(),
+            ),
+            (
+                "rdb_bgsave_in_progress",
+                (status.saving.is_some() as u8).to_string(),
+            ),
+            ("rdb_last_save_time", status.last_save.to_string()),
+            (
+                "rdb_last_bgsave_status",
+                if status.last_background_ok {
+                    "ok"
+                } else {
+                    "err"
+                }
+                .to_owned(),
+            ),
+            (
+                "rdb_last_bgsave_time_sec",
+                status.last_background_seconds.unwrap_or(-1).to_string(),
+            ),
+            (
+                "rdb_current_bgsave_time_sec",
+                status
+                    .saving
+                    .map_or(-1, |started| (now - started) / 1000)
+                    .to_string(),
+            ),
+            ("rdb_saves", status.saves.to_string()),
+            ("aof_enabled", (self.aof.is_some() as u8).to_string()),
+            (
+                "aof_last_write_status",
+                match &self.aof {
+                    Some(aof) if !aof.last_write_ok() => "err",
+                    _ => "ok",
+                }
+                .to_owned(),
+            ),
+            (
+                "aof_rewrite_in_progress",
+                (self.rewrite.is_some() as u8).to_string(),
+            ),
+            (
+                "aof_last_rewrite_time_sec",
+                self.last_rewrite_seconds.unwrap_or(-1).to_string(),
+            ),
+            (
+                "aof_current_rewrite_time_sec",
+                self.rewrite
+                    .as_ref()
+                    .map_or(-1, |rewrite| (now - rewrite.started) / 1000)
+                    .to_string(),
+            ),
+            (
+                "aof_last_bgrewrite_status",
+                if self.last_rewrite_ok { "ok" } else { "err" }.to_owned(),
+            ),
+            ("aof_rewrites", self.rewrites.to_string()),
+        ];
+        if let Some(aof) = &self.aof {
+            fields.extend(aof.info());
+        }
+        fields
+    }
+
+    /// Puts a rewritten base file in place with the AOF off, which leaves
+    /// no incremental files.
+    fn install_base(&self, temp: &Path) -> io::Result<()> {
+        fs::create_dir_all(&self.aof_dir)?;
+        let mut manifest = Manifest::read(&self.aof_dir, &self.aof_name)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+            .unwrap_or_else(|| Manifest::new(&self.aof_name));
+        aof::install_base(&self.aof_dir, &mut manifest, temp, self.aof_preamble, false).map(drop)
+    }
+
+    fn status(&self) -> MutexGuard<'_, Status> {
+        self.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn unix_seconds() -> i64 {
+    storage::unix_millis() / 1000
+}
+
+/// Fills the store from the AOF if it is on and there is one, or else from
+/// the RDB file, if there is one. The AOF is opened for the writes to come.
+pub fn load(store: &mut Store) -> Result<(), String> {
+    if !store.persistence().appendonly {
+        return load_rdb(store);
+    }
+    let dir = store.persistence().aof_dir.clone();
+    let name = store.persistence().aof_name.clone();
+    let manifest = match aof::load(store, &dir, &name)? {
+        Some(manifest) => manifest,
+        None => create_aof(store, &dir, &name)?,
+    };
+    let aof = Aof::open(&dir, manifest, store.persistence().appendfsync).map_err(|e| {
+        format!(
+            "Can't open the append-only file in {}: {}",
+            dir.display(),
+            e
+        )
+    })?;
+    let persistence = store.persistence();
+    persistence.aof = Some(aof);
+    // What was replayed is on disk already.
+    persistence.status().saved_changes = stats::read(&STATS.dirty);
+    Ok(())
+}
+
+/// Starts the AOF directory with a base file: the single-file AOF of old,
+/// moved in, if there is one, or else the dataset as the RDB file has it.
+fn create_aof(store: &mut Store, dir: &Path, name: &str) -> Result<Manifest, String> {
+    fs::create_dir_all(dir).map_err(|e| {
+        format!(
+            "Can't open or create append-only dir {}: {}",
+            dir.display(),
+            e
+        )
+    })?;
+    let mut manifest = Manifest::new(name);
+    let old = store.persistence().path.with_file_name(name);
+    if aof::load_file(store, &old, true)? {
+        fs::rename(&old, dir.join(name))
+            .and_then(|()| {
+                manifest.set_base(name);
+                manifest.save(dir)
+            })
+            .map_err(|e| {
+                format!(
+                    "Error moving the old AOF file into {}: {}",
+                    dir.display(),
+                    e
+                )
+            })?;
+        eprintln!("Successfully migrated an old-style AOF into the AOF directory");
+        return Ok(manifest);
+    }
+    load_rdb(store)?;
+    let snapshot = snapshot(store);
+    let persistence = store.persistence();
+    let temp = persistence
+        .path
+        .with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()));
+    let preamble = persistence.aof_preamble;
+    let base = write_base(&snapshot, &temp, preamble)
+        .and_then(|()| aof::install_base(dir, &mut manifest, &temp, preamble, false))
+        .map_err(|e| {
+            let _ = fs::remove_file(&temp);
+            format!("Can't create the AOF base file: {}", e)
+        })?;
+    eprintln!(
+        "Creating AOF base file {} on server start",
+        base.file_name().unwrap_or_default().to_string_lossy()
+    );
+    Ok(manifest)
+}
+
+fn load_rdb(store: &mut Store) -> Result<(), String> {
+    let path = &store.persistence().path;
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Can't read {}: {}", path.display(), e)),
+    };
+    let snapshot = rdb::read_file(&data, store.databases().len())?;
+    restore(store, snapshot)
+}
+
+/// Fills the store with what an RDB file held.
+pub fn restore(store: &mut Store, snapshot: Snapshot) -> Result<(), String> {
+    for code in snapshot.functions {
+        store
+            .functions()
+            .load(code, false)
+            .map_err(|e| format!("Failed loading a function library: {}", e))?;
+    }
+    for (index, entries) in snapshot.databases.into_iter().enumerate() {
+        let database = store.database(index);
+        for (key, stored) in entries {
+            if !stored.expired() && !stored.data.is_empty() {
+                database.insert(key, stored);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies what an RDB file holds out of the store.
+fn snapshot(store: &mut Store) -> Snapshot {
+    let functions = store.functions().codes();
+    let databases = store
+        .databases()
+        .iter()
+        .map(|database| {
+            database
+                .entries()
+                .map(|(key, stored)| (key.clone(), stored.clone()))
+                .collect()
+        })
+        .collect();
+    Snapshot {
+        functions,
+        databases,
+        time: storage::unix_millis(),
+    }
+}
+
+fn in_progress() -> Error {
+    Error::Argument("Background save already in progress".to_owned())
+}
+
+/// Writes the dataset out before returning, for SAVE.
+pub fn save(store: &mut Store) -> Result<(), Error> {
+    if store.persistence().status().saving.is_some() {
+        return Err(in_progress());
+    }
+    let changes = stats::read(&STATS.dirty);
+    let snapshot = snapshot(store);
+    let persistence = store.persistence();
+    write(&snapshot, &persistence.path).map_err(|e| {
+        eprintln!("Failed saving the DB: {}", e);
+        Error::Argument(format!("Error saving the DB: {}", e))
+    })?;
+    persistence.status().saved(changes);
+    Ok(())
+}
+
+/// Starts writing the dataset out in the background, for BGSAVE.
+pub fn background_save(store: &mut Store) -> Result<(), Error> {
+    let started = storage::unix_millis();
+    {
+        let mut status = store.persistence().status();
+        if status.saving.is_some() {
+            return Err(in_progress());
+        }
+        status.saving = Some(started);
+        status.last_attempt = started / 1000;
+    }
+    let changes = stats::read(&STATS.dirty);
+    let snapshot = snapshot(store);
+    let path = store.persistence().path.clone();
+    let status = store.persistence().status.clone();
+    tokio::task::spawn_blocking(move || {
+        let result = write(&snapshot, &path);
+        let mut status = status.lock().unwrap_or_else(|e| e.into_inner());
+        status.last_background_ok = match result {
+            Ok(()) => {
+                status.saved(changes);
+                true
+            }
+            Err(e) => {
+                eprintln!("Background saving error: {}", e);
+                false
+            }
+        };
+        status.saving = None;
+        status.last_background_seconds = Some((storage::unix_millis() - started) / 1000);
+    });
+    Ok(())
+}
+
+/// Starts a background save if a save rule is met: enough changes, and
+/// enough time since the last save. After a failed one it waits a little
+/// before trying again.
+pub fn check_rules(store: &mut Store) {
+    let now = unix_seconds();
+    let persistence = store.persistence();
+    let rule = {
+        let status = persistence.status();
+        let changes = stats::read(&STATS.dirty) - status.saved_changes;
+        if status.saving.is_some()
+            || (!status.last_background_ok && now - status.last_attempt <= RETRY_DELAY_SECONDS)
+        {
+            return;
+        }
+        persistence
+            .rules
+            .iter()
+            .copied()
+            .find(|&(seconds, minimum)| changes >= minimum && now - status.last_save > seconds)
+    };
+    if let Some((seconds, changes)) = rule {
+        eprintln!("{} changes in {} seconds. Saving...", changes, seconds);
+        let _ = background_save(store);
+    }
+}
+
+/// Starts writing a new AOF in the background, for BGREWRITEAOF.
+pub fn background_rewrite(store: &mut Store) -> Result<(), Error> {
+    if store.persistence().rewrite.is_some() {
+        return Err(Error::Argument(
+            "Background append only file rewriting already in progress".to_owned(),
+        ));
+    }
+    let snapshot = snapshot(store);
+    let persistence = store.persistence();
+    let temp = persistence
+        .path
+        .with_file_name(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+    if let Some(aof) = &mut persistence.aof {
+        aof.start_rewrite().map_err(|e| {
+            eprintln!("Can't open new incr AOF: {}", e);
+            Error::Argument(
+                "Can't execute an AOF background rewriting. Please check the server logs for \
+                 more information."
+                    .to_owned(),
+            )
+        })?;
+    }
+    let (sender, done) = oneshot::channel();
+    let path = temp.clone();
+    let preamble = persistence.aof_preamble;
+    tokio::task::spawn_blocking(move || {
+        let _ = sender.send(write_base(&snapshot, &path, preamble));
+    });
+    persistence.rewrite = Some(Rewrite {
+        started: storage::unix_millis(),
+        temp,
+        done,
+    });
+    Ok(())
+}
+
+/// Puts the new AOF in place once the rewrite under way is done.
+pub fn check_rewrite(store: &mut Store) {
+    let persistence = store.persistence();
+    let result = match &mut persistence.rewrite {
+        Some(rewrite) => match rewrite.done.try_recv() {
+            Ok(result) => result,
+            Err(oneshot::error::TryRecvError::Empty) => return,
+            Err(oneshot::error::TryRecvError::Closed) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the rewrite was interrupted",
+            )),
+        },
+        None => return,
+    };
+    let rewrite = match persistence.rewrite.take() {
+        Some(rewrite) => rewrite,
+        None => return,
+    };
+    let preamble = persistence.aof_preamble;
+    let result = result.and_then(|()| match &mut persistence.aof {
+        Some(aof) => aof.finish_rewrite(&rewrite.temp, preamble),
+        None => persistence.install_base(&rewrite.temp),
+    });
+    persistence.last_rewrite_ok = match result {
+        Ok(()) => {
+            eprintln!("Background AOF rewrite finished successfully");
+            persistence.rewrites += 1;
+            true
+        }
+        Err(e) => {
+            eprintln!("Background AOF rewrite failed: {}", e);
+            let _ = fs::remove_file(&rewrite.temp);
+            false
+        }
+    };
+    persistence.last_rewrite_seconds = Some((storage::unix_millis() - rewrite.started) / 1000);
+}
+
+/// Writes the snapshot to a temporary file first and renames it into
+/// place, so the file at `path` is always a complete one.
+fn write(snapshot: &Snapshot, path: &Path) -> io::Result<()> {
+    let temp = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    let result = write_to(snapshot, &temp, false).and_then(|()| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Writes a base file for the AOF, as an RDB if `rdb`.
+fn write_base(snapshot: &Snapshot, path: &Path, rdb: bool) -> io::Result<()> {
+    if rdb {
+        write_to(snapshot, path, true)
+    } else {
+        aof::write_rewrite(snapshot, path)
+    }
+}
+
+fn write_to(snapshot: &Snapshot, path: &Path, aof_base: bool) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    rdb::write_file(&mut file, snapshot, aof_base)?;
+    file.into_inner()?.sync_all()
+}
@@ -0,0 +1,413 @@
+//! Publish/subscribe messaging.
+//!
+//! The [`PubSub`] broker lives in the store, mapping each channel, and each
+//! glob pattern channels are matched against, to the connections subscribed
+//! to it. Shard channels, which exist for clusters, are kept apart from the
+//! others and get their own commands, but behave the same standing alone.
+//! Every connection owns an unbounded queue
+//! that PUBLISH pushes messages onto under the store lock, so messages reach
+//! a subscriber in the order they were published and only after the
+//! confirmation of its subscription.
+
+use super::command::Arguments;
+use super::glob;
+use super::{Error, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc;
+
+pub type SubscriberId = u64;
+
+static NEXT_SUBSCRIBER: AtomicU64 = AtomicU64::new(0);
+
+/// The queue messages are pushed onto for a connection to write out.
+type Sender = mpsc::UnboundedSender<Value>;
+
+/// The subscribers to each channel or pattern that has any.
+type Registry = HashMap<Vec<u8>, HashMap<SubscriberId, Sender>>;
+
+/// What a subscription is to.
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Channel,
+    Pattern,
+    Shard,
+}
+
+impl Kind {
+    /// The verbs confirming subscriptions of the kind and their end.
+    fn verbs(self) -> (&'static str, &'static str) {
+        match self {
+            Kind::Channel => ("subscribe", "unsubscribe"),
+            Kind::Pattern => ("psubscribe", "punsubscribe"),
+            Kind::Shard => ("ssubscribe", "sunsubscribe"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PubSub {
+    channels: Registry,
+    patterns: Registry,
+    shards: Registry,
+}
+
+/// Pushes `push` to each of `subscribers`, returning how many received it.
+fn push(subscribers: &HashMap<SubscriberId, Sender>, push: Value) -> usize {
+    subscribers
+        .values()
+        // Fails for connections that closed but did not unsubscribe yet.
+        .filter(|sender| sender.send(push.clone()).is_ok())
+        .count()
+}
+
+impl PubSub {
+    fn registry(&self, kind: Kind) -> &Registry {
+        match kind {
+            Kind::Channel => &self.channels,
+            Kind::Pattern => &self.patterns,
+            Kind::Shard => &self.shards,
+        }
+    }
+
+    fn registry_mut(&mut self, kind: Kind) -> &mut Registry {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shards,
+        }
+    }
+
+    fn subscribe(&mut self, kind: Kind, name: &[u8], id: SubscriberId, sender: Sender) {
+        self.registry_mut(kind)
+            .entry(name.to_vec())
+            .or_default()
+            .insert(id, sender);
+    }
+
+    fn unsubscribe(&mut self, kind: Kind, name: &[u8], id: SubscriberId) {
+        let registry = self.registry_mut(kind);
+        if let Some(subscribers) = registry.get_mut(name) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                registry.remove(name);
+            }
+        }
+    }
+
+    /// The channels of a kind with subscribers, those matching `pattern` if
+    /// given.
+    fn active(&self, kind: Kind, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+        self.registry(kind)
+            .keys()
+            .filter(|channel| pattern.map_or(true, |pattern| glob::matches(pattern, channel)))
+            .cloned()
+            .collect()
+    }
+
+    /// How many connections are subscribed to `channel` itself.
+    fn subscribers(&self, kind: Kind, channel: &[u8]) -> usize {
+        self.registry(kind).get(channel).map_or(0, HashMap::len)
+    }
+
+    /// Pushes a message to the subscribers of `channel` and of the patterns
+    /// matching it, returning how many received it; a connection subscribed
+    /// several ways receives it once for each.
+    pub fn publish(&mut self, channel: &[u8], message: &[u8]) -> usize {
+        let mut receivers = 0;
+        if let Some(subscribers) = self.channels.get(channel) {
+            receivers += push(
+                subscribers,
+                Value::array(vec![
+                    Value::bulk("message"),
+                    Value::String(channel.to_vec()),
+                    Value::String(message.to_vec()),
+                ]),
+            );
+        }
+        for (pattern, subscribers) in &self.patterns {
+            if glob::matches(pattern, channel) {
+                receivers += push(
+                    subscribers,
+                    Value::array(vec![
+                        Value::bulk("pmessage"),
+                        Value::String(pattern.clone()),
+                        Value::String(channel.to_vec()),
+                        Value::String(message.to_vec()),
+                    ]),
+                );
+            }
+        }
+        receivers
+    }
+
+    /// Pushes a message to the subscribers of shard channel `channel`,
+    /// returning how many received it.
+    fn publish_shard(&mut self, channel: &[u8], message: &[u8]) -> usize {
+        match self.shards.get(channel) {
+            Some(subscribers) => push(
+                subscribers,
+                Value::array(vec![
+                    Value::bulk("smessage"),
+                    Value::String(channel.to_vec()),
+                    Value::String(message.to_vec()),
+                ]),
+            ),
+            None => 0,
+        }
+    }
+}
+
+/// The subscriptions of a connection, and the queue of messages for it.
+pub struct Subscriptions {
+    id: SubscriberId,
+    sender: Sender,
+    pub messages: mpsc::UnboundedReceiver<Value>,
+    channels: HashSet<Vec<u8>>,
+    patterns: HashSet<Vec<u8>>,
+    shards: HashSet<Vec<u8>>,
+}
+
+impl Subscriptions {
+    pub fn new() -> Subscriptions {
+        let (sender, messages) = mpsc::unbounded_channel();
+        Subscriptions {
+            id: NEXT_SUBSCRIBER.fetch_add(1, Ordering::Relaxed),
+            sender,
+            messages,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            shards: HashSet::new(),
+        }
+    }
+
+    fn joined(&mut self, kind: Kind) -> &mut HashSet<Vec<u8>> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shards,
+        }
+    }
+
+    /// How many subscriptions confirmations of a kind report: those to
+    /// channels and patterns together, or those to shard channels.
+    fn count(&self, kind: Kind) -> usize {
+        match kind {
+            Kind::Channel | Kind::Pattern => self.channels.len() + self.patterns.len(),
+            Kind::Shard => self.shards.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count(Kind::Channel) + self.count(Kind::Shard) == 0
+    }
+
+    /// Drops every subscription, once the connection is gone or reset.
+    pub fn clear(&mut self, pubsub: &mut PubSub) {
+        for channel in self.channels.drain() {
+            pubsub.unsubscribe(Kind::Channel, &channel, self.id);
+        }
+        for pattern in self.patterns.drain() {
+            pubsub.unsubscribe(Kind::Pattern, &pattern, self.id);
+        }
+        for channel in self.shards.drain() {
+            pubsub.unsubscribe(Kind::Shard, &channel, self.id);
+        }
+    }
+}
+
+/// The confirmation of a change to a connection's subscriptions.
+fn confirmation(kind: &str, channel: Option<Vec<u8>>, count: usize) -> Value {
+    Value::array(vec![
+        Value::bulk(kind),
+        channel.map_or(Value::Nil, Value::String),
+        Value::Int(count as i64),
+    ])
+}
+
+pub enum PubSubCommand {
+    Publish(Vec<u8>, Vec<u8>),
+    /// SPUBLISH: publishes to a shard channel.
+    PublishShard(Vec<u8>, Vec<u8>),
+    /// PUBSUB CHANNELS and SHARDCHANNELS: the active channels of a kind,
+    /// matching a pattern if given.
+    Channels(Kind, Option<Vec<u8>>),
+    /// PUBSUB NUMSUB and SHARDNUMSUB: the subscribers of each channel.
+    NumSub(Kind, Vec<Vec<u8>>),
+    /// PUBSUB NUMPAT: how many patterns have subscribers.
+    NumPat,
+    Help,
+}
+
+impl PubSubCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<PubSubCommand>, Error> {
+        let command = match name {
+            "publish" | "spublish" => {
+                let channel = args.next_bytes()?;
+                let message = args.next_bytes()?;
+                args.finish()?;
+                if name == "publish" {
+                    PubSubCommand::Publish(channel, message)
+                } else {
+                    PubSubCommand::PublishShard(channel, message)
+                }
+            }
+            "pubsub" => PubSubCommand::introspect(args)?,
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    fn introspect(args: &mut Arguments) -> Result<PubSubCommand, Error> {
+        let subcommand = args.next_string()?.to_lowercase();
+        let command = match subcommand.as_str() {
+            "channels" if args.len() <= 1 => {
+                PubSubCommand::Channels(Kind::Channel, args.rest()?.pop())
+            }
+            "shardchannels" if args.len() <= 1 => {
+                PubSubCommand::Channels(Kind::Shard, args.rest()?.pop())
+            }
+            "numsub" => PubSubCommand::NumSub(Kind::Channel, args.rest()?),
+            "shardnumsub" => PubSubCommand::NumSub(Kind::Shard, args.rest()?),
+            "numpat" | "help" => {
+                if !args.is_empty() {
+                    return Err(Error::WrongArity(format!("pubsub|{}", subcommand)));
+                }
+                if subcommand == "help" {
+                    PubSubCommand::Help
+                } else {
+                    PubSubCommand::NumPat
+                }
+            }
+            "channels" | "shardchannels" => {
+                return Err(Error::Argument(format!(
+                    "unknown subcommand or wrong number of arguments for '{}'. Try PUBSUB HELP.",
+                    subcommand
+                )))
+            }
+            _ => {
+                return Err(Error::Argument(format!(
+                    "unknown subcommand '{}'. Try PUBSUB HELP.",
+                    subcommand
+                )))
+            }
+        };
+        Ok(command)
+    }
+
+    pub fn execute(self, pubsub: &mut PubSub) -> Result<Value, Error> {
+        let response = match self {
+            PubSubCommand::Publish(channel, message) => {
+                Value::Int(pubsub.publish(&channel, &message) as i64)
+            }
+            PubSubCommand::PublishShard(channel, message) => {
+                Value::Int(pubsub.publish_shard(&channel, &message) as i64)
+            }
+            PubSubCommand::Channels(kind, pattern) => Value::array(
+                pubsub
+                    .active(kind, pattern.as_deref())
+                    .into_iter()
+                    .map(Value::String)
+                    .collect(),
+            ),
+            PubSubCommand::NumSub(kind, channels) => Value::Map(
+                channels
+                    .into_iter()
+                    .map(|channel| {
+                        let count = pubsub.subscribers(kind, &channel) as i64;
+                        (Value::String(channel), Value::Int(count))
+                    })
+                    .collect(),
+            ),
+            PubSubCommand::NumPat => Value::Int(pubsub.patterns.len() as i64),
+            PubSubCommand::Help => Value::array(
+                [
+                    "PUBSUB <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                    "CHANNELS [<pattern>]",
+                    "    Return the currently active channels matching a <pattern> (default: '*').",
+                    "NUMPAT",
+                    "    Return number of subscriptions to patterns.",
+                    "NUMSUB [<channel> ...]",
+                    "    Return the number of subscribers for the specified channels, excluding",
+                    "    pattern subscriptions(default: no channels).",
+                    "SHARDCHANNELS [<pattern>]",
+                    "    Return the currently active shard level channels matching a <pattern> (default: '*').",
+                    "SHARDNUMSUB [<shardchannel> ...]",
+                    "    Return the number of subscribers for the specified shard level channel(s)",
+                    "HELP",
+                    "    Print this help.",
+                ]
+                .iter()
+                .map(|line| Value::Status((*line).to_owned()))
+                .collect(),
+            ),
+        };
+        Ok(response)
+    }
+}
+
+/// Commands changing the subscriptions of the connection that sends them.
+pub enum SubscribeCommand {
+    Subscribe(Kind, Vec<Vec<u8>>),
+    /// UNSUBSCRIBE, PUNSUBSCRIBE and SUNSUBSCRIBE: from everything of the
+    /// kind if given nothing.
+    Unsubscribe(Kind, Vec<Vec<u8>>),
+}
+
+impl SubscribeCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<SubscribeCommand>, Error> {
+        let command = match name {
+            "subscribe" | "psubscribe" | "ssubscribe" => {
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                let kind = match name {
+                    "subscribe" => Kind::Channel,
+                    "psubscribe" => Kind::Pattern,
+                    _ => Kind::Shard,
+                };
+                SubscribeCommand::Subscribe(kind, args.rest()?)
+            }
+            "unsubscribe" => SubscribeCommand::Unsubscribe(Kind::Channel, args.rest()?),
+            "punsubscribe" => SubscribeCommand::Unsubscribe(Kind::Pattern, args.rest()?),
+            "sunsubscribe" => SubscribeCommand::Unsubscribe(Kind::Shard, args.rest()?),
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    /// Applies the command, replying with a confirmation per channel or
+    /// pattern.
+    pub fn execute(self, pubsub: &mut PubSub, subscriptions: &mut Subscriptions) -> Vec<Value> {
+        let id = subscriptions.id;
+        match self {
+            SubscribeCommand::Subscribe(kind, names) => names
+                .into_iter()
+                .map(|name| {
+                    if subscriptions.joined(kind).insert(name.clone()) {
+                        pubsub.subscribe(kind, &name, id, subscriptions.sender.clone());
+                    }
+                    confirmation(kind.verbs().0, Some(name), subscriptions.count(kind))
+                })
+                .collect(),
+            SubscribeCommand::Unsubscribe(kind, mut names) => {
+                let verb = kind.verbs().1;
+                if names.is_empty() {
+                    names = subscriptions.joined(kind).iter().cloned().collect();
+                    if names.is_empty() {
+                        return vec![confirmation(verb, None, subscriptions.count(kind))];
+                    }
+                }
+                names
+                    .into_iter()
+                    .map(|name| {
+                        if subscriptions.joined(kind).remove(&name) {
+                            pubsub.unsubscribe(kind, &name, id);
+                        }
+                        confirmation(verb, Some(name), subscriptions.count(kind))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
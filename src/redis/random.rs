@@ -0,0 +1,32 @@
+//! Cheap non-cryptographic randomness for sampling keys and members.
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+thread_local! {
+    static STATE: Cell<u64> = Cell::new(seed());
+}
+
+/// Seeds each thread from the per-process random hasher keys; xorshift
+/// needs a non-zero state.
+fn seed() -> u64 {
+    RandomState::new().build_hasher().finish() | 1
+}
+
+/// xorshift64*.
+pub fn next_u64() -> u64 {
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
+
+/// A number in `0..bound`; `bound` must not be zero.
+pub fn below(bound: usize) -> usize {
+    (next_u64() % bound as u64) as usize
+}
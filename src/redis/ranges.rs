@@ -0,0 +1,148 @@
+//! The ranges commands select elements with: index windows, shared by lists
+//! and sorted sets, and the score and lexicographic intervals of sorted sets.
+
+use super::float;
+use super::Error;
+
+/// Clamps an inclusive, possibly negative, range of indexes to a collection
+/// of `len` elements; None when it selects nothing.
+pub fn indexes(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+#[derive(Clone, Copy)]
+struct ScoreBound {
+    score: f64,
+    exclusive: bool,
+}
+
+impl ScoreBound {
+    /// A score, or one following `(` to exclude it.
+    fn parse(data: &[u8]) -> Option<ScoreBound> {
+        let (exclusive, data) = match data.split_first() {
+            Some((b'(', rest)) => (true, rest),
+            _ => (false, data),
+        };
+        let score = float::parse(data)?;
+        Some(ScoreBound { score, exclusive })
+    }
+}
+
+/// An interval of scores, as in `ZRANGEBYSCORE key (1 +inf`.
+#[derive(Clone, Copy)]
+pub struct ScoreRange {
+    min: ScoreBound,
+    max: ScoreBound,
+}
+
+impl ScoreRange {
+    /// The scores from `min` up to but excluding `max`.
+    pub fn half_open(min: f64, max: f64) -> ScoreRange {
+        ScoreRange {
+            min: ScoreBound {
+                score: min,
+                exclusive: false,
+            },
+            max: ScoreBound {
+                score: max,
+                exclusive: true,
+            },
+        }
+    }
+
+    pub fn parse(min: &[u8], max: &[u8]) -> Result<ScoreRange, Error> {
+        match (ScoreBound::parse(min), ScoreBound::parse(max)) {
+            (Some(min), Some(max)) => Ok(ScoreRange { min, max }),
+            _ => Err(Error::Argument("min or max is not a float".to_owned())),
+        }
+    }
+
+    pub fn above_min(&self, score: f64) -> bool {
+        if self.min.exclusive {
+            score > self.min.score
+        } else {
+            score >= self.min.score
+        }
+    }
+
+    pub fn below_max(&self, score: f64) -> bool {
+        if self.max.exclusive {
+            score < self.max.score
+        } else {
+            score <= self.max.score
+        }
+    }
+}
+
+#[derive(Clone)]
+enum LexBound {
+    /// `-`, before every member.
+    First,
+    /// `+`, after every member.
+    Last,
+    Inclusive(Vec<u8>),
+    Exclusive(Vec<u8>),
+}
+
+impl LexBound {
+    fn parse(data: &[u8]) -> Option<LexBound> {
+        match data.split_first()? {
+            (b'-', []) => Some(LexBound::First),
+            (b'+', []) => Some(LexBound::Last),
+            (b'[', rest) => Some(LexBound::Inclusive(rest.to_vec())),
+            (b'(', rest) => Some(LexBound::Exclusive(rest.to_vec())),
+            _ => None,
+        }
+    }
+}
+
+/// An interval of members, for sorted sets whose members all share a score,
+/// as in `ZRANGEBYLEX key [a (c`.
+#[derive(Clone)]
+pub struct LexRange {
+    min: LexBound,
+    max: LexBound,
+}
+
+impl LexRange {
+    pub fn parse(min: &[u8], max: &[u8]) -> Result<LexRange, Error> {
+        match (LexBound::parse(min), LexBound::parse(max)) {
+            (Some(min), Some(max)) => Ok(LexRange { min, max }),
+            _ => Err(Error::Argument(
+                "min or max not valid string range item".to_owned(),
+            )),
+        }
+    }
+
+    pub fn above_min(&self, member: &[u8]) -> bool {
+        match &self.min {
+            LexBound::First => true,
+            LexBound::Last => false,
+            LexBound::Inclusive(min) => member >= min.as_slice(),
+            LexBound::Exclusive(min) => member > min.as_slice(),
+        }
+    }
+
+    pub fn below_max(&self, member: &[u8]) -> bool {
+        match &self.max {
+            LexBound::First => false,
+            LexBound::Last => true,
+            LexBound::Inclusive(max) => member <= max.as_slice(),
+            LexBound::Exclusive(max) => member < max.as_slice(),
+        }
+    }
+}
@@ -0,0 +1,1110 @@
+//! The RDB encoding of values, as found in DUMP payloads, and of whole
+//! datasets, as found in RDB files.
+
+use super::crc64;
+use super::float;
+use super::hashes::Hash;
+use super::lzf;
+use super::storage::{self, Data, StoredValue};
+use super::streams::{Entry, Pending, Stream, StreamId};
+use super::zsets::ZSet;
+use super::Error;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+/// Version stamped on everything we serialize; newer payloads are refused
+/// because they may use encodings this server does not know.
+pub const RDB_VERSION: u16 = 12;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+/// Sorted sets with scores as text.
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+/// Sorted sets with scores as binary doubles.
+const TYPE_ZSET_2: u8 = 5;
+/// Sets of integers as a sorted array of fixed-width integers.
+const TYPE_SET_INTSET: u8 = 11;
+/// Small hashes as one listpack of alternating fields and values.
+const TYPE_HASH_LISTPACK: u8 = 16;
+/// Small sorted sets as one listpack of alternating members and scores.
+const TYPE_ZSET_LISTPACK: u8 = 17;
+/// Small sets as one listpack of members.
+const TYPE_SET_LISTPACK: u8 = 20;
+/// Hashes with field TTLs, which are relative to the earliest of them.
+const TYPE_HASH_METADATA: u8 = 24;
+/// Small hashes with field TTLs as one listpack of field, value and
+/// absolute deadline triplets.
+const TYPE_HASH_LISTPACK_EX: u8 = 25;
+/// Lists as a sequence of listpack or plain nodes, written by Redis 7.
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+/// Streams as listpacks of entries indexed by the ID of their first one.
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+/// Streams that also record their first ID, greatest deleted ID and count
+/// of entries ever added.
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+/// Streams that also record when consumers were last active.
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+/// Quicklist node holding a single large element as is.
+const QUICKLIST_NODE_PLAIN: usize = 1;
+/// Quicklist node holding a listpack of elements.
+const QUICKLIST_NODE_PACKED: usize = 2;
+
+const ENCODING_INT8: u8 = 0;
+const ENCODING_INT16: u8 = 1;
+const ENCODING_INT32: u8 = 2;
+const ENCODING_LZF: u8 = 3;
+
+/// The slot a cluster node's keys that follow belong to, and its size.
+const OPCODE_SLOT_INFO: u8 = 244;
+/// A library of functions, as its code.
+const OPCODE_FUNCTION: u8 = 245;
+/// How long ago the key that follows was accessed, in seconds.
+const OPCODE_IDLE: u8 = 248;
+/// The access frequency counter of the key that follows.
+const OPCODE_FREQ: u8 = 249;
+/// A field about the file, like the version of Redis that wrote it.
+const OPCODE_AUX: u8 = 250;
+/// The number of keys, and of keys with a TTL, in the database that follows.
+const OPCODE_RESIZEDB: u8 = 251;
+/// The deadline of the key that follows, in unix milliseconds.
+const OPCODE_EXPIRETIME_MS: u8 = 252;
+/// The deadline of the key that follows, in unix seconds.
+const OPCODE_EXPIRETIME: u8 = 253;
+const OPCODE_SELECTDB: u8 = 254;
+const OPCODE_EOF: u8 = 255;
+
+/// The Redis version RDB files claim to be written by, the first to write
+/// their version.
+const REDIS_VERSION: &str = "7.4.0";
+
+/// How much of an RDB file is buffered before it is written out.
+const WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Flag of a stream entry that was deleted from its node.
+const STREAM_ITEM_DELETED: i64 = 1;
+/// Flag of a stream entry with the same fields as its node's first entry,
+/// which are then left out.
+const STREAM_ITEM_SAME_FIELDS: i64 = 2;
+
+/// Serializes `data` the way DUMP does: the type byte and value, then the
+/// RDB version and a CRC64 of everything before it, both little-endian.
+pub fn dump(data: &Data) -> Vec<u8> {
+    let mut out = vec![];
+    write_value(&mut out, data);
+    out.extend_from_slice(&RDB_VERSION.to_le_bytes());
+    let crc = crc64::update(0, &out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+/// What an RDB file holds, copied out of the store to be written at leisure.
+pub struct Snapshot {
+    /// The code of each library of functions.
+    pub functions: Vec<Vec<u8>>,
+    /// The live keys of each database, with their values.
+    pub databases: Vec<Vec<(Vec<u8>, StoredValue)>>,
+    /// When the snapshot was taken, in unix milliseconds.
+    pub time: i64,
+}
+
+/// Writes `snapshot` as an RDB file: a header with the version, fields
+/// about the file, the libraries, then each non-empty database as its
+/// keys, and an end marker followed by the checksum. `aof_base` marks the
+/// base file of an AOF.
+pub fn write_file<W: Write>(out: &mut W, snapshot: &Snapshot, aof_base: bool) -> io::Result<()> {
+    let mut buf = format!("REDIS{:04}", RDB_VERSION).into_bytes();
+    let mut crc = 0;
+    let ctime = (snapshot.time / 1000).to_string();
+    let aux = [
+        ("redis-ver", REDIS_VERSION),
+        ("redis-bits", "64"),
+        ("ctime", ctime.as_str()),
+        ("aof-base", if aof_base { "1" } else { "0" }),
+    ];
+    for (field, value) in aux.iter() {
+        buf.push(OPCODE_AUX);
+        write_string(&mut buf, field.as_bytes());
+        write_string(&mut buf, value.as_bytes());
+    }
+    for code in &snapshot.functions {
+        buf.push(OPCODE_FUNCTION);
+        write_string(&mut buf, code);
+    }
+    for (index, entries) in snapshot.databases.iter().enumerate() {
+        if entries.is_empty() {
+            continue;
+        }
+        buf.push(OPCODE_SELECTDB);
+        write_length(&mut buf, index);
+        buf.push(OPCODE_RESIZEDB);
+        write_length(&mut buf, entries.len());
+        let expires = entries.iter().filter(|(_, stored)| stored.expiry.is_some());
+        write_length(&mut buf, expires.count());
+        let mut value = vec![];
+        for (key, stored) in entries {
+            if let Some(expiry) = stored.expiry {
+                buf.push(OPCODE_EXPIRETIME_MS);
+                buf.extend_from_slice(&expiry.to_le_bytes());
+            }
+            // The key goes between the type byte and the value.
+            value.clear();
+            write_value(&mut value, &stored.data);
+            buf.push(value[0]);
+            write_string(&mut buf, key);
+            buf.extend_from_slice(&value[1..]);
+            if buf.len() >= WRITE_CHUNK_SIZE {
+                crc = crc64::update(crc, &buf);
+                out.write_all(&buf)?;
+                buf.clear();
+            }
+        }
+    }
+    buf.push(OPCODE_EOF);
+    crc = crc64::update(crc, &buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    out.write_all(&buf)?;
+    out.flush()
+}
+
+/// Reads an RDB file of a server with `databases` databases, failing with
+/// what is wrong with the file.
+pub fn read_file(data: &[u8], databases: usize) -> Result<Snapshot, String> {
+    read_prefix(data, databases).map(|(snapshot, _)| snapshot)
+}
+
+/// As [`read_file`], for an RDB that other data may follow, as in an AOF
+/// with an RDB preamble; also returns how long the RDB is.
+pub fn read_prefix(data: &[u8], databases: usize) -> Result<(Snapshot, usize), String> {
+    if data.len() < 9 || &data[..5] != b"REDIS" {
+        return Err("Wrong signature trying to load DB from file".to_owned());
+    }
+    let version = std::str::from_utf8(&data[5..9])
+        .ok()
+        .and_then(|version| version.parse::<u16>().ok())
+        .ok_or_else(|| "Wrong signature trying to load DB from file".to_owned())?;
+    if version > RDB_VERSION {
+        return Err(format!("Can't handle RDB format version {}", version));
+    }
+
+    let mut reader = Reader { data: &data[9..] };
+    let mut snapshot = Snapshot {
+        functions: vec![],
+        databases: (0..databases).map(|_| vec![]).collect(),
+        time: storage::unix_millis(),
+    };
+    let mut db = 0;
+    let (mut expiry, mut idle, mut frequency) = (None, None, None);
+    loop {
+        let corrupt = |reader: &Reader| {
+            format!(
+                "Bad file format reading the RDB file at offset {}",
+                data.len() - reader.data.len()
+            )
+        };
+        let kind = reader.read_u8().ok_or_else(|| corrupt(&reader))?;
+        let read = match kind {
+            OPCODE_EOF => break,
+            OPCODE_AUX => reader
+                .read_string()
+                .and_then(|_| reader.read_string())
+                .map(drop),
+            OPCODE_FUNCTION => reader
+                .read_string()
+                .map(|code| snapshot.functions.push(code)),
+            OPCODE_SELECTDB => {
+                db = reader.read_length().ok_or_else(|| corrupt(&reader))?;
+                if db >= databases {
+                    return Err(format!(
+                        "Data file was created with a Redis server configured to handle more \
+                         than {} databases",
+                        databases
+                    ));
+                }
+                Some(())
+            }
+            OPCODE_RESIZEDB => reader
+                .read_length()
+                .and_then(|_| reader.read_length())
+                .map(drop),
+            OPCODE_SLOT_INFO => (0..3).try_for_each(|_| reader.read_length().map(drop)),
+            OPCODE_EXPIRETIME_MS => reader.read_i64().map(|deadline| expiry = Some(deadline)),
+            OPCODE_EXPIRETIME => reader.read_bytes(4).map(|seconds| {
+                let seconds = u32::from_le_bytes([seconds[0], seconds[1], seconds[2], seconds[3]]);
+                expiry = Some(i64::from(seconds) * 1000);
+            }),
+            OPCODE_IDLE => reader
+                .read_length()
+                .map(|seconds| idle = i64::try_from(seconds).ok()),
+            OPCODE_FREQ => reader.read_u8().map(|counter| frequency = Some(counter)),
+            kind if supported(kind) => reader.read_string().and_then(|key| {
+                let data = reader.read_object(kind)?;
+                let stored = StoredValue::new(data, expiry.take());
+                stored.access.set(idle.take(), frequency.take());
+                snapshot.databases[db].push((key, stored));
+                Some(())
+            }),
+            kind => return Err(format!("Unknown RDB encoding type {}", kind)),
+        };
+        read.ok_or_else(|| corrupt(&reader))?;
+    }
+    // Files from version 5 on end with a checksum, zero if none was
+    // computed.
+    if version >= 5 {
+        let end = data.len() - reader.data.len();
+        let checksum = reader
+            .read_bytes(8)
+            .ok_or_else(|| "Unexpected EOF reading RDB file".to_owned())?;
+        let mut stored = [0; 8];
+        stored.copy_from_slice(checksum);
+        let stored = u64::from_le_bytes(stored);
+        let expected = crc64::update(0, &data[..end]);
+        if stored != 0 && stored != expected {
+            return Err(format!(
+                "Wrong RDB checksum expected: ({:x}) got ({:x})",
+                expected, stored
+            ));
+        }
+    }
+    Ok((snapshot, data.len() - reader.data.len()))
+}
+
+/// Whether the type byte of a value is one this server reads.
+fn supported(kind: u8) -> bool {
+    matches!(
+        kind,
+        TYPE_STRING
+            | TYPE_LIST
+            | TYPE_LIST_QUICKLIST_2
+            | TYPE_SET
+            | TYPE_SET_INTSET
+            | TYPE_SET_LISTPACK
+            | TYPE_ZSET
+            | TYPE_ZSET_2
+            | TYPE_ZSET_LISTPACK
+            | TYPE_HASH
+            | TYPE_HASH_LISTPACK
+            | TYPE_HASH_METADATA
+            | TYPE_HASH_LISTPACK_EX
+            | TYPE_STREAM_LISTPACKS
+            | TYPE_STREAM_LISTPACKS_2
+            | TYPE_STREAM_LISTPACKS_3
+    )
+}
+
+/// Validates the footer of a DUMP payload and decodes the value in it.
+pub fn restore(payload: &[u8]) -> Result<Data, Error> {
+    let wrong_footer = || Error::Argument("DUMP payload version or checksum are wrong".to_owned());
+    if payload.len() < 10 {
+        return Err(wrong_footer());
+    }
+    let (body, crc) = payload.split_at(payload.len() - 8);
+    let version = u16::from_le_bytes([body[body.len() - 2], body[body.len() - 1]]);
+    let mut expected = [0; 8];
+    expected.copy_from_slice(crc);
+    if version > RDB_VERSION || u64::from_le_bytes(expected) != crc64::update(0, body) {
+        return Err(wrong_footer());
+    }
+
+    let bad_format = || Error::Argument("Bad data format".to_owned());
+    let mut reader = Reader {
+        data: &body[..body.len() - 2],
+    };
+    let data = reader.read_value().ok_or_else(bad_format)?;
+    if !reader.data.is_empty() {
+        return Err(bad_format());
+    }
+    Ok(data)
+}
+
+fn write_value(out: &mut Vec<u8>, data: &Data) {
+    match data {
+        Data::String(data) => {
+            out.push(TYPE_STRING);
+            write_string(out, data);
+        }
+        // The plain encoding, which every Redis version still loads.
+        Data::List(list) => {
+            out.push(TYPE_LIST);
+            write_length(out, list.len());
+            for item in list {
+                write_string(out, item);
+            }
+        }
+        Data::Set(set) => {
+            out.push(TYPE_SET);
+            write_length(out, set.len());
+            for (member, _) in set.iter() {
+                write_string(out, member);
+            }
+        }
+        Data::ZSet(zset) => {
+            out.push(TYPE_ZSET_2);
+            write_length(out, zset.len());
+            for (member, score) in zset.iter() {
+                write_string(out, member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        Data::Hash(hash) => {
+            let fields: Vec<_> = hash.iter_with_expiry().collect();
+            let earliest = fields.iter().filter_map(|(_, _, expiry)| *expiry).min();
+            match earliest {
+                Some(earliest) => {
+                    out.push(TYPE_HASH_METADATA);
+                    out.extend_from_slice(&earliest.to_le_bytes());
+                    write_length(out, fields.len());
+                    for (field, value, expiry) in fields {
+                        let ttl = expiry.map_or(0, |expiry| expiry - earliest + 1);
+                        write_length(out, ttl as usize);
+                        write_string(out, field);
+                        write_string(out, value);
+                    }
+                }
+                None => {
+                    out.push(TYPE_HASH);
+                    write_length(out, fields.len());
+                    for (field, value, _) in fields {
+                        write_string(out, field);
+                        write_string(out, value);
+                    }
+                }
+            }
+        }
+        Data::Stream(stream) => {
+            out.push(TYPE_STREAM_LISTPACKS_3);
+            write_length(out, stream.nodes());
+            for node in stream.iter_nodes() {
+                write_string(out, &stream_id_key(node[0].id));
+                write_string(out, &stream_node(node));
+            }
+            write_length(out, stream.len());
+            write_stream_id(out, stream.last_id());
+            write_stream_id(out, stream.first_id());
+            write_stream_id(out, stream.max_deleted_id());
+            write_length(out, stream.entries_added() as usize);
+            write_length(out, stream.groups().len());
+            for (name, group) in stream.groups() {
+                write_string(out, name);
+                write_stream_id(out, group.last_id());
+                // Written as -1 when unknown.
+                write_length(out, group.entries_read().unwrap_or(u64::MAX) as usize);
+                write_length(out, group.pending().len());
+                for (&id, pending) in group.pending() {
+                    out.extend_from_slice(&stream_id_key(id));
+                    out.extend_from_slice(&pending.delivered.to_le_bytes());
+                    write_length(out, pending.deliveries as usize);
+                }
+                write_length(out, group.consumers().len());
+                for (name, consumer) in group.consumers() {
+                    write_string(out, name);
+                    out.extend_from_slice(&consumer.seen.to_le_bytes());
+                    out.extend_from_slice(&consumer.active.unwrap_or(-1).to_le_bytes());
+                    write_length(out, consumer.pending().len());
+                    for &id in consumer.pending() {
+                        out.extend_from_slice(&stream_id_key(id));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn write_stream_id(out: &mut Vec<u8>, id: StreamId) {
+    write_length(out, id.ms as usize);
+    write_length(out, id.seq as usize);
+}
+
+/// The big-endian form of an ID that stream nodes are indexed by.
+fn stream_id_key(id: StreamId) -> Vec<u8> {
+    let mut key = id.ms.to_be_bytes().to_vec();
+    key.extend_from_slice(&id.seq.to_be_bytes());
+    key
+}
+
+/// Encodes a node of stream entries as a listpack. It opens with a master
+/// entry: the number of live and deleted entries and the fields of the
+/// first entry. Each entry then holds flags, its ID relative to the first
+/// one, its fields unless they match the master ones, and finally the
+/// number of listpack elements before that last one, to walk backwards.
+fn stream_node(entries: &[Entry]) -> Vec<u8> {
+    let int = |n: i64| n.to_string().into_bytes();
+    let master = &entries[0];
+    let mut items = vec![int(entries.len() as i64), int(0)];
+    items.push(int(master.fields.len() as i64));
+    items.extend(master.fields.iter().map(|(field, _)| field.clone()));
+    items.push(int(0));
+    for entry in entries {
+        let same_fields = entry.fields.len() == master.fields.len()
+            && entry
+                .fields
+                .iter()
+                .zip(&master.fields)
+                .all(|((field, _), (master, _))| field == master);
+        let flags = if same_fields {
+            STREAM_ITEM_SAME_FIELDS
+        } else {
+            0
+        };
+        items.push(int(flags));
+        items.push(int(entry.id.ms.wrapping_sub(master.id.ms) as i64));
+        items.push(int(entry.id.seq.wrapping_sub(master.id.seq) as i64));
+        let count = entry.fields.len() as i64;
+        if same_fields {
+            items.extend(entry.fields.iter().map(|(_, value)| value.clone()));
+            items.push(int(count + 3));
+        } else {
+            items.push(int(count));
+            for (field, value) in &entry.fields {
+                items.push(field.clone());
+                items.push(value.clone());
+            }
+            items.push(int(count * 2 + 4));
+        }
+    }
+    write_listpack(&items)
+}
+
+/// Encodes a listpack, storing elements that are the canonical form of an
+/// integer as that integer, like Redis does. See [`read_listpack`].
+fn write_listpack(entries: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = vec![0; 6];
+    for entry in entries {
+        let start = out.len();
+        match canonical_integer(entry) {
+            Some(n) if (0..128).contains(&n) => out.push(n as u8),
+            Some(n) if (-4096..4096).contains(&n) => {
+                let n = n as u16 & 0x1fff;
+                out.push(0xc0 | (n >> 8) as u8);
+                out.push(n as u8);
+            }
+            Some(n) if i16::try_from(n).is_ok() => {
+                out.push(0xf1);
+                out.extend_from_slice(&(n as i16).to_le_bytes());
+            }
+            Some(n) if (-(1 << 23)..1 << 23).contains(&n) => {
+                out.push(0xf2);
+                out.extend_from_slice(&(n as i32).to_le_bytes()[..3]);
+            }
+            Some(n) if i32::try_from(n).is_ok() => {
+                out.push(0xf3);
+                out.extend_from_slice(&(n as i32).to_le_bytes());
+            }
+            Some(n) => {
+                out.push(0xf4);
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+            None => {
+                let len = entry.len();
+                if len < 64 {
+                    out.push(0x80 | len as u8);
+                } else if len < 4096 {
+                    out.push(0xe0 | (len >> 8) as u8);
+                    out.push(len as u8);
+                } else {
+                    out.push(0xf0);
+                    out.extend_from_slice(&(len as u32).to_le_bytes());
+                }
+                out.extend_from_slice(entry);
+            }
+        }
+        // The back length: the entry size in 7-bit groups, most significant
+        // first, with the high bit set on all but the first one.
+        let encoded = out.len() - start;
+        let groups = match encoded {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2_097_150 => 3,
+            2_097_151..=268_435_454 => 4,
+            _ => 5,
+        };
+        for group in (0..groups).rev() {
+            let bits = (encoded >> (7 * group)) as u8 & 0x7f;
+            let marker = if group == groups - 1 { 0 } else { 0x80 };
+            out.push(bits | marker);
+        }
+    }
+    out.push(0xff);
+    let total = out.len() as u32;
+    out[..4].copy_from_slice(&total.to_le_bytes());
+    let count = entries.len().min(usize::from(u16::MAX)) as u16;
+    out[4..6].copy_from_slice(&count.to_le_bytes());
+    out
+}
+
+fn write_length(out: &mut Vec<u8>, len: usize) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push((len >> 8) as u8 | 0x40);
+        out.push(len as u8);
+    } else if let Ok(len) = u32::try_from(len) {
+        out.push(0x80);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+}
+
+/// The integer `data` is the canonical decimal form of, if any.
+fn canonical_integer(data: &[u8]) -> Option<i64> {
+    if data.len() > 20 {
+        return None;
+    }
+    std::str::from_utf8(data)
+        .ok()
+        .and_then(|text| text.parse::<i64>().ok())
+        .filter(|number| number.to_string().as_bytes() == data)
+}
+
+/// Strings that are the canonical form of a small integer are stored as
+/// the integer, and longer ones compressed if that saves a few bytes, like
+/// Redis does.
+fn write_string(out: &mut Vec<u8>, data: &[u8]) {
+    if data.len() <= 11 {
+        if let Some(number) = canonical_integer(data) {
+            if let Ok(number) = i8::try_from(number) {
+                out.push(0xc0 | ENCODING_INT8);
+                out.extend_from_slice(&number.to_le_bytes());
+                return;
+            }
+            if let Ok(number) = i16::try_from(number) {
+                out.push(0xc0 | ENCODING_INT16);
+                out.extend_from_slice(&number.to_le_bytes());
+                return;
+            }
+            if let Ok(number) = i32::try_from(number) {
+                out.push(0xc0 | ENCODING_INT32);
+                out.extend_from_slice(&number.to_le_bytes());
+                return;
+            }
+        }
+    }
+    if data.len() > 20 {
+        if let Some(compressed) = lzf::compress(data, data.len() - 4) {
+            out.push(0xc0 | ENCODING_LZF);
+            write_length(out, compressed.len());
+            write_length(out, data.len());
+            out.extend_from_slice(&compressed);
+            return;
+        }
+    }
+    write_length(out, data.len());
+    out.extend_from_slice(data);
+}
+
+/// A length prefix is either a plain length or the tag of a special string
+/// encoding.
+enum Length {
+    Plain(usize),
+    Encoded(u8),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn read_u8(&mut self) -> Option<u8> {
+        Some(self.read_bytes(1)?[0])
+    }
+
+    fn read_value(&mut self) -> Option<Data> {
+        let kind = self.read_u8()?;
+        self.read_object(kind)
+    }
+
+    /// Reads a value of the type `kind` stands for, that byte already read.
+    fn read_object(&mut self, kind: u8) -> Option<Data> {
+        match kind {
+            TYPE_STRING => Some(Data::String(self.read_string()?)),
+            TYPE_LIST => {
+                let len = self.read_length()?;
+                let mut list = VecDeque::new();
+                for _ in 0..len {
+                    list.push_back(self.read_string()?);
+                }
+                Some(Data::List(list))
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.read_length()?;
+                let mut list = VecDeque::new();
+                for _ in 0..nodes {
+                    let container = self.read_length()?;
+                    let node = self.read_string()?;
+                    match container {
+                        QUICKLIST_NODE_PLAIN => list.push_back(node),
+                        QUICKLIST_NODE_PACKED => list.extend(read_listpack(&node)?),
+                        _ => return None,
+                    }
+                }
+                Some(Data::List(list))
+            }
+            TYPE_SET => {
+                let len = self.read_length()?;
+                let mut members = vec![];
+                for _ in 0..len {
+                    members.push(self.read_string()?);
+                }
+                non_empty_set(members)
+            }
+            TYPE_SET_INTSET => non_empty_set(read_intset(&self.read_string()?)?),
+            TYPE_SET_LISTPACK => non_empty_set(read_listpack(&self.read_string()?)?),
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.read_length()?;
+                let mut zset = ZSet::new();
+                for _ in 0..len {
+                    let member = self.read_string()?;
+                    let score = if kind == TYPE_ZSET {
+                        self.read_text_double()?
+                    } else {
+                        let mut bytes = [0; 8];
+                        bytes.copy_from_slice(self.read_bytes(8)?);
+                        f64::from_le_bytes(bytes)
+                    };
+                    if score.is_nan() {
+                        return None;
+                    }
+                    zset.insert(member, score);
+                }
+                non_empty_zset(zset)
+            }
+            TYPE_ZSET_LISTPACK => {
+                let entries = read_listpack(&self.read_string()?)?;
+                if entries.len() % 2 != 0 {
+                    return None;
+                }
+                let mut entries = entries.into_iter();
+                let mut zset = ZSet::new();
+                while let (Some(member), Some(score)) = (entries.next(), entries.next()) {
+                    zset.insert(member, float::parse(&score)?);
+                }
+                non_empty_zset(zset)
+            }
+            TYPE_HASH => {
+                let len = self.read_length()?;
+                let mut hash = Hash::new();
+                for _ in 0..len {
+                    let field = self.read_string()?;
+                    let value = self.read_string()?;
+                    hash.insert(field, value);
+                }
+                non_empty_hash(hash)
+            }
+            TYPE_HASH_LISTPACK => {
+                let entries = read_listpack(&self.read_string()?)?;
+                if entries.len() % 2 != 0 {
+                    return None;
+                }
+                let mut entries = entries.into_iter();
+                let mut hash = Hash::new();
+                while let (Some(field), Some(value)) = (entries.next(), entries.next()) {
+                    hash.insert(field, value);
+                }
+                non_empty_hash(hash)
+            }
+            TYPE_HASH_METADATA => {
+                let earliest = self.read_i64()?;
+                let len = self.read_length()?;
+                let mut hash = Hash::new();
+                for _ in 0..len {
+                    let ttl = self.read_length()? as i64;
+                    let field = self.read_string()?;
+                    let value = self.read_string()?;
+                    let expiry = if ttl == 0 {
+                        None
+                    } else {
+                        Some(earliest.checked_add(ttl - 1)?)
+                    };
+                    insert_field(&mut hash, field, value, expiry);
+                }
+                non_empty_hash(hash)
+            }
+            TYPE_HASH_LISTPACK_EX => {
+                self.read_i64()?;
+                let entries = read_listpack(&self.read_string()?)?;
+                if entries.len() % 3 != 0 {
+                    return None;
+                }
+                let mut entries = entries.into_iter();
+                let mut hash = Hash::new();
+                while let (Some(field), Some(value), Some(expiry)) =
+                    (entries.next(), entries.next(), entries.next())
+                {
+                    let expiry = std::str::from_utf8(&expiry).ok()?.parse::<i64>().ok()?;
+                    insert_field(
+                        &mut hash,
+                        field,
+                        value,
+                        Some(expiry).filter(|&expiry| expiry != 0),
+                    );
+                }
+                non_empty_hash(hash)
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                self.read_stream(kind)
+            }
+            _ => None,
+        }
+    }
+
+    fn read_stream(&mut self, kind: u8) -> Option<Data> {
+        let mut stream = Stream::new();
+        let nodes = self.read_length()?;
+        for _ in 0..nodes {
+            let master = stream_id_from_key(&self.read_string()?)?;
+            read_stream_node(&mut stream, master, &read_listpack(&self.read_string()?)?)?;
+        }
+        let len = self.read_length()?;
+        let last_id = self.read_stream_id()?;
+        let (max_deleted_id, entries_added) = if kind == TYPE_STREAM_LISTPACKS {
+            (StreamId::MIN, len as u64)
+        } else {
+            self.read_stream_id()?;
+            (self.read_stream_id()?, self.read_length()? as u64)
+        };
+        stream.restore_metadata(last_id, max_deleted_id, entries_added);
+        for _ in 0..self.read_length()? {
+            let name = self.read_string()?;
+            let group_last_id = self.read_stream_id()?;
+            // Older encodings leave the count of entries read to estimate.
+            let entries_read = if kind == TYPE_STREAM_LISTPACKS {
+                stream.estimate_entries_read(group_last_id)
+            } else {
+                Some(self.read_length()? as u64).filter(|&read| read != u64::MAX)
+            };
+            if !stream.create_group(name.clone(), group_last_id, entries_read) {
+                return None;
+            }
+            let group = stream.group_mut(&name)?;
+            // Consumers list the IDs of their entries, whose delivery
+            // metadata comes first.
+            let mut deliveries = BTreeMap::new();
+            for _ in 0..self.read_length()? {
+                let id = stream_id_from_key(self.read_bytes(16)?)?;
+                let delivered = self.read_i64()?;
+                deliveries.insert(id, (delivered, self.read_length()? as u64));
+            }
+            for _ in 0..self.read_length()? {
+                let consumer = self.read_string()?;
+                let seen = self.read_i64()?;
+                let active = if kind == TYPE_STREAM_LISTPACKS_3 {
+                    Some(self.read_i64()?).filter(|&active| active >= 0)
+                } else {
+                    Some(seen)
+                };
+                if !group.add_consumer(&consumer, seen) {
+                    return None;
+                }
+                group.consumer(&consumer, seen).active = active;
+                for _ in 0..self.read_length()? {
+                    let id = stream_id_from_key(self.read_bytes(16)?)?;
+                    let (delivered, deliveries) = deliveries.remove(&id)?;
+                    let pending = Pending {
+                        consumer: consumer.clone(),
+                        delivered,
+                        deliveries,
+                    };
+                    group.set_pending(id, pending);
+                }
+            }
+            if !deliveries.is_empty() {
+                return None;
+            }
+        }
+        let newest = stream.iter().next_back().map(|entry| entry.id);
+        if len != stream.len() || newest.map_or(false, |newest| newest > last_id) {
+            return None;
+        }
+        Some(Data::Stream(stream))
+    }
+
+    fn read_stream_id(&mut self) -> Option<StreamId> {
+        let ms = self.read_length()? as u64;
+        let seq = self.read_length()? as u64;
+        Some(StreamId { ms, seq })
+    }
+
+    /// A double as a length-prefixed decimal string, with special lengths
+    /// for NaN and the infinities.
+    fn read_text_double(&mut self) -> Option<f64> {
+        match self.read_u8()? {
+            253 => Some(f64::NAN),
+            254 => Some(f64::INFINITY),
+            255 => Some(f64::NEG_INFINITY),
+            len => {
+                let text = std::str::from_utf8(self.read_bytes(usize::from(len))?).ok()?;
+                text.parse().ok()
+            }
+        }
+    }
+
+    fn read_i64(&mut self) -> Option<i64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+        Some(i64::from_le_bytes(bytes))
+    }
+
+    fn read_length_prefix(&mut self) -> Option<Length> {
+        let first = self.read_u8()?;
+        let len = match first >> 6 {
+            0 => usize::from(first & 0x3f),
+            1 => usize::from(first & 0x3f) << 8 | usize::from(self.read_u8()?),
+            2 if first == 0x80 => {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(self.read_bytes(4)?);
+                usize::try_from(u32::from_be_bytes(bytes)).ok()?
+            }
+            2 if first == 0x81 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(self.read_bytes(8)?);
+                usize::try_from(u64::from_be_bytes(bytes)).ok()?
+            }
+            3 => return Some(Length::Encoded(first & 0x3f)),
+            _ => return None,
+        };
+        Some(Length::Plain(len))
+    }
+
+    fn read_length(&mut self) -> Option<usize> {
+        match self.read_length_prefix()? {
+            Length::Plain(len) => Some(len),
+            Length::Encoded(_) => None,
+        }
+    }
+
+    fn read_string(&mut self) -> Option<Vec<u8>> {
+        let number = match self.read_length_prefix()? {
+            Length::Plain(len) => return Some(self.read_bytes(len)?.to_vec()),
+            Length::Encoded(ENCODING_INT8) => i64::from(self.read_u8()? as i8),
+            Length::Encoded(ENCODING_INT16) => {
+                let mut bytes = [0; 2];
+                bytes.copy_from_slice(self.read_bytes(2)?);
+                i64::from(i16::from_le_bytes(bytes))
+            }
+            Length::Encoded(ENCODING_INT32) => {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(self.read_bytes(4)?);
+                i64::from(i32::from_le_bytes(bytes))
+            }
+            Length::Encoded(ENCODING_LZF) => {
+                let compressed = self.read_length()?;
+                let len = self.read_length()?;
+                return lzf::decompress(self.read_bytes(compressed)?, len);
+            }
+            Length::Encoded(_) => return None,
+        };
+        Some(number.to_string().into_bytes())
+    }
+}
+
+/// Adds a restored field unless its deadline already passed.
+fn insert_field(hash: &mut Hash, field: Vec<u8>, value: Vec<u8>, expiry: Option<i64>) {
+    if expiry.map_or(false, |expiry| expiry <= storage::unix_millis()) {
+        return;
+    }
+    hash.insert(field.clone(), value);
+    hash.set_expiry(&field, expiry);
+}
+
+fn non_empty_set(members: Vec<Vec<u8>>) -> Option<Data> {
+    if members.is_empty() {
+        return None;
+    }
+    Some(Data::Set(
+        members.into_iter().map(|member| (member, ())).collect(),
+    ))
+}
+
+/// Decodes an intset: the little-endian width of its integers and their
+/// count, then the integers themselves in ascending order.
+fn read_intset(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    if data.len() < 8 {
+        return None;
+    }
+    let width = usize::try_from(u32::from_le_bytes([data[0], data[1], data[2], data[3]])).ok()?;
+    let len = usize::try_from(u32::from_le_bytes([data[4], data[5], data[6], data[7]])).ok()?;
+    if ![2, 4, 8].contains(&width) || data.len() != 8 + width.checked_mul(len)? {
+        return None;
+    }
+    Some(
+        data[8..]
+            .chunks(width)
+            .map(|chunk| {
+                let raw = chunk
+                    .iter()
+                    .rev()
+                    .fold(0u64, |acc, &byte| acc << 8 | u64::from(byte));
+                sign_extend(raw as i64, width as u32 * 8)
+                    .to_string()
+                    .into_bytes()
+            })
+            .collect(),
+    )
+}
+
+fn non_empty_zset(zset: ZSet) -> Option<Data> {
+    if zset.is_empty() {
+        return None;
+    }
+    Some(Data::ZSet(zset))
+}
+
+fn stream_id_from_key(key: &[u8]) -> Option<StreamId> {
+    if key.len() != 16 {
+        return None;
+    }
+    let mut ms = [0; 8];
+    let mut seq = [0; 8];
+    ms.copy_from_slice(&key[..8]);
+    seq.copy_from_slice(&key[8..]);
+    Some(StreamId {
+        ms: u64::from_be_bytes(ms),
+        seq: u64::from_be_bytes(seq),
+    })
+}
+
+/// Appends the live entries of a stream node, laid out as described at
+/// [`stream_node`], to `stream`.
+fn read_stream_node(stream: &mut Stream, master: StreamId, items: &[Vec<u8>]) -> Option<()> {
+    let mut items = items.iter();
+    let live = next_integer(&mut items)?;
+    let deleted = next_integer(&mut items)?;
+    let master_fields = usize::try_from(next_integer(&mut items)?).ok()?;
+    let master_fields: Vec<_> = items.by_ref().take(master_fields).cloned().collect();
+    if next_integer(&mut items)? != 0 {
+        return None;
+    }
+    for _ in 0..live.checked_add(deleted)? {
+        let flags = next_integer(&mut items)?;
+        let id = StreamId {
+            ms: master.ms.wrapping_add(next_integer(&mut items)? as u64),
+            seq: master.seq.wrapping_add(next_integer(&mut items)? as u64),
+        };
+        let mut fields = vec![];
+        if flags & STREAM_ITEM_SAME_FIELDS != 0 {
+            for field in &master_fields {
+                fields.push((field.clone(), items.next()?.clone()));
+            }
+        } else {
+            for _ in 0..next_integer(&mut items)? {
+                fields.push((items.next()?.clone(), items.next()?.clone()));
+            }
+        }
+        // The element count used to walk the node backwards.
+        items.next()?;
+        if flags & STREAM_ITEM_DELETED == 0 {
+            // IDs ascend, and 0-0 is never one.
+            if id <= stream.last_id() {
+                return None;
+            }
+            stream.append(id, fields);
+        }
+    }
+    if items.next().is_some() {
+        return None;
+    }
+    Some(())
+}
+
+fn next_integer(items: &mut std::slice::Iter<'_, Vec<u8>>) -> Option<i64> {
+    std::str::from_utf8(items.next()?).ok()?.parse().ok()
+}
+
+/// Empty collections cannot exist, so a payload holding one is invalid.
+fn non_empty_hash(hash: Hash) -> Option<Data> {
+    if hash.is_empty() {
+        return None;
+    }
+    Some(Data::Hash(hash))
+}
+
+/// Decodes the elements of a listpack: a little-endian total size and
+/// element count, then entries each followed by their encoded length, then
+/// an end marker. Integers come back in their decimal string form.
+fn read_listpack(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    if data.len() < 7 {
+        return None;
+    }
+    let total = usize::try_from(u32::from_le_bytes([data[0], data[1], data[2], data[3]])).ok()?;
+    if total != data.len() || data.last() != Some(&0xff) {
+        return None;
+    }
+    let mut entries = vec![];
+    let mut i = 6;
+    while data[i] != 0xff {
+        let first = data[i];
+        let (header, len, value) = if first & 0x80 == 0 {
+            (1, 0, Some(i64::from(first & 0x7f)))
+        } else if first & 0xc0 == 0x80 {
+            (1, usize::from(first & 0x3f), None)
+        } else if first & 0xe0 == 0xc0 {
+            let raw = i64::from(first & 0x1f) << 8 | i64::from(*data.get(i + 1)?);
+            (2, 0, Some(sign_extend(raw, 13)))
+        } else if first & 0xf0 == 0xe0 {
+            (
+                2,
+                usize::from(first & 0x0f) << 8 | usize::from(*data.get(i + 1)?),
+                None,
+            )
+        } else {
+            let int = |width: usize| -> Option<i64> {
+                let bytes = data.get(i + 1..i + 1 + width)?;
+                let raw = bytes
+                    .iter()
+                    .rev()
+                    .fold(0u64, |acc, &byte| acc << 8 | u64::from(byte));
+                Some(sign_extend(raw as i64, width as u32 * 8))
+            };
+            match first {
+                0xf0 => {
+                    let len = usize::try_from(int(4)? as u32).ok()?;
+                    (5, len, None)
+                }
+                0xf1 => (3, 0, Some(int(2)?)),
+                0xf2 => (4, 0, Some(int(3)?)),
+                0xf3 => (5, 0, Some(int(4)?)),
+                0xf4 => (9, 0, Some(int(8)?)),
+                _ => return None,
+            }
+        };
+        let entry = match value {
+            Some(value) => value.to_string().into_bytes(),
+            None => data.get(i + header..i + header + len)?.to_vec(),
+        };
+        let encoded = header + len;
+        // The back length, a 7-bit-per-byte varint of the entry size.
+        let backlen = match encoded {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2_097_150 => 3,
+            2_097_151..=268_435_454 => 4,
+            _ => 5,
+        };
+        i += encoded + backlen;
+        if i >= data.len() {
+            return None;
+        }
+        entries.push(entry);
+    }
+    Some(entries)
+}
+
+/// Interprets the low `bits` bits of `raw` as a two's complement number.
+fn sign_extend(raw: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (raw << shift) >> shift
+}
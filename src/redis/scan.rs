@@ -0,0 +1,105 @@
+//! Cursor-based iteration shared by SCAN, HSCAN, SSCAN and ZSCAN.
+
+use super::command::Arguments;
+use super::glob;
+use super::storage::ValueType;
+use super::{Error, Value};
+use std::convert::TryInto;
+
+/// What a command of the SCAN family walks, which decides the options it
+/// accepts on top of MATCH and COUNT.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ScanTarget {
+    Keyspace,
+    Hash,
+    Set,
+    ZSet,
+}
+
+#[derive(Default)]
+pub struct ScanOptions {
+    pattern: Option<Vec<u8>>,
+    count: Option<usize>,
+    /// `Some(None)` for a type name Redis does not know, which matches nothing.
+    value_type: Option<Option<ValueType>>,
+    /// Set by HSCAN NOVALUES to report fields only.
+    no_values: bool,
+}
+
+pub fn parse_cursor(cursor: &[u8]) -> Result<u64, Error> {
+    std::str::from_utf8(cursor)
+        .ok()
+        .and_then(|cursor| cursor.parse::<u64>().ok())
+        .ok_or_else(|| Error::Argument("invalid cursor".to_owned()))
+}
+
+/// Reply shared by the SCAN family: the next cursor followed by the batch.
+pub fn scan_reply(cursor: u64, batch: Vec<Value>) -> Value {
+    Value::array(vec![
+        Value::String(cursor.to_string().into_bytes()),
+        Value::array(batch),
+    ])
+}
+
+impl ScanOptions {
+    /// Parses MATCH and COUNT, plus the options specific to `target`.
+    pub fn parse(args: &mut Arguments, target: ScanTarget) -> Result<ScanOptions, Error> {
+        let mut options = ScanOptions::default();
+        while !args.is_empty() {
+            match args.next_string()?.to_lowercase().as_str() {
+                "match" => options.pattern = Some(args.next_bytes()?),
+                "count" => {
+                    let count = args.next_int()?;
+                    if count < 1 {
+                        return Err(Error::Syntax);
+                    }
+                    options.count = Some(count.try_into()?);
+                }
+                "type" if target == ScanTarget::Keyspace => {
+                    let name = args.next_string()?.to_lowercase();
+                    options.value_type = Some(ValueType::from_name(&name));
+                }
+                "novalues" if target == ScanTarget::Hash => options.no_values = true,
+                _ => return Err(Error::Syntax),
+            }
+        }
+        Ok(options)
+    }
+
+    pub fn matches(&self, element: &[u8]) -> bool {
+        self.pattern
+            .as_ref()
+            .map_or(true, |pattern| glob::matches(pattern, element))
+    }
+
+    /// Whether keys of `value_type` pass the TYPE filter.
+    pub fn wants_type(&self, value_type: ValueType) -> bool {
+        self.value_type
+            .map_or(true, |wanted| wanted == Some(value_type))
+    }
+
+    /// Whether hash values are reported along with their fields.
+    pub fn values(&self) -> bool {
+        !self.no_values
+    }
+
+    /// Drives a bucket-at-a-time `step`, which counts the elements it visits,
+    /// until COUNT elements were visited, the iteration wrapped around, or
+    /// too many buckets were. Elements are counted whether they match or
+    /// not, and a hash field counts once however many reply items it makes.
+    pub fn scan<S>(&self, mut cursor: u64, mut step: S) -> u64
+    where
+        S: FnMut(u64, &mut usize) -> u64,
+    {
+        let count = self.count.unwrap_or(10);
+        let mut budget = count.saturating_mul(10);
+        let mut visited = 0;
+        loop {
+            cursor = step(cursor, &mut visited);
+            budget -= 1;
+            if cursor == 0 || visited >= count || budget == 0 {
+                return cursor;
+            }
+        }
+    }
+}
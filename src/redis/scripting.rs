@@ -0,0 +1,627 @@
+//! Lua scripts. EVAL runs a script with the store locked throughout, so no
+//! command of another connection comes in between the ones it calls.
+//!
+//! EVAL and SCRIPT LOAD cache scripts by the SHA1 of their source, which
+//! EVALSHA runs them by, and each run gets an interpreter of its own.
+//!
+//! Since the lock shuts out every other connection, a script that runs past
+//! the busy reply threshold has them answered BUSY instead of waiting, but
+//! for SCRIPT KILL, which stops it unless it wrote, and SHUTDOWN NOSAVE.
+
+use super::aof;
+use super::command::{self, Arguments, Command};
+use super::databases::DatabaseCommand;
+use super::lua::{self, Host, Interpreter, Table};
+use super::sha1;
+use super::storage::{self, Store};
+use super::{Error, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The name scripts go by in error messages.
+const CHUNK: &str = "user_script";
+
+/// Scripts run by the `_RO` variants may only call commands that do not
+/// write.
+pub enum ScriptCommand {
+    Eval {
+        source: Vec<u8>,
+        inputs: Inputs,
+        read_only: bool,
+    },
+    EvalSha {
+        sha: String,
+        inputs: Inputs,
+        read_only: bool,
+    },
+    Load(Vec<u8>),
+    Exists(Vec<String>),
+    /// Empties the cache, freeing it in the background if set.
+    Flush(bool),
+    Kill,
+    Help,
+}
+
+/// What a script runs on: the keys it names and further arguments.
+pub struct Inputs {
+    pub keys: Vec<Vec<u8>>,
+    pub args: Vec<Vec<u8>>,
+}
+
+impl Inputs {
+    /// Parses a `numkeys` count followed by the keys and the arguments.
+    pub fn parse(args: &mut Arguments) -> Result<Inputs, Error> {
+        let count = args.next_int()?;
+        if count < 0 {
+            return Err(Error::Argument(
+                "Number of keys can't be negative".to_owned(),
+            ));
+        }
+        if count > args.len() as i64 {
+            return Err(Error::Argument(
+                "Number of keys can't be greater than number of args".to_owned(),
+            ));
+        }
+        let keys = (0..count)
+            .map(|_| args.next_bytes())
+            .collect::<Result<_, _>>()?;
+        Ok(Inputs {
+            keys,
+            args: args.rest()?,
+        })
+    }
+}
+
+impl ScriptCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<ScriptCommand>, Error> {
+        let command = match name {
+            "eval" | "eval_ro" => {
+                let source = args.next_bytes()?;
+                let inputs = Inputs::parse(args)?;
+                ScriptCommand::Eval {
+                    source,
+                    inputs,
+                    read_only: name == "eval_ro",
+                }
+            }
+            "evalsha" | "evalsha_ro" => {
+                let sha = args.next_string()?.to_lowercase();
+                let inputs = Inputs::parse(args)?;
+                ScriptCommand::EvalSha {
+                    sha,
+                    inputs,
+                    read_only: name == "evalsha_ro",
+                }
+            }
+            "script" => ScriptCommand::manage(args)?,
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    fn manage(args: &mut Arguments) -> Result<ScriptCommand, Error> {
+        let subcommand = args.next_string()?.to_lowercase();
+        let wrong_arity = || Error::WrongArity(format!("script|{}", subcommand));
+        let command = match subcommand.as_str() {
+            "load" => {
+                let source = args.next_bytes().map_err(|_| wrong_arity())?;
+                args.finish().map_err(|_| wrong_arity())?;
+                ScriptCommand::Load(source)
+            }
+            "exists" => {
+                if args.is_empty() {
+                    return Err(wrong_arity());
+                }
+                let shas = args.rest()?;
+                let shas = shas
+                    .iter()
+                    .map(|sha| String::from_utf8_lossy(sha).to_lowercase());
+                ScriptCommand::Exists(shas.collect())
+            }
+            "flush" => ScriptCommand::Flush(DatabaseCommand::flush_mode(args)?),
+            "kill" => {
+                args.finish().map_err(|_| wrong_arity())?;
+                ScriptCommand::Kill
+            }
+            "help" => {
+                args.finish().map_err(|_| wrong_arity())?;
+                ScriptCommand::Help
+            }
+            _ => {
+                return Err(Error::Argument(format!(
+                    "unknown subcommand '{}'. Try SCRIPT HELP.",
+                    subcommand
+                )))
+            }
+        };
+        Ok(command)
+    }
+
+    pub fn execute(self, store: &mut Store, db: &mut usize) -> Result<Value, Error> {
+        let (sha, script, inputs, read_only) = match self {
+            ScriptCommand::Load(source) => {
+                let sha = sha1::hex(&source);
+                store.scripts().load(&sha, &source)?;
+                return Ok(Value::String(sha.into_bytes()));
+            }
+            ScriptCommand::Exists(shas) => {
+                let scripts = store.scripts();
+                let exists = shas
+                    .iter()
+                    .map(|sha| Value::Int(scripts.exists(sha) as i64));
+                return Ok(Value::array(exists.collect()));
+            }
+            ScriptCommand::Flush(lazily) => {
+                let scripts = std::mem::take(store.scripts());
+                if lazily {
+                    tokio::task::spawn_blocking(move || drop(scripts));
+                }
+                return Ok(Value::ok());
+            }
+            ScriptCommand::Kill => return kill(),
+            ScriptCommand::Help => return Ok(help()),
+            ScriptCommand::Eval {
+                source,
+                inputs,
+                read_only,
+            } => {
+                let sha = sha1::hex(&source);
+                let script = store.scripts().load(&sha, &source)?;
+                (sha, script, inputs, read_only)
+            }
+            ScriptCommand::EvalSha {
+                sha,
+                inputs,
+                read_only,
+            } => match store.scripts().get(&sha) {
+                Some(script) => (sha, script, inputs, read_only),
+                None => return Err(Error::NoScript),
+            },
+        };
+        let host = ScriptHost::new(store, *db, read_only);
+        run(&script, &sha, host, inputs)
+    }
+}
+
+/// The scripts compiled so far, by SHA1.
+#[derive(Default)]
+pub struct Scripts {
+    scripts: HashMap<String, Arc<lua::Chunk>>,
+}
+
+impl Scripts {
+    pub fn get(&self, sha: &str) -> Option<Arc<lua::Chunk>> {
+        self.scripts.get(sha).cloned()
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        self.scripts.contains_key(sha)
+    }
+
+    /// The compiled script, compiling and caching it if it is new.
+    pub fn load(&mut self, sha: &str, source: &[u8]) -> Result<Arc<lua::Chunk>, Error> {
+        if let Some(script) = self.get(sha) {
+            return Ok(script);
+        }
+        let chunk = lua::parse(source, CHUNK).map_err(|message| {
+            Error::Argument(format!(
+                "Error compiling script (new function): {}",
+                message
+            ))
+        })?;
+        let script = Arc::new(chunk);
+        self.scripts.insert(sha.to_owned(), script.clone());
+        Ok(script)
+    }
+}
+
+/// The script running, if any, as connections waiting on the store lock it
+/// holds see it.
+struct Running {
+    /// When it started, in unix milliseconds, or 0 with none running.
+    started: AtomicI64,
+    /// Whether it ran a write command, which makes it unkillable.
+    wrote: AtomicBool,
+    killed: AtomicBool,
+}
+
+static RUNNING: Running = Running {
+    started: AtomicI64::new(0),
+    wrote: AtomicBool::new(false),
+    killed: AtomicBool::new(false),
+};
+
+/// Runs a script with `run`, letting other connections know it runs.
+pub fn running<T>(run: impl FnOnce() -> T) -> T {
+    RUNNING.wrote.store(false, Ordering::SeqCst);
+    RUNNING.killed.store(false, Ordering::SeqCst);
+    RUNNING
+        .started
+        .store(storage::unix_millis().max(1), Ordering::SeqCst);
+    let result = run();
+    RUNNING.started.store(0, Ordering::SeqCst);
+    result
+}
+
+/// Whether a script has been running for longer than `threshold`.
+pub fn busy(threshold: Duration) -> bool {
+    let started = RUNNING.started.load(Ordering::SeqCst);
+    started != 0 && storage::unix_millis() - started >= threshold.as_millis() as i64
+}
+
+/// Stops the running script at its next step, for SCRIPT KILL.
+pub fn kill() -> Result<Value, Error> {
+    if RUNNING.started.load(Ordering::SeqCst) == 0 {
+        return Err(Error::NotBusy);
+    }
+    if RUNNING.wrote.load(Ordering::SeqCst) {
+        return Err(Error::Unkillable);
+    }
+    RUNNING.killed.store(true, Ordering::SeqCst);
+    Ok(Value::ok())
+}
+
+fn help() -> Value {
+    Value::array(
+        [
+            "SCRIPT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+            "EXISTS <sha1> [<sha1> ...]",
+            "    Return information about the existence of the scripts in the script cache.",
+            "FLUSH [ASYNC|SYNC]",
+            "    Flush the Lua scripts cache. Very dangerous on replicas.",
+            "    Valid modes are:",
+            "    * ASYNC: Asynchronously flush the scripts cache.",
+            "    * SYNC: Synchronously flush the scripts cache.",
+            "LOAD <script>",
+            "    Load a script into the scripts cache without executing it.",
+            "KILL",
+            "    Kill the currently executing Lua script.",
+            "HELP",
+            "    Print this help.",
+        ]
+        .iter()
+        .map(|line| Value::Status((*line).to_owned()))
+        .collect(),
+    )
+}
+
+fn run(
+    script: &lua::Chunk,
+    sha: &str,
+    mut host: ScriptHost,
+    inputs: Inputs,
+) -> Result<Value, Error> {
+    let mut interpreter = Interpreter::new(CHUNK);
+    interpreter.set_host(&mut host);
+    let keys = strings(&mut interpreter, inputs.keys);
+    let args = strings(&mut interpreter, inputs.args);
+    open_redis(&mut interpreter);
+    {
+        let mut globals = interpreter.globals.borrow_mut();
+        globals.set_str("KEYS", keys);
+        globals.set_str("ARGV", args);
+    }
+    protect_globals(&mut interpreter);
+
+    match running(|| interpreter.run(script, vec![])) {
+        Ok(values) => Ok(to_reply(values.first().unwrap_or(&lua::Value::Nil), 0)),
+        Err(error) => Err(Error::Script(error_message(&error, sha, CHUNK))),
+    }
+}
+
+pub fn strings(interpreter: &mut Interpreter, values: Vec<Vec<u8>>) -> lua::Value {
+    let values = values
+        .iter()
+        .map(|value| lua::Value::string(value))
+        .collect();
+    interpreter.new_table(Table::array(values))
+}
+
+/// Keeps scripts from defining globals or changing the libraries, as Redis
+/// does, and from reading undefined globals, which is usually a typo.
+pub fn protect_globals(interpreter: &mut Interpreter) {
+    let mut metatable = Table::new();
+    metatable.set_str(
+        "__index",
+        lua::Value::builtin(|interpreter, args| {
+            let name = match args.get(1) {
+                Some(lua::Value::String(name)) => String::from_utf8_lossy(name).into_owned(),
+                _ => String::new(),
+            };
+            Err(interpreter.error(format!(
+                "Script attempted to access nonexistent global variable '{}'",
+                name
+            )))
+        }),
+    );
+    metatable.readonly = true;
+    let metatable = match interpreter.new_table(metatable) {
+        lua::Value::Table(metatable) => metatable,
+        _ => unreachable!(),
+    };
+    let mut globals = interpreter.globals.borrow_mut();
+    for name in &["string", "table", "math", "redis"] {
+        if let lua::Value::Table(library) = globals.get_str(name) {
+            library.borrow_mut().readonly = true;
+        }
+    }
+    interpreter.strings.borrow_mut().readonly = true;
+    globals.metatable = Some(metatable);
+    globals.readonly = true;
+}
+
+/// A table describing an error reply, as `redis.pcall` returns them.
+fn error_table(interpreter: &mut Interpreter, message: &str) -> lua::Value {
+    let mut table = Table::new();
+    table.set_str("err", lua::Value::string(message.as_bytes()));
+    interpreter.new_table(table)
+}
+
+pub fn open_redis(interpreter: &mut Interpreter) {
+    let mut redis = Table::new();
+    let mut register = |name: &str, builtin: lua::Value| redis.set_str(name, builtin);
+    register(
+        "call",
+        lua::Value::builtin(|interpreter, args| Ok(vec![interpreter.call_host(args, false)?])),
+    );
+    register(
+        "pcall",
+        lua::Value::builtin(|interpreter, args| Ok(vec![interpreter.call_host(args, true)?])),
+    );
+    register(
+        "status_reply",
+        lua::Value::builtin(|interpreter, args| {
+            let mut table = Table::new();
+            table.set_str("ok", reply_argument(interpreter, &args, "status_reply")?);
+            Ok(vec![interpreter.new_table(table)])
+        }),
+    );
+    register(
+        "error_reply",
+        lua::Value::builtin(|interpreter, args| {
+            let mut table = Table::new();
+            table.set_str("err", reply_argument(interpreter, &args, "error_reply")?);
+            Ok(vec![interpreter.new_table(table)])
+        }),
+    );
+    register(
+        "sha1hex",
+        lua::Value::builtin(|interpreter, args| {
+            match args.first().and_then(lua::Value::to_bytes) {
+                Some(data) if args.len() == 1 => {
+                    Ok(vec![lua::Value::string(sha1::hex(&data).as_bytes())])
+                }
+                _ => Err(interpreter.error("wrong number of arguments")),
+            }
+        }),
+    );
+    register(
+        "log",
+        lua::Value::builtin(|interpreter, args| {
+            if args.len() < 2 {
+                return Err(interpreter.error("redis.log() requires two arguments or more."));
+            }
+            if !matches!(args[0], lua::Value::Number(_)) {
+                return Err(interpreter.error("First argument must be a number (log level)."));
+            }
+            let mut message = vec![];
+            for (i, value) in args[1..].iter().enumerate() {
+                if i > 0 {
+                    message.push(b' ');
+                }
+                message.extend_from_slice(&value.to_bytes().unwrap_or_default());
+            }
+            eprintln!("{}", String::from_utf8_lossy(&message));
+            Ok(vec![])
+        }),
+    );
+    register(
+        "replicate_commands",
+        lua::Value::builtin(|_, _| Ok(vec![lua::Value::Boolean(true)])),
+    );
+    let levels = [
+        ("LOG_DEBUG", 0.0),
+        ("LOG_VERBOSE", 1.0),
+        ("LOG_NOTICE", 2.0),
+        ("LOG_WARNING", 3.0),
+    ];
+    for (name, level) in levels.iter() {
+        redis.set_str(name, lua::Value::Number(*level));
+    }
+    let redis = interpreter.new_table(redis);
+    interpreter.globals.borrow_mut().set_str("redis", redis);
+}
+
+fn reply_argument(
+    interpreter: &Interpreter,
+    args: &[lua::Value],
+    name: &str,
+) -> Result<lua::Value, lua::Error> {
+    match args.first() {
+        Some(value @ lua::Value::String(_)) if args.len() == 1 => Ok(value.clone()),
+        _ => Err(interpreter.error(format!("wrong number or type of arguments to '{}'", name))),
+    }
+}
+
+/// Runs the commands a script calls, against the store the script was
+/// started with.
+pub struct ScriptHost<'a> {
+    store: &'a mut Store,
+    /// SELECT in a script changes the database only for the rest of it.
+    db: usize,
+    read_only: bool,
+}
+
+impl<'a> ScriptHost<'a> {
+    pub fn new(store: &'a mut Store, db: usize, read_only: bool) -> ScriptHost<'a> {
+        ScriptHost {
+            store,
+            db,
+            read_only,
+        }
+    }
+
+    fn command(&mut self, args: Vec<lua::Value>) -> Result<Value, String> {
+        if args.is_empty() {
+            return Err(
+                "ERR Please specify at least one argument for this redis lib call".to_owned(),
+            );
+        }
+        let name = match &args[0] {
+            lua::Value::String(name) => String::from_utf8_lossy(name).to_lowercase(),
+            _ => String::new(),
+        };
+        let mut message = Vec::with_capacity(args.len());
+        for arg in args {
+            let arg = match arg {
+                lua::Value::String(data) => data.to_vec(),
+                lua::Value::Number(n) => lua::format_g(n, 17, false, false).into_bytes(),
+                _ => {
+                    return Err(
+                        "ERR Lua redis lib command arguments must be strings or integers"
+                            .to_owned(),
+                    )
+                }
+            };
+            message.push(Value::String(arg));
+        }
+        let message = Value::array(message);
+        let arguments = if self.store.persistence().appendonly() && command::is_write(&name) {
+            Some(aof::arguments(&message))
+        } else {
+            None
+        };
+        let command = Command::from_value(message).map_err(|e| match e {
+            Error::UnknownCommand(_) => "ERR Unknown Redis command called from script".to_owned(),
+            e => e.to_string(),
+        })?;
+        if !command.allowed_in_script() {
+            return Err("ERR This Redis command is not allowed from script".to_owned());
+        }
+        if command::is_write(&name) {
+            if self.read_only {
+                return Err("ERR Write commands are not allowed from read-only scripts.".to_owned());
+            }
+            RUNNING.wrote.store(true, Ordering::SeqCst);
+        }
+        aof::execute(self.store, &mut self.db, command, arguments).map_err(|e| e.to_string())
+    }
+}
+
+impl<'a> Host for ScriptHost<'a> {
+    fn call(
+        &mut self,
+        interpreter: &mut Interpreter,
+        args: Vec<lua::Value>,
+        protected: bool,
+    ) -> Result<lua::Value, lua::Error> {
+        match self.command(args) {
+            Ok(reply) => Ok(to_lua(interpreter, reply)),
+            Err(message) => {
+                let error = error_table(interpreter, &message);
+                if protected {
+                    Ok(error)
+                } else {
+                    Err(interpreter.raise(error))
+                }
+            }
+        }
+    }
+
+    fn interrupt(&mut self) -> Option<lua::Value> {
+        if RUNNING.killed.load(Ordering::SeqCst) {
+            Some(lua::Value::string(
+                b"Script killed by user with SCRIPT KILL...",
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// A reply as scripts see it: nil replies become false, and status and
+/// error replies tables with an `ok` or `err` field.
+fn to_lua(interpreter: &mut Interpreter, reply: Value) -> lua::Value {
+    match reply {
+        Value::Nil | Value::NilArray => lua::Value::Boolean(false),
+        Value::Int(n) => lua::Value::Number(n as f64),
+        Value::String(data) => lua::Value::string(&data),
+        Value::Status(status) => {
+            let mut table = Table::new();
+            table.set_str("ok", lua::Value::string(status.as_bytes()));
+            interpreter.new_table(table)
+        }
+        Value::Error(message) => error_table(interpreter, &message),
+        Value::Array(_, values) => {
+            let values = values
+                .into_iter()
+                .map(|value| to_lua(interpreter, value))
+                .collect();
+            interpreter.new_table(Table::array(values))
+        }
+        Value::Map(entries) => {
+            let mut values = Vec::with_capacity(entries.len() * 2);
+            for (key, value) in entries {
+                values.push(to_lua(interpreter, key));
+                values.push(to_lua(interpreter, value));
+            }
+            interpreter.new_table(Table::array(values))
+        }
+    }
+}
+
+/// How deeply the tables a script returns may nest, as the Lua stack that
+/// Redis converts them on limits it.
+const MAX_REPLY_DEPTH: usize = 8000;
+
+/// What a script returns as a reply: numbers are truncated to integers,
+/// true is 1, and tables are arrays up to their first nil unless they have
+/// an `ok` or `err` field.
+pub fn to_reply(value: &lua::Value, depth: usize) -> Value {
+    match value {
+        lua::Value::Nil | lua::Value::Boolean(false) => Value::Nil,
+        lua::Value::Boolean(true) => Value::Int(1),
+        lua::Value::Number(n) => Value::Int(*n as i64),
+        lua::Value::String(data) => Value::String(data.to_vec()),
+        lua::Value::Table(_) if depth >= MAX_REPLY_DEPTH => {
+            Value::Error("ERR reached lua stack limit".to_owned())
+        }
+        lua::Value::Table(table) => {
+            let table = table.borrow();
+            if let lua::Value::String(message) = table.get_str("err") {
+                return Value::Error(String::from_utf8_lossy(&message).into_owned());
+            }
+            if let lua::Value::String(status) = table.get_str("ok") {
+                return Value::Status(String::from_utf8_lossy(&status).into_owned());
+            }
+            let mut values = vec![];
+            loop {
+                let value = table.get(&lua::Value::Number((values.len() + 1) as f64));
+                if value.is_nil() {
+                    break;
+                }
+                values.push(to_reply(&value, depth + 1));
+            }
+            Value::array(values)
+        }
+        lua::Value::Function(_) => Value::Nil,
+    }
+}
+
+/// The reply for an error a script raised, saying where in which script,
+/// named by its SHA1 or function name, it was raised.
+pub fn error_message(error: &lua::Error, script: &str, chunk: &str) -> String {
+    let message = match &error.value {
+        lua::Value::Table(table) => match table.borrow().get_str("err") {
+            lua::Value::String(message) => String::from_utf8_lossy(&message).into_owned(),
+            _ => "ERR unknown error".to_owned(),
+        },
+        value => format!("ERR {}", String::from_utf8_lossy(&value.to_display())),
+    };
+    format!(
+        "{} script: {}, on @{}:{}.",
+        message, script, chunk, error.line
+    )
+}
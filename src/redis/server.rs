@@ -0,0 +1,151 @@
+//! Introspection and administration of the server as a whole.
+
+use super::command::Arguments;
+use super::persistence;
+use super::stats::{self, STATS};
+use super::storage::Store;
+use super::{Error, Value};
+
+pub enum ServerCommand {
+    Info(Vec<String>),
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    /// Exits the process, saving first if SAVE is given or, failing NOSAVE,
+    /// if there are save rules. NOSAVE also works while a script is busy.
+    Shutdown {
+        save: Option<bool>,
+    },
+}
+
+impl ServerCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<ServerCommand>, Error> {
+        let command = match name {
+            "info" => {
+                let mut sections = vec![];
+                while !args.is_empty() {
+                    sections.push(args.next_string()?.to_lowercase());
+                }
+                ServerCommand::Info(sections)
+            }
+            "save" => {
+                args.finish()?;
+                ServerCommand::Save
+            }
+            "bgsave" => {
+                args.finish()?;
+                ServerCommand::BgSave
+            }
+            "lastsave" => {
+                args.finish()?;
+                ServerCommand::LastSave
+            }
+            "bgrewriteaof" => {
+                args.finish()?;
+                ServerCommand::BgRewriteAof
+            }
+            "shutdown" => {
+                let mut save = None;
+                while !args.is_empty() {
+                    match args.next_string()?.to_lowercase().as_str() {
+                        "nosave" => save = Some(false),
+                        "save" => save = Some(true),
+                        "now" | "force" => {}
+                        _ => return Err(Error::Syntax),
+                    }
+                }
+                ServerCommand::Shutdown { save }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    pub fn execute(self, store: &mut Store) -> Result<Value, Error> {
+        let response = match self {
+            ServerCommand::Info(sections) => Value::String(info(store, &sections).into_bytes()),
+            ServerCommand::Save => {
+                persistence::save(store)?;
+                Value::ok()
+            }
+            ServerCommand::BgSave => {
+                persistence::background_save(store)?;
+                Value::Status("Background saving started".to_owned())
+            }
+            ServerCommand::LastSave => Value::Int(store.persistence().last_save()),
+            ServerCommand::BgRewriteAof => {
+                persistence::background_rewrite(store)?;
+                Value::Status("Background append only file rewriting started".to_owned())
+            }
+            ServerCommand::Shutdown { save } => {
+                let save = save.unwrap_or_else(|| store.persistence().has_rules());
+                if save && persistence::save(store).is_err() {
+                    return Err(Error::Argument(
+                        "Errors trying to SHUTDOWN. Check logs.".to_owned(),
+                    ));
+                }
+                shutdown()
+            }
+        };
+        Ok(response)
+    }
+}
+
+pub fn shutdown() -> ! {
+    std::process::exit(0)
+}
+
+/// Renders the requested INFO sections; no sections, `default`, `all` and
+/// `everything` select all of them.
+fn info(store: &mut Store, sections: &[String]) -> String {
+    let all = sections.is_empty()
+        || sections
+            .iter()
+            .any(|section| ["default", "all", "everything"].contains(&section.as_str()));
+    let wanted = |name: &str| all || sections.iter().any(|section| section == name);
+
+    let mut out = String::new();
+    if wanted("persistence") {
+        out.push_str("# Persistence\r\n");
+        for (name, value) in store.persistence().info() {
+            out.push_str(&format!("{}:{}\r\n", name, value));
+        }
+    }
+    if wanted("stats") {
+        if !out.is_empty() {
+            out.push_str("\r\n");
+        }
+        out.push_str("# Stats\r\n");
+        let counters = [
+            ("keyspace_hits", &STATS.keyspace_hits),
+            ("keyspace_misses", &STATS.keyspace_misses),
+            ("expired_keys", &STATS.expired_keys),
+            ("expired_subkeys", &STATS.expired_subkeys),
+            ("evicted_keys", &STATS.evicted_keys),
+        ];
+        for (name, counter) in counters.iter() {
+            out.push_str(&format!("{}:{}\r\n", name, stats::read(counter)));
+        }
+    }
+    if wanted("keyspace") {
+        if !out.is_empty() {
+            out.push_str("\r\n");
+        }
+        out.push_str("# Keyspace\r\n");
+        for (index, database) in store.databases().iter().enumerate() {
+            if database.is_empty() {
+                continue;
+            }
+            let (expires, average_ttl) = database.expires();
+            out.push_str(&format!(
+                "db{}:keys={},expires={},avg_ttl={}\r\n",
+                index,
+                database.len(),
+                expires,
+                average_ttl
+            ));
+        }
+    }
+    out
+}
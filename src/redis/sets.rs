@@ -0,0 +1,407 @@
+use super::command::Arguments;
+use super::dict::Dict;
+use super::notify::Class;
+use super::scan::{self, ScanOptions, ScanTarget};
+use super::storage::{Data, Database, StoredValue};
+use super::{Error, Value};
+use std::convert::TryInto;
+
+/// The members of a set, each mapped to nothing.
+pub type Set = Dict<Vec<u8>, ()>;
+
+pub enum SetCommand {
+    Add(Vec<u8>, Vec<Vec<u8>>),
+    Remove(Vec<u8>, Vec<Vec<u8>>),
+    IsMember(Vec<u8>, Vec<u8>),
+    MIsMember(Vec<u8>, Vec<Vec<u8>>),
+    Card(Vec<u8>),
+    Members(Vec<u8>),
+    Move(Vec<u8>, Vec<u8>, Vec<u8>),
+    Pop(Vec<u8>, Option<usize>),
+    Scan(Vec<u8>, u64, ScanOptions),
+    RandomMember(Vec<u8>, Option<i64>),
+    Combine(Operation, Vec<Vec<u8>>),
+    Store(Operation, Vec<u8>, Vec<Vec<u8>>),
+    /// The size of the intersection, counted up to a limit when it is set.
+    InterCard(Vec<Vec<u8>>, Option<usize>),
+}
+
+/// A set algebra operation, shared with sorted sets.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Inter,
+    Union,
+    Diff,
+}
+
+impl SetCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<SetCommand>, Error> {
+        let command = match name {
+            "sadd" | "srem" => {
+                let key = args.next_bytes()?;
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                let members = args.rest()?;
+                if name == "sadd" {
+                    SetCommand::Add(key, members)
+                } else {
+                    SetCommand::Remove(key, members)
+                }
+            }
+            "sismember" => {
+                let key = args.next_bytes()?;
+                let member = args.next_bytes()?;
+                args.finish()?;
+                SetCommand::IsMember(key, member)
+            }
+            "smismember" => {
+                let key = args.next_bytes()?;
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                SetCommand::MIsMember(key, args.rest()?)
+            }
+            "scard" | "smembers" => {
+                let key = args.next_bytes()?;
+                args.finish()?;
+                if name == "scard" {
+                    SetCommand::Card(key)
+                } else {
+                    SetCommand::Members(key)
+                }
+            }
+            "smove" => {
+                let source = args.next_bytes()?;
+                let destination = args.next_bytes()?;
+                let member = args.next_bytes()?;
+                args.finish()?;
+                SetCommand::Move(source, destination, member)
+            }
+            "spop" | "srandmember" => {
+                let key = args.next_bytes()?;
+                let count = if args.is_empty() {
+                    None
+                } else {
+                    Some(args.next_int()?)
+                };
+                args.finish()?;
+                if name == "srandmember" {
+                    SetCommand::RandomMember(key, count)
+                } else {
+                    let count = count
+                        .map(|count| {
+                            count.try_into().map_err(|_| {
+                                Error::Argument(
+                                    "value is out of range, must be positive".to_owned(),
+                                )
+                            })
+                        })
+                        .transpose()?;
+                    SetCommand::Pop(key, count)
+                }
+            }
+            "sscan" => {
+                let key = args.next_bytes()?;
+                let cursor = scan::parse_cursor(&args.next_bytes()?)?;
+                let options = ScanOptions::parse(args, ScanTarget::Set)?;
+                SetCommand::Scan(key, cursor, options)
+            }
+            "sinter" | "sunion" | "sdiff" => {
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                SetCommand::Combine(Operation::from_name(name), args.rest()?)
+            }
+            "sinterstore" | "sunionstore" | "sdiffstore" => {
+                let destination = args.next_bytes()?;
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                let operation = Operation::from_name(name.trim_end_matches("store"));
+                SetCommand::Store(operation, destination, args.rest()?)
+            }
+            "sintercard" => {
+                let keys = args.next_counted_keys()?;
+                let mut limit = None;
+                while !args.is_empty() {
+                    if !args.next_string()?.eq_ignore_ascii_case("limit") || args.is_empty() {
+                        return Err(Error::Syntax);
+                    }
+                    let n: usize = args
+                        .next_int()?
+                        .try_into()
+                        .map_err(|_| Error::Argument("LIMIT can't be negative".to_owned()))?;
+                    limit = Some(n).filter(|&n| n > 0);
+                }
+                SetCommand::InterCard(keys, limit)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    pub fn execute(self, storage: &mut Database) -> Result<Value, Error> {
+        let response = match self {
+            SetCommand::Add(key, members) => {
+                let set = storage
+                    .get_or_insert_with(&key, || Data::Set(Dict::new()))
+                    .set_mut()?;
+                let added = members
+                    .into_iter()
+                    .filter(|member| set.insert(member.clone(), ()).is_none())
+                    .count();
+                if added > 0 {
+                    storage.notify(Class::Set, "sadd", &key);
+                }
+                Value::Int(added.try_into()?)
+            }
+            SetCommand::Remove(key, members) => {
+                let set = match storage.get_mut(&key) {
+                    Some(stored) => stored.set_mut()?,
+                    None => return Ok(Value::Int(0)),
+                };
+                let removed = members
+                    .iter()
+                    .filter(|member| set.remove(member.as_slice()).is_some())
+                    .count();
+                if removed > 0 {
+                    storage.notify(Class::Set, "srem", &key);
+                }
+                storage.remove_if_empty(&key);
+                Value::Int(removed.try_into()?)
+            }
+            SetCommand::IsMember(key, member) => match storage.get(&key) {
+                Some(stored) => Value::Int(stored.set()?.get(&member).is_some() as i64),
+                None => Value::Int(0),
+            },
+            SetCommand::MIsMember(key, members) => {
+                let set = match storage.get(&key) {
+                    Some(stored) => Some(stored.set()?),
+                    None => None,
+                };
+                Value::array(
+                    members
+                        .iter()
+                        .map(|member| {
+                            let found = set.map_or(false, |set| set.get(member).is_some());
+                            Value::Int(found as i64)
+                        })
+                        .collect(),
+                )
+            }
+            SetCommand::Card(key) => match storage.get(&key) {
+                Some(stored) => Value::Int(stored.set()?.len().try_into()?),
+                None => Value::Int(0),
+            },
+            SetCommand::Members(key) => match storage.get(&key) {
+                Some(stored) => Value::array(
+                    stored
+                        .set()?
+                        .iter()
+                        .map(|(member, _)| Value::String(member.clone()))
+                        .collect(),
+                ),
+                None => Value::array(vec![]),
+            },
+            SetCommand::Move(source, destination, member) => {
+                // Both keys are checked before anything moves.
+                if let Some(stored) = storage.get(&destination) {
+                    stored.set()?;
+                }
+                let set = match storage.get_mut(&source) {
+                    Some(stored) => stored.set_mut()?,
+                    None => return Ok(Value::Int(0)),
+                };
+                if source == destination {
+                    return Ok(Value::Int(set.get(&member).is_some() as i64));
+                }
+                if set.remove(member.as_slice()).is_none() {
+                    return Ok(Value::Int(0));
+                }
+                storage.notify(Class::Set, "srem", &source);
+                storage.remove_if_empty(&source);
+                let added = storage
+                    .get_or_insert_with(&destination, || Data::Set(Dict::new()))
+                    .set_mut()?
+                    .insert(member, ())
+                    .is_none();
+                if added {
+                    storage.notify(Class::Set, "sadd", &destination);
+                }
+                Value::Int(1)
+            }
+            SetCommand::Pop(key, count) => {
+                let set = match storage.get_mut(&key) {
+                    Some(stored) => stored.set_mut()?,
+                    None if count.is_some() => return Ok(Value::array(vec![])),
+                    None => return Ok(Value::Nil),
+                };
+                let members: Vec<_> = set
+                    .sample(count.unwrap_or(1))
+                    .into_iter()
+                    .map(|(member, _)| member.clone())
+                    .collect();
+                for member in &members {
+                    set.remove(member.as_slice());
+                }
+                if !members.is_empty() {
+                    storage.notify(Class::Set, "spop", &key);
+                }
+                storage.remove_if_empty(&key);
+                let mut members = members.into_iter().map(Value::String);
+                match count {
+                    Some(_) => Value::array(members.collect()),
+                    None => members.next().unwrap_or(Value::Nil),
+                }
+            }
+            SetCommand::RandomMember(key, count) => {
+                let set = match storage.get(&key) {
+                    Some(stored) => stored.set()?,
+                    None if count.is_some() => return Ok(Value::array(vec![])),
+                    None => return Ok(Value::Nil),
+                };
+                let count = match count {
+                    Some(count) => count,
+                    None => {
+                        return Ok(set
+                            .random_entry()
+                            .map_or(Value::Nil, |(member, _)| Value::String(member.clone())))
+                    }
+                };
+                // A negative count may repeat members, a positive one may not.
+                let members = if count >= 0 {
+                    set.sample(count.try_into()?)
+                } else {
+                    let count = count.unsigned_abs().try_into()?;
+                    (0..count).filter_map(|_| set.random_entry()).collect()
+                };
+                Value::array(
+                    members
+                        .into_iter()
+                        .map(|(member, _)| Value::String(member.clone()))
+                        .collect(),
+                )
+            }
+            SetCommand::Scan(key, cursor, options) => {
+                let set = match storage.get(&key) {
+                    Some(stored) => stored.set()?,
+                    None => return Ok(scan::scan_reply(0, vec![])),
+                };
+                let mut batch = vec![];
+                let cursor = options.scan(cursor, |cursor, visited| {
+                    set.scan(cursor, |member, _| {
+                        *visited += 1;
+                        if options.matches(member) {
+                            batch.push(Value::String(member.clone()));
+                        }
+                    })
+                });
+                scan::scan_reply(cursor, batch)
+            }
+            SetCommand::Combine(operation, keys) => Value::array(
+                combine(storage, operation, &keys)?
+                    .iter()
+                    .map(|(member, _)| Value::String(member.clone()))
+                    .collect(),
+            ),
+            SetCommand::Store(operation, destination, keys) => {
+                let result = combine(storage, operation, &keys)?;
+                let len = result.len().try_into()?;
+                if result.is_empty() {
+                    if storage.remove(&destination).is_some() {
+                        storage.notify(Class::Generic, "del", &destination);
+                    }
+                } else {
+                    storage.notify(Class::Set, operation.store_event(), &destination);
+                    storage.insert(destination, StoredValue::new(Data::Set(result), None));
+                }
+                Value::Int(len)
+            }
+            SetCommand::InterCard(keys, limit) => {
+                let mut sets: Vec<_> = match lookup(storage, &keys)?.into_iter().collect() {
+                    Some(sets) => sets,
+                    None => return Ok(Value::Int(0)),
+                };
+                sets.sort_by_key(|set| set.len());
+                let (smallest, others) = sets.split_first().expect("at least one key");
+                let limit = limit.unwrap_or(usize::MAX);
+                let count = smallest
+                    .iter()
+                    .filter(|(member, _)| others.iter().all(|set| set.get(*member).is_some()))
+                    .take(limit)
+                    .count();
+                Value::Int(count.try_into()?)
+            }
+        };
+        Ok(response)
+    }
+}
+
+impl Operation {
+    fn from_name(name: &str) -> Operation {
+        match name {
+            "sinter" => Operation::Inter,
+            "sunion" => Operation::Union,
+            _ => Operation::Diff,
+        }
+    }
+
+    fn store_event(self) -> &'static str {
+        match self {
+            Operation::Inter => "sinterstore",
+            Operation::Union => "sunionstore",
+            Operation::Diff => "sdiffstore",
+        }
+    }
+}
+
+/// The sets stored at `keys`, with missing keys as None.
+fn lookup<'a>(storage: &'a Database, keys: &[Vec<u8>]) -> Result<Vec<Option<&'a Set>>, Error> {
+    keys.iter()
+        .map(|key| match storage.get(key) {
+            Some(stored) => stored.set().map(Some),
+            None => Ok(None),
+        })
+        .collect()
+}
+
+fn combine(storage: &Database, operation: Operation, keys: &[Vec<u8>]) -> Result<Set, Error> {
+    let sets = lookup(storage, keys)?;
+    let mut result = Dict::new();
+    match operation {
+        Operation::Inter => {
+            // A missing key is an empty set, which empties the intersection.
+            let mut sets: Vec<_> = match sets.into_iter().collect::<Option<_>>() {
+                Some(sets) => sets,
+                None => return Ok(result),
+            };
+            // Only the smallest set has to be walked.
+            sets.sort_by_key(|set| set.len());
+            let (smallest, others) = sets.split_first().expect("at least one key");
+            for (member, _) in smallest.iter() {
+                if others.iter().all(|set| set.get(member).is_some()) {
+                    result.insert(member.clone(), ());
+                }
+            }
+        }
+        Operation::Union => {
+            for set in sets.into_iter().flatten() {
+                for (member, _) in set.iter() {
+                    result.insert(member.clone(), ());
+                }
+            }
+        }
+        Operation::Diff => {
+            let (first, others) = sets.split_first().expect("at least one key");
+            if let Some(first) = first {
+                for (member, _) in first.iter() {
+                    if others.iter().flatten().all(|set| set.get(member).is_none()) {
+                        result.insert(member.clone(), ());
+                    }
+                }
+            }
+        }
+    }
+    Ok(result)
+}
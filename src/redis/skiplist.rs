@@ -0,0 +1,522 @@
+//! Skiplist ordering the members of sorted sets by score, then member.
+//!
+//! Like Redis' `zskiplist`, every link records its span, the number of
+//! elements it skips, so ranks are found during the same logarithmic descent
+//! as the elements themselves. Nodes live in an arena and link to each other
+//! by index; the head is node 0 and holds no element.
+
+use super::random;
+use std::cmp::Ordering;
+use std::ops::Range;
+
+/// Enough levels for 4^32 elements.
+const MAX_LEVEL: usize = 32;
+/// Index of the head node in the arena.
+const HEAD: usize = 0;
+
+#[derive(Clone, Copy, Default)]
+struct Link {
+    next: Option<usize>,
+    /// Elements between this node and `next`, counting `next`.
+    span: usize,
+}
+
+#[derive(Clone)]
+struct Node {
+    score: f64,
+    member: Vec<u8>,
+    prev: Option<usize>,
+    links: Vec<Link>,
+}
+
+#[derive(Clone)]
+pub struct SkipList {
+    nodes: Vec<Node>,
+    /// Arena slots of removed nodes, reused by later inserts.
+    free: Vec<usize>,
+    /// Levels currently in use by some node.
+    level: usize,
+    len: usize,
+    tail: Option<usize>,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        let head = Node {
+            score: 0.0,
+            member: vec![],
+            prev: None,
+            links: vec![Link::default(); MAX_LEVEL],
+        };
+        SkipList {
+            nodes: vec![head],
+            free: vec![],
+            level: 1,
+            len: 0,
+            tail: None,
+        }
+    }
+}
+
+/// The order of a sorted set. Scores are never NaN.
+fn compare(score: f64, member: &[u8], other_score: f64, other_member: &[u8]) -> Ordering {
+    score
+        .partial_cmp(&other_score)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| member.cmp(other_member))
+}
+
+/// Each level is a quarter as likely as the one below it.
+fn random_level() -> usize {
+    let mut level = 1;
+    while level < MAX_LEVEL && random::next_u64() % 4 == 0 {
+        level += 1;
+    }
+    level
+}
+
+impl SkipList {
+    fn next(&self, node: usize, level: usize) -> Option<usize> {
+        self.nodes[node].links[level].next
+    }
+
+    fn precedes(&self, node: usize, score: f64, member: &[u8]) -> bool {
+        let node = &self.nodes[node];
+        compare(node.score, &node.member, score, member) == Ordering::Less
+    }
+
+    /// The last node before the position of `score` and `member` on every
+    /// level, and the rank of each of those nodes.
+    fn predecessors(&self, score: f64, member: &[u8]) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut nodes = [HEAD; MAX_LEVEL];
+        let mut ranks = [0; MAX_LEVEL];
+        let mut node = HEAD;
+        for level in (0..self.level).rev() {
+            ranks[level] = if level + 1 == self.level {
+                0
+            } else {
+                ranks[level + 1]
+            };
+            while let Some(next) = self.next(node, level) {
+                if !self.precedes(next, score, member) {
+                    break;
+                }
+                ranks[level] += self.nodes[node].links[level].span;
+                node = next;
+            }
+            nodes[level] = node;
+        }
+        (nodes, ranks)
+    }
+
+    /// Adds an element, which must not be in the list yet.
+    pub fn insert(&mut self, score: f64, member: Vec<u8>) {
+        let (mut update, mut ranks) = self.predecessors(score, &member);
+        let level = random_level();
+        if level > self.level {
+            for l in self.level..level {
+                ranks[l] = 0;
+                update[l] = HEAD;
+                self.nodes[HEAD].links[l].span = self.len;
+            }
+            self.level = level;
+        }
+        let node = Node {
+            score,
+            member,
+            prev: Some(update[0]).filter(|&prev| prev != HEAD),
+            links: vec![Link::default(); level],
+        };
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.nodes[id] = node;
+                id
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        for l in 0..level {
+            let before = self.nodes[update[l]].links[l];
+            let skipped = ranks[0] - ranks[l];
+            self.nodes[id].links[l] = Link {
+                next: before.next,
+                span: before.span - skipped,
+            };
+            self.nodes[update[l]].links[l] = Link {
+                next: Some(id),
+                span: skipped + 1,
+            };
+        }
+        for (l, &before) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[before].links[l].span += 1;
+        }
+        match self.next(id, 0) {
+            Some(next) => self.nodes[next].prev = Some(id),
+            None => self.tail = Some(id),
+        }
+        self.len += 1;
+    }
+
+    /// Removes an element; returns false if it was not in the list.
+    pub fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let (update, _) = self.predecessors(score, member);
+        let id = match self.next(update[0], 0) {
+            Some(id) if self.nodes[id].score == score && self.nodes[id].member == member => id,
+            _ => return false,
+        };
+        for (l, &before) in update.iter().enumerate().take(self.level) {
+            if self.next(before, l) == Some(id) {
+                let removed = self.nodes[id].links[l];
+                let link = &mut self.nodes[before].links[l];
+                link.span += removed.span;
+                link.span -= 1;
+                link.next = removed.next;
+            } else {
+                self.nodes[before].links[l].span -= 1;
+            }
+        }
+        let prev = self.nodes[id].prev;
+        match self.next(id, 0) {
+            Some(next) => self.nodes[next].prev = prev,
+            None => self.tail = prev,
+        }
+        while self.level > 1 && self.next(HEAD, self.level - 1).is_none() {
+            self.level -= 1;
+        }
+        self.nodes[id].member = vec![];
+        self.nodes[id].links = vec![];
+        self.free.push(id);
+        self.len -= 1;
+        true
+    }
+
+    /// The zero-based rank of an element in the list.
+    pub fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let mut rank = 0;
+        let mut node = HEAD;
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.next(node, level) {
+                let candidate = &self.nodes[next];
+                if compare(candidate.score, &candidate.member, score, member) == Ordering::Greater {
+                    break;
+                }
+                rank += self.nodes[node].links[level].span;
+                node = next;
+            }
+            if node != HEAD && self.nodes[node].score == score && self.nodes[node].member == member
+            {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    /// The node at a zero-based rank.
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut node = HEAD;
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.next(node, level) {
+                let span = self.nodes[node].links[level].span;
+                if traversed + span > target {
+                    break;
+                }
+                traversed += span;
+                node = next;
+            }
+            if traversed == target {
+                return Some(node);
+            }
+        }
+        None
+    }
+
+    /// The number of leading elements satisfying `pred`, which has to hold
+    /// for a prefix of the list and for nothing after it.
+    pub fn partition_point<P>(&self, mut pred: P) -> usize
+    where
+        P: FnMut(f64, &[u8]) -> bool,
+    {
+        let mut rank = 0;
+        let mut node = HEAD;
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.next(node, level) {
+                if !pred(self.nodes[next].score, &self.nodes[next].member) {
+                    break;
+                }
+                rank += self.nodes[node].links[level].span;
+                node = next;
+            }
+        }
+        rank
+    }
+
+    /// The elements with ranks in `ranks`, which must be within the list.
+    pub fn range(&self, ranks: Range<usize>) -> Iter<'_> {
+        let (front, back) = if ranks.start < ranks.end {
+            (self.node_at(ranks.start), self.node_at(ranks.end - 1))
+        } else {
+            (None, None)
+        };
+        Iter {
+            list: self,
+            front,
+            back,
+            remaining: ranks.len(),
+        }
+    }
+
+    /// Removes the elements with ranks in `ranks`, which must be within the
+    /// list, and returns them, lowest first.
+    pub fn remove_range(&mut self, ranks: Range<usize>) -> Vec<(Vec<u8>, f64)> {
+        let removed: Vec<_> = self
+            .range(ranks)
+            .map(|(member, score)| (member.clone(), score))
+            .collect();
+        for (member, score) in &removed {
+            self.remove(*score, member);
+        }
+        removed
+    }
+
+    /// Every element, lowest first.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            list: self,
+            front: self.next(HEAD, 0),
+            back: self.tail,
+            remaining: self.len,
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    list: &'a SkipList,
+    front: Option<usize>,
+    back: Option<usize>,
+    remaining: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Vec<u8>, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = &self.list.nodes[self.front?];
+        self.remaining -= 1;
+        self.front = node.links[0].next;
+        Some((&node.member, node.score))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = &self.list.nodes[self.back?];
+        self.remaining -= 1;
+        self.back = node.prev;
+        Some((&node.member, node.score))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ranges::ScoreRange;
+    use super::SkipList;
+
+    fn list(elements: &[(f64, &str)]) -> SkipList {
+        let mut list = SkipList::default();
+        for &(score, member) in elements {
+            list.insert(score, member.as_bytes().to_vec());
+        }
+        list
+    }
+
+    fn collect(list: &SkipList) -> Vec<(String, f64)> {
+        list.iter()
+            .map(|(member, score)| (String::from_utf8_lossy(member).into_owned(), score))
+            .collect()
+    }
+
+    fn score_ranks(list: &SkipList, min: &str, max: &str) -> std::ops::Range<usize> {
+        let range = ScoreRange::parse(min.as_bytes(), max.as_bytes()).unwrap();
+        let start = list.partition_point(|score, _| !range.above_min(score));
+        let end = list.partition_point(|score, _| range.below_max(score));
+        start..end.max(start)
+    }
+
+    #[test]
+    fn orders_by_score_then_member() {
+        let list = list(&[(2.0, "b"), (1.0, "z"), (2.0, "a"), (-1.5, "m"), (2.0, "c")]);
+        assert_eq!(
+            collect(&list),
+            vec![
+                ("m".to_owned(), -1.5),
+                ("z".to_owned(), 1.0),
+                ("a".to_owned(), 2.0),
+                ("b".to_owned(), 2.0),
+                ("c".to_owned(), 2.0),
+            ]
+        );
+        assert_eq!(list.rank(2.0, b"b"), Some(3));
+    }
+
+    #[test]
+    fn insert_remove_and_rank() {
+        let mut list = list(&[(1.0, "a"), (2.0, "b"), (3.0, "c")]);
+        assert_eq!(list.rank(1.0, b"a"), Some(0));
+        assert_eq!(list.rank(3.0, b"c"), Some(2));
+        assert_eq!(list.rank(2.0, b"x"), None);
+        assert_eq!(list.rank(3.0, b"b"), None);
+
+        assert!(!list.remove(2.0, b"x"));
+        assert!(!list.remove(3.0, b"b"));
+        assert!(list.remove(2.0, b"b"));
+        assert!(!list.remove(2.0, b"b"));
+        assert_eq!(list.rank(3.0, b"c"), Some(1));
+        assert_eq!(
+            collect(&list),
+            vec![("a".to_owned(), 1.0), ("c".to_owned(), 3.0)]
+        );
+
+        assert!(list.remove(1.0, b"a"));
+        assert!(list.remove(3.0, b"c"));
+        assert_eq!(list.iter().count(), 0);
+        assert_eq!(list.iter().next_back(), None);
+    }
+
+    #[test]
+    fn update_score_moves_the_element() {
+        let mut list = list(&[(1.0, "a"), (2.0, "b"), (3.0, "c")]);
+        assert!(list.remove(1.0, b"a"));
+        list.insert(5.0, b"a".to_vec());
+        assert_eq!(list.rank(5.0, b"a"), Some(2));
+        assert_eq!(list.rank(2.0, b"b"), Some(0));
+        assert!(list.remove(3.0, b"c"));
+        list.insert(-3.0, b"c".to_vec());
+        assert_eq!(members_of(&list), vec!["c", "b", "a"]);
+    }
+
+    fn members_of(list: &SkipList) -> Vec<String> {
+        collect(list)
+            .into_iter()
+            .map(|(member, _)| member)
+            .collect()
+    }
+
+    #[test]
+    fn by_rank() {
+        let list = list(&[(1.0, "a"), (2.0, "b"), (3.0, "c"), (4.0, "d")]);
+        let at = |rank: usize| {
+            list.range(rank..rank + 1)
+                .next()
+                .map(|(m, s)| (m.clone(), s))
+        };
+        assert_eq!(at(0), Some((b"a".to_vec(), 1.0)));
+        assert_eq!(at(3), Some((b"d".to_vec(), 4.0)));
+        let middle: Vec<_> = list.range(1..3).map(|(m, _)| m.clone()).collect();
+        assert_eq!(middle, vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(list.range(2..2).count(), 0);
+        assert_eq!(list.range(1..3).size_hint(), (2, Some(2)));
+    }
+
+    #[test]
+    fn score_range_ends() {
+        let list = list(&[(1.0, "a"), (2.0, "b"), (2.0, "c"), (3.0, "d"), (4.0, "e")]);
+        assert_eq!(score_ranks(&list, "2", "3"), 1..4);
+        assert_eq!(score_ranks(&list, "(2", "3"), 3..4);
+        assert_eq!(score_ranks(&list, "2", "(3"), 1..3);
+        assert_eq!(score_ranks(&list, "(2", "(3"), 3..3);
+        assert_eq!(score_ranks(&list, "-inf", "+inf"), 0..5);
+        assert_eq!(score_ranks(&list, "(4", "+inf"), 5..5);
+        assert_eq!(score_ranks(&list, "3", "2"), 3..3);
+    }
+
+    #[test]
+    fn iterates_from_both_ends() {
+        let list = list(&[(1.0, "a"), (2.0, "b"), (3.0, "c"), (4.0, "d")]);
+        let mut iter = list.iter();
+        assert_eq!(iter.next().map(|(m, _)| m.as_slice()), Some(&b"a"[..]));
+        assert_eq!(iter.next_back().map(|(m, _)| m.as_slice()), Some(&b"d"[..]));
+        assert_eq!(iter.next_back().map(|(m, _)| m.as_slice()), Some(&b"c"[..]));
+        assert_eq!(iter.next().map(|(m, _)| m.as_slice()), Some(&b"b"[..]));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+
+        let reversed: Vec<_> = list.range(1..4).rev().map(|(m, _)| m.clone()).collect();
+        assert_eq!(reversed, vec![b"d".to_vec(), b"c".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn remove_range_returns_the_removed() {
+        let mut list = list(&[(1.0, "a"), (2.0, "b"), (3.0, "c"), (4.0, "d"), (5.0, "e")]);
+        let removed = list.remove_range(1..3);
+        assert_eq!(removed, vec![(b"b".to_vec(), 2.0), (b"c".to_vec(), 3.0)]);
+        assert_eq!(members_of(&list), vec!["a", "d", "e"]);
+        assert_eq!(list.rank(4.0, b"d"), Some(1));
+
+        let ranks = score_ranks(&list, "(1", "+inf");
+        let removed = list.remove_range(ranks);
+        assert_eq!(removed, vec![(b"d".to_vec(), 4.0), (b"e".to_vec(), 5.0)]);
+        assert_eq!(members_of(&list), vec!["a"]);
+        assert_eq!(list.remove_range(0..0), vec![]);
+    }
+
+    /// Random inserts and removes checked against a sorted vector.
+    #[test]
+    fn matches_a_sorted_model() {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        let mut list = SkipList::default();
+        let mut model: Vec<(f64, Vec<u8>)> = vec![];
+        for _ in 0..5000 {
+            let member = format!("m{}", next(300)).into_bytes();
+            let existing = model.iter().position(|(_, m)| *m == member);
+            match existing {
+                Some(index) if next(2) == 0 => {
+                    let (score, member) = model.remove(index);
+                    assert!(list.remove(score, &member));
+                }
+                Some(_) => {}
+                None => {
+                    let score = next(50) as f64 / 2.0;
+                    list.insert(score, member.clone());
+                    model.push((score, member));
+                    model
+                        .sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap().then_with(|| a.1.cmp(&b.1)));
+                }
+            }
+            if model.is_empty() {
+                continue;
+            }
+            let probe = next(model.len() as u64) as usize;
+            let (score, member) = &model[probe];
+            assert_eq!(list.rank(*score, member), Some(probe));
+            let at = list
+                .range(probe..probe + 1)
+                .next()
+                .map(|(m, s)| (s, m.clone()));
+            assert_eq!(at, Some((*score, member.clone())));
+        }
+        let all: Vec<_> = list.iter().map(|(m, s)| (s, m.clone())).collect();
+        assert_eq!(all, model);
+        let reversed: Vec<_> = list.iter().rev().map(|(m, s)| (s, m.clone())).collect();
+        assert_eq!(reversed, model.iter().rev().cloned().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,215 @@
+//! SORT: ordering the elements of a collection, optionally by weights and
+//! projections looked up in other keys.
+
+use super::command::Arguments;
+use super::float;
+use super::notify::Class;
+use super::storage::{Data, Database, StoredValue};
+use super::{Error, Value};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
+pub struct SortOptions {
+    by: Option<Vec<u8>>,
+    limit: Option<(i64, i64)>,
+    get: Vec<Vec<u8>>,
+    descending: bool,
+    alpha: bool,
+    store: Option<Vec<u8>>,
+}
+
+impl SortOptions {
+    pub fn parse(args: &mut Arguments) -> Result<SortOptions, Error> {
+        let mut options = SortOptions {
+            by: None,
+            limit: None,
+            get: vec![],
+            descending: false,
+            alpha: false,
+            store: None,
+        };
+        while !args.is_empty() {
+            // Options missing their values are syntax errors, not arity ones.
+            let option = args.next_string()?.to_lowercase();
+            let needed = match option.as_str() {
+                "limit" => 2,
+                "by" | "get" | "store" => 1,
+                _ => 0,
+            };
+            if args.len() < needed {
+                return Err(Error::Syntax);
+            }
+            match option.as_str() {
+                "asc" => options.descending = false,
+                "desc" => options.descending = true,
+                "alpha" => options.alpha = true,
+                "limit" => {
+                    let offset = args.next_int()?;
+                    let count = args.next_int()?;
+                    options.limit = Some((offset, count));
+                }
+                "by" => options.by = Some(args.next_bytes()?),
+                "get" => options.get.push(args.next_bytes()?),
+                "store" => options.store = Some(args.next_bytes()?),
+                _ => return Err(Error::Syntax),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// An element together with the key it is ordered by.
+struct Entry {
+    element: Vec<u8>,
+    weight: Weight,
+}
+
+enum Weight {
+    Score(f64),
+    Text(Option<Vec<u8>>),
+}
+
+pub fn sort(storage: &mut Database, key: &[u8], options: SortOptions) -> Result<Value, Error> {
+    let elements = match storage.get(key) {
+        Some(stored) => elements(stored)?,
+        None => vec![],
+    };
+    // A BY pattern without a wildcard cannot vary per element, which is the
+    // documented way to skip sorting.
+    let sorted = options
+        .by
+        .as_ref()
+        .map_or(true, |pattern| pattern.contains(&b'*'));
+
+    let mut entries = vec![];
+    for element in elements {
+        let weight = if !sorted {
+            Weight::Score(0.0)
+        } else {
+            let value = match &options.by {
+                Some(pattern) => lookup(storage, pattern, &element),
+                None => Some(element.clone()),
+            };
+            if options.alpha {
+                Weight::Text(value)
+            } else {
+                let score = match value {
+                    Some(value) => float::parse(&value).ok_or_else(|| {
+                        Error::Argument(
+                            "One or more scores can't be converted into double".to_owned(),
+                        )
+                    })?,
+                    None => 0.0,
+                };
+                Weight::Score(score)
+            }
+        };
+        entries.push(Entry { element, weight });
+    }
+    if sorted {
+        entries.sort_by(|a, b| {
+            let order = compare(a, b);
+            if options.descending {
+                order.reverse()
+            } else {
+                order
+            }
+        });
+    }
+
+    let (start, end) = limit(options.limit, entries.len());
+    let mut results = vec![];
+    for entry in entries.iter().take(end).skip(start) {
+        if options.get.is_empty() {
+            results.push(Some(entry.element.clone()));
+        }
+        for pattern in &options.get {
+            results.push(lookup(storage, pattern, &entry.element));
+        }
+    }
+
+    match options.store {
+        Some(destination) => {
+            let list: VecDeque<_> = results.into_iter().map(Option::unwrap_or_default).collect();
+            let len = list.len().try_into()?;
+            if list.is_empty() {
+                if storage.remove(&destination).is_some() {
+                    storage.notify(Class::Generic, "del", &destination);
+                }
+            } else {
+                storage.notify(Class::List, "sortstore", &destination);
+                storage.insert(destination, StoredValue::new(Data::List(list), None));
+            }
+            Ok(Value::Int(len))
+        }
+        None => Ok(Value::array(
+            results
+                .into_iter()
+                .map(|result| result.map_or(Value::Nil, Value::String))
+                .collect(),
+        )),
+    }
+}
+
+/// The members of a sortable value.
+fn elements(stored: &StoredValue) -> Result<Vec<Vec<u8>>, Error> {
+    match &stored.data {
+        Data::String(_) | Data::Hash(_) | Data::Stream(_) => Err(Error::WrongType),
+        Data::List(list) => Ok(list.iter().cloned().collect()),
+        Data::Set(set) => Ok(set.iter().map(|(member, _)| member.clone()).collect()),
+        Data::ZSet(zset) => Ok(zset.iter().map(|(member, _)| member.clone()).collect()),
+    }
+}
+
+fn compare(a: &Entry, b: &Entry) -> Ordering {
+    match (&a.weight, &b.weight) {
+        // Equal scores fall back to the elements, so the order is defined.
+        (Weight::Score(x), Weight::Score(y)) => x
+            .partial_cmp(y)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.element.cmp(&b.element)),
+        // Missing weights sort first.
+        (Weight::Text(x), Weight::Text(y)) => x.cmp(y),
+        _ => Ordering::Equal,
+    }
+}
+
+/// Clamps LIMIT to a `start..end` window of `len` entries.
+fn limit(limit: Option<(i64, i64)>, len: usize) -> (usize, usize) {
+    let (offset, count) = match limit {
+        Some(limit) => limit,
+        None => return (0, len),
+    };
+    let start: usize = offset.max(0).try_into().unwrap_or(usize::MAX).min(len);
+    let end = match count.try_into() {
+        Ok(count) => start.saturating_add(count).min(len),
+        Err(_) => len,
+    };
+    (start, end)
+}
+
+/// Resolves a BY or GET pattern for `element`: `#` is the element itself,
+/// otherwise the first `*` is replaced by it to name a key, optionally
+/// followed by `->field` to address a hash field.
+fn lookup(storage: &Database, pattern: &[u8], element: &[u8]) -> Option<Vec<u8>> {
+    if pattern == b"#" {
+        return Some(element.to_vec());
+    }
+    let star = pattern.iter().position(|&b| b == b'*')?;
+    let arrow = pattern[star + 1..]
+        .windows(2)
+        .position(|window| window == b"->")
+        .map(|pos| star + 1 + pos)
+        .filter(|&pos| pos + 2 < pattern.len());
+    let key_pattern = &pattern[..arrow.unwrap_or(pattern.len())];
+    let mut key = key_pattern[..star].to_vec();
+    key.extend_from_slice(element);
+    key.extend_from_slice(&key_pattern[star + 1..]);
+
+    let stored = storage.get(&key)?;
+    match arrow {
+        Some(arrow) => stored.hash().ok()?.get(&pattern[arrow + 2..]).cloned(),
+        None => stored.string().ok().cloned(),
+    }
+}
@@ -0,0 +1,33 @@
+//! Server-wide counters reported by INFO.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct Stats {
+    pub keyspace_hits: AtomicU64,
+    pub keyspace_misses: AtomicU64,
+    /// Keys deleted because their TTL ran out, lazily or by the expiry cycle.
+    pub expired_keys: AtomicU64,
+    /// Hash fields deleted because their TTL ran out.
+    pub expired_subkeys: AtomicU64,
+    /// Keys deleted to stay under a memory limit.
+    pub evicted_keys: AtomicU64,
+    /// Changes to the dataset since startup, which the save rules count.
+    pub dirty: AtomicU64,
+}
+
+pub static STATS: Stats = Stats {
+    keyspace_hits: AtomicU64::new(0),
+    keyspace_misses: AtomicU64::new(0),
+    expired_keys: AtomicU64::new(0),
+    expired_subkeys: AtomicU64::new(0),
+    evicted_keys: AtomicU64::new(0),
+    dirty: AtomicU64::new(0),
+};
+
+pub fn increment(counter: &AtomicU64, by: u64) {
+    counter.fetch_add(by, Ordering::Relaxed);
+}
+
+pub fn read(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
@@ -0,0 +1,889 @@
+use super::blocking::{Blocked, Blocking, ClientId, Reply};
+use super::dict::Dict;
+use super::functions::Functions;
+use super::hashes::Hash;
+use super::notify::{Class, Events, Flags};
+use super::persistence::Persistence;
+use super::pubsub::PubSub;
+use super::random;
+use super::scripting::Scripts;
+use super::sets::Set;
+use super::stats::{self, STATS};
+use super::streams::Stream;
+use super::transaction::WatcherId;
+use super::zsets::ZSet;
+use super::{Error, Value};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+
+/// Set while the dataset is loaded at startup. Keys do not expire then: the
+/// commands replayed ran while they were still alive.
+static LOADING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_loading(loading: bool) {
+    LOADING.store(loading, Ordering::SeqCst);
+}
+
+/// Whether a deadline given to a command already passed, which then deletes
+/// the key rather than setting it; never so while loading.
+pub fn already_expired(expiry: i64) -> bool {
+    !LOADING.load(Ordering::Relaxed) && expiry <= unix_millis()
+}
+
+/// Current wall-clock time as milliseconds since the unix epoch.
+pub fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .and_then(|now| now.as_millis().try_into().ok())
+        .unwrap_or(0)
+}
+
+/// Values whose free effort exceeds this are dropped off the event loop.
+const LAZYFREE_THRESHOLD: usize = 64;
+
+/// Drops `values` on the blocking pool when freeing them inline could stall
+/// the event loop; cheap values are dropped right away.
+pub fn free_lazily(values: Vec<StoredValue>) {
+    let effort: usize = values.iter().map(StoredValue::free_effort).sum();
+    if effort > LAZYFREE_THRESHOLD {
+        tokio::task::spawn_blocking(move || drop(values));
+    }
+}
+
+/// Counter a new key starts from, so it is not evicted before it had a
+/// chance to be accessed again.
+const LFU_INIT_VAL: u8 = 5;
+/// How quickly the logarithmic access counter saturates.
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// The counter loses one for every this many idle minutes.
+const LFU_DECAY_MINUTES: i64 = 1;
+
+/// Access metadata behind OBJECT IDLETIME and OBJECT FREQ: the last access
+/// time and a Redis-style logarithmic access frequency counter. Kept in
+/// cells so that plain reads can record themselves.
+#[derive(Clone)]
+pub struct Access {
+    /// Unix milliseconds of the last access.
+    last: Cell<i64>,
+    counter: Cell<u8>,
+}
+
+impl Access {
+    fn new() -> Access {
+        Access {
+            last: Cell::new(unix_millis()),
+            counter: Cell::new(LFU_INIT_VAL),
+        }
+    }
+
+    pub fn touch(&self) {
+        let now = unix_millis();
+        let mut counter = self.frequency_at(now);
+        if counter < u8::MAX {
+            let base = f64::from(counter.saturating_sub(LFU_INIT_VAL));
+            let chance = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+            if (random::next_u64() >> 11) as f64 / (1u64 << 53) as f64 <= chance {
+                counter += 1;
+            }
+        }
+        self.counter.set(counter);
+        self.last.set(now);
+    }
+
+    pub fn idle_seconds(&self) -> i64 {
+        (unix_millis() - self.last.get()).max(0) / 1000
+    }
+
+    pub fn frequency(&self) -> u8 {
+        self.frequency_at(unix_millis())
+    }
+
+    fn frequency_at(&self, now: i64) -> u8 {
+        let periods = (now - self.last.get()).max(0) / 60_000 / LFU_DECAY_MINUTES;
+        self.counter
+            .get()
+            .saturating_sub(periods.min(u8::MAX.into()) as u8)
+    }
+
+    /// Overrides the metadata, as RESTORE IDLETIME and FREQ do.
+    pub fn set(&self, idle_seconds: Option<i64>, frequency: Option<u8>) {
+        if let Some(idle) = idle_seconds {
+            self.last
+                .set(unix_millis().saturating_sub(idle.saturating_mul(1000)));
+        }
+        if let Some(frequency) = frequency {
+            self.counter.set(frequency);
+        }
+    }
+}
+
+/// Kind of value held by a key, as reported by TYPE.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    String,
+    List,
+    Hash,
+    Set,
+    ZSet,
+    Stream,
+}
+
+impl ValueType {
+    pub fn from_name(name: &str) -> Option<ValueType> {
+        let value_type = match name {
+            "string" => ValueType::String,
+            "list" => ValueType::List,
+            "hash" => ValueType::Hash,
+            "set" => ValueType::Set,
+            "zset" => ValueType::ZSet,
+            "stream" => ValueType::Stream,
+            _ => return None,
+        };
+        Some(value_type)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueType::String => "string",
+            ValueType::List => "list",
+            ValueType::Hash => "hash",
+            ValueType::Set => "set",
+            ValueType::ZSet => "zset",
+            ValueType::Stream => "stream",
+        }
+    }
+}
+
+/// The payload of a key. Cloning deep-copies it, which is what COPY relies
+/// on, so every payload type has to stay `Clone`.
+#[derive(Clone)]
+pub enum Data {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(Hash),
+    Set(Set),
+    ZSet(ZSet),
+    Stream(Stream),
+}
+
+impl Data {
+    /// Collections are deleted once their last element is removed. Streams
+    /// are not, and keep their last ID.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Data::String(_) | Data::Stream(_) => false,
+            Data::List(list) => list.is_empty(),
+            Data::Hash(hash) => hash.is_empty(),
+            Data::Set(set) => set.is_empty(),
+            Data::ZSet(zset) => zset.is_empty(),
+        }
+    }
+}
+
+/// Largest collection Redis still keeps in a single compact listpack.
+const LISTPACK_MAX_ENTRIES: usize = 128;
+/// Longest element, field or value a compact listpack may hold.
+const LISTPACK_MAX_VALUE: usize = 64;
+/// Largest set of integers Redis keeps as a sorted integer array.
+const INTSET_MAX_ENTRIES: usize = 512;
+
+/// Whether `data` is the canonical decimal form of a 64-bit integer.
+fn is_integer(data: &[u8]) -> bool {
+    data.len() <= 20
+        && std::str::from_utf8(data)
+            .ok()
+            .and_then(|text| text.parse::<i64>().ok())
+            .map_or(false, |number| number.to_string().as_bytes() == data)
+}
+
+#[derive(Clone)]
+pub struct StoredValue {
+    pub data: Data,
+    /// Deadline in unix milliseconds; keys without one never expire.
+    pub expiry: Option<i64>,
+    pub access: Access,
+}
+
+impl StoredValue {
+    pub fn new(data: Data, expiry: Option<i64>) -> StoredValue {
+        StoredValue {
+            data,
+            expiry,
+            access: Access::new(),
+        }
+    }
+
+    /// The encoding Redis would report for the value.
+    pub fn encoding(&self) -> &'static str {
+        match &self.data {
+            Data::String(data) => {
+                if is_integer(data) {
+                    "int"
+                } else if data.len() <= 44 {
+                    "embstr"
+                } else {
+                    "raw"
+                }
+            }
+            Data::List(list) => {
+                let compact = list.len() <= LISTPACK_MAX_ENTRIES
+                    && list.iter().all(|item| item.len() <= LISTPACK_MAX_VALUE);
+                if compact {
+                    "listpack"
+                } else {
+                    "quicklist"
+                }
+            }
+            Data::Hash(hash) => {
+                let compact = hash.len() <= LISTPACK_MAX_ENTRIES
+                    && hash.iter().all(|(field, value)| {
+                        field.len() <= LISTPACK_MAX_VALUE && value.len() <= LISTPACK_MAX_VALUE
+                    });
+                if !compact {
+                    "hashtable"
+                } else if hash.has_expiries() {
+                    "listpackex"
+                } else {
+                    "listpack"
+                }
+            }
+            Data::Set(set) => {
+                if set.len() <= INTSET_MAX_ENTRIES
+                    && set.iter().all(|(member, _)| is_integer(member))
+                {
+                    "intset"
+                } else if set.len() <= LISTPACK_MAX_ENTRIES
+                    && set
+                        .iter()
+                        .all(|(member, _)| member.len() <= LISTPACK_MAX_VALUE)
+                {
+                    "listpack"
+                } else {
+                    "hashtable"
+                }
+            }
+            Data::ZSet(zset) => {
+                let compact = zset.len() <= LISTPACK_MAX_ENTRIES
+                    && zset
+                        .iter()
+                        .all(|(member, _)| member.len() <= LISTPACK_MAX_VALUE);
+                if compact {
+                    "listpack"
+                } else {
+                    "skiplist"
+                }
+            }
+            Data::Stream(_) => "stream",
+        }
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        match self.data {
+            Data::String(_) => ValueType::String,
+            Data::List(_) => ValueType::List,
+            Data::Hash(_) => ValueType::Hash,
+            Data::Set(_) => ValueType::Set,
+            Data::ZSet(_) => ValueType::ZSet,
+            Data::Stream(_) => ValueType::Stream,
+        }
+    }
+
+    pub fn string(&self) -> Result<&Vec<u8>, Error> {
+        match &self.data {
+            Data::String(data) => Ok(data),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn string_mut(&mut self) -> Result<&mut Vec<u8>, Error> {
+        match &mut self.data {
+            Data::String(data) => Ok(data),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn list(&self) -> Result<&VecDeque<Vec<u8>>, Error> {
+        match &self.data {
+            Data::List(list) => Ok(list),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn list_mut(&mut self) -> Result<&mut VecDeque<Vec<u8>>, Error> {
+        match &mut self.data {
+            Data::List(list) => Ok(list),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn hash(&self) -> Result<&Hash, Error> {
+        match &self.data {
+            Data::Hash(hash) => Ok(hash),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn hash_mut(&mut self) -> Result<&mut Hash, Error> {
+        match &mut self.data {
+            Data::Hash(hash) => Ok(hash),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn set(&self) -> Result<&Set, Error> {
+        match &self.data {
+            Data::Set(set) => Ok(set),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn set_mut(&mut self) -> Result<&mut Set, Error> {
+        match &mut self.data {
+            Data::Set(set) => Ok(set),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn zset(&self) -> Result<&ZSet, Error> {
+        match &self.data {
+            Data::ZSet(zset) => Ok(zset),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn zset_mut(&mut self) -> Result<&mut ZSet, Error> {
+        match &mut self.data {
+            Data::ZSet(zset) => Ok(zset),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn stream(&self) -> Result<&Stream, Error> {
+        match &self.data {
+            Data::Stream(stream) => Ok(stream),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn stream_mut(&mut self) -> Result<&mut Stream, Error> {
+        match &mut self.data {
+            Data::Stream(stream) => Ok(stream),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    /// Rough number of allocations released by dropping the value.
+    pub fn free_effort(&self) -> usize {
+        match &self.data {
+            Data::String(_) => 1,
+            Data::List(list) => list.len(),
+            Data::Hash(hash) => hash.len(),
+            Data::Set(set) => set.len(),
+            Data::ZSet(zset) => zset.len(),
+            Data::Stream(stream) => stream.len(),
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        if LOADING.load(Ordering::Relaxed) {
+            return false;
+        }
+        if let Some(expiry) = self.expiry {
+            return expiry < unix_millis();
+        }
+        false
+    }
+}
+
+/// Buckets inspected per step of the active expiry cycle.
+const ACTIVE_EXPIRE_BUCKETS: usize = 64;
+/// Upper bound on the time one database may spend in the expiry cycle.
+const ACTIVE_EXPIRE_MILLIS: i64 = 25;
+
+/// A keyspace. Expired entries are invisible to every accessor even before
+/// the background collector gets to them.
+#[derive(Default)]
+pub struct Database {
+    entries: Dict<Vec<u8>, StoredValue>,
+    /// Where the active expiry cycle resumes.
+    expire_cursor: u64,
+    /// Clients blocked on each key, in the order they blocked. These belong
+    /// to the database number and stay put across flushes and SWAPDB.
+    blocking: HashMap<Vec<u8>, Vec<ClientId>>,
+    /// Keys with blocked clients that were created since they were served.
+    ready: Vec<Vec<u8>>,
+    /// Keyspace notifications to publish once the command is done. Like
+    /// blocked clients, these stay with the database number.
+    events: Events,
+    /// The flags of the connections watching each key, raised once it
+    /// changes. These too stay with the database number.
+    watchers: HashMap<Vec<u8>, HashMap<WatcherId, Arc<AtomicBool>>>,
+}
+
+impl Database {
+    /// Number of keys, including expired ones not yet reclaimed.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Looks a key up on behalf of a command, counting it as an access.
+    pub fn get(&self, key: &[u8]) -> Option<&StoredValue> {
+        let stored = self.peek(key);
+        match stored {
+            Some(stored) => {
+                stats::increment(&STATS.keyspace_hits, 1);
+                stored.access.touch();
+            }
+            None => {
+                stats::increment(&STATS.keyspace_misses, 1);
+                self.notify(Class::KeyMiss, "keymiss", key);
+            }
+        }
+        stored
+    }
+
+    /// Looks a key up without updating its access metadata, for
+    /// introspection such as TTL, TYPE or OBJECT.
+    pub fn peek(&self, key: &[u8]) -> Option<&StoredValue> {
+        self.entries.get(key).filter(|stored| !stored.expired())
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.peek(key).is_some()
+    }
+
+    /// Whether a key is there but past its deadline, not yet reclaimed.
+    pub fn is_expired(&self, key: &[u8]) -> bool {
+        self.entries.get(key).map_or(false, StoredValue::expired)
+    }
+
+    /// Mutable access for in-place updates; an expired entry is dropped
+    /// first, so callers never resurrect a stale value.
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut StoredValue> {
+        if self.entries.get(key)?.expired() {
+            self.remove_expired(key);
+            return None;
+        }
+        let stored = self.entries.get_mut(key)?;
+        stored.access.touch();
+        Some(stored)
+    }
+
+    /// Replaces the TTL of an existing key without touching its value.
+    /// Returns false when the key does not exist.
+    pub fn set_expiry(&mut self, key: &[u8], expiry: Option<i64>) -> bool {
+        match self.get_mut(key) {
+            Some(stored) => {
+                stored.expiry = expiry;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The live entry for `key`, created from `data` if there is none,
+    /// for commands that implicitly create collections.
+    pub fn get_or_insert_with<F>(&mut self, key: &[u8], data: F) -> &mut StoredValue
+    where
+        F: FnOnce() -> Data,
+    {
+        if self.get_mut(key).is_none() {
+            self.entries
+                .insert(key.to_vec(), StoredValue::new(data(), None));
+            self.signal(key);
+            self.notify(Class::New, "new", key);
+        }
+        self.entries.get_mut(key).expect("entry was just inserted")
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, value: StoredValue) {
+        self.signal(&key);
+        if self.events.enabled(Class::New) && !self.contains(&key) {
+            self.notify(Class::New, "new", &key);
+        }
+        self.entries.insert(key, value);
+    }
+
+    /// Reports a change to `key`: the hook every command goes through for
+    /// each key it modifies, with the name of the keyspace event.
+    pub fn notify(&self, class: Class, event: &'static str, key: &[u8]) {
+        if !matches!(class, Class::KeyMiss) {
+            self.touch(key);
+        }
+        // A new key comes with the event of the command that made it.
+        if !matches!(class, Class::KeyMiss | Class::New) {
+            stats::increment(&STATS.dirty, 1);
+        }
+        self.events.push(class, event, key);
+    }
+
+    pub fn watch(&mut self, key: Vec<u8>, id: WatcherId, dirty: Arc<AtomicBool>) {
+        self.watchers.entry(key).or_default().insert(id, dirty);
+    }
+
+    pub fn unwatch(&mut self, key: &[u8], id: WatcherId) {
+        if let Some(watchers) = self.watchers.get_mut(key) {
+            watchers.remove(&id);
+            if watchers.is_empty() {
+                self.watchers.remove(key);
+            }
+        }
+    }
+
+    /// Tells the connections watching `key` that it changed.
+    fn touch(&self, key: &[u8]) {
+        for dirty in self.watchers.get(key).into_iter().flat_map(HashMap::values) {
+            dirty.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Touches every watched key that exists, as the whole keyspace is about
+    /// to be replaced.
+    fn touch_existing(&self) {
+        for key in self.watchers.keys() {
+            if self.contains(key) {
+                self.touch(key);
+            }
+        }
+    }
+
+    /// Marks `key` as ready if clients are blocked on it.
+    pub fn signal(&mut self, key: &[u8]) {
+        if self.blocking.contains_key(key) && !self.ready.iter().any(|ready| ready == key) {
+            self.ready.push(key.to_vec());
+        }
+    }
+
+    fn block(&mut self, key: &[u8], id: ClientId) {
+        let clients = self.blocking.entry(key.to_vec()).or_default();
+        if !clients.contains(&id) {
+            clients.push(id);
+        }
+    }
+
+    fn unblock(&mut self, key: &[u8], id: ClientId) {
+        if let Some(clients) = self.blocking.get_mut(key) {
+            clients.retain(|&client| client != id);
+            if clients.is_empty() {
+                self.blocking.remove(key);
+            }
+        }
+    }
+
+    /// Moves the keyspace out into a new database, leaving this one empty
+    /// but with its blocked clients.
+    pub fn flush(&mut self) -> Database {
+        self.touch_existing();
+        stats::increment(&STATS.dirty, self.entries.len() as u64);
+        Database {
+            entries: std::mem::take(&mut self.entries),
+            ..Database::default()
+        }
+    }
+
+    /// Deletes `key` if it holds a collection whose last element is gone,
+    /// reporting that as a `del` event after the one of the command.
+    pub fn remove_if_empty(&mut self, key: &[u8]) {
+        if self
+            .entries
+            .get(key)
+            .map_or(false, |stored| stored.data.is_empty())
+        {
+            self.entries.remove(key);
+            self.notify(Class::Generic, "del", key);
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<StoredValue> {
+        let stored = self.entries.remove(key)?;
+        if stored.expired() {
+            stats::increment(&STATS.expired_keys, 1);
+            self.notify(Class::Expired, "expired", key);
+            return None;
+        }
+        Some(stored)
+    }
+
+    fn remove_expired(&mut self, key: &[u8]) {
+        self.entries.remove(key);
+        stats::increment(&STATS.expired_keys, 1);
+        self.notify(Class::Expired, "expired", key);
+    }
+
+    /// Live entries, in bucket order.
+    pub fn entries(&self) -> impl Iterator<Item = (&Vec<u8>, &StoredValue)> {
+        self.entries.iter().filter(|(_, stored)| !stored.expired())
+    }
+
+    /// Live keys, in bucket order.
+    pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.entries
+            .iter()
+            .filter(|(_, stored)| !stored.expired())
+            .map(|(key, _)| key)
+    }
+
+    /// A random live key. Expired keys drawn along the way are deleted, so
+    /// the loop ends even when every remaining key has expired.
+    pub fn random_key(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let (key, stored) = self.entries.random_entry()?;
+            let key = key.clone();
+            if !stored.expired() {
+                return Some(key);
+            }
+            self.remove_expired(&key);
+        }
+    }
+
+    /// Number of live keys with a TTL and their average remaining time to
+    /// live in milliseconds, as INFO reports them.
+    pub fn expires(&self) -> (usize, i64) {
+        let now = unix_millis();
+        let (mut count, mut total) = (0, 0i64);
+        for (_, stored) in self.entries.iter() {
+            if let Some(expiry) = stored.expiry.filter(|&expiry| expiry >= now) {
+                count += 1;
+                total = total.saturating_add(expiry - now);
+            }
+        }
+        let average = if count == 0 { 0 } else { total / count as i64 };
+        (count, average)
+    }
+
+    /// Visits the live entries of one table bucket; see [`Dict::scan`].
+    pub fn scan<F>(&self, cursor: u64, mut f: F) -> u64
+    where
+        F: FnMut(&[u8], &StoredValue),
+    {
+        self.entries.scan(cursor, |key, stored| {
+            if !stored.expired() {
+                f(key, stored)
+            }
+        })
+    }
+
+    /// Deletes expired keys, and expired fields of the hashes it comes by,
+    /// from the next slice of the table. Keeps going while more than a
+    /// quarter of the inspected keys turn out to be expired, within a time
+    /// budget, so that a mostly live keyspace costs little and a mostly
+    /// expired one is reclaimed quickly.
+    pub fn expire_cycle(&mut self) {
+        let started = unix_millis();
+        loop {
+            let (mut visited, mut expired, mut hashes) = (0, vec![], vec![]);
+            let mut cursor = self.expire_cursor;
+            for _ in 0..ACTIVE_EXPIRE_BUCKETS {
+                cursor = self.entries.scan(cursor, |key, stored| {
+                    visited += 1;
+                    if stored.expired() {
+                        expired.push(key.clone());
+                    } else if let Data::Hash(hash) = &stored.data {
+                        if hash.needs_purge(started) {
+                            hashes.push(key.clone());
+                        }
+                    }
+                });
+                if cursor == 0 {
+                    break;
+                }
+            }
+            self.expire_cursor = cursor;
+            for key in &expired {
+                self.remove_expired(key);
+            }
+            for key in &hashes {
+                if let Some(Data::Hash(hash)) =
+                    self.entries.get_mut(key).map(|stored| &mut stored.data)
+                {
+                    if hash.purge() > 0 {
+                        self.notify(Class::Hash, "hexpired", key);
+                    }
+                }
+                self.remove_if_empty(key);
+            }
+            if expired.len() * 4 <= visited || unix_millis() - started >= ACTIVE_EXPIRE_MILLIS {
+                break;
+            }
+        }
+    }
+}
+
+/// The numbered databases of the server.
+pub struct Store {
+    databases: Vec<Database>,
+    blocked: Blocked,
+    pubsub: PubSub,
+    scripts: Scripts,
+    functions: Functions,
+    persistence: Persistence,
+}
+
+impl Store {
+    pub fn new(count: usize, keyspace_events: Flags, persistence: Persistence) -> Store {
+        Store {
+            databases: (0..count)
+                .map(|_| Database {
+                    events: Events::new(keyspace_events),
+                    ..Database::default()
+                })
+                .collect(),
+            blocked: Blocked::default(),
+            pubsub: PubSub::default(),
+            scripts: Scripts::default(),
+            functions: Functions::default(),
+            persistence,
+        }
+    }
+
+    /// Validates a client-supplied database number.
+    pub fn index(&self, index: i64) -> Result<usize, Error> {
+        index
+            .try_into()
+            .ok()
+            .filter(|&index: &usize| index < self.databases.len())
+            .ok_or_else(|| Error::Argument("DB index is out of range".to_owned()))
+    }
+
+    pub fn databases(&self) -> &[Database] {
+        &self.databases
+    }
+
+    pub fn database(&mut self, index: usize) -> &mut Database {
+        &mut self.databases[index]
+    }
+
+    pub fn scripts(&mut self) -> &mut Scripts {
+        &mut self.scripts
+    }
+
+    pub fn functions(&mut self) -> &mut Functions {
+        &mut self.functions
+    }
+
+    pub fn persistence(&mut self) -> &mut Persistence {
+        &mut self.persistence
+    }
+
+    pub fn pubsub(&mut self) -> &mut PubSub {
+        &mut self.pubsub
+    }
+
+    /// Empties every database, returning the old keyspaces.
+    pub fn take_all(&mut self) -> Vec<Database> {
+        self.databases.iter_mut().map(Database::flush).collect()
+    }
+
+    /// Exchanges the keyspaces of two databases. Blocked clients stay with
+    /// the database number, so keys they wait on may now be ready.
+    pub fn swap(&mut self, first: usize, second: usize) {
+        if first == second {
+            return;
+        }
+        stats::increment(&STATS.dirty, 1);
+        let (low, high) = self.databases.split_at_mut(first.max(second));
+        let (first, second) = (&mut low[first.min(second)], &mut high[0]);
+        // Watched keys change if they exist on either side.
+        first.touch_existing();
+        second.touch_existing();
+        std::mem::swap(&mut first.entries, &mut second.entries);
+        first.touch_existing();
+        second.touch_existing();
+        std::mem::swap(&mut first.expire_cursor, &mut second.expire_cursor);
+        for database in [first, second].iter_mut() {
+            let keys: Vec<_> = database.blocking.keys().cloned().collect();
+            for key in keys {
+                if database.contains(&key) {
+                    database.signal(&key);
+                }
+            }
+        }
+    }
+
+    /// Parks a command that waits on keys of database `db`; the reply
+    /// arrives on the receiver once a write serves it.
+    pub fn block(
+        &mut self,
+        db: usize,
+        operation: Blocking,
+        command: Option<Vec<Vec<u8>>>,
+    ) -> (ClientId, oneshot::Receiver<Reply>) {
+        let keys = operation.keys().to_vec();
+        let (id, receiver) = self.blocked.insert(db, operation, command);
+        for key in &keys {
+            self.databases[db].block(key, id);
+        }
+        (id, receiver)
+    }
+
+    /// Gives up on a blocked command. Returns false when it was served
+    /// already, in which case its reply is waiting on the receiver.
+    pub fn unblock(&mut self, id: ClientId) -> bool {
+        match self.blocked.remove(id) {
+            Some((db, keys)) => {
+                for key in &keys {
+                    self.databases[db].unblock(key, id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Serves the clients blocked on keys that became ready, first come
+    /// first served. Serving may fill other keys, so this runs until no key
+    /// is ready anymore.
+    pub fn serve_blocked(&mut self) {
+        loop {
+            let mut served = false;
+            for db in 0..self.databases.len() {
+                let ready = std::mem::take(&mut self.databases[db].ready);
+                for key in ready {
+                    served = true;
+                    let clients = self.databases[db]
+                        .blocking
+                        .get(&key)
+                        .cloned()
+                        .unwrap_or_default();
+                    for id in clients {
+                        if let Some(unblocked) = self.blocked.serve(id, &mut self.databases[db]) {
+                            for key in &unblocked.keys {
+                                self.databases[db].unblock(key, id);
+                            }
+                            if let Some(command) = unblocked.command {
+                                // Only SPOP and XADD are logged by their reply.
+                                self.persistence.propagate(db, command, &Value::Nil);
+                            }
+                        }
+                    }
+                }
+            }
+            if !served {
+                break;
+            }
+        }
+    }
+
+    pub fn expire_cycle(&mut self) {
+        for database in self.databases.iter_mut().filter(|db| !db.is_empty()) {
+            database.expire_cycle();
+        }
+    }
+
+    /// Publishes the keyspace notifications of the commands that ran.
+    pub fn publish_events(&mut self) {
+        for (db, database) in self.databases.iter().enumerate() {
+            database.events.publish(db, &mut self.pubsub);
+        }
+    }
+}
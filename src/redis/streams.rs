@@ -0,0 +1,2184 @@
+use super::blocking::{self, Blocking, BlockingCommand};
+use super::command::Arguments;
+use super::notify::Class;
+use super::storage::{self, Data, Database};
+use super::{Error, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::fmt;
+
+/// Entries per node of a stream, Redis' default `stream-node-max-entries`.
+/// Approximate trimming removes whole nodes only.
+pub const NODE_MAX_ENTRIES: usize = 100;
+
+/// The ID of a stream entry: a millisecond timestamp and a sequence number
+/// telling apart entries added within the same millisecond.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parses `<ms>-<seq>`, or a bare `<ms>` taking `seq` as its sequence.
+    pub fn parse(data: &[u8], seq: u64) -> Result<StreamId, Error> {
+        let text = std::str::from_utf8(data).map_err(|_| invalid_id())?;
+        let (ms, seq) = match text.find('-') {
+            Some(dash) => (&text[..dash], parse_part(&text[dash + 1..])?),
+            None => (text, seq),
+        };
+        Ok(StreamId {
+            ms: parse_part(ms)?,
+            seq,
+        })
+    }
+
+    /// The greatest ID smaller than this one.
+    pub fn predecessor(self) -> Option<StreamId> {
+        if self.seq > 0 {
+            Some(StreamId {
+                ms: self.ms,
+                seq: self.seq - 1,
+            })
+        } else if self.ms > 0 {
+            Some(StreamId {
+                ms: self.ms - 1,
+                seq: u64::MAX,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The smallest ID greater than this one.
+    pub fn successor(self) -> Option<StreamId> {
+        if self.seq < u64::MAX {
+            Some(StreamId {
+                ms: self.ms,
+                seq: self.seq + 1,
+            })
+        } else if self.ms < u64::MAX {
+            Some(StreamId {
+                ms: self.ms + 1,
+                seq: 0,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn to_value(self) -> Value {
+        Value::String(self.to_string().into_bytes())
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+fn parse_part(text: &str) -> Result<u64, Error> {
+    if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid_id());
+    }
+    text.parse().map_err(|_| invalid_id())
+}
+
+/// A bound of an XRANGE interval: `-`, `+`, or an ID, excluded from the
+/// interval if it follows `(`. A bare `<ms>` takes `seq` as its sequence.
+fn parse_bound(data: &[u8], seq: u64) -> Result<(StreamId, bool), Error> {
+    match data {
+        b"-" => Ok((StreamId::MIN, false)),
+        b"+" => Ok((StreamId::MAX, false)),
+        [b'(', id @ ..] => Ok((StreamId::parse(id, seq)?, true)),
+        _ => Ok((StreamId::parse(data, seq)?, false)),
+    }
+}
+
+/// The first ID of an interval, where a bare `<ms>` starts the millisecond.
+fn parse_start(data: &[u8]) -> Result<StreamId, Error> {
+    match parse_bound(data, 0)? {
+        (start, true) => start
+            .successor()
+            .ok_or_else(|| Error::Argument("invalid start ID for the interval".to_owned())),
+        (start, false) => Ok(start),
+    }
+}
+
+/// The last ID of an interval, where a bare `<ms>` ends the millisecond.
+fn parse_end(data: &[u8]) -> Result<StreamId, Error> {
+    match parse_bound(data, u64::MAX)? {
+        (end, true) => end
+            .predecessor()
+            .ok_or_else(|| Error::Argument("invalid end ID for the interval".to_owned())),
+        (end, false) => Ok(end),
+    }
+}
+
+fn parse_millis(data: &[u8]) -> Option<i64> {
+    std::str::from_utf8(data).ok()?.parse().ok()
+}
+
+/// The minimum idle time of XCLAIM and XAUTOCLAIM, where anything negative
+/// is taken as zero.
+fn parse_min_idle(data: &[u8], command: &str) -> Result<i64, Error> {
+    match parse_millis(data) {
+        Some(min_idle) => Ok(min_idle.max(0)),
+        None => Err(Error::Argument(format!(
+            "Invalid min-idle-time argument for {}",
+            command
+        ))),
+    }
+}
+
+fn invalid_id() -> Error {
+    Error::Argument("Invalid stream ID specified as stream command argument".to_owned())
+}
+
+/// An entry of a stream: its ID and its field/value pairs, in the order
+/// they were given.
+#[derive(Clone)]
+pub struct Entry {
+    pub id: StreamId,
+    pub fields: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// The entries of a stream in nodes of up to `NODE_MAX_ENTRIES` consecutive
+/// entries, keyed by the ID of the first entry each node was created with,
+/// so appending only ever touches the last node and IDs are found without
+/// scanning the entries before them.
+#[derive(Clone, Default)]
+struct Entries {
+    nodes: BTreeMap<StreamId, Vec<Entry>>,
+    len: usize,
+}
+
+impl Entries {
+    /// Appends an entry, whose ID must be greater than all others.
+    fn push(&mut self, entry: Entry) {
+        match self.nodes.values_mut().next_back() {
+            Some(node) if node.len() < NODE_MAX_ENTRIES => node.push(entry),
+            _ => {
+                self.nodes.insert(entry.id, vec![entry]);
+            }
+        }
+        self.len += 1;
+    }
+
+    /// The key of the node `id` belongs in, if there is one.
+    fn node_key(&self, id: StreamId) -> Option<StreamId> {
+        self.nodes.range(..=id).next_back().map(|(&key, _)| key)
+    }
+
+    fn get(&self, id: StreamId) -> Option<&Entry> {
+        let node = &self.nodes[&self.node_key(id)?];
+        let index = node.binary_search_by_key(&id, |entry| entry.id).ok()?;
+        Some(&node[index])
+    }
+
+    fn remove(&mut self, id: StreamId) -> bool {
+        let key = match self.node_key(id) {
+            Some(key) => key,
+            None => return false,
+        };
+        let node = self.nodes.get_mut(&key).expect("node was just found");
+        let index = match node.binary_search_by_key(&id, |entry| entry.id) {
+            Ok(index) => index,
+            Err(_) => return false,
+        };
+        node.remove(index);
+        if node.is_empty() {
+            self.nodes.remove(&key);
+        }
+        self.len -= 1;
+        true
+    }
+
+    /// The oldest node.
+    fn first_node(&self) -> Option<&[Entry]> {
+        self.nodes.values().next().map(Vec::as_slice)
+    }
+
+    /// Removes the `count` oldest entries.
+    fn remove_first(&mut self, mut count: usize) {
+        while count > 0 {
+            let (&key, node) = match self.nodes.iter_mut().next() {
+                Some(first) => first,
+                None => return,
+            };
+            let removed = count.min(node.len());
+            node.drain(..removed);
+            if node.is_empty() {
+                self.nodes.remove(&key);
+            }
+            self.len -= removed;
+            count -= removed;
+        }
+    }
+
+    fn range(&self, start: StreamId, end: StreamId) -> impl DoubleEndedIterator<Item = &Entry> {
+        // The node holding `start` may begin before it; entries outside the
+        // range are left out of the first and last nodes.
+        let from = if start <= end {
+            self.node_key(start).unwrap_or(start)
+        } else {
+            end
+        };
+        self.nodes
+            .range(from..=end)
+            .flat_map(|(_, node)| node.iter())
+            .filter(move |entry| entry.id >= start && entry.id <= end)
+    }
+
+    fn iter(&self) -> impl DoubleEndedIterator<Item = &Entry> {
+        self.nodes.values().flat_map(|node| node.iter())
+    }
+}
+
+/// An append-only log of entries in increasing ID order. Unlike other
+/// collections a stream outlives its last entry, keeping its last ID.
+#[derive(Clone, Default)]
+pub struct Stream {
+    entries: Entries,
+    /// The ID of the newest entry ever added, even if it was deleted since.
+    last_id: StreamId,
+    /// The greatest ID of a deleted entry.
+    max_deleted_id: StreamId,
+    /// Entries ever added, deleted ones included.
+    entries_added: u64,
+    groups: BTreeMap<Vec<u8>, Group>,
+}
+
+impl Stream {
+    pub fn new() -> Stream {
+        Stream::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len
+    }
+
+    /// How many nodes the entries take.
+    pub fn nodes(&self) -> usize {
+        self.entries.nodes.len()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn max_deleted_id(&self) -> StreamId {
+        self.max_deleted_id
+    }
+
+    pub fn entries_added(&self) -> u64 {
+        self.entries_added
+    }
+
+    /// Appends an entry, whose ID must be greater than the last one.
+    pub fn append(&mut self, id: StreamId, fields: Vec<(Vec<u8>, Vec<u8>)>) {
+        self.entries.push(Entry { id, fields });
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    /// Deletes an entry; false if there is none with that ID.
+    pub fn delete(&mut self, id: StreamId) -> bool {
+        if !self.entries.remove(id) {
+            return false;
+        }
+        self.max_deleted_id = self.max_deleted_id.max(id);
+        true
+    }
+
+    /// Deletes the oldest entries as `trim` asks, returning how many.
+    /// Entries pending in groups stay so, as with XDEL.
+    pub fn trim(&mut self, trim: &Trim) -> usize {
+        let mut removed = 0;
+        while let Some(node) = self.entries.first_node() {
+            let excess = match trim.to {
+                TrimTo::MaxLen(max_len) => self.len().saturating_sub(max_len),
+                TrimTo::MinId(min_id) => node.partition_point(|entry| entry.id < min_id),
+            };
+            if excess == 0 || (trim.limit > 0 && removed + node.len() > trim.limit) {
+                break;
+            }
+            if excess >= node.len() {
+                let whole = node.len();
+                self.entries.remove_first(whole);
+                removed += whole;
+                continue;
+            }
+            // Approximate trimming keeps the rest of the node.
+            if !trim.approximate {
+                self.entries.remove_first(excess);
+                removed += excess;
+            }
+            break;
+        }
+        removed
+    }
+
+    /// Restores the bookkeeping persisted alongside the entries.
+    pub fn restore_metadata(
+        &mut self,
+        last_id: StreamId,
+        max_deleted_id: StreamId,
+        entries_added: u64,
+    ) {
+        self.last_id = last_id;
+        self.max_deleted_id = max_deleted_id;
+        self.entries_added = entries_added;
+    }
+
+    /// The entries with IDs from `start` to `end`, both included.
+    pub fn range(&self, start: StreamId, end: StreamId) -> impl DoubleEndedIterator<Item = &Entry> {
+        self.entries.range(start, end)
+    }
+
+    /// Every entry, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    /// The entries by node, oldest first.
+    pub fn iter_nodes(&self) -> impl Iterator<Item = &[Entry]> {
+        self.entries.nodes.values().map(Vec::as_slice)
+    }
+
+    /// The consumer groups, by name.
+    pub fn groups(&self) -> &BTreeMap<Vec<u8>, Group> {
+        &self.groups
+    }
+
+    pub fn group_mut(&mut self, name: &[u8]) -> Option<&mut Group> {
+        self.groups.get_mut(name)
+    }
+
+    /// Adds a group that has seen the entries up to `last_id`, having read
+    /// `entries_read` of them if known; false if there is one by that name
+    /// already.
+    pub fn create_group(
+        &mut self,
+        name: Vec<u8>,
+        last_id: StreamId,
+        entries_read: Option<u64>,
+    ) -> bool {
+        if self.groups.contains_key(&name) {
+            return false;
+        }
+        self.groups.insert(name, Group::new(last_id, entries_read));
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &[u8]) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// The ID of the oldest entry, or 0-0 if there is none.
+    pub fn first_id(&self) -> StreamId {
+        self.entries
+            .iter()
+            .next()
+            .map_or(StreamId::MIN, |entry| entry.id)
+    }
+
+    /// Whether entries from `start` on may have been deleted.
+    fn has_tombstones(&self, start: StreamId) -> bool {
+        self.entries.len > 0
+            && self.max_deleted_id != StreamId::MIN
+            && self.first_id() <= self.max_deleted_id
+            && start <= self.max_deleted_id
+    }
+
+    /// How many entries were ever added up to `id`, if that can be told
+    /// from the entries added and deleted so far.
+    pub fn estimate_entries_read(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if id == self.last_id || (self.entries.len == 0 && id < self.last_id) {
+            return Some(self.entries_added);
+        }
+        if id > self.last_id {
+            return None;
+        }
+        let first = self.first_id();
+        let before_first = self.entries_added.saturating_sub(self.entries.len as u64);
+        // Only deletions before the first entry keep the count exact.
+        if self.max_deleted_id == StreamId::MIN || self.max_deleted_id < first {
+            if id < first {
+                return Some(before_first);
+            }
+            if id == first {
+                return Some(before_first + 1);
+            }
+        }
+        None
+    }
+
+    /// How many entries `group` has yet to read, if that can be told.
+    pub fn lag(&self, group: &Group) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let read = match group.entries_read {
+            Some(read) if !self.has_tombstones(group.last_id) => Some(read),
+            _ => self.estimate_entries_read(group.last_id),
+        };
+        read.map(|read| self.entries_added.saturating_sub(read))
+    }
+
+    /// Delivers to a consumer of `group` up to `count` entries the group
+    /// has not delivered yet, leaving them pending unless `noack` is set.
+    /// None if there is no such group.
+    pub fn deliver(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        count: usize,
+        noack: bool,
+        now: i64,
+    ) -> Option<Vec<Entry>> {
+        let (last_id, mut entries_read) = match self.groups.get(group) {
+            Some(group) => (group.last_id, group.entries_read),
+            None => return None,
+        };
+        let entries: Vec<_> = match last_id.successor() {
+            Some(start) => self
+                .entries
+                .range(start, StreamId::MAX)
+                .take(count)
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+        // The count of entries read goes up with each entry while there are
+        // no deleted entries ahead to throw it off.
+        for entry in &entries {
+            entries_read = match entries_read {
+                Some(read) if !self.has_tombstones(entry.id) => Some(read + 1),
+                _ => self.estimate_entries_read(entry.id),
+            };
+        }
+        let group = self.groups.get_mut(group)?;
+        group.consumer(consumer, now);
+        if let Some(last) = entries.last() {
+            group.last_id = last.id;
+            group.entries_read = entries_read;
+            group.consumer(consumer, now).active = Some(now);
+        }
+        if !noack {
+            for entry in &entries {
+                group.set_pending(
+                    entry.id,
+                    Pending {
+                        consumer: consumer.to_vec(),
+                        delivered: now,
+                        deliveries: 1,
+                    },
+                );
+            }
+        }
+        Some(entries)
+    }
+
+    /// Delivers again up to `count` of the entries pending for a consumer of
+    /// `group`, with IDs after `after`. Entries deleted from the stream
+    /// meanwhile come back as just their ID. None if there is no such group.
+    pub fn redeliver(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        after: StreamId,
+        count: usize,
+        now: i64,
+    ) -> Option<Vec<(StreamId, Option<Entry>)>> {
+        let group = self.groups.get_mut(group)?;
+        let ids: Vec<_> = match after.successor() {
+            Some(start) => group
+                .consumer(consumer, now)
+                .pending
+                .range(start..)
+                .take(count)
+                .copied()
+                .collect(),
+            None => vec![],
+        };
+        let mut entries = vec![];
+        for id in ids {
+            let entry = self.entries.get(id).cloned();
+            if entry.is_some() {
+                if let Some(pending) = group.pending.get_mut(&id) {
+                    pending.delivered = now;
+                    pending.deliveries += 1;
+                }
+            }
+            entries.push((id, entry));
+        }
+        Some(entries)
+    }
+
+    /// Hands the entries of `ids` pending in `group` over to `consumer`, as
+    /// XCLAIM does, skipping those idle for less than the minimum. IDs that
+    /// are no longer in the stream stop being pending. None if there is no
+    /// such group.
+    pub fn claim(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        ids: &[StreamId],
+        options: &ClaimOptions,
+        now: i64,
+    ) -> Option<Vec<Entry>> {
+        let group = self.groups.get_mut(group)?;
+        group.consumer(consumer, now);
+        let delivered = match options.delivered {
+            Some(Delivered::Idle(idle)) => now.saturating_sub(idle),
+            Some(Delivered::At(time)) => time,
+            None => now,
+        };
+        // Delivery times in the future are taken as now.
+        let delivered = if (0..=now).contains(&delivered) {
+            delivered
+        } else {
+            now
+        };
+        let mut claimed = vec![];
+        for &id in ids {
+            let entry = match self.entries.get(id) {
+                Some(entry) => entry,
+                None => {
+                    group.acknowledge(id);
+                    continue;
+                }
+            };
+            if options.force && !group.pending.contains_key(&id) {
+                group.set_pending(
+                    id,
+                    Pending {
+                        consumer: consumer.to_vec(),
+                        delivered: now,
+                        deliveries: 1,
+                    },
+                );
+            }
+            let pending = match group.pending.get(&id) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            if options.min_idle > 0 && now - pending.delivered < options.min_idle {
+                continue;
+            }
+            let deliveries = match options.deliveries {
+                Some(deliveries) => deliveries,
+                None => pending.deliveries + u64::from(!options.just_id),
+            };
+            group.take_over(id, consumer, delivered, deliveries, now);
+            claimed.push(entry.clone());
+        }
+        Some(claimed)
+    }
+
+    /// Hands over to `consumer` up to `count` entries pending in `group`
+    /// from `start` on, as XAUTOCLAIM does, looking at no more than ten
+    /// times as many. Returns the next pending ID to carry on from, 0-0 if
+    /// there is none left after those looked at, the entries claimed, and
+    /// the IDs that are no longer in the stream, which stop being pending.
+    /// None if there is no such group.
+    pub fn auto_claim(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        start: StreamId,
+        count: usize,
+        options: &ClaimOptions,
+        now: i64,
+    ) -> Option<(StreamId, Vec<Entry>, Vec<StreamId>)> {
+        let group = self.groups.get_mut(group)?;
+        group.consumer(consumer, now);
+        let mut attempts = count.saturating_mul(10);
+        let mut count = count;
+        let mut cursor = Some(start);
+        let mut claimed = vec![];
+        let mut deleted = vec![];
+        while attempts > 0 && count > 0 {
+            let (id, pending) = match cursor.and_then(|from| group.pending.range(from..).next()) {
+                Some((&id, pending)) => (id, pending),
+                None => {
+                    cursor = None;
+                    break;
+                }
+            };
+            cursor = id.successor();
+            attempts -= 1;
+            let entry = match self.entries.get(id) {
+                Some(entry) => entry,
+                None => {
+                    group.acknowledge(id);
+                    deleted.push(id);
+                    count -= 1;
+                    continue;
+                }
+            };
+            if options.min_idle > 0 && now - pending.delivered < options.min_idle {
+                continue;
+            }
+            let deliveries = pending.deliveries + u64::from(!options.just_id);
+            group.take_over(id, consumer, now, deliveries, now);
+            claimed.push(entry.clone());
+            count -= 1;
+        }
+        // The next entry still pending is where to carry on from, if any.
+        let next = cursor
+            .and_then(|from| group.pending.range(from..).next())
+            .map_or(StreamId::MIN, |(&id, _)| id);
+        Some((next, claimed, deleted))
+    }
+}
+
+/// An entry delivered to a consumer of a group and not acknowledged yet.
+#[derive(Clone)]
+pub struct Pending {
+    pub consumer: Vec<u8>,
+    /// Unix milliseconds of the last delivery.
+    pub delivered: i64,
+    pub deliveries: u64,
+}
+
+/// A reader within a consumer group.
+#[derive(Clone)]
+pub struct Consumer {
+    /// Unix milliseconds of its last command.
+    pub seen: i64,
+    /// Unix milliseconds of its last successful read, if any.
+    pub active: Option<i64>,
+    /// The entries delivered to it and not acknowledged yet.
+    pending: BTreeSet<StreamId>,
+}
+
+impl Consumer {
+    pub fn pending(&self) -> &BTreeSet<StreamId> {
+        &self.pending
+    }
+}
+
+/// Readers sharing a stream: each entry after the group's last delivered
+/// ID goes to one of them, and stays pending until it is acknowledged.
+#[derive(Clone)]
+pub struct Group {
+    last_id: StreamId,
+    /// How many entries of the stream the group has read, up to `last_id`,
+    /// unless that is unknown.
+    entries_read: Option<u64>,
+    pending: BTreeMap<StreamId, Pending>,
+    consumers: BTreeMap<Vec<u8>, Consumer>,
+}
+
+impl Group {
+    fn new(last_id: StreamId, entries_read: Option<u64>) -> Group {
+        Group {
+            last_id,
+            entries_read,
+            pending: BTreeMap::new(),
+            consumers: BTreeMap::new(),
+        }
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn set_last_id(&mut self, id: StreamId) {
+        self.last_id = id;
+    }
+
+    pub fn entries_read(&self) -> Option<u64> {
+        self.entries_read
+    }
+
+    pub fn set_entries_read(&mut self, entries_read: Option<u64>) {
+        self.entries_read = entries_read;
+    }
+
+    /// The pending entries of all consumers.
+    pub fn pending(&self) -> &BTreeMap<StreamId, Pending> {
+        &self.pending
+    }
+
+    /// The consumers, by name.
+    pub fn consumers(&self) -> &BTreeMap<Vec<u8>, Consumer> {
+        &self.consumers
+    }
+
+    /// The consumer by that name, created if there is none, marked as seen
+    /// at `now`.
+    pub fn consumer(&mut self, name: &[u8], now: i64) -> &mut Consumer {
+        let consumer = self
+            .consumers
+            .entry(name.to_vec())
+            .or_insert_with(|| Consumer {
+                seen: now,
+                active: None,
+                pending: BTreeSet::new(),
+            });
+        consumer.seen = now;
+        consumer
+    }
+
+    /// Creates a consumer; false if there is one by that name already.
+    pub fn add_consumer(&mut self, name: &[u8], now: i64) -> bool {
+        if self.consumers.contains_key(name) {
+            return false;
+        }
+        self.consumer(name, now);
+        true
+    }
+
+    /// Deletes a consumer along with its pending entries, returning how
+    /// many it had.
+    pub fn remove_consumer(&mut self, name: &[u8]) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+        for id in &consumer.pending {
+            self.pending.remove(id);
+        }
+        Some(consumer.pending.len())
+    }
+
+    /// Makes an entry pending for `pending.consumer`, which must exist,
+    /// taking it from the consumer it was pending for before if any.
+    pub fn set_pending(&mut self, id: StreamId, pending: Pending) {
+        if let Some(previous) = self.pending.get(&id) {
+            if let Some(consumer) = self.consumers.get_mut(&previous.consumer) {
+                consumer.pending.remove(&id);
+            }
+        }
+        if let Some(consumer) = self.consumers.get_mut(&pending.consumer) {
+            consumer.pending.insert(id);
+        }
+        self.pending.insert(id, pending);
+    }
+
+    /// Makes a pending entry pending for `consumer` instead, as delivered at
+    /// `delivered`, and marks the consumer as active at `now`.
+    fn take_over(
+        &mut self,
+        id: StreamId,
+        consumer: &[u8],
+        delivered: i64,
+        deliveries: u64,
+        now: i64,
+    ) {
+        self.set_pending(
+            id,
+            Pending {
+                consumer: consumer.to_vec(),
+                delivered,
+                deliveries,
+            },
+        );
+        self.consumer(consumer, now).active = Some(now);
+    }
+
+    /// Acknowledges an entry; false if it was not pending.
+    pub fn acknowledge(&mut self, id: StreamId) -> bool {
+        let pending = match self.pending.remove(&id) {
+            Some(pending) => pending,
+            None => return false,
+        };
+        if let Some(consumer) = self.consumers.get_mut(&pending.consumer) {
+            consumer.pending.remove(&id);
+        }
+        true
+    }
+}
+
+/// How XADD picks the ID of the new entry.
+pub enum NewId {
+    /// `*`: the current time, or just after the last ID if the clock lags.
+    Auto,
+    /// `<ms>-*`: the next free sequence number within the millisecond.
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl NewId {
+    fn parse(data: &[u8]) -> Result<NewId, Error> {
+        if data == b"*" {
+            return Ok(NewId::Auto);
+        }
+        if let Some(ms) = data.strip_suffix(b"-*") {
+            let ms = std::str::from_utf8(ms).map_err(|_| invalid_id())?;
+            return Ok(NewId::AutoSeq(parse_part(ms)?));
+        }
+        let id = StreamId::parse(data, 0)?;
+        if id == StreamId::MIN {
+            return Err(Error::Argument(
+                "The ID specified in XADD must be greater than 0-0".to_owned(),
+            ));
+        }
+        Ok(NewId::Explicit(id))
+    }
+
+    /// The ID of an entry added after `last`.
+    fn resolve(&self, last: StreamId) -> Result<StreamId, Error> {
+        let too_small = || {
+            Error::Argument(
+                "The ID specified in XADD is equal or smaller than the target stream top item"
+                    .to_owned(),
+            )
+        };
+        match *self {
+            NewId::Auto => {
+                let now = storage::unix_millis().max(0) as u64;
+                if now > last.ms {
+                    return Ok(StreamId { ms: now, seq: 0 });
+                }
+                last.successor().ok_or_else(|| {
+                    Error::Argument(
+                        "The stream has exhausted the last possible ID, unable to add more items"
+                            .to_owned(),
+                    )
+                })
+            }
+            NewId::AutoSeq(ms) if ms > last.ms => Ok(StreamId { ms, seq: 0 }),
+            NewId::AutoSeq(ms) if ms == last.ms && last.seq < u64::MAX => Ok(StreamId {
+                ms,
+                seq: last.seq + 1,
+            }),
+            NewId::AutoSeq(_) => Err(too_small()),
+            NewId::Explicit(id) if id > last => Ok(id),
+            NewId::Explicit(_) => Err(too_small()),
+        }
+    }
+}
+
+/// The options of XADD.
+pub struct AddOptions {
+    /// Whether a missing stream is created; cleared by NOMKSTREAM.
+    make_stream: bool,
+    /// How the stream is trimmed once the entry is added.
+    trim: Option<Trim>,
+}
+
+/// What trimming keeps of a stream.
+#[derive(Clone, Copy)]
+pub enum TrimTo {
+    /// MAXLEN: at most this many entries.
+    MaxLen(usize),
+    /// MINID: the entries with this ID or greater.
+    MinId(StreamId),
+}
+
+/// How XADD and XTRIM trim a stream.
+pub struct Trim {
+    to: TrimTo,
+    /// Whether only whole nodes are removed, as with `~`, keeping some
+    /// entries past the threshold.
+    approximate: bool,
+    /// The most entries an approximate trim removes, or 0 for no limit.
+    limit: usize,
+}
+
+/// The trimming options of XADD and XTRIM, as they are read.
+#[derive(Default)]
+struct TrimOptions {
+    to: Option<TrimTo>,
+    approximate: bool,
+    limit: Option<usize>,
+}
+
+impl TrimOptions {
+    /// Reads the arguments of `option` if it is a trimming option, which
+    /// has arguments left to read; false if it is not one.
+    fn parse(&mut self, option: &str, args: &mut Arguments) -> Result<bool, Error> {
+        match option {
+            "maxlen" | "minid" => {
+                if self.to.is_some() {
+                    return Err(Error::Argument(
+                        "syntax error, MAXLEN and MINID options at the same time are not compatible"
+                            .to_owned(),
+                    ));
+                }
+                self.approximate = false;
+                if args.len() >= 2 {
+                    if args.next_if("~") {
+                        self.approximate = true;
+                    } else {
+                        args.next_if("=");
+                    }
+                }
+                self.to = Some(if option == "maxlen" {
+                    let max_len = args.next_int()?;
+                    if max_len < 0 {
+                        return Err(Error::Argument(
+                            "The MAXLEN argument must be >= 0.".to_owned(),
+                        ));
+                    }
+                    TrimTo::MaxLen(max_len.try_into().unwrap_or(usize::MAX))
+                } else {
+                    TrimTo::MinId(StreamId::parse(&args.next_bytes()?, 0)?)
+                });
+            }
+            "limit" => {
+                let limit = args.next_int()?;
+                if limit < 0 {
+                    return Err(Error::Argument(
+                        "The LIMIT argument must be >= 0.".to_owned(),
+                    ));
+                }
+                self.limit = Some(limit.try_into().unwrap_or(usize::MAX));
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// The trim the options ask for, which XTRIM requires.
+    fn finish(self, required: bool) -> Result<Option<Trim>, Error> {
+        if self.to.is_none() {
+            if self.limit.map_or(false, |limit| limit > 0) {
+                return Err(Error::Argument(
+                    "syntax error, LIMIT cannot be used without specifying a trimming strategy"
+                        .to_owned(),
+                ));
+            }
+            if required {
+                return Err(Error::Argument(
+                    "syntax error, XTRIM must be called with a trimming strategy".to_owned(),
+                ));
+            }
+        }
+        let limit = match self.limit {
+            Some(_) if !self.approximate => {
+                return Err(Error::Argument(
+                    "syntax error, LIMIT cannot be used without the special ~ option".to_owned(),
+                ))
+            }
+            Some(limit) => limit,
+            // Approximate trimming does a bounded amount of work unless
+            // told otherwise, while exact trimming is never limited.
+            None if self.approximate => 100 * NODE_MAX_ENTRIES,
+            None => 0,
+        };
+        Ok(self.to.map(|to| Trim {
+            to,
+            approximate: self.approximate,
+            limit,
+        }))
+    }
+}
+
+/// When XCLAIM takes entries as delivered.
+pub enum Delivered {
+    /// IDLE: some milliseconds ago.
+    Idle(i64),
+    /// TIME: at a Unix time in milliseconds.
+    At(i64),
+}
+
+/// The options of XCLAIM and XAUTOCLAIM.
+#[derive(Default)]
+pub struct ClaimOptions {
+    /// How long, in milliseconds, an entry has to be idle to be claimed.
+    min_idle: i64,
+    /// When claimed entries count as delivered, now if not given.
+    delivered: Option<Delivered>,
+    /// The delivery count to set, as with RETRYCOUNT.
+    deliveries: Option<u64>,
+    /// Whether IDs that are not pending become so, as with FORCE.
+    force: bool,
+    /// Whether just IDs are returned, as with JUSTID, in which case the
+    /// claim does not count as a delivery.
+    just_id: bool,
+    /// The ID the group has read up to at least from then on, as with LASTID.
+    last_id: Option<StreamId>,
+}
+
+/// The extended form of XPENDING, detailing each pending entry.
+pub struct PendingRange {
+    min_idle: i64,
+    start: StreamId,
+    end: StreamId,
+    count: usize,
+    /// Restricts the entries to those of a consumer.
+    consumer: Option<Vec<u8>>,
+}
+
+pub enum StreamCommand {
+    Add(Vec<u8>, AddOptions, NewId, Vec<(Vec<u8>, Vec<u8>)>),
+    Trim(Vec<u8>, Trim),
+    Delete(Vec<u8>, Vec<StreamId>),
+    Len(Vec<u8>),
+    /// The entries between two IDs, both included, newest first if set,
+    /// and at most a count of them.
+    Range(Vec<u8>, StreamId, StreamId, bool, Option<usize>),
+    /// XGROUP CREATE: the group, the ID it has read up to or None for `$`,
+    /// the count of entries read as given with ENTRIESREAD, and whether a
+    /// missing stream is created, as with MKSTREAM.
+    CreateGroup(Vec<u8>, Vec<u8>, Option<StreamId>, Option<u64>, bool),
+    SetGroupId(Vec<u8>, Vec<u8>, Option<StreamId>, Option<u64>),
+    DestroyGroup(Vec<u8>, Vec<u8>),
+    CreateConsumer(Vec<u8>, Vec<u8>, Vec<u8>),
+    DeleteConsumer(Vec<u8>, Vec<u8>, Vec<u8>),
+    GroupHelp,
+    Ack(Vec<u8>, Vec<u8>, Vec<StreamId>),
+    /// XPENDING: a summary of the pending entries of a group, unless a
+    /// range is given.
+    Pending(Vec<u8>, Vec<u8>, Option<PendingRange>),
+    /// XCLAIM: the group, the consumer and the IDs it takes over.
+    Claim(Vec<u8>, Vec<u8>, Vec<u8>, Vec<StreamId>, ClaimOptions),
+    /// XAUTOCLAIM: the group, the consumer, and the ID and count of the
+    /// pending entries it scans.
+    AutoClaim(Vec<u8>, Vec<u8>, Vec<u8>, StreamId, usize, ClaimOptions),
+    /// XINFO STREAM, with the count of entries and pending entries listed
+    /// if FULL is given, 0 for all of them.
+    StreamInfo(Vec<u8>, Option<usize>),
+    GroupsInfo(Vec<u8>),
+    ConsumersInfo(Vec<u8>, Vec<u8>),
+    InfoHelp,
+}
+
+impl StreamCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<StreamCommand>, Error> {
+        let command = match name {
+            "xadd" => {
+                let key = args.next_bytes()?;
+                let mut make_stream = true;
+                let mut trim = TrimOptions::default();
+                let id = loop {
+                    let arg = args.next_bytes()?;
+                    match String::from_utf8_lossy(&arg).to_lowercase().as_str() {
+                        "nomkstream" => make_stream = false,
+                        option if !args.is_empty() && trim.parse(option, args)? => {}
+                        _ => break arg,
+                    }
+                };
+                let options = AddOptions {
+                    make_stream,
+                    trim: trim.finish(false)?,
+                };
+                if args.is_empty() || args.len() % 2 != 0 {
+                    return Err(args.wrong_arity());
+                }
+                let id = NewId::parse(&id)?;
+                let rest = args.rest()?;
+                let fields = rest
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                StreamCommand::Add(key, options, id, fields)
+            }
+            "xtrim" => {
+                let key = args.next_bytes()?;
+                if args.len() < 2 {
+                    return Err(args.wrong_arity());
+                }
+                let mut trim = TrimOptions::default();
+                while !args.is_empty() {
+                    let option = args.next_string()?.to_lowercase();
+                    if args.is_empty() || !trim.parse(&option, args)? {
+                        return Err(Error::Syntax);
+                    }
+                }
+                // Always set, as it is required.
+                match trim.finish(true)? {
+                    Some(trim) => StreamCommand::Trim(key, trim),
+                    None => return Err(Error::Syntax),
+                }
+            }
+            "xdel" => {
+                let key = args.next_bytes()?;
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                // All the IDs are checked before any entry is deleted.
+                let ids = args
+                    .rest()?
+                    .iter()
+                    .map(|id| StreamId::parse(id, 0))
+                    .collect::<Result<_, _>>()?;
+                StreamCommand::Delete(key, ids)
+            }
+            "xrange" | "xrevrange" => {
+                let key = args.next_bytes()?;
+                let mut first = args.next_bytes()?;
+                let mut second = args.next_bytes()?;
+                let rev = name == "xrevrange";
+                if rev {
+                    std::mem::swap(&mut first, &mut second);
+                }
+                let start = parse_start(&first)?;
+                let end = parse_end(&second)?;
+                let mut count = None;
+                while !args.is_empty() {
+                    let option = args.next_string()?.to_lowercase();
+                    if option != "count" || args.is_empty() {
+                        return Err(Error::Syntax);
+                    }
+                    // A negative count is taken as zero.
+                    count = Some(args.next_int()?.max(0).try_into()?);
+                }
+                StreamCommand::Range(key, start, end, rev, count)
+            }
+            "xlen" => {
+                let key = args.next_bytes()?;
+                args.finish()?;
+                StreamCommand::Len(key)
+            }
+            "xgroup" => StreamCommand::group(args)?,
+            "xinfo" => StreamCommand::info(args)?,
+            "xack" => {
+                let key = args.next_bytes()?;
+                let group = args.next_bytes()?;
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                let ids = args
+                    .rest()?
+                    .iter()
+                    .map(|id| StreamId::parse(id, 0))
+                    .collect::<Result<_, _>>()?;
+                StreamCommand::Ack(key, group, ids)
+            }
+            "xpending" => {
+                let key = args.next_bytes()?;
+                let group = args.next_bytes()?;
+                if args.is_empty() {
+                    return Ok(Some(StreamCommand::Pending(key, group, None)));
+                }
+                if !(3..=6).contains(&args.len()) {
+                    return Err(Error::Syntax);
+                }
+                let mut start = args.next_bytes()?;
+                let mut min_idle = 0;
+                if start.eq_ignore_ascii_case(b"idle") {
+                    min_idle = args.next_int()?.max(0);
+                    if args.len() < 3 {
+                        return Err(Error::Syntax);
+                    }
+                    start = args.next_bytes()?;
+                }
+                let end = args.next_bytes()?;
+                // A negative count is taken as zero.
+                let count = args.next_int()?.max(0).try_into()?;
+                let range = PendingRange {
+                    min_idle,
+                    start: parse_start(&start)?,
+                    end: parse_end(&end)?,
+                    count,
+                    consumer: if args.is_empty() {
+                        None
+                    } else {
+                        Some(args.next_bytes()?)
+                    },
+                };
+                StreamCommand::Pending(key, group, Some(range))
+            }
+            "xclaim" => {
+                let key = args.next_bytes()?;
+                let group = args.next_bytes()?;
+                let consumer = args.next_bytes()?;
+                let min_idle = args.next_bytes()?;
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                let mut options = ClaimOptions {
+                    min_idle: parse_min_idle(&min_idle, "XCLAIM")?,
+                    ..ClaimOptions::default()
+                };
+                let rest = args.rest()?;
+                // The IDs run up to the first argument that is not one.
+                let mut ids = vec![];
+                for id in &rest {
+                    match StreamId::parse(id, 0) {
+                        Ok(id) => ids.push(id),
+                        Err(_) => break,
+                    }
+                }
+                let mut rest = rest[ids.len()..].iter();
+                while let Some(option) = rest.next() {
+                    let option = String::from_utf8_lossy(option);
+                    let has_value = !rest.as_slice().is_empty();
+                    let invalid = |name: &str| {
+                        Error::Argument(format!("Invalid {} option argument for XCLAIM", name))
+                    };
+                    match option.to_lowercase().as_str() {
+                        "force" => options.force = true,
+                        "justid" => options.just_id = true,
+                        "idle" if has_value => {
+                            let idle = parse_millis(rest.next().unwrap());
+                            options.delivered =
+                                Some(Delivered::Idle(idle.ok_or_else(|| invalid("IDLE"))?));
+                        }
+                        "time" if has_value => {
+                            let time = parse_millis(rest.next().unwrap());
+                            options.delivered =
+                                Some(Delivered::At(time.ok_or_else(|| invalid("TIME"))?));
+                        }
+                        "retrycount" if has_value => {
+                            let retries = parse_millis(rest.next().unwrap())
+                                .ok_or_else(|| invalid("RETRYCOUNT"))?;
+                            // A negative count leaves the delivery count to
+                            // be incremented.
+                            options.deliveries = retries.try_into().ok();
+                        }
+                        "lastid" if has_value => {
+                            options.last_id = Some(StreamId::parse(rest.next().unwrap(), 0)?);
+                        }
+                        _ => {
+                            return Err(Error::Argument(format!(
+                                "Unrecognized XCLAIM option '{}'",
+                                option
+                            )))
+                        }
+                    }
+                }
+                StreamCommand::Claim(key, group, consumer, ids, options)
+            }
+            "xautoclaim" => {
+                let key = args.next_bytes()?;
+                let group = args.next_bytes()?;
+                let consumer = args.next_bytes()?;
+                let min_idle = args.next_bytes()?;
+                let start = args.next_bytes()?;
+                let mut options = ClaimOptions {
+                    min_idle: parse_min_idle(&min_idle, "XAUTOCLAIM")?,
+                    ..ClaimOptions::default()
+                };
+                let start = parse_start(&start)?;
+                let mut count = 100;
+                while !args.is_empty() {
+                    match args.next_string()?.to_lowercase().as_str() {
+                        "count" if !args.is_empty() => {
+                            count = args
+                                .next_int()
+                                .ok()
+                                .filter(|count| (1..=i64::MAX / 16).contains(count))
+                                .ok_or_else(|| Error::Argument("COUNT must be > 0".to_owned()))?
+                                .try_into()?;
+                        }
+                        "justid" => options.just_id = true,
+                        _ => return Err(Error::Syntax),
+                    }
+                }
+                StreamCommand::AutoClaim(key, group, consumer, start, count, options)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    fn group(args: &mut Arguments) -> Result<StreamCommand, Error> {
+        let given = args.next_string()?;
+        let subcommand = given.to_lowercase();
+        let arity = match subcommand.as_str() {
+            "help" => 0..=0,
+            "create" | "setid" => 3..=usize::MAX,
+            "createconsumer" | "delconsumer" => 3..=3,
+            "destroy" => 2..=2,
+            _ => {
+                return Err(Error::Argument(format!(
+                    "unknown subcommand '{}'. Try XGROUP HELP.",
+                    subcommand
+                )))
+            }
+        };
+        if !arity.contains(&args.len()) {
+            return Err(Error::WrongArity(format!("xgroup|{}", subcommand)));
+        }
+        if subcommand == "help" {
+            return Ok(StreamCommand::GroupHelp);
+        }
+        let key = args.next_bytes()?;
+        let group = args.next_bytes()?;
+        let command = match subcommand.as_str() {
+            "create" | "setid" => {
+                let id = parse_group_id(&args.next_bytes()?)?;
+                let mut make_stream = false;
+                let mut entries_read = None;
+                while !args.is_empty() {
+                    match args.next_string()?.to_lowercase().as_str() {
+                        "mkstream" if subcommand == "create" => make_stream = true,
+                        "entriesread" if !args.is_empty() => {
+                            entries_read = match args.next_int()? {
+                                -1 => None,
+                                read => Some(read.try_into().map_err(|_| {
+                                    Error::Argument(
+                                        "value for ENTRIESREAD must be positive or -1".to_owned(),
+                                    )
+                                })?),
+                            };
+                        }
+                        _ => {
+                            return Err(Error::Argument(format!(
+                                "unknown subcommand or wrong number of arguments for '{}'. Try XGROUP HELP.",
+                                given
+                            )))
+                        }
+                    }
+                }
+                if subcommand == "create" {
+                    StreamCommand::CreateGroup(key, group, id, entries_read, make_stream)
+                } else {
+                    StreamCommand::SetGroupId(key, group, id, entries_read)
+                }
+            }
+            "destroy" => StreamCommand::DestroyGroup(key, group),
+            "createconsumer" => StreamCommand::CreateConsumer(key, group, args.next_bytes()?),
+            _ => StreamCommand::DeleteConsumer(key, group, args.next_bytes()?),
+        };
+        Ok(command)
+    }
+
+    fn info(args: &mut Arguments) -> Result<StreamCommand, Error> {
+        let subcommand = args.next_string()?.to_lowercase();
+        let arity = match subcommand.as_str() {
+            "help" => 0..=0,
+            "stream" => 1..=usize::MAX,
+            "groups" => 1..=1,
+            "consumers" => 2..=2,
+            _ => {
+                return Err(Error::Argument(format!(
+                    "unknown subcommand '{}'. Try XINFO HELP.",
+                    subcommand
+                )))
+            }
+        };
+        if !arity.contains(&args.len()) {
+            return Err(Error::WrongArity(format!("xinfo|{}", subcommand)));
+        }
+        let command = match subcommand.as_str() {
+            "help" => StreamCommand::InfoHelp,
+            "stream" => {
+                let key = args.next_bytes()?;
+                let mut full = None;
+                if !args.is_empty() {
+                    if !args.next_if("full") {
+                        return Err(Error::Syntax);
+                    }
+                    full = Some(10);
+                    if !args.is_empty() {
+                        if args.len() != 2 || !args.next_if("count") {
+                            return Err(Error::Syntax);
+                        }
+                        // A negative count is taken as the default.
+                        full = Some(args.next_int()?.try_into().unwrap_or(10));
+                    }
+                }
+                StreamCommand::StreamInfo(key, full)
+            }
+            "groups" => StreamCommand::GroupsInfo(args.next_bytes()?),
+            _ => StreamCommand::ConsumersInfo(args.next_bytes()?, args.next_bytes()?),
+        };
+        Ok(command)
+    }
+
+    pub fn execute(self, storage: &mut Database) -> Result<Value, Error> {
+        let response = match self {
+            StreamCommand::Add(key, options, id, fields) => {
+                let last = match storage.get(&key) {
+                    Some(stored) => stored.stream()?.last_id(),
+                    None if !options.make_stream => return Ok(Value::Nil),
+                    None => StreamId::MIN,
+                };
+                let id = id.resolve(last)?;
+                let stream = storage
+                    .get_or_insert_with(&key, || Data::Stream(Stream::new()))
+                    .stream_mut()?;
+                stream.append(id, fields);
+                let trimmed = match &options.trim {
+                    Some(trim) => stream.trim(trim),
+                    None => 0,
+                };
+                storage.notify(Class::Stream, "xadd", &key);
+                if trimmed > 0 {
+                    storage.notify(Class::Stream, "xtrim", &key);
+                }
+                // Readers blocked on the stream wait for new entries, not
+                // for the key to be created.
+                storage.signal(&key);
+                id.to_value()
+            }
+            StreamCommand::Trim(key, trim) => {
+                let trimmed = match storage.get_mut(&key) {
+                    Some(stored) => stored.stream_mut()?.trim(&trim),
+                    None => 0,
+                };
+                if trimmed > 0 {
+                    storage.notify(Class::Stream, "xtrim", &key);
+                }
+                Value::Int(trimmed.try_into()?)
+            }
+            StreamCommand::Delete(key, ids) => {
+                let deleted = match storage.get_mut(&key) {
+                    Some(stored) => {
+                        let stream = stored.stream_mut()?;
+                        ids.into_iter().filter(|&id| stream.delete(id)).count()
+                    }
+                    None => 0,
+                };
+                if deleted > 0 {
+                    storage.notify(Class::Stream, "xdel", &key);
+                }
+                Value::Int(deleted.try_into()?)
+            }
+            StreamCommand::Len(key) => match storage.get(&key) {
+                Some(stored) => Value::Int(stored.stream()?.len().try_into()?),
+                None => Value::Int(0),
+            },
+            StreamCommand::Range(key, start, end, rev, count) => {
+                let stream = match storage.get(&key) {
+                    Some(stored) => stored.stream()?,
+                    None => return Ok(Value::array(vec![])),
+                };
+                if count == Some(0) {
+                    return Ok(Value::NilArray);
+                }
+                let count = count.unwrap_or(usize::MAX);
+                let range = stream.range(start, end);
+                let entries: Vec<_> = if rev {
+                    range.rev().take(count).collect()
+                } else {
+                    range.take(count).collect()
+                };
+                Value::array(entries.into_iter().map(entry_reply).collect())
+            }
+            StreamCommand::CreateGroup(key, group, id, entries_read, make_stream) => {
+                if !make_stream || storage.get(&key).is_some() {
+                    group_stream(storage, &key)?;
+                }
+                let stream = storage
+                    .get_or_insert_with(&key, || Data::Stream(Stream::new()))
+                    .stream_mut()?;
+                let id = id.unwrap_or_else(|| stream.last_id());
+                if !stream.create_group(group, id, entries_read) {
+                    return Err(Error::BusyGroup);
+                }
+                storage.notify(Class::Stream, "xgroup-create", &key);
+                Value::ok()
+            }
+            StreamCommand::SetGroupId(key, group, id, entries_read) => {
+                let stream = group_stream(storage, &key)?;
+                let last_id = stream.last_id();
+                let group = find_group(stream, &key, &group)?;
+                group.set_last_id(id.unwrap_or(last_id));
+                group.set_entries_read(entries_read);
+                storage.notify(Class::Stream, "xgroup-setid", &key);
+                Value::ok()
+            }
+            StreamCommand::DestroyGroup(key, group) => {
+                let destroyed = group_stream(storage, &key)?.destroy_group(&group);
+                if destroyed {
+                    storage.notify(Class::Stream, "xgroup-destroy", &key);
+                }
+                // Clients blocked reading as the group are told it is gone.
+                storage.signal(&key);
+                Value::Int(destroyed.into())
+            }
+            StreamCommand::CreateConsumer(key, group, consumer) => {
+                let group = find_group(group_stream(storage, &key)?, &key, &group)?;
+                let created = group.add_consumer(&consumer, storage::unix_millis());
+                if created {
+                    storage.notify(Class::Stream, "xgroup-createconsumer", &key);
+                }
+                Value::Int(created.into())
+            }
+            StreamCommand::DeleteConsumer(key, group, consumer) => {
+                let group = find_group(group_stream(storage, &key)?, &key, &group)?;
+                let pending = group.remove_consumer(&consumer);
+                if pending.is_some() {
+                    storage.notify(Class::Stream, "xgroup-delconsumer", &key);
+                }
+                Value::Int(pending.unwrap_or(0).try_into()?)
+            }
+            StreamCommand::GroupHelp => Value::array(
+                [
+                    "XGROUP <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                    "CREATE <key> <groupname> <id|$> [option]",
+                    "    Create a new consumer group. Options are:",
+                    "    * MKSTREAM",
+                    "      Create the empty stream if it does not exist.",
+                    "    * ENTRIESREAD entries_read",
+                    "      Set the group's entries_read counter (internal use).",
+                    "CREATECONSUMER <key> <groupname> <consumer>",
+                    "    Create a new consumer in the specified group.",
+                    "DELCONSUMER <key> <groupname> <consumer>",
+                    "    Remove the specified consumer.",
+                    "DESTROY <key> <groupname>",
+                    "    Remove the specified group.",
+                    "SETID <key> <groupname> <id|$> [ENTRIESREAD entries_read]",
+                    "    Set the current group ID and entries_read counter.",
+                    "HELP",
+                    "    Print this help.",
+                ]
+                .iter()
+                .map(|line| Value::Status((*line).to_owned()))
+                .collect(),
+            ),
+            StreamCommand::Ack(key, group, ids) => {
+                let group = match storage.get_mut(&key) {
+                    Some(stored) => stored.stream_mut()?.group_mut(&group),
+                    None => None,
+                };
+                let acknowledged = match group {
+                    Some(group) => ids.into_iter().filter(|&id| group.acknowledge(id)).count(),
+                    None => 0,
+                };
+                Value::Int(acknowledged.try_into()?)
+            }
+            StreamCommand::Pending(key, group, range) => {
+                let group = &claimed_stream(storage, &key, &group)?.groups()[&group];
+                match range {
+                    Some(range) => pending_range(group, range),
+                    None => pending_summary(group),
+                }
+            }
+            StreamCommand::Claim(key, group, consumer, ids, options) => {
+                let stream = claimed_stream(storage, &key, &group)?;
+                if let (Some(last_id), Some(group)) = (options.last_id, stream.group_mut(&group)) {
+                    if last_id > group.last_id() {
+                        group.set_last_id(last_id);
+                    }
+                }
+                let claimed = stream
+                    .claim(&group, &consumer, &ids, &options, storage::unix_millis())
+                    .unwrap_or_default();
+                claimed_reply(&claimed, options.just_id)
+            }
+            StreamCommand::AutoClaim(key, group, consumer, start, count, options) => {
+                let now = storage::unix_millis();
+                let (cursor, claimed, deleted) = claimed_stream(storage, &key, &group)?
+                    .auto_claim(&group, &consumer, start, count, &options, now)
+                    .unwrap_or_default();
+                Value::array(vec![
+                    cursor.to_value(),
+                    claimed_reply(&claimed, options.just_id),
+                    Value::array(deleted.into_iter().map(StreamId::to_value).collect()),
+                ])
+            }
+            StreamCommand::StreamInfo(key, full) => stream_info(info_stream(storage, &key)?, full),
+            StreamCommand::GroupsInfo(key) => {
+                let stream = info_stream(storage, &key)?;
+                let groups = stream
+                    .groups()
+                    .iter()
+                    .map(|(name, group)| {
+                        Value::Map(vec![
+                            (Value::bulk("name"), Value::String(name.clone())),
+                            (
+                                Value::bulk("consumers"),
+                                Value::Int(group.consumers().len() as i64),
+                            ),
+                            (
+                                Value::bulk("pending"),
+                                Value::Int(group.pending().len() as i64),
+                            ),
+                            (Value::bulk("last-delivered-id"), group.last_id().to_value()),
+                            (
+                                Value::bulk("entries-read"),
+                                optional_count(group.entries_read()),
+                            ),
+                            (Value::bulk("lag"), optional_count(stream.lag(group))),
+                        ])
+                    })
+                    .collect();
+                Value::array(groups)
+            }
+            StreamCommand::ConsumersInfo(key, group) => {
+                let group = find_group(info_stream(storage, &key)?, &key, &group)?;
+                let now = storage::unix_millis();
+                let consumers = group
+                    .consumers()
+                    .iter()
+                    .map(|(name, consumer)| {
+                        let inactive = match consumer.active {
+                            Some(active) => (now - active).max(0),
+                            None => -1,
+                        };
+                        Value::Map(vec![
+                            (Value::bulk("name"), Value::String(name.clone())),
+                            (
+                                Value::bulk("pending"),
+                                Value::Int(consumer.pending().len() as i64),
+                            ),
+                            (
+                                Value::bulk("idle"),
+                                Value::Int((now - consumer.seen).max(0)),
+                            ),
+                            (Value::bulk("inactive"), Value::Int(inactive)),
+                        ])
+                    })
+                    .collect();
+                Value::array(consumers)
+            }
+            StreamCommand::InfoHelp => Value::array(
+                [
+                    "XINFO <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                    "CONSUMERS <key> <groupname>",
+                    "    Show consumers of <groupname>.",
+                    "GROUPS <key>",
+                    "    Show the stream consumer groups.",
+                    "STREAM <key> [FULL [COUNT <count>]",
+                    "    Show information about the stream.",
+                    "HELP",
+                    "    Print this help.",
+                ]
+                .iter()
+                .map(|line| Value::Status((*line).to_owned()))
+                .collect(),
+            ),
+        };
+        Ok(response)
+    }
+}
+
+/// The ID XGROUP CREATE and SETID take, where `$` stands for the last one.
+fn parse_group_id(data: &[u8]) -> Result<Option<StreamId>, Error> {
+    if data == b"$" {
+        return Ok(None);
+    }
+    Ok(Some(StreamId::parse(data, 0)?))
+}
+
+/// The stream XGROUP operates on, which has to exist.
+fn group_stream<'a>(storage: &'a mut Database, key: &[u8]) -> Result<&'a mut Stream, Error> {
+    match storage.get_mut(key) {
+        Some(stored) => stored.stream_mut(),
+        None => Err(Error::Argument(
+            "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically."
+                .to_owned(),
+        )),
+    }
+}
+
+fn find_group<'a>(
+    stream: &'a mut Stream,
+    key: &[u8],
+    group: &[u8],
+) -> Result<&'a mut Group, Error> {
+    stream.group_mut(group).ok_or_else(|| {
+        Error::NoGroup(format!(
+            "No such consumer group '{}' for key name '{}'",
+            String::from_utf8_lossy(group),
+            String::from_utf8_lossy(key)
+        ))
+    })
+}
+
+/// The stream XPENDING, XCLAIM and XAUTOCLAIM operate on, which has to have
+/// the group.
+fn claimed_stream<'a>(
+    storage: &'a mut Database,
+    key: &[u8],
+    group: &[u8],
+) -> Result<&'a mut Stream, Error> {
+    let stream = match storage.get_mut(key) {
+        Some(stored) => Some(stored.stream_mut()?),
+        None => None,
+    };
+    match stream {
+        Some(stream) if stream.groups().contains_key(group) => Ok(stream),
+        _ => Err(Error::NoGroup(format!(
+            "No such key '{}' or consumer group '{}'",
+            String::from_utf8_lossy(key),
+            String::from_utf8_lossy(group)
+        ))),
+    }
+}
+
+/// The stream XINFO describes, which has to exist.
+fn info_stream<'a>(storage: &'a mut Database, key: &[u8]) -> Result<&'a mut Stream, Error> {
+    match storage.get_mut(key) {
+        Some(stored) => stored.stream_mut(),
+        None => Err(Error::Argument("no such key".to_owned())),
+    }
+}
+
+/// A count XINFO reports, or nil if it is not known.
+fn optional_count(count: Option<u64>) -> Value {
+    match count {
+        Some(count) => Value::Int(count as i64),
+        None => Value::Nil,
+    }
+}
+
+/// XINFO STREAM: the bookkeeping of the stream and either its first and
+/// last entries, or with FULL up to `count` of its entries and the state of
+/// its groups.
+fn stream_info(stream: &Stream, full: Option<usize>) -> Value {
+    // Redis keeps nodes in the leaves of a radix tree, counted along with
+    // its root.
+    let nodes = stream.nodes();
+    let mut info = vec![
+        (Value::bulk("length"), Value::Int(stream.len() as i64)),
+        (Value::bulk("radix-tree-keys"), Value::Int(nodes as i64)),
+        (
+            Value::bulk("radix-tree-nodes"),
+            Value::Int(nodes as i64 + 1),
+        ),
+        (
+            Value::bulk("last-generated-id"),
+            stream.last_id().to_value(),
+        ),
+        (
+            Value::bulk("max-deleted-entry-id"),
+            stream.max_deleted_id().to_value(),
+        ),
+        (
+            Value::bulk("entries-added"),
+            Value::Int(stream.entries_added() as i64),
+        ),
+        (
+            Value::bulk("recorded-first-entry-id"),
+            stream.first_id().to_value(),
+        ),
+    ];
+    let count = match full {
+        Some(0) => usize::MAX,
+        Some(count) => count,
+        None => {
+            let edge = |entry: Option<&Entry>| entry.map_or(Value::Nil, entry_reply);
+            info.push((
+                Value::bulk("groups"),
+                Value::Int(stream.groups().len() as i64),
+            ));
+            info.push((Value::bulk("first-entry"), edge(stream.iter().next())));
+            info.push((Value::bulk("last-entry"), edge(stream.iter().next_back())));
+            return Value::Map(info);
+        }
+    };
+    let entries = stream.iter().take(count).map(entry_reply).collect();
+    info.push((Value::bulk("entries"), Value::array(entries)));
+    let groups = stream
+        .groups()
+        .iter()
+        .map(|(name, group)| {
+            let pending = group
+                .pending()
+                .iter()
+                .take(count)
+                .map(|(id, pending)| {
+                    Value::array(vec![
+                        id.to_value(),
+                        Value::String(pending.consumer.clone()),
+                        Value::Int(pending.delivered),
+                        Value::Int(pending.deliveries as i64),
+                    ])
+                })
+                .collect();
+            let consumers = group
+                .consumers()
+                .iter()
+                .map(|(name, consumer)| {
+                    let pending = consumer
+                        .pending()
+                        .iter()
+                        .take(count)
+                        .filter_map(|id| {
+                            let pending = group.pending().get(id)?;
+                            Some(Value::array(vec![
+                                id.to_value(),
+                                Value::Int(pending.delivered),
+                                Value::Int(pending.deliveries as i64),
+                            ]))
+                        })
+                        .collect();
+                    Value::Map(vec![
+                        (Value::bulk("name"), Value::String(name.clone())),
+                        (Value::bulk("seen-time"), Value::Int(consumer.seen)),
+                        (
+                            Value::bulk("active-time"),
+                            Value::Int(consumer.active.unwrap_or(-1)),
+                        ),
+                        (
+                            Value::bulk("pel-count"),
+                            Value::Int(consumer.pending().len() as i64),
+                        ),
+                        (Value::bulk("pending"), Value::array(pending)),
+                    ])
+                })
+                .collect();
+            Value::Map(vec![
+                (Value::bulk("name"), Value::String(name.clone())),
+                (Value::bulk("last-delivered-id"), group.last_id().to_value()),
+                (
+                    Value::bulk("entries-read"),
+                    optional_count(group.entries_read()),
+                ),
+                (Value::bulk("lag"), optional_count(stream.lag(group))),
+                (
+                    Value::bulk("pel-count"),
+                    Value::Int(group.pending().len() as i64),
+                ),
+                (Value::bulk("pending"), Value::array(pending)),
+                (Value::bulk("consumers"), Value::array(consumers)),
+            ])
+        })
+        .collect();
+    info.push((Value::bulk("groups"), Value::array(groups)));
+    Value::Map(info)
+}
+
+/// The summary form of XPENDING: how many entries are pending, the lowest
+/// and highest of their IDs, and how many each consumer has.
+fn pending_summary(group: &Group) -> Value {
+    let pending = group.pending();
+    let (first, last) = match (pending.keys().next(), pending.keys().next_back()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => return Value::array(vec![Value::Int(0), Value::Nil, Value::Nil, Value::NilArray]),
+    };
+    let consumers = group
+        .consumers()
+        .iter()
+        .filter(|(_, consumer)| !consumer.pending().is_empty())
+        .map(|(name, consumer)| {
+            Value::array(vec![
+                Value::String(name.clone()),
+                Value::String(consumer.pending().len().to_string().into_bytes()),
+            ])
+        })
+        .collect();
+    Value::array(vec![
+        Value::Int(pending.len() as i64),
+        first.to_value(),
+        last.to_value(),
+        Value::array(consumers),
+    ])
+}
+
+/// The extended form of XPENDING: the ID, consumer, idle time and delivery
+/// count of each pending entry in the range.
+fn pending_range(group: &Group, range: PendingRange) -> Value {
+    if range.start > range.end {
+        return Value::array(vec![]);
+    }
+    let now = storage::unix_millis();
+    let ids: Box<dyn Iterator<Item = &StreamId>> = match &range.consumer {
+        Some(name) => match group.consumers().get(name) {
+            Some(consumer) => Box::new(consumer.pending().range(range.start..=range.end)),
+            None => return Value::array(vec![]),
+        },
+        None => Box::new(
+            group
+                .pending()
+                .range(range.start..=range.end)
+                .map(|(id, _)| id),
+        ),
+    };
+    let entries = ids
+        .filter_map(|id| Some((*id, group.pending().get(id)?)))
+        .filter(|(_, pending)| now - pending.delivered >= range.min_idle)
+        .take(range.count)
+        .map(|(id, pending)| {
+            Value::array(vec![
+                id.to_value(),
+                Value::String(pending.consumer.clone()),
+                Value::Int((now - pending.delivered).max(0)),
+                Value::Int(pending.deliveries as i64),
+            ])
+        })
+        .collect();
+    Value::array(entries)
+}
+
+/// The entries XCLAIM and XAUTOCLAIM took over, or just their IDs.
+fn claimed_reply(claimed: &[Entry], just_id: bool) -> Value {
+    if just_id {
+        Value::array(claimed.iter().map(|entry| entry.id.to_value()).collect())
+    } else {
+        Value::array(claimed.iter().map(entry_reply).collect())
+    }
+}
+
+/// Where XREAD and XREADGROUP start reading a stream.
+#[derive(Clone, Copy)]
+pub enum ReadFrom {
+    /// After an ID; for XREADGROUP, among the consumer's pending entries.
+    After(StreamId),
+    /// `$`: after the last ID of the stream when the command first runs.
+    Last,
+    /// `>`: the entries the group has not delivered to anyone yet.
+    Undelivered,
+}
+
+/// The group and consumer XREADGROUP reads as.
+pub struct GroupReader {
+    group: Vec<u8>,
+    consumer: Vec<u8>,
+    /// Whether delivered entries skip the pending list, as with NOACK.
+    noack: bool,
+}
+
+pub enum BlockingStreamCommand {
+    /// XREAD and XREADGROUP: entries from each of the streams, up to a count
+    /// per stream, from those that have some. Waits for entries only if it
+    /// was given BLOCK.
+    Read {
+        keys: Vec<Vec<u8>>,
+        from: Vec<ReadFrom>,
+        count: Option<usize>,
+        group: Option<GroupReader>,
+        waits: bool,
+    },
+}
+
+impl BlockingStreamCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<BlockingCommand>, Error> {
+        let (operation, timeout) = match name {
+            "xread" | "xreadgroup" => {
+                let mut count = None;
+                let mut block = None;
+                let mut group = None;
+                let mut noack = false;
+                loop {
+                    let option = args.next_string()?.to_lowercase();
+                    match option.as_str() {
+                        "count" => {
+                            // Zero, or anything negative, means no limit.
+                            let limit = args.next_int()?;
+                            count = Some(limit.try_into().unwrap_or(0)).filter(|&count| count > 0);
+                        }
+                        "block" => block = Some(blocking::parse_timeout_millis(args)?),
+                        "group" if name == "xreadgroup" => {
+                            group = Some((args.next_bytes()?, args.next_bytes()?));
+                        }
+                        "group" => {
+                            return Err(Error::Argument(
+                                "The GROUP option is only supported by XREADGROUP. You called XREAD instead."
+                                    .to_owned(),
+                            ))
+                        }
+                        "noack" if name == "xreadgroup" => noack = true,
+                        "streams" => break,
+                        _ => return Err(Error::Syntax),
+                    }
+                }
+                if args.is_empty() || args.len() % 2 != 0 {
+                    return Err(Error::Argument(format!(
+                        "Unbalanced '{}' list of streams: for each stream key an ID or '$' must be specified.",
+                        name
+                    )));
+                }
+                let group = match group {
+                    Some((group, consumer)) => Some(GroupReader {
+                        group,
+                        consumer,
+                        noack,
+                    }),
+                    None if name == "xreadgroup" => {
+                        return Err(Error::Argument(
+                            "Missing GROUP option for XREADGROUP".to_owned(),
+                        ))
+                    }
+                    None => None,
+                };
+                let streams = args.len() / 2;
+                let keys = (0..streams)
+                    .map(|_| args.next_bytes())
+                    .collect::<Result<_, _>>()?;
+                let mut from = vec![];
+                for _ in 0..streams {
+                    let id = args.next_bytes()?;
+                    from.push(match (id.as_slice(), &group) {
+                        (b"$", None) => ReadFrom::Last,
+                        (b">", Some(_)) => ReadFrom::Undelivered,
+                        (b"$", Some(_)) => return Err(Error::Argument(
+                            "The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set."
+                                .to_owned(),
+                        )),
+                        (b">", None) => return Err(Error::Argument(
+                            "The > ID can be specified only when calling XREADGROUP using the GROUP <group> <consumer> option."
+                                .to_owned(),
+                        )),
+                        _ => ReadFrom::After(StreamId::parse(&id, 0)?),
+                    });
+                }
+                let operation = BlockingStreamCommand::Read {
+                    keys,
+                    from,
+                    count,
+                    group,
+                    waits: block.is_some(),
+                };
+                (operation, block.flatten())
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(BlockingCommand {
+            operation: Blocking::Stream(operation),
+            timeout,
+        }))
+    }
+
+    pub fn keys(&self) -> &[Vec<u8>] {
+        match self {
+            BlockingStreamCommand::Read { keys, .. } => keys,
+        }
+    }
+
+    pub fn waits(&self) -> bool {
+        match self {
+            BlockingStreamCommand::Read { waits, .. } => *waits,
+        }
+    }
+
+    pub fn try_execute(
+        &mut self,
+        storage: &mut Database,
+        skip_other_types: bool,
+    ) -> Result<Option<Value>, Error> {
+        match self {
+            BlockingStreamCommand::Read {
+                keys,
+                from,
+                count,
+                group: Some(reader),
+                ..
+            } => read_group(
+                storage,
+                keys,
+                from,
+                count.unwrap_or(usize::MAX),
+                reader,
+                skip_other_types,
+            ),
+            BlockingStreamCommand::Read {
+                keys, from, count, ..
+            } => {
+                let mut reply = vec![];
+                for (key, from) in keys.iter().zip(from.iter_mut()) {
+                    let stream = match storage.get(key) {
+                        Some(stored) => {
+                            match blocking::skip_other_type(stored.stream(), skip_other_types)? {
+                                Some(stream) => Some(stream),
+                                None => continue,
+                            }
+                        }
+                        None => None,
+                    };
+                    // `$` is settled once, so that entries added while the
+                    // command waits are not skipped.
+                    if let ReadFrom::Last = from {
+                        *from = ReadFrom::After(
+                            stream.map_or(StreamId::MIN, |stream| stream.last_id()),
+                        );
+                    }
+                    let after = match from {
+                        ReadFrom::After(after) => *after,
+                        _ => continue,
+                    };
+                    let (stream, start) = match (stream, after.successor()) {
+                        (Some(stream), Some(start)) => (stream, start),
+                        _ => continue,
+                    };
+                    let entries: Vec<_> = stream
+                        .range(start, StreamId::MAX)
+                        .take(count.unwrap_or(usize::MAX))
+                        .map(entry_reply)
+                        .collect();
+                    if !entries.is_empty() {
+                        reply.push(Value::array(vec![
+                            Value::String(key.clone()),
+                            Value::array(entries),
+                        ]));
+                    }
+                }
+                if reply.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(Value::array(reply)))
+            }
+        }
+    }
+}
+
+/// Runs XREADGROUP. Reading from the consumer's pending entries always
+/// answers, possibly with no entries, while reading new entries waits for
+/// some.
+fn read_group(
+    storage: &mut Database,
+    keys: &[Vec<u8>],
+    from: &[ReadFrom],
+    count: usize,
+    reader: &GroupReader,
+    skip_other_types: bool,
+) -> Result<Option<Value>, Error> {
+    let is_stream = |storage: &Database, key: &[u8]| {
+        storage.peek(key).map_or(Ok(true), |stored| {
+            blocking::skip_other_type(stored.stream(), skip_other_types).map(|s| s.is_some())
+        })
+    };
+    for key in keys {
+        if !is_stream(storage, key)? {
+            continue;
+        }
+        let has_group = match storage.get(key) {
+            Some(stored) => stored.stream()?.groups().contains_key(&reader.group),
+            None => false,
+        };
+        if !has_group {
+            return Err(Error::NoGroup(format!(
+                "No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                String::from_utf8_lossy(key),
+                String::from_utf8_lossy(&reader.group)
+            )));
+        }
+    }
+    let now = storage::unix_millis();
+    let mut reply = vec![];
+    for (key, from) in keys.iter().zip(from) {
+        if !is_stream(storage, key)? {
+            continue;
+        }
+        let stream = match storage.get_mut(key) {
+            Some(stored) => stored.stream_mut()?,
+            None => continue,
+        };
+        let entries = match *from {
+            ReadFrom::After(after) => stream
+                .redeliver(&reader.group, &reader.consumer, after, count, now)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(id, entry)| match entry {
+                    Some(entry) => entry_reply(&entry),
+                    None => Value::array(vec![id.to_value(), Value::NilArray]),
+                })
+                .collect(),
+            _ => {
+                let entries = stream
+                    .deliver(&reader.group, &reader.consumer, count, reader.noack, now)
+                    .unwrap_or_default();
+                if entries.is_empty() {
+                    continue;
+                }
+                entries.iter().map(entry_reply).collect()
+            }
+        };
+        reply.push(Value::array(vec![
+            Value::String(key.clone()),
+            Value::array(entries),
+        ]));
+    }
+    if reply.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Value::array(reply)))
+}
+
+/// An entry as a pair of its ID and a flat array of its fields and values.
+fn entry_reply(entry: &Entry) -> Value {
+    let mut fields = vec![];
+    for (field, value) in &entry.fields {
+        fields.push(Value::String(field.clone()));
+        fields.push(Value::String(value.clone()));
+    }
+    Value::array(vec![entry.id.to_value(), Value::array(fields)])
+}
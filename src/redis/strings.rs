@@ -0,0 +1,614 @@
+use super::command::Arguments;
+use super::float;
+use super::notify::Class;
+use super::storage::{self, Data, Database, StoredValue};
+use super::{Error, Value};
+use std::convert::TryInto;
+
+#[derive(PartialEq)]
+enum SetCondition {
+    IfAbsent,
+    IfPresent,
+}
+
+enum SetExpiry {
+    At(i64),
+    Keep,
+}
+
+/// Largest string a write may produce, matching the default proto-max-bulk-len.
+pub const MAX_STRING_LENGTH: usize = 512 * 1024 * 1024;
+
+pub enum GetExpiry {
+    At(i64),
+    Persist,
+}
+
+#[derive(Default)]
+pub struct SetOptions {
+    condition: Option<SetCondition>,
+    get: bool,
+    expiry: Option<SetExpiry>,
+}
+
+/// Parses the argument of an EX, PX, EXAT or PXAT option into a deadline in
+/// unix milliseconds.
+fn parse_expiry(unit: &str, args: &mut Arguments) -> Result<i64, Error> {
+    let amount = args.next_int()?;
+    let invalid = || Error::InvalidExpire(args.name().to_owned());
+    if amount <= 0 {
+        return Err(invalid());
+    }
+    let millis = match unit {
+        "ex" | "exat" => amount.checked_mul(1000).ok_or_else(invalid)?,
+        _ => amount,
+    };
+    match unit {
+        "ex" | "px" => storage::unix_millis()
+            .checked_add(millis)
+            .ok_or_else(invalid),
+        _ => Ok(millis),
+    }
+}
+
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+#[derive(Default)]
+pub struct LcsOptions {
+    len: bool,
+    idx: bool,
+    min_match_len: usize,
+    with_match_len: bool,
+}
+
+pub enum StringCommand {
+    Get(Vec<u8>),
+    GetDel(Vec<u8>),
+    GetEx(Vec<u8>, Option<GetExpiry>),
+    GetRange(Vec<u8>, i64, i64),
+    Set(Vec<u8>, Vec<u8>, SetOptions),
+    SetNx(Vec<u8>, Vec<u8>),
+    SetRange(Vec<u8>, usize, Vec<u8>),
+    IncrBy(Vec<u8>, i64),
+    IncrByFloat(Vec<u8>, f64),
+    Append(Vec<u8>, Vec<u8>),
+    Strlen(Vec<u8>),
+    Lcs(Vec<u8>, Vec<u8>, LcsOptions),
+    MGet(Vec<Vec<u8>>),
+    MSet(Pairs),
+    MSetNx(Pairs),
+}
+
+impl StringCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<StringCommand>, Error> {
+        let command = match name {
+            "get" => StringCommand::Get(StringCommand::single_key(args)?),
+            "getdel" => StringCommand::GetDel(StringCommand::single_key(args)?),
+            "getex" => StringCommand::get_ex(args)?,
+            "set" => StringCommand::set(args)?,
+            "setnx" => {
+                let name = args.next_bytes()?;
+                let value = args.next_bytes()?;
+                args.finish()?;
+                StringCommand::SetNx(name, value)
+            }
+            "setex" | "psetex" => {
+                let unit = if name == "setex" { "ex" } else { "px" };
+                let name = args.next_bytes()?;
+                let expiry = parse_expiry(unit, args)?;
+                let value = args.next_bytes()?;
+                args.finish()?;
+                let options = SetOptions {
+                    expiry: Some(SetExpiry::At(expiry)),
+                    ..SetOptions::default()
+                };
+                StringCommand::Set(name, value, options)
+            }
+            "getset" => {
+                let name = args.next_bytes()?;
+                let value = args.next_bytes()?;
+                args.finish()?;
+                let options = SetOptions {
+                    get: true,
+                    ..SetOptions::default()
+                };
+                StringCommand::Set(name, value, options)
+            }
+            "incr" => StringCommand::IncrBy(StringCommand::single_key(args)?, 1),
+            "decr" => StringCommand::IncrBy(StringCommand::single_key(args)?, -1),
+            "incrby" => StringCommand::incr_by(args, false)?,
+            "decrby" => StringCommand::incr_by(args, true)?,
+            "incrbyfloat" => {
+                let name = args.next_bytes()?;
+                let increment = args.next_float()?;
+                args.finish()?;
+                StringCommand::IncrByFloat(name, increment)
+            }
+            "append" => {
+                let name = args.next_bytes()?;
+                let value = args.next_bytes()?;
+                args.finish()?;
+                StringCommand::Append(name, value)
+            }
+            "getrange" | "substr" => {
+                let name = args.next_bytes()?;
+                let start = args.next_int()?;
+                let end = args.next_int()?;
+                args.finish()?;
+                StringCommand::GetRange(name, start, end)
+            }
+            "setrange" => {
+                let name = args.next_bytes()?;
+                let offset = args
+                    .next_int()?
+                    .try_into()
+                    .map_err(|_| Error::Argument("offset is out of range".to_owned()))?;
+                let value = args.next_bytes()?;
+                args.finish()?;
+                StringCommand::SetRange(name, offset, value)
+            }
+            "strlen" => StringCommand::Strlen(StringCommand::single_key(args)?),
+            "lcs" => StringCommand::lcs(args)?,
+            "mget" => {
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                StringCommand::MGet(args.rest()?)
+            }
+            "mset" => StringCommand::MSet(StringCommand::pairs(args)?),
+            "msetnx" => StringCommand::MSetNx(StringCommand::pairs(args)?),
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    fn single_key(args: &mut Arguments) -> Result<Vec<u8>, Error> {
+        let name = args.next_bytes()?;
+        args.finish()?;
+        Ok(name)
+    }
+
+    fn pairs(args: &mut Arguments) -> Result<Pairs, Error> {
+        if args.is_empty() || args.len() % 2 != 0 {
+            return Err(args.wrong_arity());
+        }
+        let mut pairs = Vec::with_capacity(args.len() / 2);
+        while !args.is_empty() {
+            let name = args.next_bytes()?;
+            let value = args.next_bytes()?;
+            pairs.push((name, value));
+        }
+        Ok(pairs)
+    }
+
+    fn incr_by(args: &mut Arguments, negate: bool) -> Result<StringCommand, Error> {
+        let name = args.next_bytes()?;
+        let increment = args.next_int()?;
+        args.finish()?;
+        let increment = if negate {
+            increment.checked_neg().ok_or(Error::Overflow)?
+        } else {
+            increment
+        };
+        Ok(StringCommand::IncrBy(name, increment))
+    }
+
+    fn get_ex(args: &mut Arguments) -> Result<StringCommand, Error> {
+        let name = args.next_bytes()?;
+        let mut expiry = None;
+        while !args.is_empty() {
+            let flag = args.next_string()?.to_lowercase();
+            match flag.as_str() {
+                "persist" if expiry.is_none() => expiry = Some(GetExpiry::Persist),
+                "ex" | "px" | "exat" | "pxat" if expiry.is_none() => {
+                    expiry = Some(GetExpiry::At(parse_expiry(&flag, args)?));
+                }
+                _ => return Err(Error::Syntax),
+            }
+        }
+        Ok(StringCommand::GetEx(name, expiry))
+    }
+
+    fn lcs(args: &mut Arguments) -> Result<StringCommand, Error> {
+        let first = args.next_bytes()?;
+        let second = args.next_bytes()?;
+        let mut options = LcsOptions::default();
+        while !args.is_empty() {
+            match args.next_string()?.to_lowercase().as_str() {
+                "len" => options.len = true,
+                "idx" => options.idx = true,
+                "withmatchlen" => options.with_match_len = true,
+                "minmatchlen" => {
+                    // Negative lengths behave like zero, as in Redis.
+                    options.min_match_len = args.next_int()?.max(0).try_into()?;
+                }
+                _ => return Err(Error::Syntax),
+            }
+        }
+        if options.len && options.idx {
+            return Err(Error::Argument(
+                "If you want both the length and indexes, please just use IDX.".to_owned(),
+            ));
+        }
+        Ok(StringCommand::Lcs(first, second, options))
+    }
+
+    fn set(args: &mut Arguments) -> Result<StringCommand, Error> {
+        let name = args.next_bytes()?;
+        let value = args.next_bytes()?;
+        let mut options = SetOptions::default();
+        while !args.is_empty() {
+            let flag = args.next_string()?;
+            match flag.to_lowercase().as_str() {
+                "nx" if options.condition.is_none() => {
+                    options.condition = Some(SetCondition::IfAbsent)
+                }
+                "xx" if options.condition.is_none() => {
+                    options.condition = Some(SetCondition::IfPresent)
+                }
+                "get" => options.get = true,
+                "keepttl" if options.expiry.is_none() => options.expiry = Some(SetExpiry::Keep),
+                unit @ "ex" | unit @ "px" | unit @ "exat" | unit @ "pxat"
+                    if options.expiry.is_none() =>
+                {
+                    options.expiry = Some(SetExpiry::At(parse_expiry(unit, args)?));
+                }
+                _ => return Err(Error::Syntax),
+            }
+        }
+        Ok(StringCommand::Set(name, value, options))
+    }
+
+    pub fn execute(self, storage: &mut Database) -> Result<Value, Error> {
+        let response = match self {
+            StringCommand::Get(name) => match storage.get(&name) {
+                Some(stored) => Value::String(stored.string()?.clone()),
+                None => Value::Nil,
+            },
+            StringCommand::GetDel(name) => match storage.get(&name) {
+                Some(stored) => {
+                    let data = stored.string()?.clone();
+                    storage.remove(&name);
+                    storage.notify(Class::Generic, "del", &name);
+                    Value::String(data)
+                }
+                None => Value::Nil,
+            },
+            StringCommand::GetEx(name, expiry) => match storage.get_mut(&name) {
+                Some(stored) => {
+                    let data = stored.string()?.clone();
+                    let event = match expiry {
+                        Some(GetExpiry::At(expiry)) => {
+                            stored.expiry = Some(expiry);
+                            Some("expire")
+                        }
+                        Some(GetExpiry::Persist) => stored.expiry.take().map(|_| "persist"),
+                        None => None,
+                    };
+                    if let Some(event) = event {
+                        storage.notify(Class::Generic, event, &name);
+                    }
+                    Value::String(data)
+                }
+                None => Value::Nil,
+            },
+            StringCommand::GetRange(name, start, end) => match storage.get(&name) {
+                Some(stored) => Value::String(substring(stored.string()?, start, end).to_vec()),
+                None => Value::String(vec![]),
+            },
+            StringCommand::Set(name, value, options) => {
+                StringCommand::set_value(storage, name, value, options)?
+            }
+            StringCommand::SetNx(name, value) => {
+                let options = SetOptions {
+                    condition: Some(SetCondition::IfAbsent),
+                    ..SetOptions::default()
+                };
+                match StringCommand::set_value(storage, name, value, options)? {
+                    Value::Nil => Value::Int(0),
+                    _ => Value::Int(1),
+                }
+            }
+            StringCommand::SetRange(name, offset, value) => {
+                StringCommand::set_range(storage, name, offset, &value)?
+            }
+            StringCommand::IncrBy(name, increment) => {
+                StringCommand::incr_by_value(storage, name, increment)?
+            }
+            StringCommand::IncrByFloat(name, increment) => {
+                StringCommand::incr_by_float_value(storage, name, increment)?
+            }
+            StringCommand::Append(name, data) => StringCommand::append_value(storage, name, &data)?,
+            StringCommand::Strlen(name) => match storage.get(&name) {
+                Some(stored) => Value::Int(stored.string()?.len().try_into()?),
+                None => Value::Int(0),
+            },
+            StringCommand::Lcs(first, second, options) => {
+                let first = match storage.get(&first) {
+                    Some(stored) => stored.string()?.as_slice(),
+                    None => &[],
+                };
+                let second = match storage.get(&second) {
+                    Some(stored) => stored.string()?.as_slice(),
+                    None => &[],
+                };
+                lcs(first, second, &options)?
+            }
+            StringCommand::MGet(names) => Value::array(
+                names
+                    .iter()
+                    .map(|name| match storage.get(name).map(StoredValue::string) {
+                        Some(Ok(data)) => Value::String(data.clone()),
+                        _ => Value::Nil,
+                    })
+                    .collect(),
+            ),
+            StringCommand::MSet(pairs) => {
+                for (name, value) in pairs {
+                    storage.notify(Class::String, "set", &name);
+                    storage.insert(name, StoredValue::new(Data::String(value), None));
+                }
+                Value::ok()
+            }
+            StringCommand::MSetNx(pairs) => {
+                if pairs.iter().any(|(name, _)| storage.contains(name)) {
+                    return Ok(Value::Int(0));
+                }
+                for (name, value) in pairs {
+                    storage.notify(Class::String, "set", &name);
+                    storage.insert(name, StoredValue::new(Data::String(value), None));
+                }
+                Value::Int(1)
+            }
+        };
+        Ok(response)
+    }
+
+    fn set_value(
+        storage: &mut Database,
+        name: Vec<u8>,
+        value: Vec<u8>,
+        options: SetOptions,
+    ) -> Result<Value, Error> {
+        let exists = storage.contains(&name);
+        let old = if options.get {
+            storage
+                .get(&name)
+                .map(StoredValue::string)
+                .transpose()?
+                .cloned()
+        } else {
+            None
+        };
+        let allowed = match options.condition {
+            Some(SetCondition::IfAbsent) => !exists,
+            Some(SetCondition::IfPresent) => exists,
+            None => true,
+        };
+        if allowed {
+            let expiry = match options.expiry {
+                Some(SetExpiry::At(expiry)) => Some(expiry),
+                Some(SetExpiry::Keep) => storage.peek(&name).and_then(|stored| stored.expiry),
+                None => None,
+            };
+            storage.notify(Class::String, "set", &name);
+            if let Some(SetExpiry::At(_)) = options.expiry {
+                storage.notify(Class::Generic, "expire", &name);
+            }
+            storage.insert(name, StoredValue::new(Data::String(value), expiry));
+        }
+        let response = if options.get {
+            old.map_or(Value::Nil, Value::String)
+        } else if allowed {
+            Value::ok()
+        } else {
+            Value::Nil
+        };
+        Ok(response)
+    }
+
+    fn incr_by_value(
+        storage: &mut Database,
+        name: Vec<u8>,
+        increment: i64,
+    ) -> Result<Value, Error> {
+        if let Some(stored) = storage.get_mut(&name) {
+            let value = stored.string_mut()?;
+            let result = parse_int(value)?
+                .checked_add(increment)
+                .ok_or(Error::Overflow)?;
+            *value = result.to_string().into_bytes();
+            storage.notify(Class::String, "incrby", &name);
+            return Ok(Value::Int(result));
+        }
+        storage.notify(Class::String, "incrby", &name);
+        storage.insert(
+            name,
+            StoredValue::new(Data::String(increment.to_string().into_bytes()), None),
+        );
+        Ok(Value::Int(increment))
+    }
+
+    fn incr_by_float_value(
+        storage: &mut Database,
+        name: Vec<u8>,
+        increment: f64,
+    ) -> Result<Value, Error> {
+        let current = match storage.peek(&name) {
+            Some(stored) => float::parse(stored.string()?).ok_or(Error::NotFloat)?,
+            None => 0.0,
+        };
+        let result = current + increment;
+        if !result.is_finite() {
+            return Err(Error::Argument(
+                "increment would produce NaN or Infinity".to_owned(),
+            ));
+        }
+        let result = float::format_human(result).into_bytes();
+        storage.notify(Class::String, "incrbyfloat", &name);
+        match storage.get_mut(&name) {
+            Some(stored) => *stored.string_mut()? = result.clone(),
+            None => storage.insert(name, StoredValue::new(Data::String(result.clone()), None)),
+        }
+        Ok(Value::String(result))
+    }
+
+    fn append_value(storage: &mut Database, name: Vec<u8>, data: &[u8]) -> Result<Value, Error> {
+        if let Some(stored) = storage.get_mut(&name) {
+            let value = stored.string_mut()?;
+            value.extend_from_slice(data);
+            let len = value.len().try_into()?;
+            storage.notify(Class::String, "append", &name);
+            return Ok(Value::Int(len));
+        }
+        storage.notify(Class::String, "append", &name);
+        storage.insert(name, StoredValue::new(Data::String(data.to_vec()), None));
+        Ok(Value::Int(data.len().try_into()?))
+    }
+
+    fn set_range(
+        storage: &mut Database,
+        name: Vec<u8>,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<Value, Error> {
+        let end = offset + data.len();
+        if end > MAX_STRING_LENGTH {
+            return Err(Error::Argument(
+                "string exceeds maximum allowed size (proto-max-bulk-len)".to_owned(),
+            ));
+        }
+        if let Some(stored) = storage.get_mut(&name) {
+            let value = stored.string_mut()?;
+            if data.is_empty() {
+                return Ok(Value::Int(value.len().try_into()?));
+            }
+            if value.len() < end {
+                value.resize(end, 0);
+            }
+            value[offset..end].copy_from_slice(data);
+            let len = value.len().try_into()?;
+            storage.notify(Class::String, "setrange", &name);
+            return Ok(Value::Int(len));
+        }
+        if data.is_empty() {
+            return Ok(Value::Int(0));
+        }
+        let mut value = vec![0; offset];
+        value.extend_from_slice(data);
+        storage.notify(Class::String, "setrange", &name);
+        storage.insert(name, StoredValue::new(Data::String(value), None));
+        Ok(Value::Int(end.try_into()?))
+    }
+}
+
+fn parse_int(data: &[u8]) -> Result<i64, Error> {
+    super::parse_int(data).ok_or(Error::NotInteger)
+}
+
+/// Resolves an inclusive, possibly negative, Redis range against `data`.
+fn substring(data: &[u8], start: i64, end: i64) -> &[u8] {
+    let len = data.len() as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let end = if end < 0 { len + end } else { end.min(len - 1) };
+    if len == 0 || start > end {
+        return &[];
+    }
+    &data[start as usize..=end as usize]
+}
+
+/// Longest common subsequence of two strings, replying in the shape the
+/// options ask for: the subsequence itself, its length, or the matching
+/// ranges (reported from the end of the strings backwards).
+fn lcs(a: &[u8], b: &[u8], options: &LcsOptions) -> Result<Value, Error> {
+    let width = b.len() + 1;
+    let cells = (a.len() + 1)
+        .checked_mul(width)
+        .filter(|cells| cells.saturating_mul(4) <= MAX_STRING_LENGTH)
+        .ok_or_else(|| {
+            Error::Argument(
+                "Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len"
+                    .to_owned(),
+            )
+        })?;
+    let mut table = vec![0u32; cells];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            table[i * width + j] = if a[i - 1] == b[j - 1] {
+                table[(i - 1) * width + j - 1] + 1
+            } else {
+                table[(i - 1) * width + j].max(table[i * width + j - 1])
+            };
+        }
+    }
+    let len = table[a.len() * width + b.len()];
+    if options.len {
+        return Ok(Value::Int(len.into()));
+    }
+
+    let mut subsequence = vec![0; len as usize];
+    let mut matches = vec![];
+    let mut remaining = subsequence.len();
+    let (mut i, mut j) = (a.len(), b.len());
+    // The current range is empty while `a_start == a.len()`.
+    let (mut a_start, mut a_end, mut b_start, mut b_end) = (a.len(), 0, 0, 0);
+    while i > 0 && j > 0 {
+        let mut emit = false;
+        if a[i - 1] == b[j - 1] {
+            subsequence[remaining - 1] = a[i - 1];
+            if a_start == a.len() {
+                a_start = i - 1;
+                a_end = i - 1;
+                b_start = j - 1;
+                b_end = j - 1;
+            } else if a_start == i && b_start == j {
+                a_start -= 1;
+                b_start -= 1;
+            } else {
+                emit = true;
+            }
+            if a_start == 0 || b_start == 0 {
+                emit = true;
+            }
+            remaining -= 1;
+            i -= 1;
+            j -= 1;
+        } else {
+            if table[(i - 1) * width + j] > table[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            if a_start != a.len() {
+                emit = true;
+            }
+        }
+
+        if emit {
+            let match_len = a_end - a_start + 1;
+            if options.idx && match_len >= options.min_match_len {
+                let mut entry = vec![
+                    Value::array(vec![Value::Int(a_start as i64), Value::Int(a_end as i64)]),
+                    Value::array(vec![Value::Int(b_start as i64), Value::Int(b_end as i64)]),
+                ];
+                if options.with_match_len {
+                    entry.push(Value::Int(match_len as i64));
+                }
+                matches.push(Value::array(entry));
+            }
+            a_start = a.len();
+        }
+    }
+
+    if options.idx {
+        return Ok(Value::Map(vec![
+            (Value::bulk("matches"), Value::array(matches)),
+            (Value::bulk("len"), Value::Int(len.into())),
+        ]));
+    }
+    Ok(Value::String(subsequence))
+}
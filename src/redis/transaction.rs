@@ -0,0 +1,148 @@
+//! Transactions: after MULTI a connection queues its commands, and EXEC runs
+//! the queue under a single hold of the store lock, so no command of another
+//! connection comes in between.
+//!
+//! WATCH makes EXEC give up instead if one of the keys it names changes
+//! before. Databases hold a flag per connection watching a key, which every
+//! change to the key raises.
+
+use super::aof;
+use super::command::{Arguments, Command};
+use super::storage::Store;
+use super::{Error, Value};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub type WatcherId = u64;
+
+static NEXT_WATCHER: AtomicU64 = AtomicU64::new(0);
+
+/// Commands starting and ending the transaction of the connection that
+/// sends them.
+pub enum TransactionCommand {
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<Vec<u8>>),
+    Unwatch,
+}
+
+impl TransactionCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<TransactionCommand>, Error> {
+        let command = match name {
+            "multi" => TransactionCommand::Multi,
+            "exec" => TransactionCommand::Exec,
+            "discard" => TransactionCommand::Discard,
+            "watch" => {
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                return Ok(Some(TransactionCommand::Watch(args.rest()?)));
+            }
+            "unwatch" => TransactionCommand::Unwatch,
+            _ => return Ok(None),
+        };
+        args.finish()?;
+        Ok(Some(command))
+    }
+}
+
+/// The commands a connection queued since MULTI.
+#[derive(Default)]
+pub struct Transaction {
+    /// With the arguments of those that go to the AOF.
+    commands: Vec<(Command, Option<Vec<Vec<u8>>>)>,
+    /// Whether a command was rejected while queueing, so EXEC discards the
+    /// rest rather than run them without it.
+    aborted: bool,
+}
+
+impl Transaction {
+    pub fn queue(&mut self, command: Command, arguments: Option<Vec<Vec<u8>>>) {
+        self.commands.push((command, arguments));
+    }
+
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+
+    pub fn aborted(&self) -> bool {
+        self.aborted
+    }
+
+    /// Runs the queued commands in order, replying with the reply of each.
+    /// A failing command does not stop the ones after it.
+    pub fn exec(self, store: &mut Store, db: &mut usize) -> Value {
+        let replies = self
+            .commands
+            .into_iter()
+            .map(
+                |(command, arguments)| match aof::execute(store, db, command, arguments) {
+                    Ok(reply) => reply,
+                    Err(e) => Value::Error(e.to_string()),
+                },
+            )
+            .collect();
+        store.serve_blocked();
+        store.publish_events();
+        store.persistence().write_aof();
+        Value::array(replies)
+    }
+}
+
+/// The keys a connection watches, and whether any of them changed since.
+pub struct Watches {
+    id: WatcherId,
+    dirty: Arc<AtomicBool>,
+    /// The database and name of each key, and whether it had expired
+    /// already when it was watched.
+    keys: Vec<(usize, Vec<u8>, bool)>,
+}
+
+impl Watches {
+    pub fn new() -> Watches {
+        Watches {
+            id: NEXT_WATCHER.fetch_add(1, Ordering::Relaxed),
+            dirty: Arc::default(),
+            keys: vec![],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Starts watching `keys` of database `db`.
+    pub fn watch(&mut self, store: &mut Store, db: usize, keys: Vec<Vec<u8>>) {
+        for key in keys {
+            if !self
+                .keys
+                .iter()
+                .any(|(watched_db, watched, _)| *watched_db == db && *watched == key)
+            {
+                let database = store.database(db);
+                let expired = database.is_expired(&key);
+                database.watch(key.clone(), self.id, self.dirty.clone());
+                self.keys.push((db, key, expired));
+            }
+        }
+    }
+
+    /// Whether a watched key changed since it was watched. A key that
+    /// expired since counts as changed, even before it is reclaimed.
+    pub fn dirty(&self, store: &Store) -> bool {
+        self.dirty.load(Ordering::SeqCst)
+            || self
+                .keys
+                .iter()
+                .any(|(db, key, expired)| !expired && store.databases()[*db].is_expired(key))
+    }
+
+    /// Stops watching every key.
+    pub fn clear(&mut self, store: &mut Store) {
+        for (db, key, _) in self.keys.drain(..) {
+            store.database(db).unwatch(&key, self.id);
+        }
+        self.dirty.store(false, Ordering::SeqCst);
+    }
+}
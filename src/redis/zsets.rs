@@ -0,0 +1,1171 @@
+use super::blocking::{self, Blocking, BlockingCommand};
+use super::command::Arguments;
+use super::dict::Dict;
+use super::float;
+use super::lists;
+use super::notify::Class;
+use super::ranges::{self, LexRange, ScoreRange};
+use super::scan::{self, ScanOptions, ScanTarget};
+use super::sets::{Operation, Set};
+use super::skiplist::{self, SkipList};
+use super::storage::{Data, Database, StoredValue};
+use super::{Error, Value};
+use std::convert::TryInto;
+use std::ops::Range;
+
+/// Members with their scores, also kept in order of score and then member.
+#[derive(Clone, Default)]
+pub struct ZSet {
+    scores: Dict<Vec<u8>, f64>,
+    ordered: SkipList,
+}
+
+impl ZSet {
+    pub fn new() -> ZSet {
+        ZSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Sets the score of a member and returns its previous one.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> Option<f64> {
+        let previous = self.remove(&member);
+        self.ordered.insert(score, member.clone());
+        self.scores.insert(member, score);
+        previous
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        self.ordered.remove(score, member);
+        Some(score)
+    }
+
+    /// The number of members scored below `member`.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        self.ordered.rank(score, member)
+    }
+
+    /// A uniformly random member and its score.
+    pub fn random_entry(&self) -> Option<(&Vec<u8>, f64)> {
+        self.scores
+            .random_entry()
+            .map(|(member, score)| (member, *score))
+    }
+
+    /// Up to `count` distinct random members, in no particular order.
+    pub fn sample(&self, count: usize) -> Vec<(&Vec<u8>, f64)> {
+        self.scores
+            .sample(count)
+            .into_iter()
+            .map(|(member, score)| (member, *score))
+            .collect()
+    }
+
+    /// Reports the members in the bucket of the member table addressed by
+    /// `cursor`, and returns the next cursor as [`Dict::scan`] does.
+    pub fn scan<F>(&self, cursor: u64, mut f: F) -> u64
+    where
+        F: FnMut(&Vec<u8>, f64),
+    {
+        self.scores.scan(cursor, |member, score| f(member, *score))
+    }
+
+    /// Members and scores from the lowest score up.
+    pub fn iter(&self) -> skiplist::Iter<'_> {
+        self.ordered.iter()
+    }
+
+    /// The members with ranks in `ranks`, lowest first.
+    pub fn range(&self, ranks: Range<usize>) -> skiplist::Iter<'_> {
+        self.ordered.range(ranks)
+    }
+
+    /// Removes the members with ranks in `ranks`.
+    pub fn remove_ranks(&mut self, ranks: Range<usize>) {
+        for (member, _) in self.ordered.remove_range(ranks) {
+            self.scores.remove(&member);
+        }
+    }
+
+    /// The ranks of the members scored within `range`.
+    pub fn score_ranks(&self, range: &ScoreRange) -> Range<usize> {
+        let start = self
+            .ordered
+            .partition_point(|score, _| !range.above_min(score));
+        let end = self
+            .ordered
+            .partition_point(|score, _| range.below_max(score));
+        start..end.max(start)
+    }
+
+    /// The ranks of the members within `range`, assuming they all share
+    /// one score.
+    pub fn lex_ranks(&self, range: &LexRange) -> Range<usize> {
+        let start = self
+            .ordered
+            .partition_point(|_, member| !range.above_min(member));
+        let end = self
+            .ordered
+            .partition_point(|_, member| range.below_max(member));
+        start..end.max(start)
+    }
+}
+
+/// What ZRANGE and its relatives select members by.
+pub enum RangeBy {
+    Rank(i64, i64),
+    Score(ScoreRange),
+    Lex(LexRange),
+}
+
+/// A ZRANGE selection: the members in a range of ranks, scores or members,
+/// walked from the highest score down with REV and optionally sliced by
+/// LIMIT's offset and count.
+pub struct RangeQuery {
+    by: RangeBy,
+    rev: bool,
+    limit: Option<(i64, i64)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RangeKind {
+    Rank,
+    Score,
+    Lex,
+}
+
+impl RangeQuery {
+    /// Parses the bounds and options following the key of ZRANGE or one of
+    /// the commands it generalizes, and whether WITHSCORES was given. The
+    /// older commands imply the range kind and direction by their name.
+    fn parse(
+        name: &str,
+        start: Vec<u8>,
+        stop: Vec<u8>,
+        args: &mut Arguments,
+    ) -> Result<(RangeQuery, bool), Error> {
+        let (mut kind, mut rev) = match name {
+            "zrangebyscore" => (RangeKind::Score, false),
+            "zrevrangebyscore" => (RangeKind::Score, true),
+            "zrangebylex" => (RangeKind::Lex, false),
+            "zrevrangebylex" => (RangeKind::Lex, true),
+            _ => (RangeKind::Rank, false),
+        };
+        let generic = name == "zrange";
+        let mut limit = None;
+        let mut with_scores = false;
+        while !args.is_empty() {
+            match args.next_string()?.to_lowercase().as_str() {
+                "byscore" if generic => kind = RangeKind::Score,
+                "bylex" if generic => kind = RangeKind::Lex,
+                "rev" if generic => rev = true,
+                "withscores" => with_scores = true,
+                "limit" if args.len() >= 2 => {
+                    let offset = args.next_int()?;
+                    let count = args.next_int()?;
+                    limit = Some((offset, count));
+                }
+                _ => return Err(Error::Syntax),
+            }
+        }
+        if limit.is_some() && kind == RangeKind::Rank {
+            return Err(Error::Argument(
+                "syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                    .to_owned(),
+            ));
+        }
+        if with_scores && kind == RangeKind::Lex {
+            return Err(Error::Argument(
+                "syntax error, WITHSCORES not supported in combination with BYLEX".to_owned(),
+            ));
+        }
+        // Reversed score and lex ranges name their upper bound first.
+        let (min, max) = if rev {
+            (&stop, &start)
+        } else {
+            (&start, &stop)
+        };
+        let by = match kind {
+            RangeKind::Score => RangeBy::Score(ScoreRange::parse(min, max)?),
+            RangeKind::Lex => RangeBy::Lex(LexRange::parse(min, max)?),
+            RangeKind::Rank => RangeBy::Rank(
+                Value::String(start).to_int()?,
+                Value::String(stop).to_int()?,
+            ),
+        };
+        let query = RangeQuery { by, rev, limit };
+        Ok((query, with_scores))
+    }
+
+    /// The selected members and their scores, in reply order.
+    pub fn select<'a>(&self, zset: &'a ZSet) -> Vec<(&'a Vec<u8>, f64)> {
+        let entries = zset.range(self.ranks(zset));
+        let entries: Box<dyn Iterator<Item = _>> = if self.rev {
+            Box::new(entries.rev())
+        } else {
+            Box::new(entries)
+        };
+        match self.limit {
+            // A negative offset selects nothing, a negative count everything
+            // past the offset.
+            Some((offset, _)) if offset < 0 => vec![],
+            Some((offset, count)) => entries
+                .skip(offset.try_into().unwrap_or(usize::MAX))
+                .take(count.try_into().unwrap_or(usize::MAX))
+                .collect(),
+            None => entries.collect(),
+        }
+    }
+
+    /// The ranks the range covers, before LIMIT is applied.
+    fn ranks(&self, zset: &ZSet) -> Range<usize> {
+        match &self.by {
+            RangeBy::Rank(start, stop) => {
+                let len = zset.len();
+                match ranges::indexes(*start, *stop, len) {
+                    // Reversed ranks count from the highest score.
+                    Some((start, stop)) if self.rev => len - 1 - stop..len - start,
+                    Some((start, stop)) => start..stop + 1,
+                    None => 0..0,
+                }
+            }
+            RangeBy::Score(range) => zset.score_ranks(range),
+            RangeBy::Lex(range) => zset.lex_ranks(range),
+        }
+    }
+
+    /// The event of the ZREMRANGEBY command removing the range.
+    fn remove_event(&self) -> &'static str {
+        match self.by {
+            RangeBy::Rank(..) => "zremrangebyrank",
+            RangeBy::Score(_) => "zremrangebyscore",
+            RangeBy::Lex(_) => "zremrangebylex",
+        }
+    }
+}
+
+/// Which end of a sorted set pops take members from.
+#[derive(Clone, Copy)]
+pub enum Extreme {
+    Min,
+    Max,
+}
+
+impl Extreme {
+    fn parse(args: &mut Arguments) -> Result<Extreme, Error> {
+        match args.next_string()?.to_lowercase().as_str() {
+            "min" => Ok(Extreme::Min),
+            "max" => Ok(Extreme::Max),
+            _ => Err(Error::Syntax),
+        }
+    }
+
+    fn pop_event(self) -> &'static str {
+        match self {
+            Extreme::Min => "zpopmin",
+            Extreme::Max => "zpopmax",
+        }
+    }
+}
+
+/// How ZUNION and ZINTER merge the scores a member has in several inputs.
+#[derive(Clone, Copy)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            // Opposite infinities cancel out to zero rather than NaN.
+            Aggregate::Sum => zero_nan(a + b),
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+fn zero_nan(score: f64) -> f64 {
+    if score.is_nan() {
+        0.0
+    } else {
+        score
+    }
+}
+
+/// The inputs of ZUNION, ZINTER and ZDIFF, with the weight each one's
+/// scores are multiplied by.
+pub struct Combination {
+    keys: Vec<Vec<u8>>,
+    weights: Vec<f64>,
+    aggregate: Aggregate,
+}
+
+impl Combination {
+    /// Parses `numkeys key [key ...]` and the options following it, and
+    /// whether WITHSCORES was given, which commands that `store` refuse.
+    fn parse(
+        operation: Operation,
+        store: bool,
+        args: &mut Arguments,
+    ) -> Result<(Combination, bool), Error> {
+        let keys = args.next_input_keys()?;
+        let mut weights = vec![1.0; keys.len()];
+        let mut aggregate = Aggregate::Sum;
+        let mut with_scores = false;
+        let weighted = operation != Operation::Diff;
+        while !args.is_empty() {
+            match args.next_string()?.to_lowercase().as_str() {
+                "weights" if weighted && args.len() >= keys.len() => {
+                    for weight in weights.iter_mut() {
+                        *weight = float::parse(&args.next_bytes()?).ok_or_else(|| {
+                            Error::Argument("weight value is not a float".to_owned())
+                        })?;
+                    }
+                }
+                "aggregate" if weighted && !args.is_empty() => {
+                    aggregate = match args.next_string()?.to_lowercase().as_str() {
+                        "sum" => Aggregate::Sum,
+                        "min" => Aggregate::Min,
+                        "max" => Aggregate::Max,
+                        _ => return Err(Error::Syntax),
+                    };
+                }
+                "withscores" if !store => with_scores = true,
+                _ => return Err(Error::Syntax),
+            }
+        }
+        let combination = Combination {
+            keys,
+            weights,
+            aggregate,
+        };
+        Ok((combination, with_scores))
+    }
+
+    fn apply(&self, storage: &Database, operation: Operation) -> Result<ZSet, Error> {
+        let inputs = self
+            .keys
+            .iter()
+            .map(|key| storage.get(key).map(Input::new).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+        let weighted = |index: usize, score: f64| zero_nan(score * self.weights[index]);
+        let mut scores: Dict<Vec<u8>, f64> = Dict::new();
+        match operation {
+            Operation::Union => {
+                for (index, input) in inputs.iter().enumerate() {
+                    let input = match input {
+                        Some(input) => input,
+                        None => continue,
+                    };
+                    for (member, score) in input.iter() {
+                        let score = weighted(index, score);
+                        match scores.get_mut(member) {
+                            Some(total) => *total = self.aggregate.apply(*total, score),
+                            None => {
+                                scores.insert(member.clone(), score);
+                            }
+                        }
+                    }
+                }
+            }
+            Operation::Inter => {
+                // A missing key is empty, which empties the intersection.
+                let mut inputs = match inputs.into_iter().collect::<Option<Vec<_>>>() {
+                    Some(inputs) => inputs.into_iter().enumerate().collect::<Vec<_>>(),
+                    None => return Ok(ZSet::new()),
+                };
+                // Only the smallest input has to be walked.
+                inputs.sort_by_key(|(_, input)| input.len());
+                let ((first, smallest), others) = inputs.split_first().expect("at least one key");
+                'members: for (member, score) in smallest.iter() {
+                    let mut total = weighted(*first, score);
+                    for (index, input) in others {
+                        match input.score(member) {
+                            Some(score) => {
+                                total = self.aggregate.apply(total, weighted(*index, score))
+                            }
+                            None => continue 'members,
+                        }
+                    }
+                    scores.insert(member.clone(), total);
+                }
+            }
+            Operation::Diff => {
+                let (first, others) = inputs.split_first().expect("at least one key");
+                if let Some(first) = first {
+                    for (member, score) in first.iter() {
+                        if others
+                            .iter()
+                            .flatten()
+                            .all(|input| input.score(member).is_none())
+                        {
+                            scores.insert(member.clone(), score);
+                        }
+                    }
+                }
+            }
+        }
+        let mut zset = ZSet::new();
+        for (member, score) in scores.iter() {
+            zset.insert(member.clone(), *score);
+        }
+        Ok(zset)
+    }
+}
+
+/// A source of members for the sorted set algebra. Plain sets take part
+/// with every score set to 1.
+enum Input<'a> {
+    ZSet(&'a ZSet),
+    Set(&'a Set),
+}
+
+impl<'a> Input<'a> {
+    fn new(stored: &'a StoredValue) -> Result<Input<'a>, Error> {
+        match &stored.data {
+            Data::ZSet(zset) => Ok(Input::ZSet(zset)),
+            Data::Set(set) => Ok(Input::Set(set)),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Input::ZSet(zset) => zset.len(),
+            Input::Set(set) => set.len(),
+        }
+    }
+
+    fn score(&self, member: &[u8]) -> Option<f64> {
+        match self {
+            Input::ZSet(zset) => zset.score(member),
+            Input::Set(set) => set.get(member).map(|_| 1.0),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&'a Vec<u8>, f64)> + 'a> {
+        match *self {
+            Input::ZSet(zset) => Box::new(zset.iter()),
+            Input::Set(set) => Box::new(set.iter().map(|(member, _)| (member, 1.0))),
+        }
+    }
+}
+
+/// The flags of ZADD, which decide which members it may add or update.
+#[derive(Default)]
+pub struct AddOptions {
+    /// Only add new members.
+    nx: bool,
+    /// Only update existing members.
+    xx: bool,
+    /// Only update to a greater score.
+    gt: bool,
+    /// Only update to a lower score.
+    lt: bool,
+    /// Count updated members along with the added ones.
+    ch: bool,
+    /// Add to the score instead of replacing it, like ZINCRBY.
+    incr: bool,
+}
+
+impl AddOptions {
+    /// The flags of GEOADD, which adds through ZADD.
+    pub fn geo(nx: bool, xx: bool, ch: bool) -> AddOptions {
+        AddOptions {
+            nx,
+            xx,
+            ch,
+            ..AddOptions::default()
+        }
+    }
+}
+
+pub enum ZSetCommand {
+    Add(Vec<u8>, AddOptions, Vec<(f64, Vec<u8>)>),
+    Score(Vec<u8>, Vec<u8>),
+    MScore(Vec<u8>, Vec<Vec<u8>>),
+    Card(Vec<u8>),
+    Range(Vec<u8>, RangeQuery, bool),
+    /// Stores the selection from the source key into the destination.
+    RangeStore(Vec<u8>, Vec<u8>, RangeQuery),
+    Count(Vec<u8>, ScoreRange),
+    LexCount(Vec<u8>, LexRange),
+    /// The rank of a member, counted from the highest score if set, and
+    /// whether its score is wanted too.
+    Rank(Vec<u8>, Vec<u8>, bool, bool),
+    Remove(Vec<u8>, Vec<Vec<u8>>),
+    RemoveRange(Vec<u8>, RangeQuery),
+    Pop(Vec<u8>, Extreme, Option<usize>),
+    MPop(Vec<Vec<u8>>, Extreme, usize),
+    Combine(Operation, Combination, bool),
+    Store(Operation, Vec<u8>, Combination),
+    Scan(Vec<u8>, u64, ScanOptions),
+    RandomMember(Vec<u8>, Option<i64>, bool),
+}
+
+pub enum BlockingZSetCommand {
+    /// Pops one member from the first non-empty sorted set.
+    Pop(Vec<Vec<u8>>, Extreme),
+    /// Pops up to a count of members from the first non-empty sorted set.
+    MPop(Vec<Vec<u8>>, Extreme, usize),
+}
+
+impl BlockingZSetCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<BlockingCommand>, Error> {
+        let (operation, timeout) = match name {
+            "bzpopmin" | "bzpopmax" => {
+                let extreme = if name == "bzpopmin" {
+                    Extreme::Min
+                } else {
+                    Extreme::Max
+                };
+                if args.len() < 2 {
+                    return Err(args.wrong_arity());
+                }
+                let mut keys = vec![];
+                while args.len() > 1 {
+                    keys.push(args.next_bytes()?);
+                }
+                let timeout = blocking::parse_timeout(args)?;
+                (BlockingZSetCommand::Pop(keys, extreme), timeout)
+            }
+            "bzmpop" => {
+                let timeout = blocking::parse_timeout(args)?;
+                let keys = args.next_keys()?;
+                let extreme = Extreme::parse(args)?;
+                let count = lists::parse_mpop_count(args)?;
+                (BlockingZSetCommand::MPop(keys, extreme, count), timeout)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(BlockingCommand {
+            operation: Blocking::ZSet(operation),
+            timeout,
+        }))
+    }
+
+    pub fn keys(&self) -> &[Vec<u8>] {
+        match self {
+            BlockingZSetCommand::Pop(keys, _) => keys,
+            BlockingZSetCommand::MPop(keys, _, _) => keys,
+        }
+    }
+
+    pub fn try_execute(
+        &self,
+        storage: &mut Database,
+        skip_other_types: bool,
+    ) -> Result<Option<Value>, Error> {
+        match self {
+            BlockingZSetCommand::Pop(keys, extreme) => {
+                for key in keys {
+                    let zset = match storage.get_mut(key) {
+                        Some(stored) => {
+                            blocking::skip_other_type(stored.zset_mut(), skip_other_types)?
+                        }
+                        None => None,
+                    };
+                    let popped = match zset {
+                        Some(zset) => pop(zset, *extreme, 1),
+                        None => continue,
+                    };
+                    if !popped.is_empty() {
+                        storage.notify(Class::ZSet, extreme.pop_event(), key);
+                    }
+                    storage.remove_if_empty(key);
+                    if let Some((member, score)) = popped.into_iter().next() {
+                        return Ok(Some(Value::array(vec![
+                            Value::String(key.clone()),
+                            Value::String(member),
+                            score_reply(Some(score)),
+                        ])));
+                    }
+                }
+                Ok(None)
+            }
+            BlockingZSetCommand::MPop(keys, extreme, count) => {
+                mpop(storage, keys, *extreme, *count, skip_other_types)
+            }
+        }
+    }
+}
+
+impl ZSetCommand {
+    pub fn parse(name: &str, args: &mut Arguments) -> Result<Option<ZSetCommand>, Error> {
+        let command = match name {
+            "zadd" => {
+                let key = args.next_bytes()?;
+                if args.len() < 2 {
+                    return Err(args.wrong_arity());
+                }
+                let rest = args.rest()?;
+                let mut options = AddOptions::default();
+                let mut flags = 0;
+                for flag in &rest {
+                    let flag = String::from_utf8_lossy(flag).to_lowercase();
+                    match flag.as_str() {
+                        "nx" => options.nx = true,
+                        "xx" => options.xx = true,
+                        "gt" => options.gt = true,
+                        "lt" => options.lt = true,
+                        "ch" => options.ch = true,
+                        "incr" => options.incr = true,
+                        _ => break,
+                    }
+                    flags += 1;
+                }
+                let elements = &rest[flags..];
+                if elements.is_empty() || elements.len() % 2 != 0 {
+                    return Err(Error::Syntax);
+                }
+                if options.nx && options.xx {
+                    return Err(Error::Argument(
+                        "XX and NX options at the same time are not compatible".to_owned(),
+                    ));
+                }
+                if (options.gt || options.lt) && (options.nx || (options.gt && options.lt)) {
+                    return Err(Error::Argument(
+                        "GT, LT, and/or NX options at the same time are not compatible".to_owned(),
+                    ));
+                }
+                if options.incr && elements.len() > 2 {
+                    return Err(Error::Argument(
+                        "INCR option supports a single increment-element pair".to_owned(),
+                    ));
+                }
+                let mut pairs = vec![];
+                for pair in elements.chunks(2) {
+                    let score = float::parse(&pair[0]).ok_or(Error::NotFloat)?;
+                    pairs.push((score, pair[1].clone()));
+                }
+                ZSetCommand::Add(key, options, pairs)
+            }
+            "zincrby" => {
+                let key = args.next_bytes()?;
+                let increment = float::parse(&args.next_bytes()?).ok_or(Error::NotFloat)?;
+                let member = args.next_bytes()?;
+                args.finish()?;
+                let options = AddOptions {
+                    incr: true,
+                    ..AddOptions::default()
+                };
+                ZSetCommand::Add(key, options, vec![(increment, member)])
+            }
+            "zscore" => {
+                let key = args.next_bytes()?;
+                let member = args.next_bytes()?;
+                args.finish()?;
+                ZSetCommand::Score(key, member)
+            }
+            "zmscore" => {
+                let key = args.next_bytes()?;
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                ZSetCommand::MScore(key, args.rest()?)
+            }
+            "zrange" | "zrangebyscore" | "zrevrangebyscore" | "zrangebylex" | "zrevrangebylex" => {
+                let key = args.next_bytes()?;
+                let start = args.next_bytes()?;
+                let stop = args.next_bytes()?;
+                let (query, with_scores) = RangeQuery::parse(name, start, stop, args)?;
+                ZSetCommand::Range(key, query, with_scores)
+            }
+            "zrangestore" => {
+                let destination = args.next_bytes()?;
+                let source = args.next_bytes()?;
+                let start = args.next_bytes()?;
+                let stop = args.next_bytes()?;
+                let (query, with_scores) = RangeQuery::parse("zrange", start, stop, args)?;
+                if with_scores {
+                    return Err(Error::Syntax);
+                }
+                ZSetCommand::RangeStore(destination, source, query)
+            }
+            "zrevrange" => {
+                let key = args.next_bytes()?;
+                let start = args.next_int()?;
+                let stop = args.next_int()?;
+                let with_scores = match args.len() {
+                    0 => false,
+                    1 if args.next_string()?.eq_ignore_ascii_case("withscores") => true,
+                    _ => return Err(Error::Syntax),
+                };
+                let query = RangeQuery {
+                    by: RangeBy::Rank(start, stop),
+                    rev: true,
+                    limit: None,
+                };
+                ZSetCommand::Range(key, query, with_scores)
+            }
+            "zcount" | "zlexcount" => {
+                let key = args.next_bytes()?;
+                let min = args.next_bytes()?;
+                let max = args.next_bytes()?;
+                args.finish()?;
+                if name == "zcount" {
+                    ZSetCommand::Count(key, ScoreRange::parse(&min, &max)?)
+                } else {
+                    ZSetCommand::LexCount(key, LexRange::parse(&min, &max)?)
+                }
+            }
+            "zrank" | "zrevrank" => {
+                let key = args.next_bytes()?;
+                let member = args.next_bytes()?;
+                let with_score = match args.len() {
+                    0 => false,
+                    1 if args.next_string()?.eq_ignore_ascii_case("withscore") => true,
+                    _ => return Err(Error::Syntax),
+                };
+                ZSetCommand::Rank(key, member, name == "zrevrank", with_score)
+            }
+            "zrem" => {
+                let key = args.next_bytes()?;
+                if args.is_empty() {
+                    return Err(args.wrong_arity());
+                }
+                ZSetCommand::Remove(key, args.rest()?)
+            }
+            "zremrangebyrank" | "zremrangebyscore" | "zremrangebylex" => {
+                let key = args.next_bytes()?;
+                let min = args.next_bytes()?;
+                let max = args.next_bytes()?;
+                args.finish()?;
+                let by = match name {
+                    "zremrangebyrank" => {
+                        RangeBy::Rank(Value::String(min).to_int()?, Value::String(max).to_int()?)
+                    }
+                    "zremrangebyscore" => RangeBy::Score(ScoreRange::parse(&min, &max)?),
+                    _ => RangeBy::Lex(LexRange::parse(&min, &max)?),
+                };
+                let query = RangeQuery {
+                    by,
+                    rev: false,
+                    limit: None,
+                };
+                ZSetCommand::RemoveRange(key, query)
+            }
+            "zpopmin" | "zpopmax" => {
+                let extreme = if name == "zpopmin" {
+                    Extreme::Min
+                } else {
+                    Extreme::Max
+                };
+                let key = args.next_bytes()?;
+                let count = if args.is_empty() {
+                    None
+                } else {
+                    let count = args.next_int()?.try_into().map_err(|_| {
+                        Error::Argument("value is out of range, must be positive".to_owned())
+                    })?;
+                    Some(count)
+                };
+                args.finish()?;
+                ZSetCommand::Pop(key, extreme, count)
+            }
+            "zmpop" => {
+                let keys = args.next_keys()?;
+                let extreme = Extreme::parse(args)?;
+                let count = lists::parse_mpop_count(args)?;
+                ZSetCommand::MPop(keys, extreme, count)
+            }
+            "zunion" | "zinter" | "zdiff" => {
+                let operation = operation(name);
+                let (combination, with_scores) = Combination::parse(operation, false, args)?;
+                ZSetCommand::Combine(operation, combination, with_scores)
+            }
+            "zunionstore" | "zinterstore" | "zdiffstore" => {
+                let operation = operation(name.trim_end_matches("store"));
+                let destination = args.next_bytes()?;
+                let (combination, _) = Combination::parse(operation, true, args)?;
+                ZSetCommand::Store(operation, destination, combination)
+            }
+            "zscan" => {
+                let key = args.next_bytes()?;
+                let cursor = scan::parse_cursor(&args.next_bytes()?)?;
+                let options = ScanOptions::parse(args, ScanTarget::ZSet)?;
+                ZSetCommand::Scan(key, cursor, options)
+            }
+            "zrandmember" => {
+                let key = args.next_bytes()?;
+                let count = if args.is_empty() {
+                    None
+                } else {
+                    Some(args.next_int()?)
+                };
+                let with_scores = match args.len() {
+                    0 => false,
+                    1 if args.next_string()?.eq_ignore_ascii_case("withscores") => true,
+                    _ => return Err(Error::Syntax),
+                };
+                ZSetCommand::RandomMember(key, count, with_scores)
+            }
+            "zcard" => {
+                let key = args.next_bytes()?;
+                args.finish()?;
+                ZSetCommand::Card(key)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+
+    pub fn execute(self, storage: &mut Database) -> Result<Value, Error> {
+        let response = match self {
+            ZSetCommand::Add(key, options, pairs) => {
+                // XX never creates the key.
+                match storage.get(&key) {
+                    Some(stored) => {
+                        stored.zset()?;
+                    }
+                    None if options.xx => {
+                        return Ok(if options.incr {
+                            Value::Nil
+                        } else {
+                            Value::Int(0)
+                        });
+                    }
+                    None => {}
+                }
+                let zset = storage
+                    .get_or_insert_with(&key, || Data::ZSet(ZSet::new()))
+                    .zset_mut()?;
+                let response = add(zset, &options, pairs);
+                if let Ok((_, true)) = response {
+                    let event = if options.incr { "zincr" } else { "zadd" };
+                    storage.notify(Class::ZSet, event, &key);
+                }
+                // A failed INCR may leave the set it created empty.
+                storage.remove_if_empty(&key);
+                response?.0
+            }
+            ZSetCommand::Score(key, member) => match storage.get(&key) {
+                Some(stored) => score_reply(stored.zset()?.score(&member)),
+                None => Value::Nil,
+            },
+            ZSetCommand::MScore(key, members) => {
+                let zset = match storage.get(&key) {
+                    Some(stored) => Some(stored.zset()?),
+                    None => None,
+                };
+                Value::array(
+                    members
+                        .iter()
+                        .map(|member| score_reply(zset.and_then(|zset| zset.score(member))))
+                        .collect(),
+                )
+            }
+            ZSetCommand::Card(key) => match storage.get(&key) {
+                Some(stored) => Value::Int(stored.zset()?.len().try_into()?),
+                None => Value::Int(0),
+            },
+            ZSetCommand::Range(key, query, with_scores) => match storage.get(&key) {
+                Some(stored) => range_reply(query.select(stored.zset()?), with_scores),
+                None => Value::array(vec![]),
+            },
+            ZSetCommand::RangeStore(destination, source, query) => {
+                let mut zset = ZSet::new();
+                if let Some(stored) = storage.get(&source) {
+                    for (member, score) in query.select(stored.zset()?) {
+                        zset.insert(member.clone(), score);
+                    }
+                }
+                let len = zset.len().try_into()?;
+                store(storage, destination, zset, "zrangestore");
+                Value::Int(len)
+            }
+            ZSetCommand::Count(key, range) => match storage.get(&key) {
+                Some(stored) => Value::Int(stored.zset()?.score_ranks(&range).len().try_into()?),
+                None => Value::Int(0),
+            },
+            ZSetCommand::LexCount(key, range) => match storage.get(&key) {
+                Some(stored) => Value::Int(stored.zset()?.lex_ranks(&range).len().try_into()?),
+                None => Value::Int(0),
+            },
+            ZSetCommand::Rank(key, member, rev, with_score) => {
+                let zset = match storage.get(&key) {
+                    Some(stored) => stored.zset()?,
+                    None if with_score => return Ok(Value::NilArray),
+                    None => return Ok(Value::Nil),
+                };
+                match zset.rank(&member) {
+                    Some(rank) => {
+                        let rank = if rev { zset.len() - 1 - rank } else { rank };
+                        let rank = Value::Int(rank.try_into()?);
+                        if with_score {
+                            Value::array(vec![rank, score_reply(zset.score(&member))])
+                        } else {
+                            rank
+                        }
+                    }
+                    None if with_score => Value::NilArray,
+                    None => Value::Nil,
+                }
+            }
+            ZSetCommand::Remove(key, members) => {
+                let zset = match storage.get_mut(&key) {
+                    Some(stored) => stored.zset_mut()?,
+                    None => return Ok(Value::Int(0)),
+                };
+                let removed = members
+                    .iter()
+                    .filter(|member| zset.remove(member).is_some())
+                    .count();
+                if removed > 0 {
+                    storage.notify(Class::ZSet, "zrem", &key);
+                }
+                storage.remove_if_empty(&key);
+                Value::Int(removed.try_into()?)
+            }
+            ZSetCommand::RemoveRange(key, query) => {
+                let zset = match storage.get_mut(&key) {
+                    Some(stored) => stored.zset_mut()?,
+                    None => return Ok(Value::Int(0)),
+                };
+                let ranks = query.ranks(zset);
+                let removed = ranks.len();
+                zset.remove_ranks(ranks);
+                if removed > 0 {
+                    storage.notify(Class::ZSet, query.remove_event(), &key);
+                }
+                storage.remove_if_empty(&key);
+                Value::Int(removed.try_into()?)
+            }
+            ZSetCommand::Pop(key, extreme, count) => {
+                let zset = match storage.get_mut(&key) {
+                    Some(stored) => stored.zset_mut()?,
+                    None => return Ok(Value::array(vec![])),
+                };
+                let popped = pop(zset, extreme, count.unwrap_or(1));
+                if !popped.is_empty() {
+                    storage.notify(Class::ZSet, extreme.pop_event(), &key);
+                }
+                storage.remove_if_empty(&key);
+                let mut reply = vec![];
+                for (member, score) in popped {
+                    reply.push(Value::String(member));
+                    reply.push(score_reply(Some(score)));
+                }
+                Value::array(reply)
+            }
+            ZSetCommand::MPop(keys, extreme, count) => {
+                mpop(storage, &keys, extreme, count, false)?.unwrap_or(Value::NilArray)
+            }
+            ZSetCommand::Combine(operation, combination, with_scores) => {
+                let zset = combination.apply(storage, operation)?;
+                range_reply(zset.iter().collect(), with_scores)
+            }
+            ZSetCommand::Store(operation, destination, combination) => {
+                let zset = combination.apply(storage, operation)?;
+                let len = zset.len().try_into()?;
+                let event = match operation {
+                    Operation::Union => "zunionstore",
+                    Operation::Inter => "zinterstore",
+                    Operation::Diff => "zdiffstore",
+                };
+                store(storage, destination, zset, event);
+                Value::Int(len)
+            }
+            ZSetCommand::Scan(key, cursor, options) => {
+                let zset = match storage.get(&key) {
+                    Some(stored) => stored.zset()?,
+                    None => return Ok(scan::scan_reply(0, vec![])),
+                };
+                let mut batch = vec![];
+                let cursor = options.scan(cursor, |cursor, visited| {
+                    zset.scan(cursor, |member, score| {
+                        *visited += 1;
+                        if options.matches(member) {
+                            batch.push(Value::String(member.clone()));
+                            batch.push(score_reply(Some(score)));
+                        }
+                    })
+                });
+                scan::scan_reply(cursor, batch)
+            }
+            ZSetCommand::RandomMember(key, count, with_scores) => {
+                let zset = match storage.get(&key) {
+                    Some(stored) => stored.zset()?,
+                    None if count.is_some() => return Ok(Value::array(vec![])),
+                    None => return Ok(Value::Nil),
+                };
+                let count = match count {
+                    Some(count) => count,
+                    None => {
+                        return Ok(zset
+                            .random_entry()
+                            .map_or(Value::Nil, |(member, _)| Value::String(member.clone())))
+                    }
+                };
+                // A negative count may repeat members, a positive one may not.
+                let entries = if count >= 0 {
+                    zset.sample(count.try_into()?)
+                } else {
+                    let count = count.unsigned_abs().try_into()?;
+                    (0..count).filter_map(|_| zset.random_entry()).collect()
+                };
+                range_reply(entries, with_scores)
+            }
+        };
+        Ok(response)
+    }
+}
+
+fn operation(name: &str) -> Operation {
+    match name {
+        "zunion" => Operation::Union,
+        "zinter" => Operation::Inter,
+        _ => Operation::Diff,
+    }
+}
+
+/// Stores the result of a command at `destination`, which an empty one
+/// deletes instead.
+pub fn store(storage: &mut Database, destination: Vec<u8>, zset: ZSet, event: &'static str) {
+    if zset.is_empty() {
+        if storage.remove(&destination).is_some() {
+            storage.notify(Class::Generic, "del", &destination);
+        }
+    } else {
+        storage.notify(Class::ZSet, event, &destination);
+        storage.insert(destination, StoredValue::new(Data::ZSet(zset), None));
+    }
+}
+
+/// Removes up to `count` members from one end, in the order they are popped.
+fn pop(zset: &mut ZSet, extreme: Extreme, count: usize) -> Vec<(Vec<u8>, f64)> {
+    let len = zset.len();
+    let count = count.min(len);
+    let ranks = match extreme {
+        Extreme::Min => 0..count,
+        Extreme::Max => len - count..len,
+    };
+    let mut popped: Vec<_> = zset
+        .range(ranks.clone())
+        .map(|(member, score)| (member.clone(), score))
+        .collect();
+    if let Extreme::Max = extreme {
+        popped.reverse();
+    }
+    zset.remove_ranks(ranks);
+    popped
+}
+
+/// Pops up to `count` members from the first of `keys` holding a sorted
+/// set, replying with the key and the member and score pairs.
+fn mpop(
+    storage: &mut Database,
+    keys: &[Vec<u8>],
+    extreme: Extreme,
+    count: usize,
+    skip_other_types: bool,
+) -> Result<Option<Value>, Error> {
+    for key in keys {
+        let zset = match storage.get_mut(key) {
+            Some(stored) => blocking::skip_other_type(stored.zset_mut(), skip_other_types)?,
+            None => None,
+        };
+        let zset = match zset {
+            Some(zset) => zset,
+            None => continue,
+        };
+        let popped: Vec<_> = pop(zset, extreme, count)
+            .into_iter()
+            .map(|(member, score)| {
+                Value::array(vec![Value::String(member), score_reply(Some(score))])
+            })
+            .collect();
+        if !popped.is_empty() {
+            storage.notify(Class::ZSet, extreme.pop_event(), key);
+        }
+        storage.remove_if_empty(key);
+        return Ok(Some(Value::array(vec![
+            Value::String(key.clone()),
+            Value::array(popped),
+        ])));
+    }
+    Ok(None)
+}
+
+fn score_reply(score: Option<f64>) -> Value {
+    score.map_or(Value::Nil, |score| {
+        Value::String(float::format(score).into_bytes())
+    })
+}
+
+/// Members, each followed by its score if `with_scores` is set.
+fn range_reply(entries: Vec<(&Vec<u8>, f64)>, with_scores: bool) -> Value {
+    let mut reply = vec![];
+    for (member, score) in entries {
+        reply.push(Value::String(member.clone()));
+        if with_scores {
+            reply.push(Value::String(float::format(score).into_bytes()));
+        }
+    }
+    Value::array(reply)
+}
+
+/// Applies ZADD to `zset`, also returning whether anything changed. With
+/// INCR the reply is the new score, or nil if the flags ruled the update out.
+fn add(
+    zset: &mut ZSet,
+    options: &AddOptions,
+    pairs: Vec<(f64, Vec<u8>)>,
+) -> Result<(Value, bool), Error> {
+    let mut added = 0;
+    let mut changed = 0;
+    let mut incremented = None;
+    for (score, member) in pairs {
+        let current = zset.score(&member);
+        let score = match current {
+            Some(current) if options.incr => current + score,
+            _ => score,
+        };
+        if score.is_nan() {
+            return Err(Error::Argument(
+                "resulting score is not a number (NaN)".to_owned(),
+            ));
+        }
+        match current {
+            Some(_) if options.nx => continue,
+            Some(current)
+                if (options.gt && score <= current) || (options.lt && score >= current) =>
+            {
+                continue
+            }
+            Some(current) => {
+                if score != current {
+                    zset.insert(member, score);
+                    changed += 1;
+                }
+            }
+            None if options.xx => continue,
+            None => {
+                zset.insert(member, score);
+                added += 1;
+            }
+        }
+        incremented = Some(score);
+    }
+    let modified = added + changed > 0;
+    if options.incr {
+        return Ok((score_reply(incremented), modified));
+    }
+    let count = if options.ch { added + changed } else { added };
+    Ok((Value::Int(count), modified))
+}